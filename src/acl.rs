@@ -0,0 +1,860 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fs, iter,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lunatic::{abstract_process, process::ProcessRef};
+use lunatic_log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    client::{ClientProcess, ClientProcessHandler},
+    commands::{self, COMMANDS},
+    glob::glob_match,
+    types::RedisKey,
+};
+
+const NO_ACLFILE: &str = "This Redis instance is not configured to use an ACL file";
+
+/// Max number of entries kept in the ACL LOG
+const ACL_LOG_MAX_LEN: usize = 128;
+
+/// Commands changing every database, denied to the users bound to a database
+const CROSS_DATABASE_COMMANDS: [&str; 1] = ["flushall"];
+
+/// Denials of the same kind within this time are grouped in the same log entry
+const ACL_LOG_GROUPING_MS: u64 = 60_000;
+
+/// All the known categories, sorted
+pub fn categories() -> Vec<&'static str> {
+    let categories: BTreeSet<_> = COMMANDS
+        .iter()
+        .flat_map(|handler| handler.categories().iter().copied())
+        .collect();
+    categories.into_iter().collect()
+}
+
+/// Commands in the category, `all` includes every command
+pub fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    let commands: Vec<_> = COMMANDS
+        .iter()
+        .filter(|handler| category == "all" || handler.categories().contains(&category))
+        .map(|handler| handler.name())
+        .collect();
+    (!commands.is_empty()).then_some(commands)
+}
+
+fn hash_password(password: &str) -> String {
+    format!("{:x}", Sha256::digest(password.as_bytes()))
+}
+
+/// Compare two hashes in constant time to avoid timing attacks
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or_default();
+        let y = b.get(i).copied().unwrap_or_default();
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Denied command or failed authentication, shown by ACL LOG
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclLogEntry {
+    pub entry_id: u64,
+    pub count: u64,
+    /// Why it was denied: `command`, `key` or `auth`
+    pub reason: String,
+    /// Denied command or key
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+
+impl AclLogEntry {
+    pub fn age_seconds(&self) -> f64 {
+        now_ms().saturating_sub(self.created_ms) as f64 / 1000.0
+    }
+}
+
+/// Commands that can be run on some keys and channels: the root permissions of a user, or
+/// one of its selectors like `(~cache:* +get)`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Selector {
+    /// Allowed commands
+    commands: BTreeSet<String>,
+    /// Command rules in the order they were applied, to describe the user
+    command_rules: Vec<String>,
+    key_patterns: Vec<String>,
+    channel_patterns: Vec<String>,
+}
+
+impl Selector {
+    fn new() -> Self {
+        Self {
+            commands: BTreeSet::new(),
+            command_rules: vec!["-@all".into()],
+            key_patterns: vec![],
+            channel_patterns: vec![],
+        }
+    }
+
+    /// Apply a rule of the commands, keys or channels. Returns false for the other rules
+    fn set_rule(&mut self, rule: &str) -> Result<bool, &'static str> {
+        match rule.to_lowercase().as_ref() {
+            "allkeys" => self.key_patterns = vec!["*".into()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allchannels" => self.channel_patterns = vec!["*".into()],
+            "resetchannels" => self.channel_patterns.clear(),
+            "allcommands" => return self.set_rule("+@all"),
+            "nocommands" => return self.set_rule("-@all"),
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                ("~", pattern) => self.key_patterns.push(pattern.into()),
+                ("&", pattern) => self.channel_patterns.push(pattern.into()),
+                (sign @ ("+" | "-"), command) => {
+                    let command = command.to_lowercase();
+                    let commands = match command.strip_prefix('@') {
+                        Some(category) => category_commands(category),
+                        None => commands::lookup(&command).map(|handler| vec![handler.name()]),
+                    }
+                    .ok_or("Unknown command or category name in ACL")?;
+                    for name in commands {
+                        if sign == "+" {
+                            self.commands.insert(name.into());
+                        } else {
+                            self.commands.remove(name);
+                        }
+                    }
+                    // Allowing or denying everything makes the previous rules meaningless
+                    if command == "@all" {
+                        self.command_rules.clear();
+                    }
+                    self.command_rules.push(format!("{sign}{command}"));
+                }
+                _ => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    fn can_access_key(&self, key: &[u8]) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), key))
+    }
+
+    pub fn command_rules(&self) -> String {
+        self.command_rules.join(" ")
+    }
+
+    pub fn key_rules(&self) -> String {
+        let rules: Vec<_> = self.key_patterns.iter().map(|p| format!("~{p}")).collect();
+        rules.join(" ")
+    }
+
+    pub fn channel_rules(&self) -> String {
+        let rules: Vec<_> = self
+            .channel_patterns
+            .iter()
+            .map(|p| format!("&{p}"))
+            .collect();
+        rules.join(" ")
+    }
+
+    /// Key, channel and command rules, the non empty ones
+    fn rules(&self) -> Vec<String> {
+        [self.key_rules(), self.channel_rules(), self.command_rules()]
+            .into_iter()
+            .filter(|rule| !rule.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    pub nopass: bool,
+    /// Sha256 of the passwords, as hex strings
+    pub passwords: BTreeSet<String>,
+    root: Selector,
+    /// Other permissions of the user, a command is allowed when the root permissions or one
+    /// of the selectors allow it with all its keys
+    pub selectors: Vec<Selector>,
+    /// Only database the user can use, selected when it authenticates. Commands spanning
+    /// every database, like FLUSHALL, are denied
+    pub database: Option<usize>,
+}
+
+impl User {
+    /// New users are created disabled and without any permission
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            root: Selector::new(),
+            selectors: vec![],
+            database: None,
+        }
+    }
+
+    /// Apply an ACL rule, ie. `on`, `>password`, `~pattern`, `+@read`, `-del`, or add a
+    /// selector with its rules between parentheses, ie. `(~cache:* +get)`
+    pub fn set_rule(&mut self, rule: &str) -> Result<(), String> {
+        let error = |reason: &str| format!("Error in ACL SETUSER modifier '{rule}': {reason}");
+        if let Some(rules) = rule
+            .strip_prefix('(')
+            .and_then(|rule| rule.strip_suffix(')'))
+        {
+            let mut selector = Selector::new();
+            for rule in rules.split_whitespace() {
+                if !selector.set_rule(rule).map_err(error)? {
+                    return Err(error("Syntax error"));
+                }
+            }
+            self.selectors.push(selector);
+            return Ok(());
+        }
+        if self.root.set_rule(rule).map_err(error)? {
+            return Ok(());
+        }
+        match rule.to_lowercase().as_ref() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "alldbs" => self.database = None,
+            "clearselectors" => self.selectors.clear(),
+            "reset" => {
+                for rule in [
+                    "resetpass",
+                    "resetkeys",
+                    "resetchannels",
+                    "alldbs",
+                    "clearselectors",
+                    "nocommands",
+                    "off",
+                ] {
+                    self.set_rule(rule)?;
+                }
+            }
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    self.passwords.insert(hash_password(password));
+                    self.nopass = false;
+                }
+                ("<", password) => {
+                    if !self.passwords.remove(&hash_password(password)) {
+                        return Err(error("no such password"));
+                    }
+                }
+                ("#", hash) => {
+                    if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(error("Syntax error"));
+                    }
+                    self.passwords.insert(hash.to_lowercase());
+                    self.nopass = false;
+                }
+                ("!", hash) => {
+                    if !self.passwords.remove(&hash.to_lowercase()) {
+                        return Err(error("no such password"));
+                    }
+                }
+                _ if rule.to_lowercase().starts_with("db=") => {
+                    let db = rule[3..].parse().map_err(|_| error("Syntax error"))?;
+                    self.database = Some(db);
+                }
+                _ => return Err(error("Syntax error")),
+            },
+        }
+        Ok(())
+    }
+
+    /// Root permissions followed by the selectors
+    fn permissions(&self) -> impl Iterator<Item = &Selector> {
+        iter::once(&self.root).chain(&self.selectors)
+    }
+
+    pub fn can_run(&self, command: &str) -> bool {
+        let all_databases = CROSS_DATABASE_COMMANDS.contains(&command);
+        let allowed = self.permissions().any(|p| p.commands.contains(command));
+        allowed && !(all_databases && self.database.is_some())
+    }
+
+    pub fn can_access_database(&self, db: usize) -> bool {
+        self.database.map_or(true, |database| database == db)
+    }
+
+    /// Key denied to the command, when none of the permissions allowing the command allows
+    /// all its keys. The first key denied by the first of them is reported
+    pub fn denied_key<'a>(&self, command: &str, keys: &'a [RedisKey]) -> Option<&'a RedisKey> {
+        let mut denied = None;
+        for permissions in self.permissions().filter(|p| p.commands.contains(command)) {
+            match keys.iter().find(|key| !permissions.can_access_key(&key.0)) {
+                Some(key) => {
+                    denied.get_or_insert(key);
+                }
+                None => return None,
+            }
+        }
+        denied
+    }
+
+    /// Check the user can run the command with the given keys in the database, the denial
+    /// is returned with its ACL LOG reason and object
+    pub fn check(&self, command: &str, db: usize, keys: &[RedisKey]) -> Result<(), Denial> {
+        let denial = |reason, object, message| Denial {
+            reason,
+            object,
+            message,
+        };
+        if !self.can_run(command) {
+            return Err(denial(
+                "command",
+                command.into(),
+                format!(
+                    "User {} has no permissions to run the '{command}' command",
+                    self.name
+                ),
+            ));
+        }
+        if !self.can_access_database(db) {
+            return Err(denial(
+                "database",
+                db.to_string(),
+                format!("No permissions to access the database {db}"),
+            ));
+        }
+        if let Some(key) = self.denied_key(command, keys) {
+            return Err(denial(
+                "key",
+                key.to_string(),
+                "No permissions to access a key".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        let hash = hash_password(password);
+        // Check all the passwords to keep the time constant
+        self.passwords.iter().fold(false, |valid, current| {
+            constant_time_eq(current.as_bytes(), hash.as_bytes()) | valid
+        })
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn command_rules(&self) -> String {
+        self.root.command_rules()
+    }
+
+    pub fn key_rules(&self) -> String {
+        self.root.key_rules()
+    }
+
+    pub fn channel_rules(&self) -> String {
+        self.root.channel_rules()
+    }
+
+    pub fn database_rule(&self) -> String {
+        self.database
+            .map(|db| format!("db={db}"))
+            .unwrap_or_default()
+    }
+
+    /// Describe the user as a list of rules, the format used by ACL LIST
+    pub fn describe(&self) -> String {
+        let mut rules = vec![format!("user {}", self.name)];
+        rules.extend(self.flags().iter().map(|flag| flag.to_string()));
+        rules.extend(self.passwords.iter().map(|hash| format!("#{hash}")));
+        let database = self.database_rule();
+        if !database.is_empty() {
+            rules.push(database);
+        }
+        rules.extend(self.root.rules());
+        for selector in &self.selectors {
+            rules.push(format!("({})", selector.rules().join(" ")));
+        }
+        rules.join(" ")
+    }
+}
+
+/// Command denied to a user
+#[derive(Debug)]
+pub struct Denial {
+    /// Reason and object of the ACL LOG entry
+    pub reason: &'static str,
+    pub object: String,
+    pub message: String,
+}
+
+/// Access control lists, keeps the users and their permissions
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    /// Newest entries first
+    log: VecDeque<AclLogEntry>,
+    next_log_id: u64,
+    requirepass: Option<String>,
+    /// File where the users are persisted, in the redis ACL file format
+    aclfile: Option<String>,
+    /// Clients keeping a copy of the permissions of their user, by client id
+    clients: BTreeMap<u64, ProcessRef<ClientProcess>>,
+}
+
+/// Check the users of the ACL file can be loaded, the error is `path:line: reason`
+pub fn check_aclfile(path: &str) -> Result<(), String> {
+    Acl::new(None, None).read_users(path).map(|_| ())
+}
+
+impl Acl {
+    /// Only the default user, the users of the aclfile are loaded separately
+    fn new(requirepass: Option<String>, aclfile: Option<String>) -> Self {
+        let mut acl = Self {
+            users: BTreeMap::new(),
+            log: VecDeque::new(),
+            next_log_id: 0,
+            requirepass,
+            aclfile,
+            clients: BTreeMap::new(),
+        };
+        let default = acl.default_user();
+        acl.users.insert(default.name.clone(), default);
+        acl
+    }
+
+    /// Add an entry to the ACL LOG, or update a recent entry for the same denial
+    fn log(&mut self, reason: &str, object: String, username: String, client_info: String) {
+        let now = now_ms();
+        let recent = self.log.iter().position(|entry| {
+            entry.reason == reason
+                && entry.object == object
+                && entry.username == username
+                && now.saturating_sub(entry.updated_ms) < ACL_LOG_GROUPING_MS
+        });
+        let entry = match recent.and_then(|position| self.log.remove(position)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.updated_ms = now;
+                entry.client_info = client_info;
+                entry
+            }
+            None => {
+                self.next_log_id += 1;
+                AclLogEntry {
+                    entry_id: self.next_log_id - 1,
+                    count: 1,
+                    reason: reason.into(),
+                    object,
+                    username,
+                    client_info,
+                    created_ms: now,
+                    updated_ms: now,
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(ACL_LOG_MAX_LEN);
+    }
+
+    /// The users changed, the clients reload the permissions of their user
+    fn invalidate(&self) {
+        for client in self.clients.values() {
+            client.reload_user();
+        }
+    }
+
+    /// The default user is created with all the permissions, using requirepass as password
+    fn default_user(&self) -> User {
+        let mut default = User::new("default".into());
+        let password = match &self.requirepass {
+            Some(password) => format!(">{password}"),
+            None => "nopass".into(),
+        };
+        for rule in [
+            "on",
+            password.as_str(),
+            "allkeys",
+            "allchannels",
+            "allcommands",
+        ] {
+            default.set_rule(rule).unwrap();
+        }
+        default
+    }
+
+    /// Read the users from the ACL file, the default user is kept unless the file defines it
+    fn read_users(&self, path: &str) -> Result<BTreeMap<String, User>, String> {
+        let content = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let mut defined = BTreeSet::new();
+        let default = self.default_user();
+        let mut users = BTreeMap::from([(default.name.clone(), default)]);
+        for (number, line) in content.lines().enumerate() {
+            let error = |reason: &str| format!("{path}:{}: {reason}", number + 1);
+            let mut parts = line.split_whitespace();
+            let username = match (parts.next(), parts.next()) {
+                (None, _) => continue,
+                (Some("user"), Some(username)) => username.to_string(),
+                _ => {
+                    return Err(error(
+                        "should start with user keyword followed by the username",
+                    ))
+                }
+            };
+            if !defined.insert(username.clone()) {
+                return Err(error(&format!("duplicate user '{username}' found")));
+            }
+            // The rules of a selector are separated by spaces too, ie. `(~cache:* +get)`
+            let mut rules: Vec<String> = vec![];
+            for part in parts {
+                match rules.last_mut() {
+                    Some(selector) if selector.starts_with('(') && !selector.ends_with(')') => {
+                        selector.push(' ');
+                        selector.push_str(part);
+                    }
+                    _ => rules.push(part.into()),
+                }
+            }
+            let mut user = User::new(username.clone());
+            for rule in rules {
+                user.set_rule(&rule).map_err(|err| error(&err))?;
+            }
+            users.insert(username, user);
+        }
+        Ok(users)
+    }
+}
+
+#[abstract_process(visibility = pub)]
+impl Acl {
+    /// Users are loaded from the aclfile if one is configured, it's checked before the
+    /// server is started. If it became invalid meanwhile no user can authenticate
+    #[init]
+    fn init(_: ProcessRef<Self>, args: (Option<String>, Option<String>)) -> Self {
+        let (requirepass, aclfile) = args;
+        let mut acl = Self::new(requirepass, aclfile);
+        if let Some(path) = &acl.aclfile {
+            match acl.read_users(path) {
+                Ok(users) => acl.users = users,
+                Err(err) => {
+                    error!("Can't load the users: {err}");
+                    acl.users.clear();
+                }
+            }
+        }
+        acl
+    }
+
+    /// Check the credentials of a user, without password only users with nopass are valid
+    /// failed attempts with a password are added to the ACL LOG
+    #[handle_request]
+    fn authenticate(
+        &mut self,
+        username: String,
+        password: Option<String>,
+        client_info: String,
+    ) -> bool {
+        let valid = match (self.users.get(&username), &password) {
+            (Some(user), _) if user.enabled && user.nopass => true,
+            (Some(user), Some(password)) if user.enabled => user.check_password(password),
+            _ => false,
+        };
+        if !valid && password.is_some() {
+            self.log("auth", "AUTH".into(), username, client_info);
+        }
+        valid
+    }
+
+    /// Keep the client updated when the users change
+    #[handle_message]
+    fn subscribe(&mut self, id: u64, client: ProcessRef<ClientProcess>) {
+        self.clients.insert(id, client);
+    }
+
+    #[handle_message]
+    fn unsubscribe(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    /// Add a command denied by the permissions of a client to the ACL LOG
+    #[handle_message]
+    fn log_denial(
+        &mut self,
+        reason: String,
+        object: String,
+        username: String,
+        client_info: String,
+    ) {
+        self.log(&reason, object, username, client_info);
+    }
+
+    /// Latest entries of the ACL LOG
+    #[handle_request]
+    fn log_entries(&mut self, count: usize) -> Vec<AclLogEntry> {
+        self.log.iter().take(count).cloned().collect()
+    }
+
+    #[handle_request]
+    fn reset_log(&mut self) {
+        self.log.clear();
+    }
+
+    /// Create or modify a user, rules are applied only if all of them are valid
+    #[handle_request]
+    fn set_user(&mut self, username: String, rules: Vec<String>) -> Result<(), String> {
+        let mut user = self
+            .users
+            .get(&username)
+            .cloned()
+            .unwrap_or_else(|| User::new(username.clone()));
+        for rule in rules {
+            user.set_rule(&rule)?;
+        }
+        self.users.insert(username, user);
+        self.invalidate();
+        Ok(())
+    }
+
+    #[handle_request]
+    fn get_user(&mut self, username: String) -> Option<User> {
+        self.users.get(&username).cloned()
+    }
+
+    /// Remove users, returns how many were removed
+    #[handle_request]
+    fn del_users(&mut self, usernames: Vec<String>) -> Result<i64, String> {
+        if usernames.iter().any(|username| username == "default") {
+            return Err("The 'default' user cannot be removed".into());
+        }
+        let removed = usernames
+            .iter()
+            .filter(|username| self.users.remove(*username).is_some())
+            .count();
+        if removed > 0 {
+            self.invalidate();
+        }
+        Ok(removed as i64)
+    }
+
+    #[handle_request]
+    fn users(&mut self) -> Vec<User> {
+        self.users.values().cloned().collect()
+    }
+
+    /// Replace all the users with the ones in the ACL file, nothing changes if the file is invalid
+    #[handle_request]
+    fn load(&mut self) -> Result<(), String> {
+        let path = self.aclfile.as_ref().ok_or(NO_ACLFILE)?;
+        self.users = self.read_users(path)?;
+        self.invalidate();
+        Ok(())
+    }
+
+    #[handle_request]
+    fn save(&mut self) -> Result<(), String> {
+        let path = self.aclfile.as_ref().ok_or(NO_ACLFILE)?;
+        let mut content = String::new();
+        for user in self.users.values() {
+            content.push_str(&user.describe());
+            content.push('\n');
+        }
+        fs::write(path, content)
+            .map_err(|err| format!("There was an error trying to save the ACLs: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BulkString;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("user".into());
+        for rule in rules {
+            user.set_rule(rule).unwrap();
+        }
+        user
+    }
+
+    fn keys(keys: &[&str]) -> Vec<RedisKey> {
+        keys.iter()
+            .map(|key| BulkString(key.to_string().into()))
+            .collect()
+    }
+
+    #[test]
+    fn new_users_are_disabled_without_permissions() {
+        let user = user(&[]);
+        assert_eq!(user.describe(), "user user off -@all");
+        assert!(!user.can_run("get"));
+        assert!(!user.check_password(""));
+    }
+
+    #[test]
+    fn command_rules_apply_in_order() {
+        let reader = user(&["+@read", "-get", "+set"]);
+        assert!(reader.can_run("mget") && reader.can_run("set"));
+        assert!(!reader.can_run("get") && !reader.can_run("del"));
+        assert_eq!(reader.command_rules(), "-@all +@read -get +set");
+        // Allowing everything makes the previous rules meaningless
+        let admin = user(&["+get", "allcommands", "-del"]);
+        assert_eq!(admin.command_rules(), "+@all -del");
+        assert!(admin.can_run("get") && !admin.can_run("del"));
+    }
+
+    #[test]
+    fn unknown_rules_are_errors() {
+        let mut user = user(&[]);
+        assert_eq!(
+            user.set_rule("+nosuchcommand").unwrap_err(),
+            "Error in ACL SETUSER modifier '+nosuchcommand': Unknown command or category name \
+             in ACL"
+        );
+        assert_eq!(
+            user.set_rule("+@nosuchcategory").unwrap_err(),
+            "Error in ACL SETUSER modifier '+@nosuchcategory': Unknown command or category \
+             name in ACL"
+        );
+        for rule in ["bogus", "db=x", "#1234", "(~a on)"] {
+            assert_eq!(
+                user.set_rule(rule).unwrap_err(),
+                format!("Error in ACL SETUSER modifier '{rule}': Syntax error")
+            );
+        }
+        assert!(user.selectors.is_empty());
+    }
+
+    #[test]
+    fn passwords_are_kept_hashed() {
+        let mut user = user(&["on", ">secret", ">other"]);
+        assert!(user.check_password("secret") && user.check_password("other"));
+        assert!(!user.check_password("wrong"));
+        assert!(!user.passwords.contains("secret"));
+        user.set_rule("<other").unwrap();
+        assert!(!user.check_password("other"));
+        assert_eq!(
+            user.set_rule("<other").unwrap_err(),
+            "Error in ACL SETUSER modifier '<other': no such password"
+        );
+        let hash = hash_password("hashed").to_uppercase();
+        user.set_rule(&format!("#{hash}")).unwrap();
+        assert!(user.check_password("hashed"));
+        user.set_rule(&format!("!{hash}")).unwrap();
+        assert!(!user.check_password("hashed"));
+        user.set_rule("nopass").unwrap();
+        assert!(user.nopass && user.passwords.is_empty());
+        assert_eq!(user.flags(), ["on", "nopass"]);
+        user.set_rule(">again").unwrap();
+        assert!(!user.nopass);
+    }
+
+    #[test]
+    fn key_patterns_are_globs() {
+        let cache = user(&["+@all", "~cache:*", "~user:?"]);
+        assert_eq!(cache.key_rules(), "~cache:* ~user:?");
+        assert_eq!(cache.denied_key("get", &keys(&["cache:a", "user:1"])), None);
+        assert_eq!(
+            cache.denied_key("mget", &keys(&["cache:a", "user:10", "other"])),
+            Some(&keys(&["user:10"])[0])
+        );
+        let keyless = user(&["+@all", "allkeys", "resetkeys"]);
+        assert_eq!(
+            keyless.denied_key("get", &keys(&["a"])),
+            Some(&keys(&["a"])[0])
+        );
+        assert_eq!(keyless.denied_key("ping", &keys(&[])), None);
+    }
+
+    #[test]
+    fn selectors_allow_more_commands_on_other_keys() {
+        let user = user(&["on", "~public:*", "+@read", "(~private:* +get)"]);
+        assert!(user.can_run("get") && user.can_run("exists") && !user.can_run("set"));
+        assert_eq!(user.denied_key("get", &keys(&["private:a"])), None);
+        assert_eq!(
+            user.denied_key("exists", &keys(&["private:a"])),
+            Some(&keys(&["private:a"])[0])
+        );
+        // The keys of a command must all be allowed by the same permissions
+        assert_eq!(
+            user.denied_key("mget", &keys(&["public:a", "private:a"])),
+            Some(&keys(&["private:a"])[0])
+        );
+        assert_eq!(
+            user.describe(),
+            "user user on ~public:* -@all +@read (~private:* -@all +get)"
+        );
+        let mut user = user;
+        user.set_rule("clearselectors").unwrap();
+        assert!(user.selectors.is_empty());
+    }
+
+    #[test]
+    fn databases_bind_the_users() {
+        let mut user = user(&["+@all", "DB=1"]);
+        assert_eq!(user.database, Some(1));
+        assert!(user.can_access_database(1) && !user.can_access_database(0));
+        assert!(user.can_run("flushdb") && !user.can_run("flushall"));
+        assert_eq!(user.database_rule(), "db=1");
+        user.set_rule("alldbs").unwrap();
+        assert!(user.can_access_database(0) && user.can_run("flushall"));
+        assert_eq!(user.database_rule(), "");
+    }
+
+    #[test]
+    fn denials_report_the_log_reason() {
+        let user = user(&["+get", "~a", "db=0"]);
+        assert!(user.check("get", 0, &keys(&["a"])).is_ok());
+        let denial = user.check("set", 0, &keys(&["a"])).unwrap_err();
+        assert_eq!((denial.reason, denial.object.as_str()), ("command", "set"));
+        let denial = user.check("get", 1, &keys(&["a"])).unwrap_err();
+        assert_eq!((denial.reason, denial.object.as_str()), ("database", "1"));
+        let denial = user.check("get", 0, &keys(&["a", "b"])).unwrap_err();
+        assert_eq!((denial.reason, denial.object.as_str()), ("key", "b"));
+    }
+
+    #[test]
+    fn reset_removes_everything() {
+        let mut user = user(&["on", ">secret", "allkeys", "allchannels", "+@all", "db=1"]);
+        user.set_rule("(~a +get)").unwrap();
+        user.set_rule("reset").unwrap();
+        assert_eq!(user.describe(), "user user off -@all");
+        assert!(user.selectors.is_empty() && user.database.is_none());
+    }
+
+    #[test]
+    fn the_default_user_uses_requirepass() {
+        let acl = Acl::new(None, None);
+        assert_eq!(acl.users["default"].flags(), ["on", "nopass"]);
+        assert_eq!(acl.users["default"].key_rules(), "~*");
+        let acl = Acl::new(Some("secret".into()), None);
+        let default = &acl.users["default"];
+        assert!(!default.nopass && default.check_password("secret"));
+        assert!(default.can_run("flushall"));
+    }
+}
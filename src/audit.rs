@@ -0,0 +1,77 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lunatic::{abstract_process, process::ProcessRef};
+use lunatic_log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::acl;
+
+/// Administrative commands always written to the audit log
+const AUDITED_COMMANDS: &[&str] = &["config", "flushall", "flushdb", "acl", "debug", "shutdown"];
+
+/// Whether the command is audited, writes are only audited with `audit_writes`
+pub fn is_audited(command: &str, audit_writes: bool) -> bool {
+    let is_write =
+        || acl::category_commands("write").map_or(false, |writes| writes.contains(&command));
+    AUDITED_COMMANDS.contains(&command) || (audit_writes && is_write())
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Command executed by a client. The arguments aren't included, they can contain passwords
+/// (ACL SETUSER) or values the audit log must not store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp_ms: u64,
+    pub client_id: u64,
+    pub addr: String,
+    pub user: Option<String>,
+    pub command: String,
+    pub subcommand: Option<String>,
+    pub keys: Vec<String>,
+    /// `OK` or the prefix of the error replied
+    pub result: String,
+}
+
+/// Appends the audit entries to a file as JSON lines, the clients send the entries as
+/// messages so the commands don't wait for the write
+pub struct Audit {
+    file: File,
+    path: String,
+}
+
+#[abstract_process(visibility = pub)]
+impl Audit {
+    #[init]
+    fn init(_: ProcessRef<Self>, path: String) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|err| panic!("Can't open the audit log {path}: {err}"));
+        Self { file, path }
+    }
+
+    #[handle_message]
+    fn record(&mut self, entry: AuditEntry) {
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Can't serialize the audit entry {entry:?}: {err}");
+                return;
+            }
+        };
+        if let Err(err) = writeln!(self.file, "{line}") {
+            error!("Can't write to the audit log {}: {err}", self.path);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Load generator similar to redis-benchmark (`moonis bench`), each connection runs in its
+//! own lunatic process
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use lunatic::{Mailbox, Process};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    remote::{command, RemoteClient},
+    types::RespValue,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchConfig {
+    pub host: String,
+    pub port: u16,
+    /// Concurrent connections
+    pub clients: usize,
+    /// Requests of each test, split between the connections
+    pub requests: usize,
+    /// Requests sent together before reading the replies
+    pub pipeline: usize,
+    /// Size of the SET values
+    pub data_size: usize,
+    /// Keys used are chosen at random between this many keys
+    pub keyspace: usize,
+    /// Tests to run: set, get, incr
+    pub tests: Vec<String>,
+}
+
+/// Latencies in microseconds of the requests of a connection, and the errors replied
+type ClientResult = (Vec<u64>, usize);
+
+/// Request of the test, on a random key
+fn request(test: &str, config: &BenchConfig, value: &[u8]) -> Vec<u8> {
+    let key = rand::thread_rng().gen_range(0..config.keyspace.max(1));
+    match test {
+        "set" => command(&[b"SET", format!("key:{key:012}").as_bytes(), value]),
+        "get" => command(&[b"GET", format!("key:{key:012}").as_bytes()]),
+        "incr" => command(&[b"INCR", format!("counter:{key:012}").as_bytes()]),
+        _ => command(&[b"PING"]),
+    }
+}
+
+/// Send the requests of a connection, `pipeline` at a time
+fn run_client(test: &str, config: &BenchConfig, requests: usize) -> io::Result<ClientResult> {
+    let mut client = RemoteClient::connect(&config.host, config.port)?;
+    let value = vec![b'x'; config.data_size];
+    let mut latencies = Vec::with_capacity(requests);
+    let mut errors = 0;
+    let mut sent = 0;
+    while sent < requests {
+        let batch = config.pipeline.max(1).min(requests - sent);
+        let mut buffer = Vec::new();
+        for _ in 0..batch {
+            buffer.extend(request(test, config, &value));
+        }
+        let start = Instant::now();
+        client.send(&buffer)?;
+        for _ in 0..batch {
+            if let RespValue::Error(..) = client.read_reply()? {
+                errors += 1;
+            }
+        }
+        // The requests of a pipeline share its latency, like in redis-benchmark
+        let latency = start.elapsed().as_micros() as u64;
+        latencies.extend(std::iter::repeat(latency).take(batch));
+        sent += batch;
+    }
+    Ok((latencies, errors))
+}
+
+fn percentile(sorted: &[u64], fraction: f64) -> f64 {
+    let index = ((sorted.len() as f64 * fraction).ceil() as usize).clamp(1, sorted.len());
+    sorted[index - 1] as f64 / 1000.0
+}
+
+fn report(test: &str, config: &BenchConfig, elapsed: Duration, results: Vec<ClientResult>) {
+    let errors: usize = results.iter().map(|(_, errors)| errors).sum();
+    let mut latencies: Vec<u64> = results
+        .into_iter()
+        .flat_map(|(latencies, _)| latencies)
+        .collect();
+    latencies.sort_unstable();
+    println!("====== {} ======", test.to_uppercase());
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!("  {} parallel clients", config.clients);
+    println!("  {} bytes payload", config.data_size);
+    println!("  pipeline of {} requests", config.pipeline);
+    if latencies.is_empty() {
+        println!();
+        return;
+    }
+    let throughput = latencies.len() as f64 / elapsed.as_secs_f64();
+    let avg = latencies.iter().sum::<u64>() as f64 / latencies.len() as f64 / 1000.0;
+    println!("  throughput: {throughput:.2} requests per second");
+    println!(
+        "  latency (msec): avg={avg:.3} min={:.3} p50={:.3} p95={:.3} p99={:.3} max={:.3}",
+        latencies[0] as f64 / 1000.0,
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.95),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 1.0),
+    );
+    if errors > 0 {
+        println!("  {errors} requests replied with an error");
+    }
+    println!();
+}
+
+/// Run each test with all the connections at the same time, waiting for all of them
+/// before running the next test
+fn run_test(test: &str, config: &BenchConfig, mailbox: &Mailbox<Option<ClientResult>>) {
+    let clients = config.clients.max(1);
+    let start = Instant::now();
+    for client in 0..clients {
+        // The remainder is split between the first connections
+        let requests = config.requests / clients + usize::from(client < config.requests % clients);
+        Process::spawn_link(
+            (test.to_string(), config.clone(), requests, mailbox.this()),
+            |(test, config, requests, parent), _: Mailbox<()>| {
+                let result = run_client(&test, &config, requests);
+                if let Err(err) = &result {
+                    eprintln!(
+                        "Connection to {}:{} failed: {err}",
+                        config.host, config.port
+                    );
+                }
+                parent.send(result.ok());
+            },
+        );
+    }
+    let results: Vec<_> = (0..clients).filter_map(|_| mailbox.receive()).collect();
+    report(test, config, start.elapsed(), results);
+}
+
+/// Run the benchmark and print the report of each test
+pub fn run(config: BenchConfig, mailbox: Mailbox<()>) {
+    Process::spawn_link(
+        (config, mailbox.this()),
+        |(config, main), mailbox: Mailbox<Option<ClientResult>>| {
+            for test in &config.tests {
+                run_test(test, &config, &mailbox);
+            }
+            main.send(());
+        },
+    );
+    let _ = mailbox.receive();
+}
@@ -0,0 +1,214 @@
+//! Scalable bloom filters stored as values (BF.RESERVE, BF.ADD...). Once the last sub-filter
+//! holds its capacity a bigger one is added with a tighter error rate, so the error rate of
+//! the whole filter stays under the one requested
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::consts::LN_2,
+    hash::{Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::RespValue;
+
+pub const DEFAULT_ERROR_RATE: f64 = 0.01;
+pub const DEFAULT_CAPACITY: usize = 100;
+/// Each sub-filter holds this many times the items of the previous one
+pub const DEFAULT_EXPANSION: usize = 2;
+
+/// Error rate of each sub-filter compared to the previous one
+const TIGHTENING_RATIO: f64 = 0.5;
+
+/// Two hashes of the item, combined to get the position of each bit. DefaultHasher uses
+/// fixed keys, so the replicas setting the same items get the same bits
+fn hash(item: &[u8]) -> (u64, u64) {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    let first = hasher.finish();
+    first.hash(&mut hasher);
+    // An odd step reaches every position of the power of two sized filters
+    (first, hasher.finish() | 1)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SubFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: usize,
+    items: usize,
+}
+
+impl SubFilter {
+    /// Filter with the optimal number of bits and hashes for the capacity and error rate
+    fn new(capacity: usize, error_rate: f64) -> Self {
+        let bits = -(capacity as f64) * error_rate.ln() / (LN_2 * LN_2);
+        let words = (bits / 64.0).ceil().max(1.0) as usize;
+        let hashes = (bits / capacity as f64 * LN_2).ceil().max(1.0) as u32;
+        Self {
+            bits: vec![0; words.next_power_of_two()],
+            hashes,
+            capacity,
+            items: 0,
+        }
+    }
+
+    fn positions(&self, (first, step): (u64, u64)) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        let positions: Vec<_> = self.positions(hash).collect();
+        for position in positions {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+        self.items += 1;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    filters: Vec<SubFilter>,
+    /// Error rate of the first sub-filter
+    error_rate: f64,
+    /// 0 for the filters that don't scale, adding items fails once they are full
+    expansion: usize,
+}
+
+impl BloomFilter {
+    pub fn new(error_rate: f64, capacity: usize, expansion: usize) -> Self {
+        // The first sub-filter gets half the error rate, so the sum of all of them stays
+        // under the requested one
+        let tightened = if expansion > 0 {
+            error_rate * TIGHTENING_RATIO
+        } else {
+            error_rate
+        };
+        Self {
+            filters: vec![SubFilter::new(capacity, tightened)],
+            error_rate: tightened,
+            expansion,
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        let hash = hash(item);
+        self.filters.iter().any(|filter| filter.contains(hash))
+    }
+
+    /// Add the item, returns false when it may already be in the filter
+    pub fn add(&mut self, item: &[u8]) -> Result<bool, String> {
+        let hash = hash(item);
+        if self.filters.iter().any(|filter| filter.contains(hash)) {
+            return Ok(false);
+        }
+        let last = &self.filters[self.filters.len() - 1];
+        if last.items >= last.capacity {
+            if self.expansion == 0 {
+                return Err("non scaling filter is full".into());
+            }
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.filters.len() as i32);
+            let filter = SubFilter::new(last.capacity * self.expansion, error_rate);
+            self.filters.push(filter);
+        }
+        if let Some(filter) = self.filters.last_mut() {
+            filter.insert(hash);
+        }
+        Ok(true)
+    }
+
+    /// Bytes used by the bits of the sub-filters
+    pub fn size(&self) -> usize {
+        self.filters
+            .iter()
+            .map(|filter| filter.bits.len() * 8)
+            .sum()
+    }
+}
+
+/// Reply of an item added to a bloom filter (BF.ADD, BF.MADD)
+pub fn added_reply(added: Result<bool, String>) -> RespValue {
+    match added {
+        Ok(added) => RespValue::Integer(added.into()),
+        Err(message) => RespValue::Error("ERR".into(), Some(message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(i: usize) -> Vec<u8> {
+        format!("item:{i}").into_bytes()
+    }
+
+    #[test]
+    fn added_items_are_contained() {
+        let mut filter = BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY, 0);
+        assert!(!filter.contains(b"a"));
+        assert_eq!(filter.add(b"a"), Ok(true));
+        assert_eq!(filter.add(b"a"), Ok(false));
+        assert!(filter.contains(b"a"));
+        assert!(matches!(added_reply(Ok(true)), RespValue::Integer(1)));
+    }
+
+    #[test]
+    fn false_positives_stay_under_the_error_rate() {
+        let mut filter = BloomFilter::new(0.01, 1000, DEFAULT_EXPANSION);
+        for i in 0..1000 {
+            filter.add(&item(i)).unwrap();
+        }
+        assert!((0..1000).all(|i| filter.contains(&item(i))));
+        let false_positives = (1000..11000).filter(|i| filter.contains(&item(*i))).count();
+        assert!(false_positives < 100, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn full_filters_scale() {
+        let mut filter = BloomFilter::new(0.01, 10, DEFAULT_EXPANSION);
+        let size = filter.size();
+        for i in 0..100 {
+            filter.add(&item(i)).unwrap();
+        }
+        assert!(filter.filters.len() > 1);
+        assert!(filter.size() > size);
+        assert!((0..100).all(|i| filter.contains(&item(i))));
+        let capacities: Vec<_> = filter
+            .filters
+            .iter()
+            .map(|filter| filter.capacity)
+            .collect();
+        assert_eq!(capacities[..3], [10, 20, 40]);
+    }
+
+    #[test]
+    fn non_scaling_filters_fill_up() {
+        let mut filter = BloomFilter::new(0.01, 10, 0);
+        for i in 0..10 {
+            assert!(filter.add(&item(i)).is_ok());
+        }
+        let full = (10..20).find_map(|i| filter.add(&item(i)).err());
+        assert_eq!(full.as_deref(), Some("non scaling filter is full"));
+        assert_eq!(filter.filters.len(), 1);
+        assert!(matches!(
+            added_reply(Err("non scaling filter is full".into())),
+            RespValue::Error(prefix, Some(_)) if prefix == "ERR"
+        ));
+    }
+
+    #[test]
+    fn sub_filters_have_a_power_of_two_words() {
+        for capacity in [1, 10, 100, 1000] {
+            let filter = SubFilter::new(capacity, 0.01);
+            assert!(filter.bits.len().is_power_of_two());
+            assert!(filter.hashes >= 1);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+use lunatic::{abstract_process, process::ProcessRef, Process};
+use lunatic_log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::now_ms,
+    glob::glob_match,
+    storage::{Change, Value},
+};
+
+/// Value of a key in a CDC event. Strings and JSON documents have their text, invalid UTF-8
+/// is replaced, the other types only their type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CdcValue {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: Option<String>,
+}
+
+impl From<&Value> for CdcValue {
+    fn from(value: &Value) -> Self {
+        let text = match value {
+            Value::String(value) => Some(String::from_utf8_lossy(&value.0).to_string()),
+            Value::Json(document) => Some(document.0.to_string()),
+            _ => None,
+        };
+        Self {
+            kind: value.type_name().into(),
+            value: text,
+        }
+    }
+}
+
+/// Write applied by a primary, with the value of the key before and after it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CdcEvent {
+    pub timestamp_ms: u64,
+    pub db: usize,
+    /// `set`, `del` or `flushdb`
+    pub operation: String,
+    /// None for flushdb
+    pub key: Option<String>,
+    pub old: Option<CdcValue>,
+    pub new: Option<CdcValue>,
+}
+
+impl CdcEvent {
+    pub fn new(db: usize, change: &Change, old: Option<Value>) -> Self {
+        let (operation, key, new) = match change {
+            Change::Set(key, value, _) => ("set", Some(key), Some(value.into())),
+            Change::Del(key) => ("del", Some(key), None),
+            Change::Clear => ("flushdb", None, None),
+        };
+        Self {
+            timestamp_ms: now_ms(),
+            db,
+            operation: operation.into(),
+            key: key.map(|key| String::from_utf8_lossy(&key.0).to_string()),
+            old: old.as_ref().map(CdcValue::from),
+            new,
+        }
+    }
+}
+
+/// Arguments of the CDC process: the log file, the name of the consumer process and the
+/// notifier process of each webhook with its pattern
+pub type CdcArgs = (
+    Option<String>,
+    Option<String>,
+    Vec<(String, Process<CdcEvent>)>,
+);
+
+/// Receives the writes of the primaries of every database, appends them to the CDC log as
+/// JSON lines and forwards them to the consumer and webhook processes. The events of each
+/// key are in the order of its writes
+pub struct Cdc {
+    file: Option<(File, String)>,
+    /// Name of the process registered by the application to receive the events
+    consumer: Option<String>,
+    notifiers: Vec<(String, Process<CdcEvent>)>,
+}
+
+#[abstract_process(visibility = pub)]
+impl Cdc {
+    #[init]
+    fn init(_: ProcessRef<Self>, (path, consumer, notifiers): CdcArgs) -> Self {
+        let file = path.map(|path| {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap_or_else(|err| panic!("Can't open the CDC log {path}: {err}"));
+            (file, path)
+        });
+        Self {
+            file,
+            consumer,
+            notifiers,
+        }
+    }
+
+    #[handle_message]
+    fn record(&mut self, event: CdcEvent) {
+        // The consumer is looked up on each event, so it can be restarted
+        if let Some(consumer) = &self.consumer {
+            if let Some(process) = Process::<CdcEvent>::lookup(consumer) {
+                process.send(event.clone());
+            }
+        }
+        for (pattern, notifier) in &self.notifiers {
+            let matches = event
+                .key
+                .as_ref()
+                .map_or(true, |key| glob_match(pattern.as_bytes(), key.as_bytes()));
+            if matches {
+                notifier.send(event.clone());
+            }
+        }
+        let (file, path) = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Can't serialize the CDC event {event:?}: {err}");
+                return;
+            }
+        };
+        if let Err(err) = writeln!(file, "{line}") {
+            error!("Can't write to the CDC log {path}: {err}");
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Fault injection to check the supervisors recover the storage and the clients handle
+//! dropped connections and slow replies. Only applied in debug builds
+
+use std::time::Duration;
+
+use lunatic::{process::ProcessRef, sleep};
+use lunatic_log::warn;
+use rand::Rng;
+
+use crate::{
+    config::{ChaosConfig, Config},
+    shards::{replica_name, shard_name},
+    storage::Storage,
+};
+
+/// Sleep before a storage reply, sometimes
+pub fn delay(chaos: &ChaosConfig) {
+    if cfg!(debug_assertions) && chaos.max_delay_ms > 0 {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(chaos.delay_rate.clamp(0.0, 1.0)) {
+            sleep(Duration::from_millis(rng.gen_range(0..=chaos.max_delay_ms)));
+        }
+    }
+}
+
+/// Whether to drop the connection instead of processing the commands received
+pub fn drop_connection(chaos: &ChaosConfig) -> bool {
+    cfg!(debug_assertions) && rand::thread_rng().gen_bool(chaos.drop_rate.clamp(0.0, 1.0))
+}
+
+/// Kill a random storage process every `kill_interval_secs`, runs forever
+pub fn kill_storage(config: Config) {
+    let chaos = config.chaos;
+    if !cfg!(debug_assertions) || chaos.kill_interval_secs == 0 {
+        return;
+    }
+    // A primary recovers its keys from its replicas, without them they would be lost
+    if config.storage_replicas == 0 {
+        warn!("Chaos: storage processes aren't killed without storage replicas");
+        return;
+    }
+    let mut rng = rand::thread_rng();
+    loop {
+        sleep(Duration::from_secs(chaos.kill_interval_secs));
+        let db = rng.gen_range(0..config.databases);
+        let shard = rng.gen_range(0..config.shards);
+        // The primary or one of its replicas
+        let name = match rng.gen_range(0..=config.storage_replicas) {
+            0 => shard_name(db, shard),
+            replica => replica_name(db, shard, replica - 1),
+        };
+        if let Some(process) = ProcessRef::<Storage>::lookup(&name) {
+            warn!("Chaos: killing {name}");
+            process.kill();
+        }
+    }
+}
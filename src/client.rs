@@ -1,79 +1,149 @@
 use std::{
-    io::{Read, Write},
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    mem,
+    net::SocketAddr,
+    slice,
+    time::{Duration, Instant},
 };
 
-use bytes::{Buf, BufMut, BytesMut};
-use combine::{easy, parser::combinator::AnySendPartialState, stream::PartialStream};
-use lunatic::{abstract_process, net::TcpStream, process::ProcessRef, Mailbox, Process};
+use bytes::BytesMut;
+use lunatic::{abstract_process, process::ProcessRef, Mailbox, Process};
 
-use anyhow::anyhow;
 use lunatic_log::debug;
+use rand::Rng;
 
 use crate::{
-    encoder::encode,
-    storage::{Storage, StorageHandler},
-    types::{RedisCmd, RespValue},
+    acl::{self, Acl, AclHandler, User},
+    audit::{self, Audit, AuditEntry, AuditHandler},
+    chaos, commands,
+    config::Config,
+    connection::Connection,
+    encoder::{encode, encode_array_header},
+    glob::glob_match,
+    metrics::{Metrics, MetricsHandler, Stats},
+    parser::{self, DecodeState, Request},
+    registry::{Registry, RegistryHandler},
+    shards::Shards,
+    storage::StorageHandler,
+    telemetry::{now_ns, Span, Telemetry, TelemetryHandler, TraceParent},
+    types::{
+        AclCmd, BulkString, ClientCmd, ConfigCmd, MemoryCmd, RedisCmd, RedisKey, ReplyMode,
+        RespValue,
+    },
 };
 
+/// Part of the client memory the pending input can use, the commands are copied while
+/// they are parsed and sent to the other processes
+pub(crate) const QUERY_BUFFER_FRACTION: usize = 4;
+
+/// Capacity of the reply buffer kept between the batches of a connection, a bigger one
+/// allocated for big replies is released once they are written
+const REPLY_BUFFER_CAPACITY: usize = 16 * 1024;
+
 struct RespReader {
-    stream: TcpStream,
+    stream: Connection,
     buffer: BytesMut,
-    state: AnySendPartialState,
+    state: DecodeState,
+    /// Commands bigger than this are rejected instead of exhausting the process memory
+    max_query_buffer: usize,
+    /// Commands parsed in each batch, the rest of the pipeline stays unread in the socket
+    /// until the batch is replied. 0 means no limit
+    max_pipeline: usize,
+    /// Request parsed after a batch reached `max_pipeline`, it starts the next batch
+    next_request: Option<anyhow::Result<Request>>,
+    /// Bytes read at a time, doubled while the reads fill it up to `max_chunk` and halved
+    /// back to `min_chunk` while they use less than a quarter of it
+    chunk: usize,
+    min_chunk: usize,
+    max_chunk: usize,
 }
 
 impl RespReader {
-    fn new(stream: TcpStream) -> Self {
+    fn new(
+        stream: Connection,
+        max_memory: usize,
+        max_pipeline: usize,
+        chunks: (usize, usize),
+    ) -> Self {
+        let (min_chunk, max_chunk) = chunks;
+        // Reading 0 bytes means the connection was closed
+        let min_chunk = min_chunk.max(1);
         Self {
             stream,
-            buffer: BytesMut::with_capacity(1024),
-            state: AnySendPartialState::default(),
+            buffer: BytesMut::with_capacity(min_chunk),
+            state: DecodeState::default(),
+            max_query_buffer: max_memory / QUERY_BUFFER_FRACTION,
+            max_pipeline,
+            next_request: None,
+            chunk: min_chunk,
+            min_chunk,
+            max_chunk: max_chunk.max(min_chunk),
         }
     }
 
     fn read(&mut self) -> usize {
-        let buffer = &mut [0; 1024];
-        let readed = self.stream.read(&mut buffer[..]).unwrap();
-        self.buffer.put(&buffer[..readed]);
+        // The buffer grown by a burst, like a big SET, is released once parsed
+        if self.buffer.is_empty() && self.buffer.capacity() > self.max_chunk {
+            self.buffer = BytesMut::with_capacity(self.min_chunk);
+        }
+        // Read straight into the buffer, it only reallocates when it's full
+        let start = self.buffer.len();
+        self.buffer.resize(start + self.chunk, 0);
+        // Read errors, like reaching the idle timeout, close the connection
+        let readed = match self.stream.read(&mut self.buffer[start..]) {
+            Ok(readed) => readed,
+            Err(err) => {
+                debug!("Closing connection: {err}");
+                0
+            }
+        };
+        self.buffer.truncate(start + readed);
+        if readed == self.chunk {
+            self.chunk = (self.chunk * 2).min(self.max_chunk);
+        } else if readed < self.chunk / 4 {
+            self.chunk = (self.chunk / 2).max(self.min_chunk);
+        }
         readed
     }
 
+    /// A complete request was already read, the next batch is parsed without reading. The
+    /// incomplete requests wait for more input, their batch can't be replied together with
+    /// the current one
+    fn has_pending(&self) -> bool {
+        self.next_request.is_some()
+    }
+
     /// Read next Resp messages, a vector is returned because of pipelining
     /// https://redis.io/docs/manual/pipelining/
-    fn next(&mut self) -> Option<Vec<RespValue>> {
-        if self.buffer.len() == 0 {
+    /// The error is replied before closing the connection
+    fn next(&mut self) -> Option<Result<Vec<Request>, RespValue>> {
+        let mut resp_messages = vec![];
+        match self.next_request.take() {
+            Some(Ok(request)) => resp_messages.push(request),
+            Some(Err(err)) => return Some(Err(invalid_input(err))),
             // disconnected
-            if self.read() == 0 {
-                return None;
-            }
+            None if self.buffer.len() == 0 && self.read() == 0 => return None,
+            None => (),
         }
 
-        let mut resp_messages = vec![];
-
-        while self.buffer.len() > 0 {
-            let (resp, removed_len) = combine::stream::decode(
-                crate::parser::resp_parser(),
-                &mut easy::Stream(PartialStream(&self.buffer[..])),
-                &mut self.state,
-            )
-            .map_err(|err| {
-                let err = err
-                    .map_range(|r| {
-                        std::str::from_utf8(r)
-                            .ok()
-                            .map_or_else(|| format!("{:?}", r), |s| s.to_string())
-                    })
-                    .map_position(|p| p.translate_position(&self.buffer[..]));
-                anyhow!(
-                    "{}\nIn input: `{}`",
-                    err,
-                    std::str::from_utf8(&self.buffer).unwrap()
-                )
-            })
-            .unwrap();
-            self.buffer.advance(removed_len);
-
+        while self.buffer.len() > 0
+            && (self.max_pipeline == 0 || resp_messages.len() < self.max_pipeline)
+        {
+            let resp = match parser::decode_request(&mut self.buffer, &mut self.state) {
+                Ok(decoded) => decoded,
+                Err(err) => return Some(Err(invalid_input(err))),
+            };
             match resp {
                 // If buffer is incomplete, try to read more data
+                None if self.buffer.len() + self.state.pending() > self.max_query_buffer => {
+                    return Some(Err(RespValue::Error(
+                        "ERR".into(),
+                        Some("Protocol error: command too big for the client memory limit".into()),
+                    )));
+                }
+                // The complete requests are replied first, without waiting for the rest
+                None if !resp_messages.is_empty() => break,
                 None if self.buffer.len() > 0 => {
                     // disconnected
                     if self.read() == 0 {
@@ -84,106 +154,1285 @@ impl RespReader {
                 None => (),
             }
         }
-        Some(resp_messages)
+        // The parser tells if the rest of the input has a complete request, parsed now for
+        // the next batch
+        if self.max_pipeline > 0 && resp_messages.len() == self.max_pipeline {
+            self.next_request =
+                parser::decode_request(&mut self.buffer, &mut self.state).transpose();
+        }
+        Some(Ok(resp_messages))
     }
 }
 
+/// Reply to invalid input, the connection is closed after it
+fn invalid_input(err: anyhow::Error) -> RespValue {
+    debug!("Invalid input: {err}");
+    RespValue::Error("ERR".into(), Some("Protocol error: invalid input".into()))
+}
+
+/// Write encoded replies to the connection, updating the output buffer of the client in the
+/// registry when it's tracked. The error is why the client must be disconnected
+fn write_replies(
+    stream: &mut Connection,
+    replies: &[u8],
+    id: u64,
+    registry: Option<&ProcessRef<Registry>>,
+) -> Result<(), String> {
+    if replies.is_empty() {
+        return Ok(());
+    }
+    if let Some(registry) = registry {
+        registry.set_output_buffer(id, replies.len());
+    }
+    let written = stream.write_all(replies);
+    if let Some(registry) = registry {
+        registry.set_output_buffer(id, 0);
+    }
+    match written {
+        Ok(()) => Ok(()),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Err(format!("Disconnecting slow client {id}, it isn't reading"))
+        }
+        Err(err) => Err(format!("Closing client {id}, write failed: {err}")),
+    }
+}
+
+const PROTECTED_MODE_ERROR: &str = "Moonis is running in protected mode because protected mode is enabled, no bind address was specified and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Moonis you may adopt one of the following solutions: 1) Restart the server with the '--protected-mode no' option, however MAKE SURE Moonis is not publicly accessible from internet if you do so. 2) Restart the server binding explicitly the addresses to listen with the '--address' option. 3) Set up an authentication password for the default user with '--requirepass' or ACL SETUSER from the loopback interface. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.";
+
+/// Error of the commands using a shard while it's restarted by its supervisor
+const SHARD_UNAVAILABLE_ERROR: &str = "Shard unavailable, it's being restarted";
+
+fn is_loopback(addr: &str) -> bool {
+    addr.parse::<SocketAddr>()
+        .map_or(false, |addr| addr.ip().is_loopback())
+}
+
+/// Client names can't contain spaces, newlines or special characters
+fn is_valid_name(name: &BulkString) -> bool {
+    name.0.iter().all(|c| (b'!'..=b'~').contains(c))
+}
+
 pub struct ClientProcess {
-    storage: ProcessRef<Storage>,
+    id: u64,
+    addr: String,
+    name: Option<String>,
+    /// Client library and its version, set with CLIENT SETINFO lib-name and lib-ver
+    lib_name: Option<String>,
+    lib_ver: Option<String>,
+    reply_mode: ReplyMode,
+    no_evict: bool,
+    pub(crate) no_touch: bool,
+    /// Authenticated user, None until the connection authenticates
+    user: Option<String>,
+    /// Copy of the permissions of the user, reloaded when the ACL process reports a change
+    /// to the users. None when the user was removed
+    permissions: Option<User>,
+    /// Connection from outside the loopback interface in protected mode, its commands are
+    /// rejected while its user has no password
+    remote: bool,
+    /// Storage shards of each database
+    pub(crate) databases: Vec<Shards>,
+    /// Selected database
+    pub(crate) db: usize,
+    acl: ProcessRef<Acl>,
+    registry: ProcessRef<Registry>,
+    config: Config,
+    /// Collects the command stats of all the clients
+    metrics: ProcessRef<Metrics>,
+    /// Stats of the commands run since they were last sent to the metrics process
+    stats: Stats,
+    /// Receives the administrative commands, None when there is no audit log
+    audit: Option<ProcessRef<Audit>>,
+    /// Receives the spans of the traced commands, None when tracing is disabled
+    telemetry: Option<ProcessRef<Telemetry>>,
+    /// Trace the commands are part of, set with CLIENT SETINFO traceparent
+    trace_parent: Option<TraceParent>,
+    /// Commands received by the connection, the id of the last one. Logged and added to the
+    /// spans to find a command in the server logs (DEBUG COMMANDID)
+    pub(crate) command_id: u64,
+    /// QUIT was received, the connection is closed after its reply
+    pub(crate) quit: bool,
 }
 
-#[abstract_process(visibility = pub)]
 impl ClientProcess {
-    #[init]
-    fn init(this: ProcessRef<Self>, stream: TcpStream) -> Self {
-        debug!("Starting client");
-        Process::spawn_link(
-            (this.clone(), stream),
-            |(client, mut stream), _: Mailbox<()>| {
-                let mut resp_reader = RespReader::new(stream.clone());
-                while let Some(resp_values) = resp_reader.next() {
-                    let mut response_buffer = BytesMut::new();
-                    for resp_value in resp_values {
-                        let response = client.process(resp_value);
-                        encode(response, &mut response_buffer);
+    /// Shards of the selected database
+    pub(crate) fn storage(&self) -> &Shards {
+        &self.databases[self.db]
+    }
+
+    /// Authenticate the connection, without username the default user is used
+    fn authenticate(
+        &mut self,
+        username: Option<&BulkString>,
+        password: &BulkString,
+    ) -> Result<(), RespValue> {
+        let username = username.map_or_else(|| "default".into(), ToString::to_string);
+        if !self.acl.authenticate(
+            username.clone(),
+            Some(password.to_string()),
+            self.client_info(),
+        ) {
+            return Err(RespValue::Error(
+                "WRONGPASS".into(),
+                Some("invalid username-password pair or user is disabled.".into()),
+            ));
+        }
+        self.user = Some(username);
+        self.reload_permissions();
+        if let Some(db) = self.bound_database() {
+            self.db = db.min(self.databases.len() - 1);
+        }
+        Ok(())
+    }
+
+    /// Load the permissions of the user from the ACL process
+    fn reload_permissions(&mut self) {
+        self.permissions = self.user.clone().and_then(|user| self.acl.get_user(user));
+    }
+
+    /// Database the user of the connection is bound to, the only one it can select
+    pub(crate) fn bound_database(&self) -> Option<usize> {
+        self.permissions.as_ref()?.database
+    }
+
+    pub(crate) fn auth(
+        &mut self,
+        username: Option<&BulkString>,
+        password: &BulkString,
+    ) -> RespValue {
+        if username.is_none()
+            && self
+                .acl
+                .authenticate("default".into(), None, self.client_info())
+        {
+            return RespValue::Error(
+                "ERR".into(),
+                Some(
+                    "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"
+                        .into(),
+                ),
+            );
+        }
+        match self.authenticate(username, password) {
+            Ok(()) => RespValue::SimpleString("OK".into()),
+            Err(err) => err,
+        }
+    }
+
+    /// Short description of the connection, used in the ACL LOG
+    fn client_info(&self) -> String {
+        format!(
+            "id={} addr={} name={} user={}",
+            self.id,
+            self.addr,
+            self.name.as_deref().unwrap_or_default(),
+            self.user.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Set or clear (with an empty name) the connection name
+    fn set_name(&mut self, name: &BulkString) -> Result<(), RespValue> {
+        if !is_valid_name(name) {
+            return Err(RespValue::Error(
+                "ERR".into(),
+                Some("Client names cannot contain spaces, newlines or special characters.".into()),
+            ));
+        }
+        let name = Some(name.to_string()).filter(|name| !name.is_empty());
+        self.registry.set_name(self.id, name.clone());
+        self.name = name;
+        Ok(())
+    }
+
+    pub(crate) fn client(&mut self, cmd: &ClientCmd) -> RespValue {
+        match cmd {
+            ClientCmd::Id => RespValue::Integer(self.id as i64),
+            ClientCmd::GetName => match &self.name {
+                Some(name) => RespValue::BulkString(BulkString(name.clone().into())),
+                None => RespValue::Null,
+            },
+            ClientCmd::SetName(name) => match self.set_name(name) {
+                Ok(()) => RespValue::SimpleString("OK".into()),
+                Err(err) => err,
+            },
+            ClientCmd::Reply(mode) => {
+                self.reply_mode = *mode;
+                RespValue::SimpleString("OK".into())
+            }
+            ClientCmd::NoEvict(enabled) => {
+                self.no_evict = *enabled;
+                self.registry
+                    .set_flags(self.id, self.no_evict, self.no_touch);
+                RespValue::SimpleString("OK".into())
+            }
+            ClientCmd::NoTouch(enabled) => {
+                self.no_touch = *enabled;
+                self.registry
+                    .set_flags(self.id, self.no_evict, self.no_touch);
+                RespValue::SimpleString("OK".into())
+            }
+            ClientCmd::Consistency(consistency) => {
+                for shards in &mut self.databases {
+                    shards.set_consistency(*consistency);
+                }
+                RespValue::SimpleString("OK".into())
+            }
+            ClientCmd::SetInfo(attribute, value) => match attribute.to_lowercase().as_ref() {
+                // An empty traceparent leaves the trace
+                "traceparent" if value.0.is_empty() => {
+                    self.trace_parent = None;
+                    RespValue::SimpleString("OK".into())
+                }
+                "traceparent" => match TraceParent::parse(&value.to_string()) {
+                    Some(parent) => {
+                        self.trace_parent = Some(parent);
+                        RespValue::SimpleString("OK".into())
                     }
-                    if response_buffer.len() > 0 {
-                        stream.write_all(&response_buffer).unwrap();
+                    None => RespValue::Error("ERR".into(), Some("Invalid traceparent".into())),
+                },
+                "lib-name" | "lib-ver" if !is_valid_name(value) => RespValue::Error(
+                    "ERR".into(),
+                    Some(format!(
+                        "{} cannot contain spaces, newlines or special characters.",
+                        attribute.to_lowercase()
+                    )),
+                ),
+                // An empty value clears the attribute
+                "lib-name" | "lib-ver" => {
+                    let value = Some(value.to_string()).filter(|value| !value.is_empty());
+                    if attribute.eq_ignore_ascii_case("lib-name") {
+                        self.lib_name = value;
+                    } else {
+                        self.lib_ver = value;
                     }
+                    self.registry
+                        .set_lib(self.id, self.lib_name.clone(), self.lib_ver.clone());
+                    RespValue::SimpleString("OK".into())
                 }
-                debug!("Client Disconnected");
+                _ => RespValue::Error(
+                    "ERR".into(),
+                    Some(format!("Unrecognized option '{attribute}'")),
+                ),
+            },
+            ClientCmd::List => {
+                let mut list = String::new();
+                for client in self.registry.list() {
+                    list.push_str(&client.to_line());
+                    list.push('\n');
+                }
+                RespValue::BulkString(BulkString(list.into()))
+            }
+            ClientCmd::Info => match self.registry.info(self.id) {
+                Some(client) => {
+                    RespValue::BulkString(BulkString(format!("{}\n", client.to_line()).into()))
+                }
+                None => RespValue::Null,
             },
-        );
-        ClientProcess {
-            storage: ProcessRef::<Storage>::lookup("storage").unwrap(),
         }
     }
 
-    /// Handle resp messages
-    #[handle_request]
-    fn process(&mut self, resp: RespValue) -> RespValue {
-        let mut cmd: RedisCmd = match resp.try_into() {
-            Ok(cmd) => cmd,
-            Err(_) => {
-                return RespValue::Error("INVALID_COMMAND".into(), None);
+    pub(crate) fn hello(
+        &mut self,
+        protover: Option<i64>,
+        auth: &Option<(BulkString, BulkString)>,
+        name: &Option<BulkString>,
+    ) -> RespValue {
+        // Only RESP2 is supported for now
+        if matches!(protover, Some(protover) if protover != 2) {
+            return RespValue::Error(
+                "NOPROTO".into(),
+                Some("unsupported protocol version".into()),
+            );
+        }
+        match auth {
+            Some((username, password)) => {
+                if let Err(err) = self.authenticate(Some(username), password) {
+                    return err;
+                }
             }
-        };
+            None if self.user.is_none() => {
+                return RespValue::Error(
+                    "NOAUTH".into(),
+                    Some("HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".into()),
+                );
+            }
+            None => (),
+        }
+        if let Some(name) = name {
+            if let Err(err) = self.set_name(name) {
+                return err;
+            }
+        }
 
-        // XXX: create persistence process
-        // let mut storage: HashMap<RedisKey, crate::types::RedisValue> = HashMap::new();
-
-        match &mut cmd {
-            RedisCmd::Ping(None) => RespValue::SimpleString("PONG".into()),
-            RedisCmd::Ping(Some(value)) => RespValue::BulkString(value.clone()),
-            RedisCmd::Get(key) => {
-                debug!("Getting key: {}", key);
-                // let storage = storage.lock();
-                if let Some(value) = self.storage.get(key.clone()) {
-                    RespValue::BulkString(value.clone())
-                } else {
-                    RespValue::Null
+        let bulk = |value: &'static str| RespValue::BulkString(BulkString(value.into()));
+        RespValue::Array(
+            [
+                bulk("server"),
+                bulk("moonis"),
+                bulk("version"),
+                bulk(env!("CARGO_PKG_VERSION")),
+                bulk("proto"),
+                RespValue::Integer(2),
+                bulk("id"),
+                RespValue::Integer(self.id as i64),
+                bulk("mode"),
+                bulk("standalone"),
+                bulk("role"),
+                bulk("master"),
+                bulk("modules"),
+                RespValue::Array(VecDeque::new()),
+            ]
+            .into(),
+        )
+    }
+
+    pub(crate) fn acl_cmd(&mut self, cmd: &AclCmd) -> RespValue {
+        let bulk = |value: String| RespValue::BulkString(BulkString(value.into()));
+        let error = |err: String| RespValue::Error("ERR".into(), Some(err));
+        match cmd {
+            AclCmd::SetUser(username, rules) => {
+                match self.acl.set_user(username.clone(), rules.clone()) {
+                    Ok(()) => {
+                        self.reload_permissions();
+                        RespValue::SimpleString("OK".into())
+                    }
+                    Err(err) => error(err),
                 }
             }
-            RedisCmd::Set(key, value) => {
-                debug!("Setting: {}: {}", key, value);
-                // storage.lock().insert(key.clone(), value.clone());
-                self.storage.set(key.clone(), value.clone());
+            AclCmd::GetUser(username) => match self.acl.get_user(username.clone()) {
+                Some(user) => RespValue::Array(
+                    [
+                        bulk("flags".into()),
+                        RespValue::Array(
+                            user.flags()
+                                .iter()
+                                .map(|flag| bulk(flag.to_string()))
+                                .collect(),
+                        ),
+                        bulk("passwords".into()),
+                        RespValue::Array(user.passwords.iter().cloned().map(bulk).collect()),
+                        bulk("commands".into()),
+                        bulk(user.command_rules()),
+                        bulk("keys".into()),
+                        bulk(user.key_rules()),
+                        bulk("channels".into()),
+                        bulk(user.channel_rules()),
+                        bulk("database".into()),
+                        bulk(user.database_rule()),
+                        bulk("selectors".into()),
+                        RespValue::Array(
+                            user.selectors
+                                .iter()
+                                .map(|selector| {
+                                    RespValue::Array(
+                                        [
+                                            bulk("commands".into()),
+                                            bulk(selector.command_rules()),
+                                            bulk("keys".into()),
+                                            bulk(selector.key_rules()),
+                                            bulk("channels".into()),
+                                            bulk(selector.channel_rules()),
+                                        ]
+                                        .into(),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                    ]
+                    .into(),
+                ),
+                None => RespValue::Null,
+            },
+            AclCmd::DelUser(usernames) => match self.acl.del_users(usernames.clone()) {
+                Ok(removed) => {
+                    self.reload_permissions();
+                    RespValue::Integer(removed)
+                }
+                Err(err) => error(err),
+            },
+            AclCmd::List => RespValue::Array(
+                self.acl
+                    .users()
+                    .iter()
+                    .map(|user| bulk(user.describe()))
+                    .collect(),
+            ),
+            AclCmd::Users => RespValue::Array(
+                self.acl
+                    .users()
+                    .into_iter()
+                    .map(|user| bulk(user.name))
+                    .collect(),
+            ),
+            AclCmd::Load => match self.acl.load() {
+                Ok(()) => {
+                    self.reload_permissions();
+                    RespValue::SimpleString("OK".into())
+                }
+                Err(err) => error(err),
+            },
+            AclCmd::Save => match self.acl.save() {
+                Ok(()) => RespValue::SimpleString("OK".into()),
+                Err(err) => error(err),
+            },
+            AclCmd::Log(count) => RespValue::Array(
+                self.acl
+                    .log_entries(*count)
+                    .into_iter()
+                    .map(|entry| {
+                        RespValue::Array(
+                            [
+                                bulk("count".into()),
+                                RespValue::Integer(entry.count as i64),
+                                bulk("reason".into()),
+                                bulk(entry.reason.clone()),
+                                bulk("context".into()),
+                                bulk("toplevel".into()),
+                                bulk("object".into()),
+                                bulk(entry.object.clone()),
+                                bulk("username".into()),
+                                bulk(entry.username.clone()),
+                                bulk("age-seconds".into()),
+                                bulk(format!("{:.3}", entry.age_seconds())),
+                                bulk("client-info".into()),
+                                bulk(entry.client_info.clone()),
+                                bulk("entry-id".into()),
+                                RespValue::Integer(entry.entry_id as i64),
+                                bulk("timestamp-created".into()),
+                                RespValue::Integer(entry.created_ms as i64),
+                                bulk("timestamp-last-updated".into()),
+                                RespValue::Integer(entry.updated_ms as i64),
+                            ]
+                            .into(),
+                        )
+                    })
+                    .collect(),
+            ),
+            AclCmd::LogReset => {
+                self.acl.reset_log();
                 RespValue::SimpleString("OK".into())
             }
-            RedisCmd::Delete(keys) => {
-                debug!("Deleting key: {:?}", keys);
-                RespValue::Integer(self.storage.del(keys.clone()))
-            }
-            RedisCmd::Append(key, value) => {
-                debug!("Appending: {}: {}", key, value);
-                RespValue::Integer(self.storage.append(key.clone(), value.clone()))
-            }
-            RedisCmd::Keys(pattern) => {
-                debug!("pattern: {}", pattern);
-                // TODO: handle patterns
-                RespValue::Array(
-                    self.storage
-                        .keys(pattern.clone())
-                        .iter()
-                        .map(|k| RespValue::BulkString(k.clone()))
+            AclCmd::WhoAmI => match &self.user {
+                Some(user) => bulk(user.clone()),
+                None => RespValue::Null,
+            },
+            AclCmd::Cat(None) => RespValue::Array(
+                acl::categories()
+                    .into_iter()
+                    .map(|category| bulk(category.into()))
+                    .collect(),
+            ),
+            AclCmd::Cat(Some(category)) => match acl::category_commands(category) {
+                Some(commands) if category != "all" => RespValue::Array(
+                    commands
+                        .into_iter()
+                        .map(|command| bulk(command.into()))
                         .collect(),
+                ),
+                _ => error(format!("Unknown category '{category}'")),
+            },
+        }
+    }
+
+    pub(crate) fn memory(&mut self, cmd: &MemoryCmd) -> RespValue {
+        let bulk = |value: &'static str| RespValue::BulkString(BulkString(value.into()));
+        match cmd {
+            MemoryCmd::Usage(key) => match self.storage().shard(key).memory_usage(key.clone()) {
+                Some(usage) => RespValue::Integer(usage as i64),
+                None => RespValue::Null,
+            },
+            // All the values are strings, so there are only the biggest strings
+            MemoryCmd::BigKeys(count) => RespValue::Array(
+                self.storage()
+                    .biggest_keys(*count)
+                    .into_iter()
+                    .flat_map(|(type_name, keys)| {
+                        let keys = keys
+                            .into_iter()
+                            .flat_map(|(key, size)| {
+                                [RespValue::BulkString(key), RespValue::Integer(size as i64)]
+                            })
+                            .collect();
+                        [
+                            RespValue::BulkString(BulkString(type_name.into())),
+                            RespValue::Array(keys),
+                        ]
+                    })
+                    .collect(),
+            ),
+            MemoryCmd::Stats => {
+                let stats = self.storage().memory_stats();
+                let bytes_per_key = stats.used.checked_div(stats.keys).unwrap_or_default();
+                RespValue::Array(
+                    [
+                        bulk("peak.allocated"),
+                        RespValue::Integer(stats.peak as i64),
+                        bulk("total.allocated"),
+                        RespValue::Integer(stats.used as i64),
+                        bulk("maxmemory"),
+                        RespValue::Integer(stats.maxmemory as i64),
+                        bulk("keys.count"),
+                        RespValue::Integer(stats.keys as i64),
+                        bulk("keys.bytes-per-key"),
+                        RespValue::Integer(bytes_per_key as i64),
+                        bulk("dataset.bytes"),
+                        RespValue::Integer(stats.dataset as i64),
+                        bulk("overhead.total"),
+                        RespValue::Integer((stats.used - stats.dataset) as i64),
+                        bulk("compaction.reclaimed"),
+                        RespValue::Integer(stats.reclaimed as i64),
+                        bulk("trash.keys"),
+                        RespValue::Integer(stats.trash_keys as i64),
+                        bulk("trash.bytes"),
+                        RespValue::Integer(stats.trash as i64),
+                    ]
+                    .into(),
                 )
             }
-            RedisCmd::Exists(key) => {
-                debug!("exists: {}", key);
-                // TODO: handle patterns
-                RespValue::Integer(self.storage.exists(key.clone()))
+            MemoryCmd::Doctor => {
+                let stats = self.storage().memory_stats();
+                let report = if stats.keys == 0 {
+                    "Hi Sam, this instance is empty, my issues detector can't be used in these conditions."
+                } else if stats.maxmemory > 0 && stats.used * 10 > stats.maxmemory * 9 {
+                    "Hi Sam, the used memory is over 90% of maxmemory, keys will be evicted or writes rejected soon."
+                } else {
+                    "Hi Sam, I can't find any memory issue in your instance."
+                };
+                bulk(report)
             }
-            RedisCmd::FlushAll => {
-                debug!("flush all");
-                self.storage.clear();
+            MemoryCmd::Purge => {
+                self.storage().purge();
+                RespValue::SimpleString("OK".into())
+            }
+        }
+    }
+
+    pub(crate) fn config_cmd(&mut self, cmd: &ConfigCmd) -> RespValue {
+        match cmd {
+            ConfigCmd::Get(patterns) => RespValue::Array(
+                self.config
+                    .parameters()
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        patterns.iter().any(|pattern| {
+                            glob_match(pattern.to_lowercase().as_bytes(), name.as_bytes())
+                        })
+                    })
+                    .flat_map(|(name, value)| {
+                        [
+                            RespValue::BulkString(BulkString(name.into())),
+                            RespValue::BulkString(BulkString(value.into())),
+                        ]
+                    })
+                    .collect(),
+            ),
+            ConfigCmd::ResetStat => {
+                self.stats = Stats::default();
+                self.metrics.reset();
+                self.databases.iter().for_each(Shards::reset_stats);
                 RespValue::SimpleString("OK".into())
             }
-            // Unimplemented command
-            cmd => {
-                debug!("Command not implemented: {cmd:?}");
-                RespValue::Error("NOT_IMPLEMENTED".into(), None)
+        }
+    }
+
+    /// Check the connection is allowed to run the command
+    fn check(&self, cmd: &RedisCmd) -> Result<(), RespValue> {
+        // The connection can always authenticate as a user with a password, or quit
+        if matches!(
+            cmd,
+            RedisCmd::Auth(..) | RedisCmd::Hello(..) | RedisCmd::Quit
+        ) {
+            return Ok(());
+        }
+        if self.remote && self.passwordless() {
+            return Err(RespValue::Error(
+                "DENIED".into(),
+                Some(PROTECTED_MODE_ERROR.into()),
+            ));
+        }
+        let keys = cmd.keys();
+        self.check_acl(cmd.name(), &keys)?;
+        self.check_shards(cmd, &keys)
+    }
+
+    /// Check the shards of the keys are running, the commands of the keyspace are rejected
+    /// while one of their shards is restarted
+    fn check_shards(&self, cmd: &RedisCmd, keys: &[RedisKey]) -> Result<(), RespValue> {
+        let categories = commands::categories(cmd);
+        let keyspace = ["read", "write", "keyspace"]
+            .iter()
+            .any(|category| categories.contains(category));
+        if keyspace && !self.storage().available(keys) {
+            return Err(RespValue::Error(
+                "TRYAGAIN".into(),
+                Some(SHARD_UNAVAILABLE_ERROR.into()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the user of the connection doesn't need a password, like the default user
+    /// until one is set. Checked for each command, setting a password lifts protected mode
+    fn passwordless(&self) -> bool {
+        self.permissions
+            .as_ref()
+            .map_or(false, |user| user.enabled && user.nopass)
+    }
+
+    /// Check the user can run the command on the keys, the denials are added to the ACL LOG
+    fn check_acl(&self, name: &str, keys: &[RedisKey]) -> Result<(), RespValue> {
+        let username = match &self.user {
+            Some(username) => username,
+            None => {
+                return Err(RespValue::Error(
+                    "NOAUTH".into(),
+                    Some("Authentication required.".into()),
+                ))
+            }
+        };
+        let user = match &self.permissions {
+            Some(user) if user.enabled => user,
+            _ => {
+                return Err(RespValue::Error(
+                    "NOPERM".into(),
+                    Some(format!("User {username} is disabled or was removed")),
+                ))
+            }
+        };
+        match user.check(name, self.db, keys) {
+            Ok(()) => Ok(()),
+            Err(denial) => {
+                self.acl.log_denial(
+                    denial.reason.into(),
+                    denial.object,
+                    username.clone(),
+                    self.client_info(),
+                );
+                Err(RespValue::Error("NOPERM".into(), Some(denial.message)))
+            }
+        }
+    }
+
+    /// Keys the user can access with the command, for the keys found while it runs
+    pub(crate) fn accessible_keys(&self, name: &str, keys: Vec<RedisKey>) -> Vec<RedisKey> {
+        match &self.permissions {
+            Some(user) if user.enabled => keys
+                .into_iter()
+                .filter(|key| user.denied_key(name, slice::from_ref(key)).is_none())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether the reply of the next command is sent, consumes CLIENT REPLY SKIP
+    fn next_replied(&mut self) -> bool {
+        let replied = self.reply_mode == ReplyMode::On;
+        if self.reply_mode == ReplyMode::Skip {
+            self.reply_mode = ReplyMode::On;
+        }
+        replied
+    }
+
+    fn record(&mut self, name: &str, elapsed: Duration, response: &RespValue) {
+        let stats = self.stats.commands.entry(name.to_string()).or_default();
+        stats.record(
+            elapsed.as_micros() as u64,
+            matches!(response, RespValue::Error(..)),
+        );
+        self.stats.count_error(response);
+    }
+
+    /// Trace and parent span of a command, None when it isn't traced. Commands outside of a
+    /// trace start their own with the sample ratio
+    fn span_context(&self) -> Option<(u128, Option<u64>)> {
+        self.telemetry.as_ref()?;
+        match self.trace_parent {
+            Some(parent) => parent
+                .sampled
+                .then_some((parent.trace_id, Some(parent.span_id))),
+            None => {
+                let mut rng = rand::thread_rng();
+                (rng.gen::<f64>() < self.config.trace_sample_ratio)
+                    .then(|| (rng.gen::<u128>().max(1), None))
+            }
+        }
+    }
+
+    /// Send the span of an executed command to the telemetry process, the commands of a
+    /// batch share its time
+    fn trace(&self, name: &str, id: u64, elapsed: Duration, response: &RespValue, batch: usize) {
+        let (telemetry, (trace_id, parent_span_id)) = match (&self.telemetry, self.span_context()) {
+            (Some(telemetry), Some(context)) => (telemetry, context),
+            _ => return,
+        };
+        let end_ns = now_ns();
+        let mut attributes = vec![
+            ("db.system".to_string(), "redis".to_string()),
+            ("db.operation".into(), name.to_uppercase()),
+            ("db.redis.database_index".into(), self.db.to_string()),
+            ("moonis.client.id".into(), self.id.to_string()),
+            ("moonis.command.id".into(), id.to_string()),
+        ];
+        if batch > 1 {
+            attributes.push(("moonis.batch.size".into(), batch.to_string()));
+        }
+        telemetry.record(Span {
+            trace_id,
+            span_id: rand::thread_rng().gen::<u64>().max(1),
+            parent_span_id,
+            name: name.to_uppercase(),
+            start_ns: end_ns.saturating_sub(elapsed.as_nanos() as u64),
+            end_ns,
+            attributes,
+            error: match response {
+                RespValue::Error(prefix, Some(description)) => {
+                    Some(format!("{prefix} {description}"))
+                }
+                RespValue::Error(prefix, None) => Some(prefix.clone()),
+                _ => None,
+            },
+        });
+    }
+
+    fn reject(&mut self, name: &str, err: &RespValue) {
+        self.stats
+            .commands
+            .entry(name.to_string())
+            .or_default()
+            .reject();
+        self.stats.count_error(err);
+    }
+
+    fn is_audited(&self, cmd: &RedisCmd) -> bool {
+        self.audit.is_some() && audit::is_audited(cmd.name(), self.config.audit_writes)
+    }
+
+    /// Write the executed command to the audit log
+    fn audit(
+        &self,
+        command: &str,
+        subcommand: Option<&str>,
+        keys: Vec<RedisKey>,
+        response: &RespValue,
+    ) {
+        if let Some(audit) = &self.audit {
+            audit.record(AuditEntry {
+                timestamp_ms: audit::now_ms(),
+                client_id: self.id,
+                addr: self.addr.clone(),
+                user: self.user.clone(),
+                command: command.into(),
+                subcommand: subcommand.map(Into::into),
+                keys: keys.iter().map(ToString::to_string).collect(),
+                result: match response {
+                    RespValue::Error(prefix, _) => prefix.clone(),
+                    _ => "OK".into(),
+                },
+            });
+        }
+    }
+
+    /// Send the stats collected since the last time to the metrics process
+    fn send_stats(&mut self) {
+        if !self.stats.is_empty() {
+            self.metrics.record(mem::take(&mut self.stats));
+        }
+    }
+
+    pub(crate) fn info(&mut self, sections: &[String]) -> RespValue {
+        let all = sections
+            .iter()
+            .any(|section| section == "all" || section == "everything");
+        let default = sections.is_empty() || sections.iter().any(|section| section == "default");
+        // commandstats and latencystats are only included when asked for, like in redis
+        let included = |section: &str, in_default: bool| {
+            all || (default && in_default) || sections.iter().any(|name| name == section)
+        };
+        let mut info = String::new();
+        if included("server", true) {
+            info.push_str("# Server\r\n");
+            info.push_str(&format!("moonis_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+            info.push_str(&format!("tcp_port:{}\r\n", self.config.port));
+            info.push_str("\r\n");
+        }
+        if included("clients", true) {
+            info.push_str("# Clients\r\n");
+            info.push_str(&format!(
+                "connected_clients:{}\r\n",
+                self.registry.list().len()
+            ));
+            info.push_str("\r\n");
+        }
+        if included("stats", true) {
+            let (hits, misses) = self
+                .databases
+                .iter()
+                .map(Shards::keyspace_stats)
+                .fold((0, 0), |(hits, misses), stats| {
+                    (hits + stats.0, misses + stats.1)
+                });
+            info.push_str("# Stats\r\n");
+            info.push_str(&format!("keyspace_hits:{hits}\r\n"));
+            info.push_str(&format!("keyspace_misses:{misses}\r\n"));
+            info.push_str("\r\n");
+        }
+        if included("keyspace", true) {
+            info.push_str("# Keyspace\r\n");
+            for (db, storage) in self.databases.iter().enumerate() {
+                // Only the databases with keys are listed, like in redis
+                let keys = storage.dbsize();
+                if keys > 0 {
+                    info.push_str(&format!("db{db}:keys={keys},expires=0,avg_ttl=0\r\n"));
+                }
+            }
+            info.push_str("\r\n");
+        }
+        let commandstats = included("commandstats", false);
+        let latencystats = included("latencystats", false);
+        let errorstats = included("errorstats", true);
+        // Include the commands of this client that weren't sent yet
+        if commandstats || latencystats || errorstats {
+            self.send_stats();
+        }
+        if commandstats || latencystats {
+            let commands = self.metrics.commands();
+            if commandstats {
+                info.push_str("# Commandstats\r\n");
+                for (name, stats) in &commands {
+                    info.push_str(&format!("cmdstat_{name}:{}\r\n", stats.info_line()));
+                }
+                info.push_str("\r\n");
+            }
+            if latencystats {
+                info.push_str("# Latencystats\r\n");
+                for (name, stats) in commands.iter().filter(|(_, stats)| stats.calls > 0) {
+                    let line = stats.latency_line();
+                    info.push_str(&format!("latency_percentiles_usec_{name}:{line}\r\n"));
+                }
+                info.push_str("\r\n");
+            }
+        }
+        if errorstats {
+            info.push_str("# Errorstats\r\n");
+            for (prefix, count) in self.metrics.errors() {
+                info.push_str(&format!("errorstat_{prefix}:count={count}\r\n"));
+            }
+            info.push_str("\r\n");
+        }
+        RespValue::BulkString(BulkString(info.into()))
+    }
+
+    /// Execute the SET and APPEND commands of a DEBUG LOADPROTO payload in a single batch,
+    /// without parsing and checking them one by one. Returns the number of commands
+    pub(crate) fn load_proto(&mut self, payload: &BulkString) -> RespValue {
+        let error = |description: String| RespValue::Error("ERR".into(), Some(description));
+        let mut buffer = BytesMut::from(&payload.0[..]);
+        let mut state = DecodeState::default();
+        let mut cmds = Vec::new();
+        while !buffer.is_empty() {
+            let resp = match parser::decode(&mut buffer, &mut state) {
+                Ok(Some(resp)) => resp,
+                Ok(None) => return error("Protocol error: incomplete command in payload".into()),
+                Err(err) => return error(format!("Protocol error: {err}")),
+            };
+            match RedisCmd::try_from(resp) {
+                Ok(cmd @ (RedisCmd::Set(..) | RedisCmd::Append(..))) => cmds.push(cmd),
+                Ok(cmd) => return error(format!("'{}' can't be loaded", cmd.name())),
+                Err(err) => return error(format!("Invalid command in payload: {err}")),
+            }
+        }
+
+        // The permissions are checked once for all the keys of each command
+        let mut keys: BTreeMap<&str, Vec<RedisKey>> = BTreeMap::new();
+        for cmd in &cmds {
+            keys.entry(cmd.name()).or_default().extend(cmd.keys());
+        }
+        for (name, keys) in &keys {
+            if let Err(err) = self.check_acl(name, keys) {
+                return err;
+            }
+        }
+        let keys: Vec<RedisKey> = keys.into_values().flatten().collect();
+        if !self.storage().available(&keys) {
+            return RespValue::Error("TRYAGAIN".into(), Some(SHARD_UNAVAILABLE_ERROR.into()));
+        }
+
+        let count = cmds.len();
+        let responses = self.storage().batch(cmds, !self.no_touch);
+        let mut errors = responses
+            .into_iter()
+            .filter(|response| matches!(response, RespValue::Error(..)));
+        match errors.next() {
+            Some(RespValue::Error(prefix, description)) => RespValue::Error(
+                prefix,
+                Some(format!(
+                    "{} of {count} commands failed: {}",
+                    errors.count() + 1,
+                    description.unwrap_or_default()
+                )),
+            ),
+            _ => RespValue::Integer(count as i64),
+        }
+    }
+
+    /// Send the queued storage commands, filling the reply slots of the ones replied
+    fn flush(
+        &mut self,
+        queued: &mut Vec<(Option<usize>, u64, RedisCmd)>,
+        replies: &mut [Option<RespValue>],
+    ) {
+        if queued.is_empty() {
+            return;
+        }
+        let (slots, cmds): (Vec<_>, Vec<_>) = queued
+            .drain(..)
+            .map(|(slot, id, cmd)| ((slot, id), cmd))
+            .unzip();
+        let names: Vec<&str> = cmds.iter().map(RedisCmd::name).collect();
+        let audited: Vec<_> = cmds
+            .iter()
+            .map(|cmd| self.is_audited(cmd).then(|| cmd.keys()))
+            .collect();
+        let start = Instant::now();
+        let responses = self.storage().batch(cmds, !self.no_touch);
+        // The commands of a batch share its latency
+        let elapsed = start.elapsed() / names.len() as u32;
+        let batch = names.len();
+        let commands = names.into_iter().zip(audited).zip(&slots);
+        for (((name, keys), (_, id)), response) in commands.zip(&responses) {
+            self.record(name, elapsed, response);
+            self.trace(name, *id, elapsed, response, batch);
+            if let Some(keys) = keys {
+                self.audit(name, None, keys, response);
+            }
+        }
+        for ((slot, _), response) in slots.into_iter().zip(responses) {
+            if let Some(slot) = slot {
+                replies[slot] = Some(response);
+            }
+        }
+    }
+
+    /// Execute a parsed command, returns None when the reply must be suppressed (CLIENT REPLY)
+    fn reply(&mut self, cmd: anyhow::Result<RedisCmd>) -> Option<RespValue> {
+        let suppressed = !self.next_replied();
+
+        let (response, reply_on) = match cmd {
+            Ok(cmd) => {
+                let response = match self.check(&cmd) {
+                    Ok(()) => {
+                        let start = Instant::now();
+                        let response = self.execute(&cmd);
+                        let elapsed = start.elapsed();
+                        self.record(cmd.name(), elapsed, &response);
+                        self.trace(cmd.name(), self.command_id, elapsed, &response, 1);
+                        if self.is_audited(&cmd) {
+                            self.audit(cmd.name(), cmd.subcommand(), cmd.keys(), &response);
+                        }
+                        response
+                    }
+                    Err(err) => {
+                        self.reject(cmd.name(), &err);
+                        err
+                    }
+                };
+                (
+                    response,
+                    matches!(cmd, RedisCmd::Client(ClientCmd::Reply(ReplyMode::On))),
+                )
+            }
+            Err(_) => {
+                let err = RespValue::Error("INVALID_COMMAND".into(), None);
+                self.stats.count_error(&err);
+                (err, false)
+            }
+        };
+
+        // CLIENT REPLY ON is the only command replied after replies were suppressed
+        (self.reply_mode == ReplyMode::On && (!suppressed || reply_on)).then_some(response)
+    }
+
+    /// Assign the next id to a received command
+    fn receive(&mut self, cmd: &anyhow::Result<RedisCmd>) -> u64 {
+        self.command_id += 1;
+        match cmd {
+            Ok(cmd) => debug!(
+                "Client {} command {}: {}",
+                self.id,
+                self.command_id,
+                cmd.name()
+            ),
+            Err(err) => debug!("Client {} command {}: {err}", self.id, self.command_id),
+        }
+        self.command_id
+    }
+
+    /// The storage processes are looked up once for each request of the connection, a
+    /// pipeline included, the processes restarted meanwhile are found again
+    fn forget_processes(&self) {
+        self.databases.iter().for_each(Shards::forget);
+    }
+
+    /// Run a command already checked to be allowed, with the handler of the command table
+    fn execute(&mut self, cmd: &RedisCmd) -> RespValue {
+        commands::execute(self, cmd)
+    }
+}
+
+#[abstract_process(visibility = pub)]
+impl ClientProcess {
+    #[init]
+    fn init(this: ProcessRef<Self>, args: (Option<Connection>, String, Config)) -> Self {
+        let (stream, addr, config) = args;
+        debug!("Starting client");
+        let registry = ProcessRef::<Registry>::lookup("registry").unwrap();
+        let id = registry.register(addr.clone());
+        let acl = ProcessRef::<Acl>::lookup("acl").unwrap();
+        // Subscribed before the permissions are loaded, a change meanwhile reloads them
+        acl.subscribe(id, this.clone());
+        // Connections are authenticated as the default user when it doesn't require a password
+        let user = acl
+            .authenticate("default".into(), None, String::new())
+            .then(|| "default".to_string());
+        // Without password only connections from the loopback interface are accepted
+        let remote = config.protected_mode && !is_loopback(&addr);
+        // The output buffers are only tracked when they are limited, or slow clients
+        // are disconnected
+        let track_output = config.maxmemory_clients > 0 || config.client_write_timeout > 0;
+        let limit = config.client_output_buffer_limit.normal;
+        let timeout = (config.timeout > 0).then(|| Duration::from_secs(config.timeout));
+        let write_timeout = (config.client_write_timeout > 0)
+            .then(|| Duration::from_secs(config.client_write_timeout));
+        let options = (
+            track_output,
+            limit,
+            (timeout, write_timeout),
+            (
+                config.client_max_memory,
+                config.pipeline_max_commands,
+                config.pipeline_max_reply_bytes,
+                (config.read_buffer_size, config.read_buffer_max),
+            ),
+            config.chaos,
+        );
+        // Without connection the client is driven by the requests of another process, like
+        // the HTTP gateway
+        if let Some(stream) = stream {
+            let writer = Process::spawn_link(
+                (this.clone(), stream, id, registry.clone(), options),
+                |(client, mut stream, id, registry, options), _: Mailbox<()>| {
+                    let (
+                        track_output,
+                        limit,
+                        (timeout, write_timeout),
+                        (max_memory, max_pipeline, max_reply_bytes, read_chunks),
+                        chaos,
+                    ) = options;
+                    let mut reader_stream = stream.clone();
+                    if let Err(err) = reader_stream.set_read_timeout(timeout) {
+                        debug!("Can't set the idle timeout: {err}");
+                    }
+                    if let Err(err) = stream.set_write_timeout(write_timeout) {
+                        debug!("Can't set the write timeout: {err}");
+                    }
+                    let mut resp_reader =
+                        RespReader::new(reader_stream, max_memory, max_pipeline, read_chunks);
+                    let mut over_soft_since = None;
+                    // Reused by all the batches, the replies of consecutive batches are
+                    // coalesced in it
+                    let mut response_buffer = BytesMut::with_capacity(REPLY_BUFFER_CAPACITY);
+                    while let Some(resp_values) = resp_reader.next() {
+                        if chaos::drop_connection(&chaos) {
+                            debug!("Chaos: dropping client {id}");
+                            break;
+                        }
+                        let resp_values = match resp_values {
+                            Ok(resp_values) => resp_values,
+                            Err(err) => {
+                                debug!("Closing client {id}: {err:?}");
+                                encode(err, &mut response_buffer);
+                                let _ = stream.write_all(&response_buffer);
+                                break;
+                            }
+                        };
+                        let mut flush = |buffer: &mut BytesMut| {
+                            if limit.reached(buffer.len(), &mut over_soft_since) {
+                                return Err(format!(
+                                    "Closing client {id}, output buffer limit reached"
+                                ));
+                            }
+                            let tracked = track_output.then_some(&registry);
+                            write_replies(&mut stream, buffer, id, tracked)?;
+                            if buffer.capacity() > REPLY_BUFFER_CAPACITY {
+                                *buffer = BytesMut::with_capacity(REPLY_BUFFER_CAPACITY);
+                            } else {
+                                buffer.clear();
+                            }
+                            Ok(())
+                        };
+                        // The replies are written each time they reach max_reply_bytes, the
+                        // socket is read again once all of them are written. The elements of
+                        // arrays are written as they are encoded, so a big reply like KEYS
+                        // isn't encoded all at once
+                        let mut flushed = Ok(());
+                        let (responses, quit) = client.process_batch(resp_values);
+                        for response in responses {
+                            let mut elements = match response {
+                                RespValue::Array(elements) => {
+                                    encode_array_header(elements.len(), &mut response_buffer);
+                                    elements.into_iter()
+                                }
+                                response => {
+                                    encode(response, &mut response_buffer);
+                                    VecDeque::new().into_iter()
+                                }
+                            };
+                            loop {
+                                if max_reply_bytes > 0 && response_buffer.len() >= max_reply_bytes {
+                                    flushed = flush(&mut response_buffer);
+                                    if flushed.is_err() {
+                                        break;
+                                    }
+                                }
+                                match elements.next() {
+                                    Some(element) => encode(element, &mut response_buffer),
+                                    None => break,
+                                }
+                            }
+                            if flushed.is_err() {
+                                break;
+                            }
+                        }
+                        // When the next request was already read the replies of its batch
+                        // are written together with these ones, in a single write
+                        if flushed.is_ok() && (quit || !resp_reader.has_pending()) {
+                            flushed = flush(&mut response_buffer);
+                        }
+                        if let Err(reason) = flushed {
+                            debug!("{reason}");
+                            break;
+                        }
+                        if quit {
+                            debug!("Client {id} quit");
+                            break;
+                        }
+                    }
+                    // Errors end the loop instead of panicking, so the client process is shut down
+                    // and deregistered together with its reader
+                    debug!("Client Disconnected");
+                    client.shutdown();
+                },
+            );
+            registry.set_writer(id, writer);
+        }
+        let mut client = ClientProcess {
+            id,
+            addr,
+            name: None,
+            lib_name: None,
+            lib_ver: None,
+            reply_mode: ReplyMode::On,
+            no_evict: false,
+            no_touch: false,
+            user,
+            permissions: None,
+            remote,
+            databases: (0..config.databases)
+                .map(|db| Shards::new(db, config.shards, config.storage_replicas))
+                .collect(),
+            db: 0,
+            acl,
+            registry,
+            config,
+            metrics: ProcessRef::<Metrics>::lookup("metrics").unwrap(),
+            stats: Stats::default(),
+            audit: ProcessRef::<Audit>::lookup("audit"),
+            telemetry: ProcessRef::<Telemetry>::lookup("telemetry"),
+            trace_parent: None,
+            command_id: 0,
+            quit: false,
+        };
+        client.reload_permissions();
+        // A default user bound to a database starts in it
+        if let Some(db) = client.bound_database() {
+            client.db = db.min(client.databases.len() - 1);
+        }
+        client
+    }
+
+    #[terminate]
+    fn terminate(self) {
+        self.acl.unsubscribe(self.id);
+        self.registry.deregister(self.id);
+    }
+
+    /// The users were changed by ACL SETUSER, DELUSER or LOAD
+    #[handle_message]
+    fn reload_user(&mut self) {
+        self.reload_permissions();
+    }
+
+    /// Handle resp messages, returns None when the reply must be suppressed (CLIENT REPLY)
+    #[handle_request]
+    fn process(&mut self, resp: RespValue) -> Option<RespValue> {
+        self.forget_processes();
+        let cmd = RedisCmd::try_from(resp);
+        self.receive(&cmd);
+        self.reply(cmd)
+    }
+
+    /// Handle a command parsed by another protocol, like the memcached commands
+    #[handle_request]
+    fn process_command(&mut self, cmd: RedisCmd) -> Option<RespValue> {
+        self.forget_processes();
+        let cmd = Ok(cmd);
+        self.receive(&cmd);
+        self.reply(cmd)
+    }
+
+    /// Handle pipelined requests, consecutive storage commands are sent together in a single
+    /// request to each shard. The commands are checked against the permissions kept by the
+    /// client, only the denials are sent to the ACL process. Returns the replies that aren't
+    /// suppressed, and whether the connection must be closed after them (QUIT)
+    #[handle_request]
+    fn process_batch(&mut self, requests: Vec<Request>) -> (Vec<RespValue>, bool) {
+        self.forget_processes();
+        let mut replies = vec![None; requests.len()];
+        let mut queued = Vec::new();
+        for (slot, request) in requests.into_iter().enumerate() {
+            // The commands pipelined after QUIT are ignored
+            if self.quit {
+                break;
+            }
+            let cmd = request.into_command();
+            let id = self.receive(&cmd);
+            match cmd {
+                Ok(cmd) if cmd.is_batchable() => {
+                    let slot = self.next_replied().then_some(slot);
+                    match (self.check(&cmd), slot) {
+                        (Ok(()), _) => queued.push((slot, id, cmd)),
+                        (Err(err), slot) => {
+                            self.reject(cmd.name(), &err);
+                            if let Some(slot) = slot {
+                                replies[slot] = Some(err);
+                            }
+                        }
+                    }
+                }
+                cmd => {
+                    // The command can depend on the previous ones, like SELECT or FLUSHALL
+                    self.flush(&mut queued, &mut replies);
+                    replies[slot] = self.reply(cmd);
+                }
             }
         }
+        self.flush(&mut queued, &mut replies);
+        self.send_stats();
+        (replies.into_iter().flatten().collect(), self.quit)
     }
 }
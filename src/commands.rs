@@ -0,0 +1,2681 @@
+//! Command table, each command has a handler declaring its ACL categories, parsing its
+//! arguments and executing it in the client
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lunatic_log::debug;
+
+use crate::{
+    bloom::{self, DEFAULT_EXPANSION},
+    client::ClientProcess,
+    json::{self, Condition, Document, Path},
+    shards::Shards,
+    sketch::{
+        self, TOPK_DEFAULT_DECAY, TOPK_DEFAULT_DEPTH, TOPK_DEFAULT_WIDTH, TOPK_MAX_INCREMENT,
+    },
+    storage::{StorageError, StorageHandler},
+    timeseries::{self, Aggregation, Aggregator, Filter, SeriesOptions},
+    types::{
+        get_next_value, get_remaining_strings, AclCmd, BloomCmd, BulkString, ClientCmd, CmsCmd,
+        ConfigCmd, DebugCmd, JsonCmd, MemoryCmd, ObjectCmd, RedisCmd, RedisKey, RedisValue,
+        RespValue, TopKCmd, TsCmd,
+    },
+};
+
+/// Art returned by LOLWUT
+const LOLWUT: &str = r"
+       _.._
+     .' .-'`
+    /  /
+    |  |
+    \  \
+     '._'-._
+        ```
+";
+
+/// Properties of the commands used to run them, like the categories are for the ACL rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    /// Replicated by executing it again, the others are replicated by their effects (ie.
+    /// SPOP as SREM, INCRBYFLOAT as SET)
+    Deterministic,
+    /// Single key command executed by Storage, it can be sent together with others in a batch
+    Batchable,
+}
+
+pub trait CommandHandler: Sync {
+    /// Arguments of the command, the fields of its `RedisCmd`
+    type Args<'a>;
+
+    /// Lowercase name of the command, as used by ACL rules
+    fn name(&self) -> &'static str;
+
+    /// ACL categories of the command
+    fn categories(&self) -> &'static [&'static str];
+
+    /// How the command is batched and replicated
+    fn flags(&self) -> &'static [Flag] {
+        &[]
+    }
+
+    /// Parse the arguments following the name of the command
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd>;
+
+    /// Keys accessed by the command, checked against the ACL rules of the user
+    fn keys(&self, _: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![]
+    }
+
+    /// Execute a command parsed by this handler, already checked to be allowed
+    fn execute(&self, client: &mut ClientProcess, args: Self::Args<'_>) -> RespValue;
+}
+
+/// Handler in the command table, without the types of the arguments so any command can be
+/// looked up by its name
+pub trait CommandEntry: Sync {
+    fn name(&self) -> &'static str;
+
+    fn categories(&self) -> &'static [&'static str];
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd>;
+}
+
+impl<H: CommandHandler> CommandEntry for H {
+    fn name(&self) -> &'static str {
+        CommandHandler::name(self)
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        CommandHandler::categories(self)
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        CommandHandler::parse(self, args)
+    }
+}
+
+/// All the commands, in the order they are listed by ACL CAT
+pub const COMMANDS: &[&dyn CommandEntry] = &[
+    &Ping,
+    &Quit,
+    &Get,
+    &MGet,
+    &Set,
+    &Del,
+    &Append,
+    &Cas,
+    &GetVer,
+    &Undelete,
+    &McIncr,
+    &McDecr,
+    &Keys,
+    &DbSize,
+    &Exists,
+    &FlushAll,
+    &FlushDb,
+    &Select,
+    &Command,
+    &Client,
+    &Hello,
+    &Time,
+    &Lolwut,
+    &Auth,
+    &Acl,
+    &Object,
+    &Memory,
+    &Config,
+    &Info,
+    &Debug,
+    &JsonSet,
+    &JsonGet,
+    &JsonDel,
+    &JsonArrAppend,
+    &BfReserve,
+    &BfAdd,
+    &BfMAdd,
+    &BfExists,
+    &CmsInitByDim,
+    &CmsInitByProb,
+    &CmsIncrBy,
+    &CmsQuery,
+    &CmsMerge,
+    &TopKReserve,
+    &TopKAdd,
+    &TopKIncrBy,
+    &TopKQuery,
+    &TopKList,
+    &TsCreate,
+    &TsAdd,
+    &TsCreateRule,
+    &TsRange,
+    &TsMRange,
+];
+
+/// Handler of the command with the name, in any case
+pub fn lookup(name: &str) -> Option<&'static dyn CommandEntry> {
+    COMMANDS
+        .iter()
+        .find(|handler| handler.name().eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Run with the handler of a command and its arguments, by `visit`
+trait Visitor {
+    type Output;
+
+    fn visit<H: CommandHandler>(self, handler: &H, args: H::Args<'_>) -> Self::Output;
+}
+
+struct KeysVisitor;
+
+impl Visitor for KeysVisitor {
+    type Output = Vec<RedisKey>;
+
+    fn visit<H: CommandHandler>(self, handler: &H, args: H::Args<'_>) -> Vec<RedisKey> {
+        handler.keys(args)
+    }
+}
+
+struct FlagsVisitor;
+
+impl Visitor for FlagsVisitor {
+    type Output = &'static [Flag];
+
+    fn visit<H: CommandHandler>(self, handler: &H, _: H::Args<'_>) -> &'static [Flag] {
+        handler.flags()
+    }
+}
+
+struct CategoriesVisitor;
+
+impl Visitor for CategoriesVisitor {
+    type Output = &'static [&'static str];
+
+    fn visit<H: CommandHandler>(self, handler: &H, _: H::Args<'_>) -> &'static [&'static str] {
+        handler.categories()
+    }
+}
+
+struct ExecuteVisitor<'a>(&'a mut ClientProcess);
+
+impl Visitor for ExecuteVisitor<'_> {
+    type Output = RespValue;
+
+    fn visit<H: CommandHandler>(self, handler: &H, args: H::Args<'_>) -> RespValue {
+        handler.execute(self.0, args)
+    }
+}
+
+/// Keys accessed by the command
+pub fn keys(cmd: &RedisCmd) -> Vec<RedisKey> {
+    visit(cmd, KeysVisitor)
+}
+
+/// Flags of the command, declared by its handler
+pub fn flags(cmd: &RedisCmd) -> &'static [Flag] {
+    visit(cmd, FlagsVisitor)
+}
+
+/// ACL categories of the command
+pub fn categories(cmd: &RedisCmd) -> &'static [&'static str] {
+    visit(cmd, CategoriesVisitor)
+}
+
+/// Execute the command with its handler, already checked to be allowed
+pub fn execute(client: &mut ClientProcess, cmd: &RedisCmd) -> RespValue {
+    visit(cmd, ExecuteVisitor(client))
+}
+
+/// Run the visitor with the handler of the command, the only place where the commands are
+/// matched to their handlers
+fn visit<V: Visitor>(cmd: &RedisCmd, visitor: V) -> V::Output {
+    match cmd {
+        RedisCmd::Ping(value) => visitor.visit(&Ping, value.as_ref()),
+        RedisCmd::Quit => visitor.visit(&Quit, ()),
+        RedisCmd::Get(key) => visitor.visit(&Get, key),
+        RedisCmd::MGet(keys) => visitor.visit(&MGet, keys),
+        RedisCmd::Delete(keys) => visitor.visit(&Del, keys),
+        RedisCmd::Set(key, value) => visitor.visit(&Set, (key, value)),
+        RedisCmd::Append(key, value) => visitor.visit(&Append, (key, value)),
+        RedisCmd::Cas(key, expected, value) => visitor.visit(&Cas, (key, *expected, value)),
+        RedisCmd::GetVer(key) => visitor.visit(&GetVer, key),
+        RedisCmd::Undelete(key) => visitor.visit(&Undelete, key),
+        RedisCmd::McIncr(key, delta, false) => visitor.visit(&McIncr, (key, *delta)),
+        RedisCmd::McIncr(key, delta, true) => visitor.visit(&McDecr, (key, *delta)),
+        RedisCmd::Keys(pattern) => visitor.visit(&Keys, pattern),
+        RedisCmd::DbSize => visitor.visit(&DbSize, ()),
+        RedisCmd::Exists(key) => visitor.visit(&Exists, key),
+        RedisCmd::FlushAll => visitor.visit(&FlushAll, ()),
+        RedisCmd::FlushDb => visitor.visit(&FlushDb, ()),
+        RedisCmd::Select(index) => visitor.visit(&Select, index),
+        RedisCmd::Command => visitor.visit(&Command, ()),
+        RedisCmd::Client(cmd) => visitor.visit(&Client, cmd),
+        RedisCmd::Hello(protover, auth, name) => visitor.visit(&Hello, (*protover, auth, name)),
+        RedisCmd::Time => visitor.visit(&Time, ()),
+        RedisCmd::Lolwut => visitor.visit(&Lolwut, ()),
+        RedisCmd::Auth(username, password) => visitor.visit(&Auth, (username.as_ref(), password)),
+        RedisCmd::Acl(cmd) => visitor.visit(&Acl, cmd),
+        RedisCmd::Object(cmd) => visitor.visit(&Object, cmd),
+        RedisCmd::Memory(cmd) => visitor.visit(&Memory, cmd),
+        RedisCmd::Config(cmd) => visitor.visit(&Config, cmd),
+        RedisCmd::Info(sections) => visitor.visit(&Info, sections),
+        RedisCmd::Debug(cmd) => visitor.visit(&Debug, cmd),
+        RedisCmd::Json(cmd) => match cmd {
+            JsonCmd::Set(key, path, document, condition) => {
+                visitor.visit(&JsonSet, (key, path, document, *condition))
+            }
+            JsonCmd::Get(key, paths) => visitor.visit(&JsonGet, (key, paths)),
+            JsonCmd::Del(key, path) => visitor.visit(&JsonDel, (key, path)),
+            JsonCmd::ArrAppend(key, path, values) => {
+                visitor.visit(&JsonArrAppend, (key, path, values))
+            }
+        },
+        RedisCmd::Bloom(cmd) => match cmd {
+            BloomCmd::Reserve(key, error_rate, capacity, expansion) => {
+                visitor.visit(&BfReserve, (key, *error_rate, *capacity, *expansion))
+            }
+            BloomCmd::Add(key, item) => visitor.visit(&BfAdd, (key, item)),
+            BloomCmd::MAdd(key, items) => visitor.visit(&BfMAdd, (key, items)),
+            BloomCmd::Exists(key, item) => visitor.visit(&BfExists, (key, item)),
+        },
+        RedisCmd::Cms(cmd) => match cmd {
+            CmsCmd::InitByDim(key, width, depth) => {
+                visitor.visit(&CmsInitByDim, (key, *width, *depth))
+            }
+            CmsCmd::InitByProb(key, error, probability) => {
+                visitor.visit(&CmsInitByProb, (key, *error, *probability))
+            }
+            CmsCmd::IncrBy(key, increments) => visitor.visit(&CmsIncrBy, (key, increments)),
+            CmsCmd::Query(key, items) => visitor.visit(&CmsQuery, (key, items)),
+            CmsCmd::Merge(key, sources, weights) => {
+                visitor.visit(&CmsMerge, (key, sources, weights))
+            }
+        },
+        RedisCmd::TopK(cmd) => match cmd {
+            TopKCmd::Reserve(key, k, width, depth, decay) => {
+                visitor.visit(&TopKReserve, (key, *k, *width, *depth, *decay))
+            }
+            TopKCmd::Add(key, items) => visitor.visit(&TopKAdd, (key, items)),
+            TopKCmd::IncrBy(key, increments) => visitor.visit(&TopKIncrBy, (key, increments)),
+            TopKCmd::Query(key, items) => visitor.visit(&TopKQuery, (key, items)),
+            TopKCmd::List(key, with_count) => visitor.visit(&TopKList, (key, *with_count)),
+        },
+        RedisCmd::Ts(cmd) => match cmd {
+            TsCmd::Create(key, options) => visitor.visit(&TsCreate, (key, options)),
+            TsCmd::Add(key, timestamp, value, options) => {
+                visitor.visit(&TsAdd, (key, *timestamp, *value, options))
+            }
+            TsCmd::CreateRule(key, dest, aggregator) => {
+                visitor.visit(&TsCreateRule, (key, dest, *aggregator))
+            }
+            TsCmd::Range(key, from, to, aggregator) => {
+                visitor.visit(&TsRange, (key, *from, *to, *aggregator))
+            }
+            TsCmd::MRange(from, to, aggregator, filters, with_labels) => {
+                visitor.visit(&TsMRange, (*from, *to, *aggregator, filters, *with_labels))
+            }
+        },
+    }
+}
+
+fn ok() -> RespValue {
+    RespValue::SimpleString("OK".into())
+}
+
+/// All the remaining arguments as keys
+fn get_remaining_keys(args: &mut VecDeque<RespValue>) -> Result<Vec<RedisKey>> {
+    args.drain(..)
+        .map(|key| match key {
+            RespValue::BulkString(key) => Ok(key),
+            // Null bulk strings can be sent by the clients
+            _ => Err(anyhow!("Invalid argument, must be BulkString")),
+        })
+        .collect()
+}
+
+/// Get the next argument as the path of a JSON document
+fn get_next_path(args: &mut VecDeque<RespValue>) -> Result<Path> {
+    Path::parse(&get_next_value(args)?.to_string()).map_err(|err| anyhow!(err))
+}
+
+/// Get the next argument as a JSON value
+fn get_next_document(args: &mut VecDeque<RespValue>) -> Result<Document> {
+    Document::parse(&get_next_value(args)?.to_string()).map_err(|err| anyhow!(err))
+}
+
+/// Get the remaining arguments as pairs of an item and its increment
+fn get_remaining_increments(
+    args: &mut VecDeque<RespValue>,
+    name: &str,
+) -> Result<Vec<(BulkString, u64)>> {
+    let mut increments = vec![];
+    while let Ok(item) = get_next_value(args) {
+        let increment: u64 = get_next_value(args)
+            .with_context(|| format!("Increment must be set for each item of {name}"))?
+            .to_string()
+            .parse()
+            .context("Increment is not an integer")?;
+        increments.push((item, increment));
+    }
+    if increments.is_empty() {
+        bail!("Items must be set for {name}");
+    }
+    Ok(increments)
+}
+
+/// Get the options of a time series, RETENTION and LABELS as the last option
+fn get_series_options(args: &mut VecDeque<RespValue>) -> Result<SeriesOptions> {
+    let mut options = SeriesOptions::default();
+    while let Ok(option) = get_next_value(args) {
+        match option.to_string().to_uppercase().as_ref() {
+            "RETENTION" => {
+                options.retention = get_next_value(args)
+                    .context("Retention must be set")?
+                    .to_string()
+                    .parse()
+                    .context("Retention is not an integer")?;
+            }
+            "LABELS" => {
+                let labels = get_remaining_strings(args)?;
+                if labels.is_empty() || labels.len() % 2 != 0 {
+                    bail!("Each label must have a value");
+                }
+                options.labels = labels
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+            }
+            _ => bail!("Invalid time series option"),
+        }
+    }
+    Ok(options)
+}
+
+/// Get the next argument as a timestamp of a range, `-` and `+` for the first and the last
+fn get_next_timestamp(args: &mut VecDeque<RespValue>) -> Result<u64> {
+    match get_next_value(args)?.to_string().as_ref() {
+        "-" => Ok(0),
+        "+" => Ok(u64::MAX),
+        timestamp => timestamp.parse().context("Timestamp is not an integer"),
+    }
+}
+
+/// Get the type and the bucket duration following AGGREGATION
+fn get_next_aggregator(args: &mut VecDeque<RespValue>) -> Result<Aggregator> {
+    let aggregation = get_next_value(args).context("Aggregation type must be set")?;
+    let aggregation = Aggregation::parse(&aggregation.to_string())
+        .ok_or_else(|| anyhow!("Unknown aggregation type {aggregation}"))?;
+    let bucket: u64 = get_next_value(args)
+        .context("Bucket duration must be set")?
+        .to_string()
+        .parse()
+        .context("Bucket duration is not an integer")?;
+    if bucket == 0 {
+        bail!("Bucket duration must be larger than 0");
+    }
+    Ok(Aggregator {
+        aggregation,
+        bucket,
+    })
+}
+
+pub struct Ping;
+
+impl CommandHandler for Ping {
+    type Args<'a> = Option<&'a RedisValue>;
+
+    fn name(&self) -> &'static str {
+        "ping"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast", "connection"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Ping(get_next_value(&mut args).ok()))
+    }
+
+    fn execute(&self, _: &mut ClientProcess, value: Self::Args<'_>) -> RespValue {
+        match value {
+            None => RespValue::SimpleString("PONG".into()),
+            Some(value) => RespValue::BulkString(value.clone()),
+        }
+    }
+}
+
+pub struct Quit;
+
+impl CommandHandler for Quit {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast", "connection"]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Quit)
+    }
+
+    /// The connection is closed once the replies of the batch are written
+    fn execute(&self, client: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        client.quit = true;
+        ok()
+    }
+}
+
+pub struct Get;
+
+impl CommandHandler for Get {
+    type Args<'a> = &'a RedisKey;
+
+    fn name(&self) -> &'static str {
+        "get"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "string", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Batchable]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Get(get_next_value(&mut args)?))
+    }
+
+    fn keys(&self, key: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, key: Self::Args<'_>) -> RespValue {
+        debug!("Getting key: {}", key);
+        let shard = client.storage().read_shard(key);
+        match shard.get(key.clone(), !client.no_touch) {
+            Ok(Some(value)) => RespValue::BulkString(value),
+            Ok(None) => RespValue::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct MGet;
+
+impl CommandHandler for MGet {
+    type Args<'a> = &'a [RedisKey];
+
+    fn name(&self) -> &'static str {
+        "mget"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "string", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let keys = get_remaining_keys(&mut args)?;
+        if keys.is_empty() {
+            bail!("Keys must be set for MGET CMD");
+        }
+        Ok(RedisCmd::MGet(keys))
+    }
+
+    fn keys(&self, keys: Self::Args<'_>) -> Vec<RedisKey> {
+        keys.to_vec()
+    }
+
+    fn execute(&self, client: &mut ClientProcess, keys: Self::Args<'_>) -> RespValue {
+        debug!("Getting keys: {:?}", keys);
+        RespValue::Array(
+            client
+                .storage()
+                .mget(keys, !client.no_touch)
+                .into_iter()
+                .map(|value| value.map_or(RespValue::Null, RespValue::BulkString))
+                .collect(),
+        )
+    }
+}
+
+pub struct Set;
+
+impl CommandHandler for Set {
+    type Args<'a> = (&'a RedisKey, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "set"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "string", "slow"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic, Flag::Batchable]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Set(
+            get_next_value(&mut args).context("Can't get the key of set CMD")?,
+            get_next_value(&mut args).context("Value must be set for set CMD")?,
+        ))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, value): Self::Args<'_>) -> RespValue {
+        debug!("Setting: {}: {}", key, value);
+        match client.storage().shard(key).set(key.clone(), value.clone()) {
+            Ok(_) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct Del;
+
+impl CommandHandler for Del {
+    type Args<'a> = &'a [RedisKey];
+
+    fn name(&self) -> &'static str {
+        "del"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "keyspace", "slow"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Delete(get_remaining_keys(&mut args)?))
+    }
+
+    fn keys(&self, keys: Self::Args<'_>) -> Vec<RedisKey> {
+        keys.to_vec()
+    }
+
+    fn execute(&self, client: &mut ClientProcess, keys: Self::Args<'_>) -> RespValue {
+        debug!("Deleting key: {:?}", keys);
+        RespValue::Integer(client.storage().del(keys))
+    }
+}
+
+pub struct Append;
+
+impl CommandHandler for Append {
+    type Args<'a> = (&'a RedisKey, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "append"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "string", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic, Flag::Batchable]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Append(
+            get_next_value(&mut args).context("Can't get the key of append CMD")?,
+            get_next_value(&mut args).context("Value must be set for append CMD")?,
+        ))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, value): Self::Args<'_>) -> RespValue {
+        debug!("Appending: {}: {}", key, value);
+        match client
+            .storage()
+            .shard(key)
+            .append(key.clone(), value.clone())
+        {
+            Ok(len) => RespValue::Integer(len),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct Cas;
+
+impl CommandHandler for Cas {
+    type Args<'a> = (&'a RedisKey, u64, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "cas"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "string", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Cas(
+            get_next_value(&mut args).context("Can't get the key of cas CMD")?,
+            get_next_value(&mut args)
+                .context("Version must be set for cas CMD")?
+                .to_string()
+                .parse()
+                .context("Version is not an integer")?,
+            get_next_value(&mut args).context("Value must be set for cas CMD")?,
+        ))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, expected, value): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("Compare and set: {}: {} at {}", key, value, expected);
+        let shard = client.storage().shard(key);
+        match shard.cas(key.clone(), expected, value.clone()) {
+            Ok(Some(version)) => RespValue::Integer(version as i64),
+            Ok(None) => RespValue::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct GetVer;
+
+impl CommandHandler for GetVer {
+    type Args<'a> = &'a RedisKey;
+
+    fn name(&self) -> &'static str {
+        "getver"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "string", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::GetVer(get_next_value(&mut args)?))
+    }
+
+    fn keys(&self, key: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, key: Self::Args<'_>) -> RespValue {
+        debug!("Getting the version of key: {}", key);
+        // The replicas have their own versions, only the primary ones are compared
+        let shard = client.storage().shard(key);
+        match shard.get_version(key.clone(), !client.no_touch) {
+            Ok(Some((value, version))) => RespValue::Array(
+                [
+                    RespValue::BulkString(value),
+                    RespValue::Integer(version as i64),
+                ]
+                .into(),
+            ),
+            Ok(None) => RespValue::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct Undelete;
+
+impl CommandHandler for Undelete {
+    type Args<'a> = &'a RedisKey;
+
+    fn name(&self) -> &'static str {
+        "undelete"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["admin", "write", "keyspace", "slow"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Undelete(
+            get_next_value(&mut args).context("Can't get the key of undelete CMD")?,
+        ))
+    }
+
+    fn keys(&self, key: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, key: Self::Args<'_>) -> RespValue {
+        debug!("Undeleting key: {}", key);
+        let shard = client.storage().shard(key);
+        match shard.undelete(key.clone()) {
+            Ok(restored) => RespValue::Integer(restored.into()),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+/// Parse the key and the delta of MC.INCR and MC.DECR
+fn parse_mc_counter(mut args: VecDeque<RespValue>, decrement: bool) -> Result<RedisCmd> {
+    let key = get_next_value(&mut args).context("Can't get the key of the counter")?;
+    let delta = get_next_value(&mut args)
+        .context("Delta must be set")?
+        .to_string()
+        .parse()
+        .context("Delta must be an unsigned integer")?;
+    Ok(RedisCmd::McIncr(key, delta, decrement))
+}
+
+/// New value of the counter as a bulk string, it can be bigger than an integer reply
+fn execute_mc_counter(
+    client: &mut ClientProcess,
+    key: &RedisKey,
+    delta: u64,
+    decrement: bool,
+) -> RespValue {
+    let shard = client.storage().shard(key);
+    match shard.incr_unsigned(key.clone(), delta, decrement) {
+        Ok(Some(value)) => RespValue::BulkString(BulkString(value.to_string().into())),
+        Ok(None) => RespValue::Null,
+        Err(err) => err.into(),
+    }
+}
+
+/// Increment of the memcached `incr` command
+pub struct McIncr;
+
+impl CommandHandler for McIncr {
+    type Args<'a> = (&'a RedisKey, u64);
+
+    fn name(&self) -> &'static str {
+        "mc.incr"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "string", "fast"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        parse_mc_counter(args, false)
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, delta): Self::Args<'_>) -> RespValue {
+        execute_mc_counter(client, key, delta, false)
+    }
+}
+
+/// Decrement of the memcached `decr` command
+pub struct McDecr;
+
+impl CommandHandler for McDecr {
+    type Args<'a> = (&'a RedisKey, u64);
+
+    fn name(&self) -> &'static str {
+        "mc.decr"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "string", "fast"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        parse_mc_counter(args, true)
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, delta): Self::Args<'_>) -> RespValue {
+        execute_mc_counter(client, key, delta, true)
+    }
+}
+
+pub struct Keys;
+
+impl CommandHandler for Keys {
+    type Args<'a> = &'a RedisValue;
+
+    fn name(&self) -> &'static str {
+        "keys"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "keyspace", "slow", "dangerous"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Keys(get_next_value(&mut args)?))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, pattern: Self::Args<'_>) -> RespValue {
+        debug!("pattern: {}", pattern);
+        // Only the requests to the shards are paged, the reply holds all the keys
+        RespValue::Array(
+            client
+                .storage()
+                .keys(pattern)
+                .map(RespValue::BulkString)
+                .collect(),
+        )
+    }
+}
+
+pub struct Exists;
+
+impl CommandHandler for Exists {
+    type Args<'a> = &'a RedisKey;
+
+    fn name(&self) -> &'static str {
+        "exists"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "keyspace", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Batchable]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Exists(get_next_value(&mut args)?))
+    }
+
+    fn keys(&self, key: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, key: Self::Args<'_>) -> RespValue {
+        debug!("exists: {}", key);
+        RespValue::Integer(client.storage().read_shard(key).exists(key.clone()))
+    }
+}
+
+pub struct DbSize;
+
+impl CommandHandler for DbSize {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "dbsize"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "keyspace", "fast"]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::DbSize)
+    }
+
+    fn execute(&self, client: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        RespValue::Integer(client.storage().dbsize() as i64)
+    }
+}
+
+pub struct FlushAll;
+
+impl CommandHandler for FlushAll {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "flushall"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "keyspace", "slow", "dangerous"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::FlushAll)
+    }
+
+    fn execute(&self, client: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        debug!("flush all");
+        client.databases.iter().for_each(Shards::clear);
+        ok()
+    }
+}
+
+pub struct FlushDb;
+
+impl CommandHandler for FlushDb {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "flushdb"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "keyspace", "slow", "dangerous"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::FlushDb)
+    }
+
+    fn execute(&self, client: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        debug!("flush db: {}", client.db);
+        client.storage().clear();
+        ok()
+    }
+}
+
+pub struct Select;
+
+impl CommandHandler for Select {
+    type Args<'a> = &'a RedisValue;
+
+    fn name(&self) -> &'static str {
+        "select"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast", "connection"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Select(
+            get_next_value(&mut args).context("Index must be set for select CMD")?,
+        ))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, index: Self::Args<'_>) -> RespValue {
+        debug!("select: {index}");
+        match index.to_string().parse::<usize>() {
+            Ok(db) if client.bound_database().map_or(false, |bound| bound != db) => {
+                RespValue::Error(
+                    "NOPERM".into(),
+                    Some(format!("No permissions to access the database {db}")),
+                )
+            }
+            Ok(db) if db < client.databases.len() => {
+                client.db = db;
+                ok()
+            }
+            Ok(_) => RespValue::Error("ERR".into(), Some("DB index is out of range".into())),
+            Err(_) => RespValue::Error(
+                "ERR".into(),
+                Some("value is not an integer or out of range".into()),
+            ),
+        }
+    }
+}
+
+pub struct Command;
+
+impl CommandHandler for Command {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["slow", "connection"]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Command)
+    }
+
+    fn execute(&self, _: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        debug!("Command not implemented: COMMAND");
+        RespValue::Error("NOT_IMPLEMENTED".into(), None)
+    }
+}
+
+pub struct Client;
+
+impl CommandHandler for Client {
+    type Args<'a> = &'a ClientCmd;
+
+    fn name(&self) -> &'static str {
+        "client"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["slow", "connection"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Client(args.try_into()?))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        debug!("client: {cmd:?}");
+        client.client(cmd)
+    }
+}
+
+pub struct Hello;
+
+impl CommandHandler for Hello {
+    type Args<'a> = (
+        Option<i64>,
+        &'a Option<(RedisValue, RedisValue)>,
+        &'a Option<RedisValue>,
+    );
+
+    fn name(&self) -> &'static str {
+        "hello"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast", "connection"]
+    }
+
+    /// Parse the arguments of HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let protover = match get_next_value(&mut args).ok() {
+            Some(value) => Some(
+                value
+                    .to_string()
+                    .parse()
+                    .context("Protocol version is not an integer")?,
+            ),
+            None => None,
+        };
+        let mut auth = None;
+        let mut name = None;
+        while let Ok(option) = get_next_value(&mut args) {
+            match option.to_string().to_uppercase().as_ref() {
+                "AUTH" => {
+                    auth = Some((
+                        get_next_value(&mut args).context("Username must be set for HELLO AUTH")?,
+                        get_next_value(&mut args).context("Password must be set for HELLO AUTH")?,
+                    ))
+                }
+                "SETNAME" => {
+                    name = Some(
+                        get_next_value(&mut args).context("Name must be set for HELLO SETNAME")?,
+                    )
+                }
+                _ => bail!("Invalid HELLO option"),
+            }
+        }
+        Ok(RedisCmd::Hello(protover, auth, name))
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (protover, auth, name): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("hello: {protover:?}");
+        client.hello(protover, auth, name)
+    }
+}
+
+pub struct Time;
+
+impl CommandHandler for Time {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "time"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast"]
+    }
+
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Time)
+    }
+
+    fn execute(&self, _: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        RespValue::Array(
+            [
+                RespValue::BulkString(BulkString(now.as_secs().to_string().into())),
+                RespValue::BulkString(BulkString(now.subsec_micros().to_string().into())),
+            ]
+            .into(),
+        )
+    }
+}
+
+pub struct Lolwut;
+
+impl CommandHandler for Lolwut {
+    type Args<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "lolwut"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "fast"]
+    }
+
+    /// Arguments like VERSION are accepted but ignored, there is only one art
+    fn parse(&self, _: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Lolwut)
+    }
+
+    fn execute(&self, _: &mut ClientProcess, _: Self::Args<'_>) -> RespValue {
+        RespValue::BulkString(BulkString(
+            format!("{LOLWUT}\nMoonis ver. {}\n", env!("CARGO_PKG_VERSION")).into(),
+        ))
+    }
+}
+
+pub struct Auth;
+
+impl CommandHandler for Auth {
+    type Args<'a> = (Option<&'a RedisValue>, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "auth"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["fast", "connection"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let first = get_next_value(&mut args).context("Password must be set for AUTH")?;
+        match get_next_value(&mut args).ok() {
+            Some(password) => Ok(RedisCmd::Auth(Some(first), password)),
+            None => Ok(RedisCmd::Auth(None, first)),
+        }
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (username, password): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("auth: {username:?}");
+        client.auth(username.as_ref(), password)
+    }
+}
+
+pub struct Acl;
+
+impl CommandHandler for Acl {
+    type Args<'a> = &'a AclCmd;
+
+    fn name(&self) -> &'static str {
+        "acl"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["admin", "slow", "dangerous"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Acl(args.try_into()?))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        debug!("acl: {cmd:?}");
+        client.acl_cmd(cmd)
+    }
+}
+
+pub struct Object;
+
+impl CommandHandler for Object {
+    type Args<'a> = &'a ObjectCmd;
+
+    fn name(&self) -> &'static str {
+        "object"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "keyspace", "slow"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Object(args.try_into()?))
+    }
+
+    fn keys(&self, cmd: Self::Args<'_>) -> Vec<RedisKey> {
+        match cmd {
+            ObjectCmd::IdleTime(key) | ObjectCmd::Freq(key) | ObjectCmd::Encoding(key) => {
+                vec![key.clone()]
+            }
+        }
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        match cmd {
+            ObjectCmd::IdleTime(key) => match client.storage().shard(key).idle_time(key.clone()) {
+                Some(idle_time) => RespValue::Integer(idle_time as i64),
+                None => RespValue::Null,
+            },
+            ObjectCmd::Encoding(key) => match client.storage().shard(key).encoding(key.clone()) {
+                Some(encoding) => RespValue::BulkString(BulkString(encoding.into())),
+                None => RespValue::Null,
+            },
+            ObjectCmd::Freq(key) => match client.storage().shard(key).frequency(key.clone()) {
+                Some(frequency) => RespValue::Integer(frequency.into()),
+                None => RespValue::Null,
+            },
+        }
+    }
+}
+
+pub struct Memory;
+
+impl CommandHandler for Memory {
+    type Args<'a> = &'a MemoryCmd;
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "slow"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Memory(args.try_into()?))
+    }
+
+    fn keys(&self, cmd: Self::Args<'_>) -> Vec<RedisKey> {
+        match cmd {
+            MemoryCmd::Usage(key) => vec![key.clone()],
+            MemoryCmd::BigKeys(_) | MemoryCmd::Stats | MemoryCmd::Doctor | MemoryCmd::Purge => {
+                vec![]
+            }
+        }
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        debug!("memory: {cmd:?}");
+        client.memory(cmd)
+    }
+}
+
+pub struct Config;
+
+impl CommandHandler for Config {
+    type Args<'a> = &'a ConfigCmd;
+
+    fn name(&self) -> &'static str {
+        "config"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["admin", "slow", "dangerous"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Config(args.try_into()?))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        debug!("config: {cmd:?}");
+        client.config_cmd(cmd)
+    }
+}
+
+pub struct Info;
+
+impl CommandHandler for Info {
+    type Args<'a> = &'a [String];
+
+    fn name(&self) -> &'static str {
+        "info"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["slow", "dangerous"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Info(
+            get_remaining_strings(&mut args)?
+                .iter()
+                .map(|section| section.to_lowercase())
+                .collect(),
+        ))
+    }
+
+    fn execute(&self, client: &mut ClientProcess, sections: Self::Args<'_>) -> RespValue {
+        debug!("info: {sections:?}");
+        client.info(sections)
+    }
+}
+
+pub struct Debug;
+
+impl CommandHandler for Debug {
+    type Args<'a> = &'a DebugCmd;
+
+    fn name(&self) -> &'static str {
+        "debug"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["admin", "slow", "dangerous"]
+    }
+
+    fn parse(&self, args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Debug(args.try_into()?))
+    }
+
+    fn keys(&self, cmd: Self::Args<'_>) -> Vec<RedisKey> {
+        match cmd {
+            DebugCmd::Object(key) => vec![key.clone()],
+            DebugCmd::LoadProto(_) | DebugCmd::CommandId => vec![],
+        }
+    }
+
+    fn execute(&self, client: &mut ClientProcess, cmd: Self::Args<'_>) -> RespValue {
+        match cmd {
+            DebugCmd::LoadProto(payload) => {
+                debug!("debug loadproto: {} bytes", payload.0.len());
+                client.load_proto(payload)
+            }
+            DebugCmd::CommandId => RespValue::Integer(client.command_id as i64),
+            DebugCmd::Object(key) => match client.storage().shard(key).debug_object(key.clone()) {
+                Some(object) => RespValue::SimpleString(object),
+                None => RespValue::Error("ERR".into(), Some("no such key".into())),
+            },
+        }
+    }
+}
+
+pub struct JsonSet;
+
+impl CommandHandler for JsonSet {
+    type Args<'a> = (&'a RedisKey, &'a Path, &'a Document, Option<Condition>);
+
+    fn name(&self) -> &'static str {
+        "json.set"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "json", "slow"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of JSON.SET key path value [NX | XX]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of JSON.SET")?;
+        let path = get_next_path(&mut args).context("Path must be set for JSON.SET")?;
+        let document = get_next_document(&mut args).context("Value must be set for JSON.SET")?;
+        let condition = match get_next_value(&mut args).ok() {
+            Some(option) => match option.to_string().to_uppercase().as_ref() {
+                "NX" => Some(Condition::Nx),
+                "XX" => Some(Condition::Xx),
+                _ => bail!("Invalid JSON.SET option"),
+            },
+            None => None,
+        };
+        Ok(RedisCmd::Json(JsonCmd::Set(key, path, document, condition)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, path, document, condition): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("json set: {}: {}", key, path.text());
+        let shard = client.storage().shard(key);
+        match shard.json_set(key.clone(), path.clone(), document.clone(), condition) {
+            Ok(true) => ok(),
+            Ok(false) => RespValue::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct JsonGet;
+
+impl CommandHandler for JsonGet {
+    type Args<'a> = (&'a RedisKey, &'a [Path]);
+
+    fn name(&self) -> &'static str {
+        "json.get"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "json", "slow"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of JSON.GET")?;
+        let mut paths = vec![];
+        while !args.is_empty() {
+            paths.push(get_next_path(&mut args)?);
+        }
+        Ok(RedisCmd::Json(JsonCmd::Get(key, paths)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, paths): Self::Args<'_>) -> RespValue {
+        debug!("json get: {}", key);
+        let shard = client.storage().read_shard(key);
+        match shard.json_get(key.clone(), paths.to_vec(), !client.no_touch) {
+            Ok(Some(text)) => RespValue::BulkString(BulkString(text.into())),
+            Ok(None) => RespValue::Null,
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct JsonDel;
+
+impl CommandHandler for JsonDel {
+    type Args<'a> = (&'a RedisKey, &'a Path);
+
+    fn name(&self) -> &'static str {
+        "json.del"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "json", "slow"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of JSON.DEL")?;
+        let path = if args.is_empty() {
+            Path::root()
+        } else {
+            get_next_path(&mut args)?
+        };
+        Ok(RedisCmd::Json(JsonCmd::Del(key, path)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, path): Self::Args<'_>) -> RespValue {
+        debug!("json del: {}: {}", key, path.text());
+        match client
+            .storage()
+            .shard(key)
+            .json_del(key.clone(), path.clone())
+        {
+            Ok(removed) => RespValue::Integer(removed),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct JsonArrAppend;
+
+impl CommandHandler for JsonArrAppend {
+    type Args<'a> = (&'a RedisKey, &'a Path, &'a [Document]);
+
+    fn name(&self) -> &'static str {
+        "json.arrappend"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "json", "slow"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of JSON.ARRAPPEND key path value [value ...]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of JSON.ARRAPPEND")?;
+        let path = get_next_path(&mut args).context("Path must be set for JSON.ARRAPPEND")?;
+        if args.is_empty() {
+            bail!("Values must be set for JSON.ARRAPPEND");
+        }
+        let mut values = vec![];
+        while !args.is_empty() {
+            values.push(get_next_document(&mut args)?);
+        }
+        Ok(RedisCmd::Json(JsonCmd::ArrAppend(key, path, values)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, path, values): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("json arrappend: {}: {}", key, path.text());
+        let shard = client.storage().shard(key);
+        match shard.json_arr_append(key.clone(), path.clone(), values.to_vec()) {
+            Ok(lengths) => json::lengths_reply(path, lengths),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct BfReserve;
+
+impl CommandHandler for BfReserve {
+    type Args<'a> = (&'a RedisKey, f64, usize, usize);
+
+    fn name(&self) -> &'static str {
+        "bf.reserve"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "bloom", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of BF.RESERVE key error_rate capacity [EXPANSION n] [NONSCALING]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of BF.RESERVE")?;
+        let error_rate: f64 = get_next_value(&mut args)
+            .context("Error rate must be set for BF.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Error rate is not a number")?;
+        if !(error_rate > 0.0 && error_rate < 1.0) {
+            bail!("Error rate must be between 0 and 1");
+        }
+        let capacity: usize = get_next_value(&mut args)
+            .context("Capacity must be set for BF.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Capacity is not an integer")?;
+        if capacity == 0 {
+            bail!("Capacity must be larger than 0");
+        }
+        let mut expansion = DEFAULT_EXPANSION;
+        while let Ok(option) = get_next_value(&mut args) {
+            match option.to_string().to_uppercase().as_ref() {
+                "EXPANSION" => {
+                    expansion = get_next_value(&mut args)
+                        .context("Expansion must be set for BF.RESERVE")?
+                        .to_string()
+                        .parse()
+                        .context("Expansion is not an integer")?;
+                    if expansion == 0 {
+                        bail!("Expansion must be larger than 0");
+                    }
+                }
+                "NONSCALING" => expansion = 0,
+                _ => bail!("Invalid BF.RESERVE option"),
+            }
+        }
+        Ok(RedisCmd::Bloom(BloomCmd::Reserve(
+            key, error_rate, capacity, expansion,
+        )))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, error_rate, capacity, expansion): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("bf reserve: {}", key);
+        let shard = client.storage().shard(key);
+        match shard.bf_reserve(key.clone(), error_rate, capacity, expansion) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct BfAdd;
+
+impl CommandHandler for BfAdd {
+    type Args<'a> = (&'a RedisKey, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "bf.add"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "bloom", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Bloom(BloomCmd::Add(
+            get_next_value(&mut args).context("Can't get the key of BF.ADD")?,
+            get_next_value(&mut args).context("Item must be set for BF.ADD")?,
+        )))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, item): Self::Args<'_>) -> RespValue {
+        debug!("bf add: {}: {}", key, item);
+        let shard = client.storage().shard(key);
+        match shard.bf_add(key.clone(), vec![item.clone()]) {
+            Ok(mut added) => bloom::added_reply(added.remove(0)),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct BfMAdd;
+
+impl CommandHandler for BfMAdd {
+    type Args<'a> = (&'a RedisKey, &'a [RedisValue]);
+
+    fn name(&self) -> &'static str {
+        "bf.madd"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "bloom", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of BF.MADD")?;
+        let items = get_remaining_keys(&mut args)?;
+        if items.is_empty() {
+            bail!("Items must be set for BF.MADD");
+        }
+        Ok(RedisCmd::Bloom(BloomCmd::MAdd(key, items)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, items): Self::Args<'_>) -> RespValue {
+        debug!("bf madd: {}: {:?}", key, items);
+        match client
+            .storage()
+            .shard(key)
+            .bf_add(key.clone(), items.to_vec())
+        {
+            Ok(added) => RespValue::Array(added.into_iter().map(bloom::added_reply).collect()),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct BfExists;
+
+impl CommandHandler for BfExists {
+    type Args<'a> = (&'a RedisKey, &'a RedisValue);
+
+    fn name(&self) -> &'static str {
+        "bf.exists"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "bloom", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        Ok(RedisCmd::Bloom(BloomCmd::Exists(
+            get_next_value(&mut args).context("Can't get the key of BF.EXISTS")?,
+            get_next_value(&mut args).context("Item must be set for BF.EXISTS")?,
+        )))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, item): Self::Args<'_>) -> RespValue {
+        debug!("bf exists: {}: {}", key, item);
+        let shard = client.storage().read_shard(key);
+        match shard.bf_exists(key.clone(), vec![item.clone()], !client.no_touch) {
+            Ok(exists) => RespValue::Integer(exists.contains(&true).into()),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct CmsInitByDim;
+
+impl CommandHandler for CmsInitByDim {
+    type Args<'a> = (&'a RedisKey, usize, usize);
+
+    fn name(&self) -> &'static str {
+        "cms.initbydim"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "cms", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of CMS.INITBYDIM")?;
+        let width: usize = get_next_value(&mut args)
+            .context("Width must be set for CMS.INITBYDIM")?
+            .to_string()
+            .parse()
+            .context("Width is not an integer")?;
+        let depth: usize = get_next_value(&mut args)
+            .context("Depth must be set for CMS.INITBYDIM")?
+            .to_string()
+            .parse()
+            .context("Depth is not an integer")?;
+        if width == 0 || depth == 0 {
+            bail!("Width and depth must be larger than 0");
+        }
+        Ok(RedisCmd::Cms(CmsCmd::InitByDim(key, width, depth)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, width, depth): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("cms initbydim: {}", key);
+        let shard = client.storage().shard(key);
+        match shard.cms_init(key.clone(), width, depth) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct CmsInitByProb;
+
+impl CommandHandler for CmsInitByProb {
+    type Args<'a> = (&'a RedisKey, f64, f64);
+
+    fn name(&self) -> &'static str {
+        "cms.initbyprob"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "cms", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of CMS.INITBYPROB")?;
+        let error: f64 = get_next_value(&mut args)
+            .context("Error must be set for CMS.INITBYPROB")?
+            .to_string()
+            .parse()
+            .context("Error is not a number")?;
+        let probability: f64 = get_next_value(&mut args)
+            .context("Probability must be set for CMS.INITBYPROB")?
+            .to_string()
+            .parse()
+            .context("Probability is not a number")?;
+        if !(error > 0.0 && error < 1.0 && probability > 0.0 && probability < 1.0) {
+            bail!("Error and probability must be between 0 and 1");
+        }
+        Ok(RedisCmd::Cms(CmsCmd::InitByProb(key, error, probability)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, error, probability): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("cms initbyprob: {}", key);
+        let (width, depth) = sketch::dimensions(error, probability);
+        let shard = client.storage().shard(key);
+        match shard.cms_init(key.clone(), width, depth) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct CmsIncrBy;
+
+impl CommandHandler for CmsIncrBy {
+    type Args<'a> = (&'a RedisKey, &'a [(RedisValue, u64)]);
+
+    fn name(&self) -> &'static str {
+        "cms.incrby"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "cms", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of CMS.INCRBY")?;
+        let increments = get_remaining_increments(&mut args, "CMS.INCRBY")?;
+        Ok(RedisCmd::Cms(CmsCmd::IncrBy(key, increments)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, increments): Self::Args<'_>) -> RespValue {
+        debug!("cms incrby: {}: {:?}", key, increments);
+        let shard = client.storage().shard(key);
+        match shard.cms_incr_by(key.clone(), increments.to_vec()) {
+            Ok(counts) => sketch::counts_reply(counts),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct CmsQuery;
+
+impl CommandHandler for CmsQuery {
+    type Args<'a> = (&'a RedisKey, &'a [RedisValue]);
+
+    fn name(&self) -> &'static str {
+        "cms.query"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "cms", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of CMS.QUERY")?;
+        let items = get_remaining_keys(&mut args)?;
+        if items.is_empty() {
+            bail!("Items must be set for CMS.QUERY");
+        }
+        Ok(RedisCmd::Cms(CmsCmd::Query(key, items)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, items): Self::Args<'_>) -> RespValue {
+        debug!("cms query: {}: {:?}", key, items);
+        let shard = client.storage().read_shard(key);
+        match shard.cms_query(key.clone(), items.to_vec(), !client.no_touch) {
+            Ok(counts) => sketch::counts_reply(counts),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct CmsMerge;
+
+impl CommandHandler for CmsMerge {
+    type Args<'a> = (&'a RedisKey, &'a [RedisKey], &'a [u64]);
+
+    fn name(&self) -> &'static str {
+        "cms.merge"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "cms", "slow"]
+    }
+
+    /// Parse the arguments of CMS.MERGE destination numkeys source... [WEIGHTS weight...]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of CMS.MERGE")?;
+        let count: usize = get_next_value(&mut args)
+            .context("Number of sources must be set for CMS.MERGE")?
+            .to_string()
+            .parse()
+            .context("Number of sources is not an integer")?;
+        if count == 0 {
+            bail!("Number of sources must be larger than 0");
+        }
+        let sources = (0..count)
+            .map(|_| get_next_value(&mut args).context("Not enough sources for CMS.MERGE"))
+            .collect::<Result<Vec<_>>>()?;
+        let weights = match get_next_value(&mut args) {
+            Ok(option) if option.to_string().eq_ignore_ascii_case("WEIGHTS") => (0..count)
+                .map(|_| {
+                    get_next_value(&mut args)
+                        .context("Not enough weights for CMS.MERGE")?
+                        .to_string()
+                        .parse()
+                        .context("Weight is not an integer")
+                })
+                .collect::<Result<Vec<u64>>>()?,
+            Ok(_) => bail!("Invalid CMS.MERGE option"),
+            Err(_) => vec![1; count],
+        };
+        if !args.is_empty() {
+            bail!("Too many arguments for CMS.MERGE");
+        }
+        Ok(RedisCmd::Cms(CmsCmd::Merge(key, sources, weights)))
+    }
+
+    fn keys(&self, (key, sources, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        let mut keys = vec![key.clone()];
+        keys.extend(sources.iter().cloned());
+        keys
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, sources, weights): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("cms merge: {}: {:?}", key, sources);
+        // The sources can be in other shards, they are sent to the destination's
+        let mut sketches = Vec::with_capacity(sources.len());
+        for (source, weight) in sources.iter().zip(weights.iter()) {
+            match client.storage().shard(source).cms_sketch(source.clone()) {
+                Ok(sketch) => sketches.push((sketch, *weight)),
+                Err(err) => return err.into(),
+            }
+        }
+        let shard = client.storage().shard(key);
+        match shard.cms_merge(key.clone(), sketches) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TopKReserve;
+
+impl CommandHandler for TopKReserve {
+    type Args<'a> = (&'a RedisKey, usize, usize, usize, f64);
+
+    fn name(&self) -> &'static str {
+        "topk.reserve"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "topk", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of TOPK.RESERVE key topk [width depth decay]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TOPK.RESERVE")?;
+        let k: usize = get_next_value(&mut args)
+            .context("Number of items must be set for TOPK.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Number of items is not an integer")?;
+        if k == 0 {
+            bail!("Number of items must be larger than 0");
+        }
+        if args.is_empty() {
+            return Ok(RedisCmd::TopK(TopKCmd::Reserve(
+                key,
+                k,
+                TOPK_DEFAULT_WIDTH,
+                TOPK_DEFAULT_DEPTH,
+                TOPK_DEFAULT_DECAY,
+            )));
+        }
+        let width: usize = get_next_value(&mut args)
+            .context("Width must be set for TOPK.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Width is not an integer")?;
+        let depth: usize = get_next_value(&mut args)
+            .context("Depth must be set for TOPK.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Depth is not an integer")?;
+        let decay: f64 = get_next_value(&mut args)
+            .context("Decay must be set for TOPK.RESERVE")?
+            .to_string()
+            .parse()
+            .context("Decay is not a number")?;
+        if width == 0 || depth == 0 {
+            bail!("Width and depth must be larger than 0");
+        }
+        if !(decay > 0.0 && decay <= 1.0) {
+            bail!("Decay must be between 0 and 1");
+        }
+        Ok(RedisCmd::TopK(TopKCmd::Reserve(
+            key, k, width, depth, decay,
+        )))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, k, width, depth, decay): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("topk reserve: {}", key);
+        let shard = client.storage().shard(key);
+        match shard.topk_reserve(key.clone(), k, width, depth, decay) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TopKAdd;
+
+impl CommandHandler for TopKAdd {
+    type Args<'a> = (&'a RedisKey, &'a [RedisValue]);
+
+    fn name(&self) -> &'static str {
+        "topk.add"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "topk", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TOPK.ADD")?;
+        let items = get_remaining_keys(&mut args)?;
+        if items.is_empty() {
+            bail!("Items must be set for TOPK.ADD");
+        }
+        Ok(RedisCmd::TopK(TopKCmd::Add(key, items)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, items): Self::Args<'_>) -> RespValue {
+        debug!("topk add: {}: {:?}", key, items);
+        let increments = items.iter().map(|item| (item.clone(), 1)).collect();
+        let shard = client.storage().shard(key);
+        match shard.topk_incr_by(key.clone(), increments) {
+            Ok(expelled) => sketch::expelled_reply(expelled),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TopKIncrBy;
+
+impl CommandHandler for TopKIncrBy {
+    type Args<'a> = (&'a RedisKey, &'a [(RedisValue, u64)]);
+
+    fn name(&self) -> &'static str {
+        "topk.incrby"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "topk", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TOPK.INCRBY")?;
+        let increments = get_remaining_increments(&mut args, "TOPK.INCRBY")?;
+        if increments
+            .iter()
+            .any(|(_, increment)| *increment > TOPK_MAX_INCREMENT)
+        {
+            bail!("Increment must be at most {TOPK_MAX_INCREMENT}");
+        }
+        Ok(RedisCmd::TopK(TopKCmd::IncrBy(key, increments)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, increments): Self::Args<'_>) -> RespValue {
+        debug!("topk incrby: {}: {:?}", key, increments);
+        let shard = client.storage().shard(key);
+        match shard.topk_incr_by(key.clone(), increments.to_vec()) {
+            Ok(expelled) => sketch::expelled_reply(expelled),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TopKQuery;
+
+impl CommandHandler for TopKQuery {
+    type Args<'a> = (&'a RedisKey, &'a [RedisValue]);
+
+    fn name(&self) -> &'static str {
+        "topk.query"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "topk", "fast"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TOPK.QUERY")?;
+        let items = get_remaining_keys(&mut args)?;
+        if items.is_empty() {
+            bail!("Items must be set for TOPK.QUERY");
+        }
+        Ok(RedisCmd::TopK(TopKCmd::Query(key, items)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, items): Self::Args<'_>) -> RespValue {
+        debug!("topk query: {}: {:?}", key, items);
+        let shard = client.storage().read_shard(key);
+        match shard.topk_query(key.clone(), items.to_vec(), !client.no_touch) {
+            Ok(found) => RespValue::Array(
+                found
+                    .into_iter()
+                    .map(|found| RespValue::Integer(found.into()))
+                    .collect(),
+            ),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TopKList;
+
+impl CommandHandler for TopKList {
+    type Args<'a> = (&'a RedisKey, bool);
+
+    fn name(&self) -> &'static str {
+        "topk.list"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "topk", "slow"]
+    }
+
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TOPK.LIST")?;
+        let with_count = match get_next_value(&mut args) {
+            Ok(option) if option.to_string().eq_ignore_ascii_case("WITHCOUNT") => true,
+            Ok(_) => bail!("Invalid TOPK.LIST option"),
+            Err(_) => false,
+        };
+        Ok(RedisCmd::TopK(TopKCmd::List(key, with_count)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, with_count): Self::Args<'_>) -> RespValue {
+        debug!("topk list: {}", key);
+        let shard = client.storage().read_shard(key);
+        match shard.topk_list(key.clone(), !client.no_touch) {
+            Ok(top) => RespValue::Array(
+                top.into_iter()
+                    .flat_map(|(item, count)| {
+                        let mut reply = vec![RespValue::BulkString(item)];
+                        if with_count {
+                            reply.push(RespValue::Integer(count as i64));
+                        }
+                        reply
+                    })
+                    .collect(),
+            ),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TsCreate;
+
+impl CommandHandler for TsCreate {
+    type Args<'a> = (&'a RedisKey, &'a SeriesOptions);
+
+    fn name(&self) -> &'static str {
+        "ts.create"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "timeseries", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of TS.CREATE key [RETENTION ms] [LABELS label value...]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TS.CREATE")?;
+        let options = get_series_options(&mut args)?;
+        Ok(RedisCmd::Ts(TsCmd::Create(key, options)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(&self, client: &mut ClientProcess, (key, options): Self::Args<'_>) -> RespValue {
+        debug!("ts create: {}", key);
+        let shard = client.storage().shard(key);
+        match shard.ts_create(key.clone(), options.clone()) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TsAdd;
+
+impl CommandHandler for TsAdd {
+    type Args<'a> = (&'a RedisKey, Option<u64>, f64, &'a Option<SeriesOptions>);
+
+    fn name(&self) -> &'static str {
+        "ts.add"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "timeseries", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of TS.ADD key timestamp|* value [RETENTION ms] [LABELS...]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TS.ADD")?;
+        let timestamp = match get_next_value(&mut args)
+            .context("Timestamp must be set for TS.ADD")?
+            .to_string()
+            .as_ref()
+        {
+            "*" => None,
+            timestamp => Some(timestamp.parse().context("Timestamp is not an integer")?),
+        };
+        let value: f64 = get_next_value(&mut args)
+            .context("Value must be set for TS.ADD")?
+            .to_string()
+            .parse()
+            .context("Value is not a number")?;
+        let options = get_series_options(&mut args)?;
+        Ok(RedisCmd::Ts(TsCmd::Add(
+            key,
+            timestamp,
+            value,
+            Some(options),
+        )))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, timestamp, value, options): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("ts add: {}: {:?} {}", key, timestamp, value);
+        let shard = client.storage().shard(key);
+        let (timestamp, mut closed) =
+            match shard.ts_add(key.clone(), timestamp, value, options.clone()) {
+                Ok(added) => added,
+                Err(err) => return err.into(),
+            };
+        // The destinations of the compaction rules can be in other shards and have
+        // rules of their own. A destination deleted meanwhile skips its samples
+        while let Some((dest, (time, value))) = closed.pop() {
+            let shard = client.storage().shard(&dest);
+            if let Ok((_, more)) = shard.ts_add(dest, Some(time), value, None) {
+                closed.extend(more);
+            }
+        }
+        RespValue::Integer(timestamp as i64)
+    }
+}
+
+pub struct TsCreateRule;
+
+impl CommandHandler for TsCreateRule {
+    type Args<'a> = (&'a RedisKey, &'a RedisKey, Aggregator);
+
+    fn name(&self) -> &'static str {
+        "ts.createrule"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["write", "timeseries", "fast"]
+    }
+
+    fn flags(&self) -> &'static [Flag] {
+        &[Flag::Deterministic]
+    }
+
+    /// Parse the arguments of TS.CREATERULE source destination AGGREGATION type bucket
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TS.CREATERULE")?;
+        let dest = get_next_value(&mut args).context("Destination must be set")?;
+        if key == dest {
+            bail!("TSDB: the source key and destination key should be different");
+        }
+        let aggregator = match get_next_value(&mut args) {
+            Ok(option) if option.to_string().eq_ignore_ascii_case("AGGREGATION") => {
+                get_next_aggregator(&mut args)?
+            }
+            _ => bail!("AGGREGATION must be set for TS.CREATERULE"),
+        };
+        Ok(RedisCmd::Ts(TsCmd::CreateRule(key, dest, aggregator)))
+    }
+
+    fn keys(&self, (key, dest, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone(), dest.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, dest, aggregator): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("ts createrule: {} -> {}", key, dest);
+        // The destination can be in another shard, its type is checked first
+        match client.storage().shard(dest).encoding(dest.clone()) {
+            Some(encoding) if encoding == "timeseries" => (),
+            Some(_) => return StorageError::WrongType.into(),
+            None => return StorageError::Invalid("TSDB: the key does not exist".into()).into(),
+        }
+        let shard = client.storage().shard(key);
+        match shard.ts_create_rule(key.clone(), dest.clone(), aggregator) {
+            Ok(()) => ok(),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TsRange;
+
+impl CommandHandler for TsRange {
+    type Args<'a> = (&'a RedisKey, u64, u64, Option<Aggregator>);
+
+    fn name(&self) -> &'static str {
+        "ts.range"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "timeseries", "slow"]
+    }
+
+    /// Parse the arguments of TS.RANGE key from to [AGGREGATION type bucket]
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let key = get_next_value(&mut args).context("Can't get the key of TS.RANGE")?;
+        let from = get_next_timestamp(&mut args).context("Invalid start of TS.RANGE")?;
+        let to = get_next_timestamp(&mut args).context("Invalid end of TS.RANGE")?;
+        let aggregator = match get_next_value(&mut args) {
+            Ok(option) if option.to_string().eq_ignore_ascii_case("AGGREGATION") => {
+                Some(get_next_aggregator(&mut args)?)
+            }
+            Ok(_) => bail!("Invalid TS.RANGE option"),
+            Err(_) => None,
+        };
+        if !args.is_empty() {
+            bail!("Invalid TS.RANGE option");
+        }
+        Ok(RedisCmd::Ts(TsCmd::Range(key, from, to, aggregator)))
+    }
+
+    fn keys(&self, (key, ..): Self::Args<'_>) -> Vec<RedisKey> {
+        vec![key.clone()]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (key, from, to, aggregator): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("ts range: {}: {} {}", key, from, to);
+        let shard = client.storage().read_shard(key);
+        match shard.ts_range(key.clone(), from, to, aggregator, !client.no_touch) {
+            Ok(samples) => timeseries::samples_reply(samples),
+            Err(err) => err.into(),
+        }
+    }
+}
+
+pub struct TsMRange;
+
+impl CommandHandler for TsMRange {
+    type Args<'a> = (u64, u64, Option<Aggregator>, &'a [Filter], bool);
+
+    fn name(&self) -> &'static str {
+        "ts.mrange"
+    }
+
+    fn categories(&self) -> &'static [&'static str] {
+        &["read", "timeseries", "slow"]
+    }
+
+    /// Parse the arguments of TS.MRANGE from to [WITHLABELS] [AGGREGATION type bucket]
+    /// FILTER filter...
+    fn parse(&self, mut args: VecDeque<RespValue>) -> Result<RedisCmd> {
+        let from = get_next_timestamp(&mut args).context("Invalid start of TS.MRANGE")?;
+        let to = get_next_timestamp(&mut args).context("Invalid end of TS.MRANGE")?;
+        let mut with_labels = false;
+        let mut aggregator = None;
+        loop {
+            let option = get_next_value(&mut args).context("FILTER must be set for TS.MRANGE")?;
+            match option.to_string().to_uppercase().as_ref() {
+                "WITHLABELS" => with_labels = true,
+                "AGGREGATION" => aggregator = Some(get_next_aggregator(&mut args)?),
+                "FILTER" => break,
+                _ => bail!("Invalid TS.MRANGE option"),
+            }
+        }
+        let filters = get_remaining_strings(&mut args)?
+            .iter()
+            .map(|filter| Filter::parse(filter).ok_or_else(|| anyhow!("Invalid filter {filter}")))
+            .collect::<Result<Vec<_>>>()?;
+        if filters.is_empty() {
+            bail!("Filters must be set for TS.MRANGE");
+        }
+        Ok(RedisCmd::Ts(TsCmd::MRange(
+            from,
+            to,
+            aggregator,
+            filters,
+            with_labels,
+        )))
+    }
+
+    /// The matched series are filtered by the permissions of the user when it runs
+    fn keys(&self, _: Self::Args<'_>) -> Vec<RedisKey> {
+        vec![]
+    }
+
+    fn execute(
+        &self,
+        client: &mut ClientProcess,
+        (from, to, aggregator, filters, with_labels): Self::Args<'_>,
+    ) -> RespValue {
+        debug!("ts mrange: {} {}", from, to);
+        let series = client.storage().ts_mrange(from, to, aggregator, filters);
+        // The keys are only known once matched, the series the user can't access
+        // are left out. Both lists are sorted by key
+        let keys = series.iter().map(|(key, ..)| key.clone()).collect();
+        let accessible = client.accessible_keys(CommandHandler::name(self), keys);
+        RespValue::Array(
+            series
+                .into_iter()
+                .filter(|(key, ..)| accessible.binary_search_by(|k| k.0.cmp(&key.0)).is_ok())
+                .map(|(key, labels, samples)| {
+                    let labels = if with_labels { labels } else { vec![] };
+                    RespValue::Array(
+                        [
+                            RespValue::BulkString(key),
+                            timeseries::labels_reply(labels),
+                            timeseries::samples_reply(samples),
+                        ]
+                        .into(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arguments of a command as sent by the clients, bulk strings
+    fn args(args: &[&str]) -> VecDeque<RespValue> {
+        args.iter()
+            .map(|arg| RespValue::BulkString(BulkString(arg.to_string().into())))
+            .collect()
+    }
+
+    fn key(key: &str) -> RedisKey {
+        BulkString(key.to_string().into())
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup("GET").map(|handler| handler.name()), Some("get"));
+        assert_eq!(
+            lookup("Ts.MRange").map(|handler| handler.name()),
+            Some("ts.mrange")
+        );
+        assert!(lookup("unknown").is_none());
+    }
+
+    #[test]
+    fn set_is_batched_and_replicated_verbatim() {
+        let cmd = CommandHandler::parse(&Set, args(&["key", "value"])).unwrap();
+        assert_eq!(keys(&cmd), [key("key")]);
+        assert_eq!(flags(&cmd), [Flag::Deterministic, Flag::Batchable]);
+        assert!(CommandHandler::parse(&Set, args(&["key"])).is_err());
+    }
+
+    #[test]
+    fn cas_is_replicated_by_its_effects() {
+        let cmd = CommandHandler::parse(&Cas, args(&["key", "3", "value"])).unwrap();
+        assert!(matches!(cmd, RedisCmd::Cas(_, 3, _)));
+        assert_eq!(Cas.keys((&key("key"), 3, &key("value"))), [key("key")]);
+        assert!(flags(&cmd).is_empty());
+        assert!(CommandHandler::parse(&Cas, args(&["key", "three", "value"])).is_err());
+    }
+
+    #[test]
+    fn mget_needs_keys() {
+        let cmd = CommandHandler::parse(&MGet, args(&["a", "b"])).unwrap();
+        assert_eq!(keys(&cmd), [key("a"), key("b")]);
+        assert!(flags(&cmd).is_empty());
+        assert!(CommandHandler::parse(&MGet, args(&[])).is_err());
+    }
+
+    #[test]
+    fn mc_counters_share_their_command() {
+        let incr = CommandHandler::parse(&McIncr, args(&["counter", "2"])).unwrap();
+        let decr = CommandHandler::parse(&McDecr, args(&["counter", "2"])).unwrap();
+        assert!(matches!(incr, RedisCmd::McIncr(_, 2, false)));
+        assert!(matches!(decr, RedisCmd::McIncr(_, 2, true)));
+        assert_eq!(keys(&decr), [key("counter")]);
+        assert!(CommandHandler::parse(&McDecr, args(&["counter", "-2"])).is_err());
+    }
+
+    #[test]
+    fn cms_merge_accesses_the_sources() {
+        let cmd = CommandHandler::parse(&CmsMerge, args(&["dest", "2", "a", "b"])).unwrap();
+        assert!(matches!(&cmd, RedisCmd::Cms(CmsCmd::Merge(_, _, weights)) if weights == &[1, 1]));
+        assert_eq!(keys(&cmd), [key("dest"), key("a"), key("b")]);
+        let weighted = args(&["dest", "2", "a", "b", "WEIGHTS", "2", "3"]);
+        let cmd = CommandHandler::parse(&CmsMerge, weighted).unwrap();
+        assert!(matches!(&cmd, RedisCmd::Cms(CmsCmd::Merge(_, _, weights)) if weights == &[2, 3]));
+        assert!(CommandHandler::parse(&CmsMerge, args(&["dest", "2", "a"])).is_err());
+    }
+
+    #[test]
+    fn ts_createrule_accesses_both_series() {
+        let rule = args(&["source", "dest", "AGGREGATION", "avg", "60"]);
+        let cmd = CommandHandler::parse(&TsCreateRule, rule).unwrap();
+        assert_eq!(keys(&cmd), [key("source"), key("dest")]);
+        assert_eq!(flags(&cmd), [Flag::Deterministic]);
+        let same = args(&["source", "source", "AGGREGATION", "avg", "60"]);
+        assert!(CommandHandler::parse(&TsCreateRule, same).is_err());
+        let empty = args(&["source", "dest", "AGGREGATION", "avg", "0"]);
+        assert!(CommandHandler::parse(&TsCreateRule, empty).is_err());
+    }
+
+    #[test]
+    fn ts_mrange_keys_are_filtered_when_it_runs() {
+        let cmd = CommandHandler::parse(&TsMRange, args(&["-", "+", "FILTER", "a=b"])).unwrap();
+        assert!(keys(&cmd).is_empty());
+        assert!(CommandHandler::parse(&TsMRange, args(&["-", "+", "FILTER"])).is_err());
+        assert!(CommandHandler::parse(&TsMRange, args(&["-", "+", "a=b"])).is_err());
+    }
+
+    #[test]
+    fn subcommands_access_their_keys() {
+        let object = CommandHandler::parse(&Object, args(&["ENCODING", "key"])).unwrap();
+        assert_eq!(keys(&object), [key("key")]);
+        let stats = CommandHandler::parse(&Memory, args(&["STATS"])).unwrap();
+        assert!(keys(&stats).is_empty());
+        let usage = CommandHandler::parse(&Memory, args(&["USAGE", "key"])).unwrap();
+        assert_eq!(keys(&usage), [key("key")]);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{str::FromStr, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Server configuration shared with every client process
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Addresses the listeners are bound to
+    pub bind: Vec<String>,
+    /// Port of the listener accepting the connection, the one assigned when 0 is requested
+    pub port: u16,
+    /// Same as port for TLS connections, 0 without TLS
+    pub tls_port: u16,
+    /// Password required to authenticate the connections, no auth when None
+    pub requirepass: Option<String>,
+    /// File used to load and save the ACL users
+    pub aclfile: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// Only accept TLS connections, the plaintext port is not opened
+    pub tls_only: bool,
+    /// Reject connections from outside the loopback interface when the default user has no
+    /// password, enabled only if no bind address was explicitly configured
+    pub protected_mode: bool,
+    /// Max memory used by the keys of each database, 0 means no limit
+    pub maxmemory: usize,
+    /// How keys are evicted when maxmemory is reached
+    pub maxmemory_policy: EvictionPolicy,
+    /// Number of keys sampled to find the best key to evict
+    pub maxmemory_samples: usize,
+    /// Number of Storage processes the keyspace is partitioned across
+    pub shards: usize,
+    /// Number of logical databases
+    pub databases: usize,
+    /// Processes spawning the clients of the connections accepted by each listener
+    pub acceptors: usize,
+    /// File receiving the audit log as JSON lines, disabled when None
+    pub audit_log: Option<String>,
+    /// Audit all the write commands, not only the administrative ones
+    pub audit_writes: bool,
+    /// File receiving the writes of every database as JSON lines, disabled when None
+    pub cdc_log: Option<String>,
+    /// Name of the process receiving the writes of every database, registered by the
+    /// application embedding the server
+    pub cdc_consumer: Option<String>,
+    /// Endpoints receiving the writes to the keys matching their patterns
+    pub webhooks: Vec<Webhook>,
+    /// OTLP/HTTP endpoint of the collector receiving the spans, tracing is disabled when None
+    pub otlp_endpoint: Option<HttpUrl>,
+    /// Part of the commands traced when the client didn't join a trace, from 0 to 1
+    pub trace_sample_ratio: f64,
+    /// Port of the HTTP listener exposing the Prometheus metrics, disabled when None
+    pub metrics_port: Option<u16>,
+    /// Port of the memcached protocol listener serving the first database, disabled when None
+    pub memcached_port: Option<u16>,
+    /// Port of the HTTP gateway to the keys and the commands, disabled when None
+    pub http_port: Option<u16>,
+    /// Port of the WebSocket bridge carrying RESP or JSON commands, disabled when None
+    pub websocket_port: Option<u16>,
+    /// Origins of the browser pages allowed to open WebSocket connections, besides the pages
+    /// served by the host of the connection, ie. `https://app.example.com`
+    pub websocket_origins: Vec<String>,
+    /// Max memory of each client process
+    pub client_max_memory: usize,
+    /// Commands of a connection parsed and waiting to be executed, the connection isn't
+    /// read beyond them until they are replied. 0 means no limit
+    pub pipeline_max_commands: usize,
+    /// Replies of a connection encoded before they are written, the next commands are
+    /// only read once they are written. 0 means no limit
+    pub pipeline_max_reply_bytes: usize,
+    /// Bytes read from a connection at a time, the reads grow while they fill the buffer
+    pub read_buffer_size: usize,
+    /// Max bytes read from a connection at a time, the buffers grown over it by big commands
+    /// are released once they are parsed
+    pub read_buffer_max: usize,
+    /// Max concurrent connections from the same IP address, 0 means no limit
+    pub maxclients_per_ip: usize,
+    /// Max new connections per second from the same IP address, 0 means no limit
+    pub max_connection_rate: usize,
+    /// Close the connections idle for more than this seconds, 0 means never
+    pub timeout: u64,
+    /// Disconnect the clients not reading their replies for this many seconds, 0 means never
+    pub client_write_timeout: u64,
+    /// Max memory used by the output buffers of all the clients, 0 means no limit
+    pub maxmemory_clients: usize,
+    /// Output buffer limits of each client class
+    pub client_output_buffer_limit: OutputBufferLimits,
+    /// Values bigger than this are stored compressed with LZ4, 0 disables compression
+    pub compression_threshold: usize,
+    /// Read-only copies of each storage process, reads are spread across them
+    pub storage_replicas: usize,
+    /// Seconds between the compactions of the over-allocated keyspace of each storage
+    /// process, 0 disables them
+    pub compaction_interval: u64,
+    /// Seconds the keys deleted by DEL and FLUSHDB are kept in the trash of their shard to
+    /// be restored with UNDELETE, 0 disables the trash. The trash counts towards maxmemory,
+    /// it's dropped before any key is evicted
+    pub trash_retention: u64,
+    /// Seconds between the pings of the storage processes by the watchdog, 0 disables it
+    pub watchdog_interval: u64,
+    /// Milliseconds a storage process has to reply to a ping before it's reported as stalled
+    pub watchdog_deadline_ms: u64,
+    /// Kill the storage processes stalled for several pings so their supervisors restart
+    /// them, only with storage replicas for the primaries to recover their keys from
+    pub watchdog_restart: bool,
+    /// Faults injected in debug builds
+    pub chaos: ChaosConfig,
+}
+
+impl Config {
+    /// The writes are sent to the CDC process, for its log, consumer or webhooks
+    pub fn cdc_enabled(&self) -> bool {
+        self.cdc_log.is_some() || self.cdc_consumer.is_some() || !self.webhooks.is_empty()
+    }
+
+    /// Parameters returned by CONFIG GET, with their values
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        let yes_no = |enabled: bool| if enabled { "yes" } else { "no" }.to_string();
+        vec![
+            ("bind", self.bind.join(" ")),
+            ("port", self.port.to_string()),
+            ("tls-port", self.tls_port.to_string()),
+            ("protected-mode", yes_no(self.protected_mode)),
+            ("databases", self.databases.to_string()),
+            ("timeout", self.timeout.to_string()),
+            (
+                "client-write-timeout",
+                self.client_write_timeout.to_string(),
+            ),
+            ("maxmemory", self.maxmemory.to_string()),
+            ("maxmemory-policy", self.maxmemory_policy.name().to_string()),
+            ("maxmemory-samples", self.maxmemory_samples.to_string()),
+            ("maxmemory-clients", self.maxmemory_clients.to_string()),
+        ]
+    }
+}
+
+/// Faults injected to test the recovery from failures, ignored in release builds
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ChaosConfig {
+    /// Probability of delaying a storage reply
+    pub delay_rate: f64,
+    /// Storage replies are delayed up to this many milliseconds, 0 disables the delays
+    pub max_delay_ms: u64,
+    /// Probability of dropping the connection when a client sends commands
+    pub drop_rate: f64,
+    /// A random storage process is killed this often, 0 disables the kills. Nothing is
+    /// killed without storage replicas, the primaries would lose their keys
+    pub kill_interval_secs: u64,
+}
+
+/// Plain HTTP URL the server sends requests to, like the webhooks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpUrl {
+    /// Address to connect to, `host:port`
+    pub addr: String,
+    /// Host header of the requests
+    pub host: String,
+    pub path: String,
+}
+
+impl FromStr for HttpUrl {
+    type Err = String;
+
+    /// Parse `http://<host>[:port][/path]`, HTTPS isn't supported
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Invalid URL, it must start with http://: {url}"))?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("Invalid URL, the host is missing: {url}"));
+        }
+        // IPv6 addresses are in brackets, so their colons aren't a port
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => host.to_string(),
+            _ => format!("{host}:80"),
+        };
+        Ok(Self {
+            addr,
+            host: host.into(),
+            path: path.into(),
+        })
+    }
+}
+
+/// HTTP endpoint receiving a POST with the CDC event of each write to the keys matching the
+/// pattern, flushes are sent to every endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub pattern: String,
+    pub url: HttpUrl,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    /// Parse `<pattern> <url>`, ie. `user:* http://localhost:8000/events`
+    fn from_str(webhook: &str) -> Result<Self, Self::Err> {
+        let (pattern, url) = match webhook.trim().split_once(' ') {
+            Some((pattern, url)) => (pattern, url.trim()),
+            None => return Err("The webhook needs a pattern and an URL".into()),
+        };
+        Ok(Self {
+            pattern: pattern.into(),
+            url: url.parse()?,
+        })
+    }
+}
+
+/// Listener for TLS connections, bound to each address on `tls_port`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain of the server
+    pub cert_file: String,
+    /// PEM file with the private key of the server
+    pub key_file: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Reject writes when maxmemory is reached
+    #[default]
+    NoEviction,
+    AllKeysRandom,
+    AllKeysLru,
+    AllKeysLfu,
+    VolatileRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Name of the policy, as set in the config
+    pub fn name(&self) -> &'static str {
+        use EvictionPolicy::*;
+        match self {
+            NoEviction => "noeviction",
+            AllKeysRandom => "allkeys-random",
+            AllKeysLru => "allkeys-lru",
+            AllKeysLfu => "allkeys-lfu",
+            VolatileRandom => "volatile-random",
+            VolatileLru => "volatile-lru",
+            VolatileLfu => "volatile-lfu",
+            VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        use EvictionPolicy::*;
+        match policy.to_lowercase().as_ref() {
+            "noeviction" => Ok(NoEviction),
+            "allkeys-random" => Ok(AllKeysRandom),
+            "allkeys-lru" => Ok(AllKeysLru),
+            "allkeys-lfu" => Ok(AllKeysLfu),
+            "volatile-random" => Ok(VolatileRandom),
+            "volatile-lru" => Ok(VolatileLru),
+            "volatile-lfu" => Ok(VolatileLfu),
+            "volatile-ttl" => Ok(VolatileTtl),
+            _ => Err(format!("Invalid eviction policy: {policy}")),
+        }
+    }
+}
+
+/// Limits of the output buffer of a connection, 0 disables a limit
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct OutputBufferLimit {
+    /// The connection is closed as soon as the output is over this
+    pub hard: usize,
+    /// The connection is closed when the output stays over this for `soft_seconds`
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Whether a connection with this pending output must be closed, `over_soft_since`
+    /// keeps when the output of the connection went over the soft limit
+    pub fn reached(&self, size: usize, over_soft_since: &mut Option<Instant>) -> bool {
+        if self.hard > 0 && size > self.hard {
+            return true;
+        }
+        if self.soft == 0 || size <= self.soft {
+            *over_soft_since = None;
+            return false;
+        }
+        let since = over_soft_since.get_or_insert_with(Instant::now);
+        since.elapsed().as_secs() >= self.soft_seconds
+    }
+}
+
+/// Output buffer limits by client class (client-output-buffer-limit)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl Default for OutputBufferLimits {
+    /// Same defaults as redis
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit::default(),
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+impl FromStr for OutputBufferLimits {
+    type Err = String;
+
+    /// Parse `<class> <hard> <soft> <seconds>` repeated for each class to change, ie.
+    /// `normal 0 0 0 pubsub 32mb 8mb 60`, the classes not present keep their defaults
+    fn from_str(limits: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = limits.split_whitespace().collect();
+        if parts.is_empty() || parts.len() % 4 != 0 {
+            return Err("Wrong number of arguments in buffer limit configuration.".into());
+        }
+        let mut output_limits = Self::default();
+        for limit in parts.chunks(4) {
+            let class = match limit[0].to_lowercase().as_ref() {
+                "normal" => &mut output_limits.normal,
+                "replica" | "slave" => &mut output_limits.replica,
+                "pubsub" => &mut output_limits.pubsub,
+                class => return Err(format!("Invalid client class: {class}")),
+            };
+            *class = OutputBufferLimit {
+                hard: parse_memory(limit[1])?,
+                soft: parse_memory(limit[2])?,
+                soft_seconds: limit[3]
+                    .parse()
+                    .map_err(|_| format!("Invalid soft limit seconds: {}", limit[3]))?,
+            };
+        }
+        Ok(output_limits)
+    }
+}
+
+/// Parse a memory size like redis does, ie. `100`, `1k` (1000 bytes) or `1kb` (1024 bytes)
+pub fn parse_memory(size: &str) -> Result<usize, String> {
+    let size = size.to_lowercase();
+    let units: &[(&str, usize)] = &[
+        ("kb", 1024),
+        ("mb", 1024 * 1024),
+        ("gb", 1024 * 1024 * 1024),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+        ("b", 1),
+    ];
+    let (number, unit) = units
+        .iter()
+        .find_map(|(suffix, unit)| size.strip_suffix(suffix).map(|number| (number, *unit)))
+        .unwrap_or((size.as_str(), 1));
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("Invalid memory size: {size}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sizes_have_units() {
+        assert_eq!(parse_memory("100"), Ok(100));
+        assert_eq!(parse_memory("1kb"), Ok(1024));
+        assert_eq!(parse_memory("2M"), Ok(2_000_000));
+        assert!(parse_memory("1tb").is_err());
+        let overflow = format!("{}gb", usize::MAX);
+        assert_eq!(
+            parse_memory(&overflow),
+            Err(format!("Invalid memory size: {}gb", usize::MAX))
+        );
+    }
+}
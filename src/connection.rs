@@ -0,0 +1,57 @@
+use std::{
+    io::{Read, Result, Write},
+    time::Duration,
+};
+
+use lunatic::net::{TcpStream, TlsStream};
+use serde::{Deserialize, Serialize};
+
+/// Client connection, plaintext or over TLS
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Connection {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Connection {
+    /// Reads fail after waiting this long for data, None waits forever
+    pub fn set_read_timeout(&mut self, duration: Option<Duration>) -> Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(duration),
+            Connection::Tls(stream) => stream.set_read_timeout(duration),
+        }
+    }
+
+    /// Writes fail after blocking this long, None waits forever
+    pub fn set_write_timeout(&mut self, duration: Option<Duration>) -> Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(duration),
+            Connection::Tls(stream) => stream.set_write_timeout(duration),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::{hash::Hash, mem};
+
+use ahash::RandomState;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// Entries moved from the old table to the new one on each operation while rehashing
+const REHASH_STEP: usize = 100;
+
+/// Hash table that grows incrementally like the redis dict, when the table is full a new
+/// one with double capacity is allocated and the entries are moved a few at a time on each
+/// operation, so inserting the key that triggers the resize doesn't copy the whole keyspace.
+/// Keys are hashed with aHash, much faster than SipHash for short keys, the seed is random
+/// for each process so the bucket of a key can't be predicted (HashDoS)
+pub struct Dict<K, V> {
+    table: IndexMap<K, V, RandomState>,
+    /// Previous table, empty when there is no rehash in progress
+    rehashing: IndexMap<K, V, RandomState>,
+    /// Rehashes started, a scan finds the table it was scanning became the old one
+    expansions: u64,
+}
+
+/// Where a scan of the dict continues. The old table is scanned before the new one, both
+/// from their last entry to the first: the entries removed move the last one to their
+/// position and the rehash moves the last entries of the old table to the new one, so the
+/// entries not scanned yet never move to the scanned positions
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Cursor {
+    /// Expansions of the dict when the cursor was returned
+    expansions: u64,
+    /// The old table was scanned, the new one is being scanned
+    in_table: bool,
+    /// Entries of the table still to scan, the ones before this position. None when its
+    /// scan hasn't started
+    remaining: Option<usize>,
+}
+
+impl<K, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        let hasher = RandomState::new();
+        Self {
+            table: IndexMap::with_hasher(hasher.clone()),
+            rehashing: IndexMap::with_hasher(hasher),
+            expansions: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn len(&self) -> usize {
+        self.table.len() + self.rehashing.len()
+    }
+
+    /// Entries the tables can hold without allocating
+    pub fn capacity(&self) -> usize {
+        self.table.capacity() + self.rehashing.capacity()
+    }
+
+    /// Approximate bytes allocated by the tables, used or not: each slot has the entry, its
+    /// hash and its index
+    pub fn allocated(&self) -> usize {
+        self.capacity() * (mem::size_of::<(K, V)>() + 2 * mem::size_of::<usize>())
+    }
+
+    /// Move some entries to the new table, the old table is freed once empty
+    fn rehash_step(&mut self) {
+        if self.rehashing.is_empty() {
+            return;
+        }
+        for _ in 0..REHASH_STEP {
+            match self.rehashing.pop() {
+                Some((key, value)) => {
+                    self.table.insert(key, value);
+                }
+                None => break,
+            }
+        }
+        if self.rehashing.is_empty() {
+            self.rehashing = IndexMap::with_hasher(self.table.hasher().clone());
+        }
+    }
+
+    /// Start a rehash when the table is full, instead of letting it grow all at once
+    fn expand_if_needed(&mut self) {
+        if self.rehashing.is_empty() && self.table.len() == self.table.capacity() {
+            let capacity = (self.table.capacity() * 2).max(4);
+            let table = IndexMap::with_capacity_and_hasher(capacity, self.table.hasher().clone());
+            self.rehashing = mem::replace(&mut self.table, table);
+            self.expansions += 1;
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.rehash_step();
+        let old = self.rehashing.swap_remove(&key);
+        if old.is_none() {
+            self.expand_if_needed();
+        }
+        self.table.insert(key, value).or(old)
+    }
+
+    pub fn swap_remove(&mut self, key: &K) -> Option<V> {
+        self.rehash_step();
+        self.table
+            .swap_remove(key)
+            .or_else(|| self.rehashing.swap_remove(key))
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.table.get(key).or_else(|| self.rehashing.get(key))
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.rehash_step();
+        match self.table.get_mut(key) {
+            Some(value) => Some(value),
+            None => self.rehashing.get_mut(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.table.contains_key(key) || self.rehashing.contains_key(key)
+    }
+
+    /// Entry by position, positions go through the new table and then the old one
+    pub fn get_index(&self, index: usize) -> Option<(&K, &V)> {
+        match index.checked_sub(self.table.len()) {
+            None => self.table.get_index(index),
+            Some(index) => self.rehashing.get_index(index),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.table.keys().chain(self.rehashing.keys())
+    }
+
+    /// At most `count` keys from the cursor, starting with `Cursor::default()`, and the
+    /// cursor of the next ones, None once all of them were scanned. Like SCAN, the keys
+    /// present during the whole scan are returned, the keys written meanwhile may be missed
+    /// and the keys moved by the writes may be returned twice
+    pub fn scan(&self, mut cursor: Cursor, count: usize) -> (Vec<&K>, Option<Cursor>) {
+        match self.expansions - cursor.expansions {
+            0 => (),
+            // The new table became the old one, with the same positions
+            1 if cursor.in_table => cursor.in_table = false,
+            // The entries not scanned yet may have moved to another table
+            _ => {
+                cursor.in_table = false;
+                cursor.remaining = None;
+            }
+        }
+        cursor.expansions = self.expansions;
+        let mut keys = vec![];
+        while keys.len() < count {
+            let table = if cursor.in_table {
+                &self.table
+            } else {
+                &self.rehashing
+            };
+            let remaining = cursor
+                .remaining
+                .map_or(table.len(), |remaining| remaining.min(table.len()));
+            if remaining == 0 {
+                if cursor.in_table {
+                    return (keys, None);
+                }
+                cursor.in_table = true;
+                cursor.remaining = None;
+                continue;
+            }
+            if let Some((key, _)) = table.get_index(remaining - 1) {
+                keys.push(key);
+            }
+            cursor.remaining = Some(remaining - 1);
+        }
+        (keys, Some(cursor))
+    }
+
+    /// Remove all the entries, returning them
+    pub fn drain(&mut self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.table.drain(..).chain(self.rehashing.drain(..))
+    }
+
+    pub fn clear(&mut self) {
+        self.table.clear();
+        self.rehashing = IndexMap::with_hasher(self.table.hasher().clone());
+    }
+
+    /// Release the unused capacity, finishing the rehash in progress
+    pub fn shrink_to_fit(&mut self) {
+        self.table.extend(self.rehashing.drain(..));
+        self.rehashing = IndexMap::with_hasher(self.table.hasher().clone());
+        self.table.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn dict(keys: impl IntoIterator<Item = u32>) -> Dict<u32, u32> {
+        let mut dict = Dict::default();
+        for key in keys {
+            dict.insert(key, key);
+        }
+        dict
+    }
+
+    /// Dict in the middle of a rehash, with more entries in the old table than a step moves
+    fn rehashing() -> Dict<u32, u32> {
+        let mut dict = Dict::default();
+        let mut key = 0;
+        while dict.rehashing.len() <= REHASH_STEP {
+            dict.insert(key, key);
+            key += 1;
+        }
+        dict
+    }
+
+    /// Keys of the scan from the cursor to its end
+    fn scan_all(dict: &Dict<u32, u32>, mut cursor: Cursor, count: usize) -> Vec<u32> {
+        let mut scanned = vec![];
+        loop {
+            let (keys, next) = dict.scan(cursor, count);
+            scanned.extend(keys.into_iter().copied());
+            match next {
+                Some(next) => cursor = next,
+                None => return scanned,
+            }
+        }
+    }
+
+    #[test]
+    fn entries_are_found_while_rehashing() {
+        let mut dict = rehashing();
+        let len = dict.len() as u32;
+        assert!((0..len).all(|key| dict.get(&key) == Some(&key)));
+        let old = *dict.rehashing.get_index(0).unwrap().0;
+        assert_eq!(dict.insert(old, 0), Some(old));
+        assert_eq!(dict.get(&old), Some(&0));
+        assert_eq!(dict.swap_remove(&1), Some(1));
+        assert_eq!(dict.swap_remove(&1), None);
+        assert!(!dict.contains_key(&1));
+        assert_eq!(dict.len(), len as usize - 1);
+        assert_eq!(dict.keys().count(), len as usize - 1);
+    }
+
+    #[test]
+    fn rehash_moves_a_few_entries_at_a_time() {
+        let mut dict = rehashing();
+        let (len, old) = (dict.len(), dict.rehashing.len());
+        dict.insert(u32::MAX, 0);
+        assert_eq!(dict.rehashing.len(), old - REHASH_STEP);
+        assert_eq!(dict.len(), len + 1);
+        dict.shrink_to_fit();
+        assert!(dict.rehashing.is_empty());
+        assert_eq!(dict.len(), len + 1);
+    }
+
+    #[test]
+    fn get_index_goes_through_both_tables() {
+        let dict = rehashing();
+        let keys: HashSet<_> = (0..dict.len())
+            .map(|index| *dict.get_index(index).unwrap().0)
+            .collect();
+        assert_eq!(keys, (0..dict.len() as u32).collect());
+        assert!(dict.get_index(dict.len()).is_none());
+    }
+
+    #[test]
+    fn scan_returns_each_key_once() {
+        let dict = rehashing();
+        let mut keys = scan_all(&dict, Cursor::default(), 10);
+        keys.sort();
+        assert_eq!(keys, (0..dict.len() as u32).collect::<Vec<_>>());
+        assert!(scan_all(&Dict::default(), Cursor::default(), 10).is_empty());
+    }
+
+    #[test]
+    fn scan_survives_the_expansions() {
+        let mut dict = dict(0..100);
+        let (first, cursor) = dict.scan(Cursor::default(), 10);
+        let mut scanned: HashSet<_> = first.into_iter().copied().collect();
+        // Enough writes to start and finish several rehashes between the calls
+        for key in 100..1000 {
+            dict.insert(key, key);
+        }
+        scanned.extend(scan_all(&dict, cursor.unwrap(), 10));
+        assert!((0..100).all(|key| scanned.contains(&key)));
+    }
+
+    #[test]
+    fn scan_survives_the_removals_and_the_rehash() {
+        let mut dict = rehashing();
+        let len = dict.len() as u32;
+        let (first, cursor) = dict.scan(Cursor::default(), 10);
+        let mut scanned: HashSet<_> = first.into_iter().copied().collect();
+        let removed: Vec<_> = (0..len)
+            .filter(|key| !scanned.contains(key))
+            .take(20)
+            .collect();
+        for key in &removed {
+            dict.swap_remove(key);
+        }
+        scanned.extend(scan_all(&dict, cursor.unwrap(), 10));
+        assert!((0..len)
+            .filter(|key| !removed.contains(key))
+            .all(|key| scanned.contains(&key)));
+    }
+
+    #[test]
+    fn drain_and_clear_empty_both_tables() {
+        let mut drained = rehashing();
+        let len = drained.len();
+        assert_eq!(drained.drain().count(), len);
+        assert_eq!(drained.len(), 0);
+        let mut cleared = rehashing();
+        cleared.clear();
+        assert_eq!(cleared.len(), 0);
+        assert!(cleared.get(&0).is_none());
+    }
+}
@@ -1,6 +1,6 @@
-use bytes::{BytesMut, BufMut};
+use bytes::{BufMut, BytesMut};
 
-use crate::types::{RespValue, BulkString};
+use crate::types::{BulkString, RespValue};
 
 pub fn encode_string(prefix: u8, value: String, buf: &mut BytesMut) {
     buf.reserve(value.len() + 3);
@@ -9,6 +9,11 @@ pub fn encode_string(prefix: u8, value: String, buf: &mut BytesMut) {
     buf.put(&b"\r\n"[..]);
 }
 
+/// Header of an array of `len` elements, the elements are encoded after it
+pub fn encode_array_header(len: usize, buf: &mut BytesMut) {
+    encode_string(b'*', len.to_string(), buf);
+}
+
 // Encode a RespValue as bytes
 pub fn encode(resp: RespValue, buf: &mut BytesMut) {
     match resp {
@@ -17,8 +22,10 @@ pub fn encode(resp: RespValue, buf: &mut BytesMut) {
             buf.put(&b"$-1\r\n"[..]);
         }
         RespValue::SimpleString(value) => encode_string(b'+', value, buf),
-        // TODO: support description
-        RespValue::Error(value, _description) => encode_string(b'-', value, buf),
+        RespValue::Error(value, None) => encode_string(b'-', value, buf),
+        RespValue::Error(value, Some(description)) => {
+            encode_string(b'-', format!("{value} {description}"), buf)
+        }
         RespValue::Integer(value) => encode_string(b':', value.to_string(), buf),
         RespValue::BulkString(BulkString(value)) => {
             let len_str = value.len().to_string();
@@ -30,11 +37,8 @@ pub fn encode(resp: RespValue, buf: &mut BytesMut) {
             buf.put(&b"\r\n"[..]);
         }
         RespValue::Array(mut values) => {
-            let len_str = values.len().to_string();
-            buf.reserve(values.len() * 2 + len_str.len());
-            buf.put_u8(b'*');
-            buf.put(&len_str.into_bytes()[..]);
-            buf.put(&b"\r\n"[..]);
+            buf.reserve(values.len() * 2);
+            encode_array_header(values.len(), buf);
             values.drain(..).for_each(|value| {
                 encode(value, buf);
             });
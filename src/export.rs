@@ -0,0 +1,128 @@
+//! Export of the keys of a database to a JSON lines or CSV file (`moonis export`), read
+//! through a connection like any other client
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    remote::{reply_error, RemoteClient},
+    types::RespValue,
+};
+
+/// Keys read by each MGET
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    /// One JSON object per line with the key, type, ttl and value
+    #[default]
+    Json,
+    /// `key,type,ttl,value` rows with a header
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_ref() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("Invalid export format: {format}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportConfig {
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+    pub db: usize,
+    pub format: ExportFormat,
+    pub output: String,
+}
+
+/// Quoted CSV field, when it has separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\r' | '\n')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Keys and values are written as UTF-8, invalid sequences are replaced
+fn write_key(
+    output: &mut impl Write,
+    format: ExportFormat,
+    key: &[u8],
+    value: &[u8],
+) -> io::Result<()> {
+    let (key, value) = (String::from_utf8_lossy(key), String::from_utf8_lossy(value));
+    // All the keys are strings without expiration
+    match format {
+        ExportFormat::Json => {
+            let line = serde_json::json!({
+                "key": key,
+                "type": "string",
+                "ttl": -1,
+                "value": value,
+            });
+            writeln!(output, "{line}")
+        }
+        ExportFormat::Csv => {
+            writeln!(
+                output,
+                "{},string,-1,{}",
+                csv_field(&key),
+                csv_field(&value)
+            )
+        }
+    }
+}
+
+/// Write all the strings of the database to the output file, returns how many were
+/// written. Keys deleted while exporting and the other types are skipped
+pub fn run(config: &ExportConfig) -> io::Result<usize> {
+    let mut client = RemoteClient::connect(&config.host, config.port)?;
+    client.login(config.password.as_deref(), config.db)?;
+    let keys = match client.call(&[b"KEYS", b"*"])? {
+        RespValue::Array(keys) => keys,
+        reply => return Err(reply_error(reply)),
+    };
+    let keys: Vec<_> = keys
+        .into_iter()
+        .filter_map(|key| match key {
+            RespValue::BulkString(key) => Some(key),
+            _ => None,
+        })
+        .collect();
+
+    let mut output = BufWriter::new(File::create(&config.output)?);
+    if config.format == ExportFormat::Csv {
+        writeln!(output, "key,type,ttl,value")?;
+    }
+    let mut exported = 0;
+    for batch in keys.chunks(BATCH_SIZE) {
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend(batch.iter().map(|key| key.0.as_ref()));
+        let values = match client.call(&args)? {
+            RespValue::Array(values) => values,
+            reply => return Err(reply_error(reply)),
+        };
+        for (key, value) in batch.iter().zip(values) {
+            if let RespValue::BulkString(value) = value {
+                write_key(&mut output, config.format, &key.0, &value.0)?;
+                exported += 1;
+            }
+        }
+    }
+    output.flush()?;
+    Ok(exported)
+}
@@ -0,0 +1,48 @@
+//! Entry points for fuzzers, they run the network input through the same parsing the
+//! clients do. The `moonis_fuzz_*` functions are exported from the wasm module so wasm
+//! fuzzers can call them directly
+
+use std::slice;
+
+use bytes::BytesMut;
+
+use crate::parser::{self, DecodeState};
+
+/// Parse the input delivered in chunks of `chunk_size` bytes, like reads from a connection,
+/// and convert each message into a command. Returns the number of messages parsed, parsing
+/// stops at the first protocol error like the client does
+pub fn parse_input(data: &[u8], chunk_size: usize) -> usize {
+    let mut buffer = BytesMut::new();
+    let mut state = DecodeState::default();
+    let mut messages = 0;
+    for chunk in data.chunks(chunk_size.max(1)) {
+        buffer.extend_from_slice(chunk);
+        while !buffer.is_empty() {
+            match parser::decode_request(&mut buffer, &mut state) {
+                Ok(Some(request)) => {
+                    messages += 1;
+                    // Invalid commands are replied with an error, they must not panic
+                    let _ = request.into_command();
+                }
+                Ok(None) => break,
+                Err(_) => return messages,
+            }
+        }
+    }
+    messages
+}
+
+/// # Safety
+/// `data` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn moonis_fuzz_input(data: *const u8, len: usize) -> usize {
+    if data.is_null() {
+        return 0;
+    }
+    let data = slice::from_raw_parts(data, len);
+    // The first byte chooses the chunk size, to also exercise the partial parsing
+    match data.split_first() {
+        Some((chunk_size, data)) => parse_input(data, *chunk_size as usize),
+        None => 0,
+    }
+}
@@ -0,0 +1,129 @@
+/// Glob-style pattern matching, a port of redis `stringmatchlen`
+/// supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape special characters
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len())
+                    .any(|start| glob_match(&pattern[p + 1..], &string[start..]));
+            }
+            b'?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                if s >= string.len() {
+                    return false;
+                }
+                p += 1;
+                let not = pattern.get(p) == Some(&b'^');
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                while p < pattern.len() && pattern[p] != b']' {
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= pattern[p] == string[s];
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                        let (start, end) = (pattern[p], pattern[p + 2]);
+                        let (start, end) = (start.min(end), start.max(end));
+                        matched |= (start..=end).contains(&string[s]);
+                        p += 2;
+                    } else {
+                        matched |= pattern[p] == string[s];
+                    }
+                    p += 1;
+                }
+                if matched == not {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if s >= string.len() || pattern[p] != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if s >= string.len() || c != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+    s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn literals_match_the_whole_string() {
+        assert!(matches("key", "key"));
+        assert!(!matches("key", "keys"));
+        assert!(!matches("keys", "key"));
+        assert!(matches("", ""));
+        assert!(!matches("", "key"));
+    }
+
+    #[test]
+    fn stars_match_any_part() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "key"));
+        assert!(matches("user:*", "user:1"));
+        assert!(matches("user:*", "user:"));
+        assert!(matches("*:name", "user:1:name"));
+        assert!(matches("a*b*c", "a-b-b-c"));
+        assert!(matches("a**c", "abc"));
+        assert!(!matches("a*b*c", "a-c-b"));
+    }
+
+    #[test]
+    fn question_marks_match_one_byte() {
+        assert!(matches("k?y", "key"));
+        assert!(!matches("k?y", "ky"));
+        assert!(!matches("key?", "key"));
+    }
+
+    #[test]
+    fn classes_match_their_bytes() {
+        assert!(matches("k[aeiou]y", "key"));
+        assert!(!matches("k[aiou]y", "key"));
+        assert!(matches("k[^aiou]y", "key"));
+        assert!(!matches("k[^e]y", "key"));
+        assert!(matches("[a-c]", "b"));
+        assert!(matches("[c-a]", "b"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(!matches("[a]", ""));
+    }
+
+    #[test]
+    fn escaped_characters_match_themselves() {
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "key"));
+        assert!(matches(r"a\?", "a?"));
+        assert!(!matches(r"a\?", "ab"));
+        // A trailing backslash is a literal one
+        assert!(matches("a\\", "a\\"));
+    }
+}
@@ -0,0 +1,374 @@
+//! HTTP gateway for the environments without a redis client: `GET`, `PUT` and `DELETE` on
+//! `/keys/{key}` read and write the strings, and `POST /command` runs the command of a JSON
+//! array and replies its result as JSON. Each connection is a client of its own, with the
+//! ACL user of its basic authorization. `post_json` is the client side, for the webhooks and
+//! the trace exporter
+//! ```text
+//! curl -X PUT --data 1 localhost:8080/keys/counter
+//! curl -d '["APPEND", "counter", "2"]' localhost:8080/command
+//! ```
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use lunatic::{net::TcpStream, process::ProcessRef, Mailbox, Process};
+use lunatic_log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::{
+    client::{ClientProcess, ClientProcessHandler, QUERY_BUFFER_FRACTION},
+    config::{Config, HttpUrl},
+    server::{self, admitted, client_config, connection_limiter, Bound},
+    types::{BulkString, RespValue},
+};
+
+/// Longest request line and headers
+const HEADERS_MAX_LENGTH: usize = 8192;
+
+/// The endpoints must answer in this time, or the attempt failed
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// POST the JSON body to the URL, successful when it replies a 2xx status
+pub(crate) fn post_json(url: &HttpUrl, body: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect(url.addr.as_str()).map_err(|err| err.to_string())?;
+    stream
+        .set_read_timeout(Some(POST_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(POST_TIMEOUT)))
+        .map_err(|err| err.to_string())?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|err| err.to_string())?;
+    // Only the status line is needed, `HTTP/1.1 200 OK`
+    let mut status = [0; 12];
+    stream
+        .read_exact(&mut status)
+        .map_err(|err| err.to_string())?;
+    match &status[9..10] {
+        b"2" => Ok(()),
+        _ => Err(format!("replied {}", String::from_utf8_lossy(&status[9..]))),
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// Username and password of the basic authorization
+    credentials: Option<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: &'static str, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: vec![],
+        }
+    }
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut decoded = vec![];
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|c| *c == byte)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Read a request, the body is limited to the memory of a client like the RESP commands
+fn read_request(stream: &mut TcpStream, max_body: usize) -> Result<Request, Response> {
+    let bad_request = || Response::error("400 Bad Request", "invalid HTTP request");
+    let mut buffer = vec![];
+    let mut read = [0; 4096];
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > HEADERS_MAX_LENGTH {
+            return Err(Response::error(
+                "431 Request Header Fields Too Large",
+                "headers too long",
+            ));
+        }
+        match stream.read(&mut read) {
+            Ok(0) | Err(_) => return Err(bad_request()),
+            Ok(readed) => buffer.extend_from_slice(&read[..readed]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(bad_request()),
+    };
+    let mut length = 0;
+    let mut credentials = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => return Err(bad_request()),
+        };
+        match name.as_ref() {
+            "content-length" => length = value.parse().map_err(|_| bad_request())?,
+            "authorization" => {
+                let decoded = value
+                    .strip_prefix("Basic ")
+                    .and_then(base64_decode)
+                    .and_then(|decoded| String::from_utf8(decoded).ok());
+                credentials = match decoded.as_ref().and_then(|decoded| decoded.split_once(':')) {
+                    Some((username, password)) => Some((username.into(), password.into())),
+                    None => return Err(bad_request()),
+                };
+            }
+            _ => (),
+        }
+    }
+    if length > max_body {
+        return Err(Response::error(
+            "413 Payload Too Large",
+            "body too big for the client memory limit",
+        ));
+    }
+    let mut body = buffer.split_off(end + 4);
+    while body.len() < length {
+        match stream.read(&mut read) {
+            Ok(0) | Err(_) => return Err(bad_request()),
+            Ok(readed) => body.extend_from_slice(&read[..readed]),
+        }
+    }
+    body.truncate(length);
+    Ok(Request {
+        method,
+        path,
+        credentials,
+        body,
+    })
+}
+
+fn bulk(value: Vec<u8>) -> RespValue {
+    RespValue::BulkString(BulkString(value.into()))
+}
+
+/// Command of the arguments, as sent by the redis clients
+fn command(args: Vec<RespValue>) -> RespValue {
+    RespValue::Array(args.into())
+}
+
+/// Reply of a command as JSON, bulk strings that aren't UTF-8 are converted lossily
+pub(crate) fn to_json(reply: RespValue) -> Value {
+    match reply {
+        RespValue::SimpleString(value) => Value::String(value),
+        RespValue::BulkString(value) => Value::String(String::from_utf8_lossy(&value.0).into()),
+        RespValue::Integer(value) => Value::from(value),
+        RespValue::Array(values) => Value::Array(values.into_iter().map(to_json).collect()),
+        RespValue::Null => Value::Null,
+        RespValue::Error(prefix, description) => match description {
+            Some(description) => Value::String(format!("{prefix} {description}")),
+            None => Value::String(prefix),
+        },
+    }
+}
+
+/// Response of an error reply, with the HTTP status matching its prefix
+fn error_response(prefix: &str, description: Option<String>) -> Response {
+    let status = match prefix {
+        "NOAUTH" | "WRONGPASS" => "401 Unauthorized",
+        "NOPERM" | "DENIED" => "403 Forbidden",
+        "OOM" => "507 Insufficient Storage",
+        _ => "400 Bad Request",
+    };
+    let message = match description {
+        Some(description) => format!("{prefix} {description}"),
+        None => prefix.to_string(),
+    };
+    Response::error(status, &message)
+}
+
+/// Arguments of the JSON array of POST /command, numbers are sent as their text
+pub(crate) fn command_args(body: &[u8]) -> Option<Vec<RespValue>> {
+    match serde_json::from_slice(body).ok()? {
+        Value::Array(args) if !args.is_empty() => args
+            .into_iter()
+            .map(|arg| match arg {
+                Value::String(arg) => Some(bulk(arg.into_bytes())),
+                Value::Number(arg) => Some(bulk(arg.to_string().into_bytes())),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn route(client: &ProcessRef<ClientProcess>, request: Request) -> Response {
+    let reply = |args: Vec<RespValue>| client.process(command(args)).unwrap_or(RespValue::Null);
+    let key = request
+        .path
+        .strip_prefix("/keys/")
+        .filter(|key| !key.is_empty())
+        .map(percent_decode);
+    match (request.method.as_ref(), request.path.as_ref(), key) {
+        ("GET", _, Some(key)) => match reply(vec![bulk(b"GET".to_vec()), bulk(key)]) {
+            RespValue::BulkString(value) => Response {
+                status: "200 OK",
+                content_type: "application/octet-stream",
+                body: value.0.to_vec(),
+            },
+            RespValue::Null => Response::error("404 Not Found", "key not found"),
+            RespValue::Error(prefix, description) => error_response(&prefix, description),
+            reply => Response::json("200 OK", to_json(reply)),
+        },
+        ("PUT", _, Some(key)) => {
+            match reply(vec![bulk(b"SET".to_vec()), bulk(key), bulk(request.body)]) {
+                RespValue::Error(prefix, description) => error_response(&prefix, description),
+                _ => Response::empty("204 No Content"),
+            }
+        }
+        ("DELETE", _, Some(key)) => match reply(vec![bulk(b"DEL".to_vec()), bulk(key)]) {
+            RespValue::Integer(0) => Response::error("404 Not Found", "key not found"),
+            RespValue::Error(prefix, description) => error_response(&prefix, description),
+            _ => Response::empty("204 No Content"),
+        },
+        ("POST", "/command", _) => match command_args(&request.body) {
+            Some(args) => match reply(args) {
+                RespValue::Error(prefix, description) => error_response(&prefix, description),
+                reply => Response::json("200 OK", json!({ "result": to_json(reply) })),
+            },
+            None => Response::error(
+                "400 Bad Request",
+                "the body must be a JSON array of strings",
+            ),
+        },
+        (_, "/command", _) | (_, _, Some(_)) => {
+            Response::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Response::error("404 Not Found", "unknown path"),
+    }
+}
+
+/// Serve a request on the connection, with a client registered for its peer. The client
+/// is started first so the connection counted by the per-IP limits is always released
+fn serve_connection(mut stream: TcpStream, peer: String, config: Config) {
+    let max_body = config.client_max_memory / QUERY_BUFFER_FRACTION;
+    let client =
+        ClientProcess::start_config((None, peer, config.clone()), None, &client_config(&config));
+    let response = match read_request(&mut stream, max_body) {
+        Ok(request) => {
+            let authenticated = match &request.credentials {
+                Some((username, password)) => {
+                    let auth = command(vec![
+                        bulk(b"AUTH".to_vec()),
+                        bulk(username.clone().into_bytes()),
+                        bulk(password.clone().into_bytes()),
+                    ]);
+                    match client.process(auth) {
+                        Some(RespValue::Error(prefix, description)) => {
+                            Err(error_response(&prefix, description))
+                        }
+                        _ => Ok(()),
+                    }
+                }
+                None => Ok(()),
+            };
+            match authenticated {
+                Ok(()) => route(&client, request),
+                Err(response) => response,
+            }
+        }
+        Err(response) => response,
+    };
+    client.shutdown();
+    let mut head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+    head.extend_from_slice(&response.body);
+    if let Err(err) = stream.write_all(&head) {
+        debug!("Can't send the HTTP response: {err}");
+    }
+}
+
+/// Accept HTTP connections on the address, each one is served by its own process
+pub fn serve(addr: String, bound: Bound, config: Config) {
+    let listener = server::bind(addr, bound);
+    info!("Serving HTTP on: {}", listener.local_addr().unwrap());
+
+    let registry = connection_limiter(&config);
+
+    while let Ok((stream, peer)) = listener.accept() {
+        if !admitted(registry.as_ref(), peer) {
+            continue;
+        }
+        Process::spawn(
+            (stream, peer.to_string(), config.clone()),
+            |(stream, peer, config), _: Mailbox<()>| serve_connection(stream, peer, config),
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Mass insertion of a file of RESP commands (`moonis import`), the same format as the
+//! input of `redis-cli --pipe`. The commands are sent in chunks with DEBUG LOADPROTO, so the
+//! server executes each chunk as a single storage batch
+
+use std::{fs, io};
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoder::encode,
+    parser::{self, DecodeState},
+    remote::{reply_error, RemoteClient},
+    types::RespValue,
+};
+
+/// Bytes of commands sent by each DEBUG LOADPROTO, well under the query buffer of a client
+const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportConfig {
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+    pub db: usize,
+    pub input: String,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load_chunk(client: &mut RemoteClient, chunk: &mut BytesMut) -> io::Result<usize> {
+    let reply = client.call(&[b"DEBUG", b"LOADPROTO", &chunk[..]])?;
+    chunk.clear();
+    match reply {
+        RespValue::Integer(count) => Ok(count as usize),
+        reply => Err(reply_error(reply)),
+    }
+}
+
+/// Load all the commands of the input file, returns how many were executed. The chunks
+/// loaded before an error are kept
+pub fn run(config: &ImportConfig) -> io::Result<usize> {
+    let mut input = BytesMut::from(&fs::read(&config.input)?[..]);
+    let mut client = RemoteClient::connect(&config.host, config.port)?;
+    client.login(config.password.as_deref(), config.db)?;
+
+    let mut state = DecodeState::default();
+    let mut chunk = BytesMut::new();
+    let mut loaded = 0;
+    while !input.is_empty() {
+        // Inline commands are sent as arrays, each chunk ends at a command boundary
+        match parser::decode(&mut input, &mut state) {
+            Ok(Some(resp)) => encode(resp, &mut chunk),
+            Ok(None) => return Err(invalid_input("incomplete command at the end".into())),
+            Err(err) => return Err(invalid_input(err.to_string())),
+        }
+        if chunk.len() >= CHUNK_SIZE {
+            loaded += load_chunk(&mut client, &mut chunk)?;
+        }
+    }
+    if !chunk.is_empty() {
+        loaded += load_chunk(&mut client, &mut chunk)?;
+    }
+    Ok(loaded)
+}
@@ -0,0 +1,544 @@
+//! JSON documents stored as values (JSON.SET, JSON.GET...), addressed with the JSONPath
+//! subset of RedisJSON: `$`, `.key`, `["key"]`, `[index]` and the `*` wildcard. Paths not
+//! starting with `$` are legacy paths, they match a single value
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::types::RespValue;
+
+/// Approximate memory used by each JSON value besides its strings
+const NODE_OVERHEAD: usize = 16;
+
+/// Step of a path, from a value to its children
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Step {
+    Key(String),
+    /// Negative indexes count from the end of the array
+    Index(i64),
+    Wildcard,
+}
+
+/// Position of a value matched by a path, from its parent
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Location {
+    Key(String),
+    Index(usize),
+}
+
+/// Condition of JSON.SET on the existence of the path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Condition {
+    /// Only set the path when it doesn't exist
+    Nx,
+    /// Only set the path when it already exists
+    Xx,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Path {
+    /// As sent by the client, used by the errors and the replies of several paths
+    text: String,
+    legacy: bool,
+    steps: Vec<Step>,
+}
+
+/// Step between brackets, `rest` starts after the `[`
+fn parse_bracket(rest: &str) -> Option<(Step, &str)> {
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'');
+    let (step, rest) = match quote {
+        Some(quote) => {
+            let end = rest[1..].find(quote)? + 1;
+            (Step::Key(rest[1..end].into()), &rest[end + 1..])
+        }
+        None => {
+            let end = rest.find(']')?;
+            let step = match rest[..end].trim() {
+                "*" => Step::Wildcard,
+                index => Step::Index(index.parse().ok()?),
+            };
+            (step, &rest[end..])
+        }
+    };
+    rest.strip_prefix(']').map(|rest| (step, rest))
+}
+
+impl Path {
+    /// `$`, the whole document
+    pub fn root() -> Self {
+        Self {
+            text: "$".into(),
+            legacy: false,
+            steps: vec![],
+        }
+    }
+
+    /// Parse a path, recursive descent (`..`) and filters aren't supported
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (legacy, path) = match text.strip_prefix('$') {
+            Some(path) => (false, path.to_string()),
+            None if text == "." => (true, String::new()),
+            None if text.starts_with(['.', '[']) => (true, text.to_string()),
+            None => (true, format!(".{text}")),
+        };
+        let invalid = || format!("invalid or unsupported path '{text}'");
+        let mut steps = vec![];
+        let mut rest = path.as_str();
+        while !rest.is_empty() {
+            rest = if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                steps.push(match &after[..end] {
+                    "" => return Err(invalid()),
+                    "*" => Step::Wildcard,
+                    name => Step::Key(name.into()),
+                });
+                &after[end..]
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let (step, after) = parse_bracket(after).ok_or_else(invalid)?;
+                steps.push(step);
+                after
+            } else {
+                return Err(invalid());
+            };
+        }
+        Ok(Self {
+            text: text.into(),
+            legacy,
+            steps,
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Legacy paths match a single value, JSONPaths are replied with all their matches
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+/// Children of the value selected by the step, with their locations
+fn children<'a>(value: &'a Value, step: &Step) -> Vec<(Location, &'a Value)> {
+    match (step, value) {
+        (Step::Key(key), Value::Object(object)) => object
+            .get(key)
+            .map(|child| (Location::Key(key.clone()), child))
+            .into_iter()
+            .collect(),
+        (Step::Index(index), Value::Array(array)) => {
+            let index = if *index < 0 {
+                array.len() as i64 + index
+            } else {
+                *index
+            };
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| {
+                    array
+                        .get(index)
+                        .map(|child| (Location::Index(index), child))
+                })
+                .into_iter()
+                .collect()
+        }
+        (Step::Wildcard, Value::Object(object)) => object
+            .iter()
+            .map(|(key, child)| (Location::Key(key.clone()), child))
+            .collect(),
+        (Step::Wildcard, Value::Array(array)) => array
+            .iter()
+            .enumerate()
+            .map(|(index, child)| (Location::Index(index), child))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// All the values matched by the steps with their locations, in document order
+fn matches<'a>(value: &'a Value, steps: &[Step]) -> Vec<(Vec<Location>, &'a Value)> {
+    let mut matches = vec![(vec![], value)];
+    for step in steps {
+        matches = matches
+            .into_iter()
+            .flat_map(|(location, value)| {
+                children(value, step)
+                    .into_iter()
+                    .map(move |(child, value)| {
+                        let mut location = location.clone();
+                        location.push(child);
+                        (location, value)
+                    })
+            })
+            .collect();
+    }
+    matches
+}
+
+fn locate(value: &Value, steps: &[Step]) -> Vec<Vec<Location>> {
+    matches(value, steps)
+        .into_iter()
+        .map(|(location, _)| location)
+        .collect()
+}
+
+fn get_mut<'a>(mut value: &'a mut Value, location: &[Location]) -> Option<&'a mut Value> {
+    for step in location {
+        value = match (step, value) {
+            (Location::Key(key), Value::Object(object)) => object.get_mut(key)?,
+            (Location::Index(index), Value::Array(array)) => array.get_mut(*index)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn value_size(value: &Value) -> usize {
+    NODE_OVERHEAD
+        + match value {
+            Value::String(string) => string.len(),
+            Value::Array(array) => array.iter().map(value_size).sum(),
+            Value::Object(object) => object
+                .iter()
+                .map(|(key, value)| key.len() + value_size(value))
+                .sum(),
+            _ => 0,
+        }
+}
+
+/// Name of the type of the value, as shown by the errors
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// JSON value, sent to other processes as its text since the format of the messages
+/// can't deserialize a serde_json Value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document(pub Value);
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        serde_json::from_str(text)
+            .map(Self)
+            .map_err(|err| format!("invalid JSON: {err}"))
+    }
+
+    /// Bytes used by the document, approximated
+    pub fn size(&self) -> usize {
+        value_size(&self.0)
+    }
+
+    pub fn get(&self, path: &Path) -> Vec<&Value> {
+        matches(&self.0, &path.steps)
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        !locate(&self.0, &path.steps).is_empty()
+    }
+
+    /// Text of the values matched by the paths (JSON.GET), a legacy path replies its value
+    /// and a JSONPath an array of its matches. Several paths reply an object by path
+    pub fn format(&self, paths: &[Path]) -> Result<String, String> {
+        let legacy = paths.iter().all(Path::is_legacy);
+        let values_at = |path: &Path| {
+            let values = self.get(path);
+            if !legacy {
+                return Ok(Value::Array(values.into_iter().cloned().collect()));
+            }
+            match values.first() {
+                Some(value) => Ok((*value).clone()),
+                None => Err(format!("Path '{}' does not exist", path.text)),
+            }
+        };
+        let value = match paths {
+            [] => self.0.clone(),
+            [path] => values_at(path)?,
+            paths => Value::Object(
+                paths
+                    .iter()
+                    .map(|path| Ok((path.text.clone(), values_at(path)?)))
+                    .collect::<Result<Map<_, _>, String>>()?,
+            ),
+        };
+        Ok(value.to_string())
+    }
+
+    /// Replace the values matched by the path. When nothing matches and the path ends with a
+    /// key, the key is added to the matched objects. Returns false when nothing was set
+    pub fn set(&mut self, path: &Path, value: &Value) -> bool {
+        let locations = locate(&self.0, &path.steps);
+        if !locations.is_empty() {
+            for location in locations {
+                if let Some(target) = get_mut(&mut self.0, &location) {
+                    *target = value.clone();
+                }
+            }
+            return true;
+        }
+        let (key, parent) = match path.steps.split_last() {
+            Some((Step::Key(key), parent)) => (key, parent),
+            _ => return false,
+        };
+        let mut set = false;
+        for location in locate(&self.0, parent) {
+            if let Some(Value::Object(object)) = get_mut(&mut self.0, &location) {
+                object.insert(key.clone(), value.clone());
+                set = true;
+            }
+        }
+        set
+    }
+
+    /// Remove the values matched by the path from their parents, returns how many were
+    /// removed. The root can't be removed this way, the key must be deleted instead
+    pub fn delete(&mut self, path: &Path) -> usize {
+        let mut locations = locate(&self.0, &path.steps);
+        // The last indexes of an array are removed first, so the others stay valid
+        locations.sort_unstable_by(|a, b| b.cmp(a));
+        let mut removed = 0;
+        for location in locations {
+            let (child, parent) = match location.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let removed_child = match (child, get_mut(&mut self.0, parent)) {
+                (Location::Key(key), Some(Value::Object(object))) => object.remove(key).is_some(),
+                (Location::Index(index), Some(Value::Array(array))) => {
+                    array.remove(*index);
+                    true
+                }
+                _ => false,
+            };
+            if removed_child {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Append the values to the arrays matched by the path, returns the new length of
+    /// each match, None for the matches that aren't arrays. A legacy path must match an
+    /// array
+    pub fn arr_append(
+        &mut self,
+        path: &Path,
+        values: &[Document],
+    ) -> Result<Vec<Option<usize>>, String> {
+        if path.legacy {
+            match self.get(path).first().map(|value| type_name(value)) {
+                Some("array") => (),
+                Some(found) => {
+                    return Err(format!(
+                        "wrong type of path value - expected array but found {found}"
+                    ))
+                }
+                None => return Err(format!("Path '{}' does not exist", path.text)),
+            }
+        }
+        Ok(locate(&self.0, &path.steps)
+            .iter()
+            .map(|location| match get_mut(&mut self.0, location) {
+                Some(Value::Array(array)) => {
+                    array.extend(values.iter().map(|value| value.0.clone()));
+                    Some(array.len())
+                }
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Reply of the new lengths of the arrays (JSON.ARRAPPEND), the length of the last array
+/// for a legacy path
+pub fn lengths_reply(path: &Path, lengths: Vec<Option<usize>>) -> RespValue {
+    let reply = |length: Option<usize>| match length {
+        Some(length) => RespValue::Integer(length as i64),
+        None => RespValue::Null,
+    };
+    if path.legacy {
+        return reply(lengths.into_iter().flatten().last());
+    }
+    RespValue::Array(lengths.into_iter().map(reply).collect())
+}
+
+impl Serialize for Document {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Document {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Document::parse(&text).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn path(text: &str) -> Path {
+        Path::parse(text).unwrap()
+    }
+
+    fn document() -> Document {
+        Document(json!({"a": [1, 2, 3], "b": {"c": "x", "d": [4]}, "e f": true}))
+    }
+
+    #[test]
+    fn paths_are_parsed_into_steps() {
+        assert_eq!(path("$").steps, []);
+        assert!(path("$").is_root() && !path("$").is_legacy());
+        assert_eq!(
+            path("$.a[0]").steps,
+            [Step::Key("a".into()), Step::Index(0)]
+        );
+        assert_eq!(
+            path(r#"$["e f"]['b'][-1][*].*"#).steps,
+            [
+                Step::Key("e f".into()),
+                Step::Key("b".into()),
+                Step::Index(-1),
+                Step::Wildcard,
+                Step::Wildcard,
+            ]
+        );
+        assert_eq!(path(r#"$["a.b"]"#).steps, [Step::Key("a.b".into())]);
+    }
+
+    #[test]
+    fn paths_without_dollar_are_legacy() {
+        for (text, steps) in [
+            (".", vec![]),
+            (".a", vec![Step::Key("a".into())]),
+            ("a.b", vec![Step::Key("a".into()), Step::Key("b".into())]),
+            ("[1]", vec![Step::Index(1)]),
+        ] {
+            let path = path(text);
+            assert!(path.is_legacy(), "{text}");
+            assert_eq!(path.steps, steps, "{text}");
+            assert_eq!(path.text(), text);
+        }
+    }
+
+    #[test]
+    fn unsupported_paths_are_rejected() {
+        for text in ["$..a", "$.", "$[0", "$[x]", "$['a]", "$a", "$[0]x"] {
+            assert_eq!(
+                Path::parse(text).unwrap_err(),
+                format!("invalid or unsupported path '{text}'")
+            );
+        }
+    }
+
+    #[test]
+    fn get_returns_the_matches_in_document_order() {
+        let document = document();
+        assert_eq!(document.get(&path("$.a[1]")), [&json!(2)]);
+        assert_eq!(document.get(&path("$.a[-1]")), [&json!(3)]);
+        assert!(document.get(&path("$.a[3]")).is_empty());
+        assert!(document.get(&path("$.a[-4]")).is_empty());
+        assert_eq!(
+            document.get(&path("$.a[*]")),
+            [&json!(1), &json!(2), &json!(3)]
+        );
+        assert_eq!(document.get(&path("$.b.*")), [&json!("x"), &json!([4])]);
+        assert_eq!(document.get(&path("$.*[0]")), [&json!(1)]);
+        assert_eq!(document.get(&path("$.b.*[0]")), [&json!(4)]);
+        assert!(document.get(&path("$.b.c.d")).is_empty());
+        assert!(document.exists(&path(r#"$["e f"]"#)));
+        assert!(!document.exists(&path("$.g")));
+    }
+
+    #[test]
+    fn format_depends_on_the_paths() {
+        let document = document();
+        assert_eq!(document.format(&[path("$.b.c")]).unwrap(), r#"["x"]"#);
+        assert_eq!(document.format(&[path(".b.c")]).unwrap(), r#""x""#);
+        assert_eq!(
+            document.format(&[path(".g")]).unwrap_err(),
+            "Path '.g' does not exist"
+        );
+        assert_eq!(
+            document.format(&[path(".a[0]"), path(".b.c")]).unwrap(),
+            r#"{".a[0]":1,".b.c":"x"}"#
+        );
+        // A JSONPath among the paths makes them all reply their matches
+        assert_eq!(
+            document.format(&[path("$.g"), path(".b.c")]).unwrap(),
+            r#"{"$.g":[],".b.c":["x"]}"#
+        );
+    }
+
+    #[test]
+    fn set_replaces_the_matches_or_adds_the_last_key() {
+        let mut document = document();
+        assert!(document.set(&path("$.a[*]"), &json!(0)));
+        assert_eq!(document.0["a"], json!([0, 0, 0]));
+        assert!(document.set(&path("$.b.new"), &json!(1)));
+        assert_eq!(document.0["b"]["new"], json!(1));
+        assert!(!document.set(&path("$.a[5]"), &json!(1)));
+        assert!(!document.set(&path("$.g.h"), &json!(1)));
+        assert!(document.set(&Path::root(), &json!([])));
+        assert_eq!(document.0, json!([]));
+    }
+
+    #[test]
+    fn delete_removes_the_matches_from_their_parents() {
+        let mut document = document();
+        assert_eq!(document.delete(&path("$.a[*]")), 3);
+        assert_eq!(document.0["a"], json!([]));
+        assert_eq!(document.delete(&path("$.b.*")), 2);
+        assert_eq!(document.0["b"], json!({}));
+        assert_eq!(document.delete(&path("$.g")), 0);
+        assert_eq!(document.delete(&Path::root()), 0);
+    }
+
+    #[test]
+    fn arr_append_extends_the_arrays() {
+        let mut document = document();
+        let values = [Document(json!(5)), Document(json!("y"))];
+        assert_eq!(
+            document.arr_append(&path("$.*"), &values).unwrap(),
+            [Some(5), None, None]
+        );
+        assert_eq!(document.0["a"], json!([1, 2, 3, 5, "y"]));
+        assert_eq!(
+            document.arr_append(&path(".b.c"), &values).unwrap_err(),
+            "wrong type of path value - expected array but found string"
+        );
+        assert_eq!(
+            document.arr_append(&path(".g"), &values).unwrap_err(),
+            "Path '.g' does not exist"
+        );
+        let reply = lengths_reply(&path(".b.d"), vec![Some(1), None, Some(3)]);
+        assert!(matches!(reply, RespValue::Integer(3)));
+    }
+
+    #[test]
+    fn documents_are_sent_as_their_text() {
+        let document = document();
+        let text = serde_json::to_string(&document).unwrap();
+        assert_eq!(serde_json::from_str::<Document>(&text).unwrap(), document);
+        assert!(Document::parse("{").is_err());
+        assert_eq!(Document(json!({"a": "xy"})).size(), 2 * NODE_OVERHEAD + 3);
+    }
+}
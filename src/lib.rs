@@ -0,0 +1,46 @@
+//! Mini redis running on lunatic, it can be embedded in other lunatic applications:
+//!
+//! ```no_run
+//! use moonis::{server::Server, storage::StorageHandler, types::BulkString};
+//!
+//! let server = Server::builder().port(6379).start().unwrap();
+//! let storage = server.storage(0).unwrap();
+//! let key = BulkString("key".into());
+//! storage.shard(&key).set(key.clone(), BulkString("value".into())).unwrap();
+//! ```
+
+pub mod acl;
+pub mod audit;
+pub mod bench;
+pub mod bloom;
+pub mod cdc;
+pub mod chaos;
+pub mod client;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod dict;
+pub mod encoder;
+pub mod export;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod glob;
+pub mod http;
+pub mod import;
+pub mod json;
+pub mod logging;
+pub mod memcached;
+pub mod metrics;
+pub mod parser;
+pub mod registry;
+pub mod remote;
+pub mod server;
+pub mod shards;
+pub mod sketch;
+pub mod storage;
+pub mod telemetry;
+pub mod timeseries;
+pub mod types;
+pub mod watchdog;
+pub mod webhook;
+pub mod websocket;
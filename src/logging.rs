@@ -0,0 +1,129 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lunatic_log::{
+    subscriber::{fmt::FmtSubscriber, Subscriber},
+    Event, LevelFilter, Metadata,
+};
+use serde::{Deserialize, Serialize};
+
+/// How each log line is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogFormat {
+    /// Human readable, colored when logging to stdout
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregation systems
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_ref() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format: {format}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+    /// Logs are written to this file instead of stdout
+    pub file: Option<String>,
+    /// The file is rotated when it grows over this size, 0 disables rotation
+    pub max_size: usize,
+    /// Rotated files kept, named like the logfile with a `.1`, `.2`... suffix
+    pub keep: usize,
+}
+
+/// Writes the events to stdout or to a logfile, as plain text or JSON lines
+#[derive(Serialize, Deserialize)]
+struct LogSubscriber {
+    config: LogConfig,
+}
+
+impl LogSubscriber {
+    fn line(&self, event: &Event) -> String {
+        let metadata = event.metadata();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        match self.config.format {
+            LogFormat::Json => serde_json::json!({
+                "timestamp": timestamp,
+                "level": metadata.level().to_string(),
+                "target": metadata.target(),
+                "message": event.message(),
+            })
+            .to_string(),
+            LogFormat::Pretty => format!(
+                "{timestamp:.3} {:>5} {}: {}",
+                metadata.level(),
+                metadata.target(),
+                event.message()
+            ),
+        }
+    }
+
+    /// Shift the rotated files, the oldest one is removed
+    fn rotate(&self, path: &str) {
+        if self.config.keep == 0 {
+            let _ = fs::remove_file(path);
+            return;
+        }
+        for index in (1..self.config.keep).rev() {
+            let _ = fs::rename(format!("{path}.{index}"), format!("{path}.{}", index + 1));
+        }
+        let _ = fs::rename(path, format!("{path}.1"));
+    }
+
+    fn write_file(&self, path: &str, line: &str) {
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len() as usize);
+        if self.config.max_size > 0 && size + line.len() > self.config.max_size && size > 0 {
+            self.rotate(path);
+        }
+        // The file is opened for each event, so it is recreated if removed by logrotate
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(mut file) => {
+                let _ = file.write_all(line.as_bytes());
+            }
+            Err(err) => eprintln!("Can't write to the logfile {path}: {err}"),
+        }
+    }
+}
+
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        *metadata.level() <= self.config.level
+    }
+
+    fn event(&self, event: &Event) {
+        if !self.enabled(event.metadata()) {
+            return;
+        }
+        let line = format!("{}\n", self.line(event));
+        match &self.config.file {
+            Some(path) => self.write_file(path, &line),
+            None => print!("{line}"),
+        }
+    }
+}
+
+/// Start the logging process, pretty printing to stdout keeps using the lunatic formatter
+pub fn init(config: LogConfig) {
+    if config.format == LogFormat::Pretty && config.file.is_none() {
+        lunatic_log::init(FmtSubscriber::new(config.level).pretty());
+    } else {
+        lunatic_log::init(LogSubscriber { config });
+    }
+}
@@ -1,52 +1,227 @@
-mod client;
-mod encoder;
-mod parser;
-mod types;
-mod storage;
+use clap::{parser::ValueSource, value_parser, Arg, ArgAction, Command};
+use std::str::FromStr;
 
-use clap::{value_parser, Arg, Command};
-use lunatic::{net::TcpListener, process::StartProcess, Mailbox, ProcessConfig};
-use lunatic_log::{info, subscriber::fmt::FmtSubscriber, LevelFilter};
+use lunatic::Mailbox;
+use lunatic_log::LevelFilter;
 
-use crate::{client::ClientProcess, storage::Storage};
+use moonis::{
+    bench::{self, BenchConfig},
+    config::{
+        parse_memory, ChaosConfig, Config, EvictionPolicy, HttpUrl, OutputBufferLimits, TlsConfig,
+        Webhook,
+    },
+    export::{self, ExportConfig, ExportFormat},
+    import::{self, ImportConfig},
+    logging::{self, LogConfig, LogFormat},
+    server::Server,
+};
+
+/// What the binary was asked to run
+enum Mode {
+    Server(LogConfig, Config),
+    Bench(BenchConfig),
+    Export(ExportConfig),
+    Import(ImportConfig),
+}
 
 #[lunatic::main]
-fn main(_: Mailbox<()>) {
-    let (addr, log_level) = parse_args();
-    lunatic_log::init(FmtSubscriber::new(log_level).pretty());
+fn main(mailbox: Mailbox<()>) {
+    match parse_args() {
+        Mode::Server(log_config, config) => {
+            logging::init(log_config);
+            if let Err(err) = Server::builder().config(config).start() {
+                eprintln!("Can't start the server: {err}");
+                return;
+            }
+            // The listeners are linked, so failing to bind any address stops the server
+            let _ = mailbox.receive();
+        }
+        Mode::Bench(config) => bench::run(config, mailbox),
+        Mode::Export(config) => match export::run(&config) {
+            Ok(exported) => println!("Exported {exported} keys to {}", config.output),
+            Err(err) => eprintln!("Export failed: {err}"),
+        },
+        Mode::Import(config) => match import::run(&config) {
+            Ok(loaded) => println!("Imported {loaded} commands from {}", config.input),
+            Err(err) => eprintln!("Import failed: {err}"),
+        },
+    }
+}
 
-    Storage::start_link((), Some("storage"));
+fn bench_command() -> Command {
+    Command::new("bench")
+        .about("Benchmarks a server, like redis-benchmark")
+        .arg(
+            Arg::new("HOST")
+                .default_value("127.0.0.1")
+                .long("host")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::new("PORT")
+                .value_parser(value_parser!(u16))
+                .default_value("6142")
+                .short('p')
+                .long("port")
+                .help("Server port"),
+        )
+        .arg(
+            Arg::new("CLIENTS")
+                .value_parser(value_parser!(usize))
+                .default_value("50")
+                .short('c')
+                .long("clients")
+                .help("Number of parallel connections"),
+        )
+        .arg(
+            Arg::new("REQUESTS")
+                .value_parser(value_parser!(usize))
+                .default_value("100000")
+                .short('n')
+                .long("requests")
+                .help("Total number of requests of each test"),
+        )
+        .arg(
+            Arg::new("PIPELINE")
+                .value_parser(value_parser!(usize))
+                .default_value("1")
+                .short('P')
+                .long("pipeline")
+                .help("Pipeline this many requests"),
+        )
+        .arg(
+            Arg::new("DATA_SIZE")
+                .value_parser(value_parser!(usize))
+                .default_value("3")
+                .short('d')
+                .long("data-size")
+                .help("Data size of SET values in bytes"),
+        )
+        .arg(
+            Arg::new("KEYSPACE")
+                .value_parser(value_parser!(usize))
+                .default_value("100000")
+                .short('r')
+                .long("keyspace")
+                .help("Use random keys from this many keys"),
+        )
+        .arg(
+            Arg::new("TESTS")
+                .default_value("set,get,incr")
+                .value_delimiter(',')
+                .short('t')
+                .long("tests")
+                .help("Comma separated list of tests: set, get, incr"),
+        )
+}
 
-    info!("Listening to: {addr}");
-    let listener = TcpListener::bind(addr).unwrap();
-    let mut client_conf = ProcessConfig::new().unwrap();
-    client_conf.set_max_memory(5_000_000);
-    client_conf.set_can_spawn_processes(true);
+fn export_command() -> Command {
+    Command::new("export")
+        .about("Exports the keys of a database to a JSON lines or CSV file")
+        .arg(
+            Arg::new("OUTPUT")
+                .required(true)
+                .help("File the keys are written to"),
+        )
+        .arg(
+            Arg::new("HOST")
+                .default_value("127.0.0.1")
+                .long("host")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::new("PORT")
+                .value_parser(value_parser!(u16))
+                .default_value("6142")
+                .short('p')
+                .long("port")
+                .help("Server port"),
+        )
+        .arg(
+            Arg::new("PASSWORD")
+                .short('a')
+                .long("pass")
+                .help("Password to authenticate with"),
+        )
+        .arg(
+            Arg::new("DB")
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+                .short('n')
+                .long("db")
+                .help("Database to export"),
+        )
+        .arg(
+            Arg::new("FORMAT")
+                .value_parser(ExportFormat::from_str)
+                .default_value("json")
+                .long("format")
+                .help("Output format, json (one JSON object per line) or csv"),
+        )
+}
 
-    while let Ok((stream, _)) = listener.accept() {
-        ClientProcess::start_config(stream, None, &client_conf);
-    }
+fn import_command() -> Command {
+    Command::new("import")
+        .about("Loads a file of RESP commands, like redis-cli --pipe")
+        .arg(
+            Arg::new("INPUT")
+                .required(true)
+                .help("File with the commands, only SET and APPEND can be loaded"),
+        )
+        .arg(
+            Arg::new("HOST")
+                .default_value("127.0.0.1")
+                .long("host")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::new("PORT")
+                .value_parser(value_parser!(u16))
+                .default_value("6142")
+                .short('p')
+                .long("port")
+                .help("Server port"),
+        )
+        .arg(
+            Arg::new("PASSWORD")
+                .short('a')
+                .long("pass")
+                .help("Password to authenticate with"),
+        )
+        .arg(
+            Arg::new("DB")
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+                .short('n')
+                .long("db")
+                .help("Database the commands are loaded into"),
+        )
 }
 
-fn parse_args() -> (String, LevelFilter) {
+fn parse_args() -> Mode {
     let matches = Command::new("moonis")
         .version("0.1")
         .author("Roger")
         .about("An implementation of redis using lunatic")
+        .subcommand(bench_command())
+        .subcommand(export_command())
+        .subcommand(import_command())
         .arg(
             Arg::new("ADDR")
                 .default_value("127.0.0.1")
+                .num_args(1..)
+                .value_delimiter(' ')
                 .short('a')
                 .long("address")
-                .help("Sets the listening addr for the server"),
+                .help("Sets the listening addrs for the server, ie. \"127.0.0.1 ::1\""),
         )
         .arg(
             Arg::new("PORT")
-                .value_parser(value_parser!(u16).range(1..65535))
+                .value_parser(value_parser!(u16))
                 .default_value("6142")
                 .short('p')
                 .long("port")
-                .help("Sets the listening port for the server"),
+                .help("Sets the listening port for the server, 0 to use any free port"),
         )
         .arg(
             Arg::new("LOG_LEVEL")
@@ -56,9 +231,462 @@ fn parse_args() -> (String, LevelFilter) {
                 .long("log_level")
                 .help("Sets the log level"),
         )
+        .arg(
+            Arg::new("LOG_FORMAT")
+                .value_parser(LogFormat::from_str)
+                .default_value("pretty")
+                .long("log-format")
+                .help("Log format, pretty or json (one JSON object per line)"),
+        )
+        .arg(
+            Arg::new("LOGFILE")
+                .long("logfile")
+                .help("Writes the logs to this file instead of stdout"),
+        )
+        .arg(
+            Arg::new("LOGFILE_MAX_SIZE")
+                .value_parser(parse_memory)
+                .default_value("10mb")
+                .long("logfile-max-size")
+                .help("Rotates the logfile when it grows over this size, 0 disables rotation"),
+        )
+        .arg(
+            Arg::new("LOGFILE_KEEP")
+                .value_parser(value_parser!(usize))
+                .default_value("5")
+                .long("logfile-keep")
+                .help("Number of rotated logfiles kept"),
+        )
+        .arg(
+            Arg::new("REQUIREPASS")
+                .long("requirepass")
+                .help("Requires clients to authenticate with this password"),
+        )
+        .arg(
+            Arg::new("ACLFILE")
+                .long("aclfile")
+                .help("Loads the ACL users from this file, ACL SAVE writes them back"),
+        )
+        .arg(
+            Arg::new("TLS_PORT")
+                .value_parser(value_parser!(u16))
+                .long("tls-port")
+                .requires_all(["TLS_CERT_FILE", "TLS_KEY_FILE"])
+                .help("Sets the listening port for TLS connections"),
+        )
+        .arg(
+            Arg::new("TLS_CERT_FILE")
+                .long("tls-cert-file")
+                .help("PEM file with the server certificate chain"),
+        )
+        .arg(
+            Arg::new("TLS_KEY_FILE")
+                .long("tls-key-file")
+                .help("PEM file with the server private key"),
+        )
+        .arg(
+            Arg::new("TLS_ONLY")
+                .long("tls-only")
+                .action(ArgAction::SetTrue)
+                .requires("TLS_PORT")
+                .help("Don't listen for plaintext connections"),
+        )
+        .arg(
+            Arg::new("PROTECTED_MODE")
+                .value_parser(["yes", "no"])
+                .default_value("yes")
+                .long("protected-mode")
+                .help("Without password and explicit address only accept loopback clients"),
+        )
+        .arg(
+            Arg::new("MAXMEMORY")
+                .value_parser(parse_memory)
+                .default_value("0")
+                .long("maxmemory")
+                .help("Max memory used by the keys (ie. 100mb), 0 means no limit"),
+        )
+        .arg(
+            Arg::new("AUDIT_LOG")
+                .long("audit-log")
+                .help("Writes who executed CONFIG, FLUSHALL, FLUSHDB and ACL to this file"),
+        )
+        .arg(
+            Arg::new("AUDIT_WRITES")
+                .long("audit-writes")
+                .action(ArgAction::SetTrue)
+                .requires("AUDIT_LOG")
+                .help("Also writes all the write commands to the audit log"),
+        )
+        .arg(
+            Arg::new("CDC_LOG")
+                .long("cdc-log")
+                .help("Writes every key change with its old and new value to this file"),
+        )
+        .arg(
+            Arg::new("WEBHOOK")
+                .value_parser(Webhook::from_str)
+                .action(ArgAction::Append)
+                .long("webhook")
+                .help("POSTs the changes of the keys matching a pattern: \"<pattern> <url>\""),
+        )
+        .arg(
+            Arg::new("OTLP_ENDPOINT")
+                .value_parser(HttpUrl::from_str)
+                .long("otlp-endpoint")
+                .help("Exports the spans of the commands to this OTLP/HTTP collector URL"),
+        )
+        .arg(
+            Arg::new("TRACE_SAMPLE_RATIO")
+                .value_parser(value_parser!(f64))
+                .default_value("0")
+                .long("trace-sample-ratio")
+                .help("Part of the commands traced when the client didn't set a traceparent"),
+        )
+        .arg(
+            Arg::new("METRICS_PORT")
+                .value_parser(value_parser!(u16))
+                .long("metrics-port")
+                .help("Sets the port of the HTTP listener exposing Prometheus metrics"),
+        )
+        .arg(
+            Arg::new("MEMCACHED_PORT")
+                .value_parser(value_parser!(u16))
+                .long("memcached-port")
+                .help(
+                    "Sets the port of a memcached listener for database 0, run as the default user",
+                ),
+        )
+        .arg(
+            Arg::new("HTTP_PORT")
+                .value_parser(value_parser!(u16))
+                .long("http-port")
+                .help("Sets the port of the HTTP gateway to the keys and the commands"),
+        )
+        .arg(
+            Arg::new("WEBSOCKET_PORT")
+                .value_parser(value_parser!(u16))
+                .long("websocket-port")
+                .help("Sets the port of the WebSocket bridge for RESP and JSON commands"),
+        )
+        .arg(
+            Arg::new("WEBSOCKET_ORIGIN")
+                .action(ArgAction::Append)
+                .long("websocket-origin")
+                .help("Allows the pages of another origin to open WebSocket connections"),
+        )
+        .arg(
+            Arg::new("CLIENT_MAX_MEMORY")
+                .value_parser(parse_memory)
+                .default_value("5m")
+                .long("client-max-memory")
+                .help("Max memory of each client process, bigger commands are rejected"),
+        )
+        .arg(
+            Arg::new("PIPELINE_MAX_COMMANDS")
+                .value_parser(value_parser!(usize))
+                .default_value("1024")
+                .long("pipeline-max-commands")
+                .help(
+                    "Pipelined commands executed at once, the rest waits unread, 0 means no limit",
+                ),
+        )
+        .arg(
+            Arg::new("PIPELINE_MAX_REPLY_BYTES")
+                .value_parser(parse_memory)
+                .default_value("1mb")
+                .long("pipeline-max-reply-bytes")
+                .help("Replies buffered before they are written, 0 means no limit"),
+        )
+        .arg(
+            Arg::new("READ_BUFFER_SIZE")
+                .value_parser(parse_memory)
+                .default_value("1kb")
+                .long("read-buffer-size")
+                .help("Bytes read from a connection at a time, growing while the reads fill them"),
+        )
+        .arg(
+            Arg::new("READ_BUFFER_MAX")
+                .value_parser(parse_memory)
+                .default_value("64kb")
+                .long("read-buffer-max")
+                .help("Max bytes read from a connection at a time"),
+        )
+        .arg(
+            Arg::new("MAXCLIENTS_PER_IP")
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+                .long("maxclients-per-ip")
+                .help("Max concurrent connections from the same IP address, 0 means no limit"),
+        )
+        .arg(
+            Arg::new("MAX_CONNECTION_RATE")
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+                .long("max-connection-rate")
+                .help("Max new connections per second from the same IP address, 0 means no limit"),
+        )
+        .arg(
+            Arg::new("ACCEPTORS")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("1")
+                .long("acceptors")
+                .help("Number of processes spawning the clients of each listener"),
+        )
+        .arg(
+            Arg::new("TIMEOUT")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("timeout")
+                .help("Close the connections idle for more than this seconds, 0 means never"),
+        )
+        .arg(
+            Arg::new("CLIENT_WRITE_TIMEOUT")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("client-write-timeout")
+                .help("Close the connections not reading their replies for this many seconds"),
+        )
+        .arg(
+            Arg::new("MAXMEMORY_CLIENTS")
+                .value_parser(parse_memory)
+                .default_value("0")
+                .long("maxmemory-clients")
+                .help("Max memory used by the client output buffers, 0 means no limit"),
+        )
+        .arg(
+            Arg::new("CLIENT_OUTPUT_BUFFER_LIMIT")
+                .value_parser(OutputBufferLimits::from_str)
+                .long("client-output-buffer-limit")
+                .help("Output buffer limits by client class: <class> <hard> <soft> <seconds>"),
+        )
+        .arg(
+            Arg::new("COMPRESSION_THRESHOLD")
+                .value_parser(parse_memory)
+                .default_value("0")
+                .long("compression-threshold")
+                .help("Compress values bigger than this (ie. 4kb), 0 disables compression"),
+        )
+        .arg(
+            Arg::new("MAXMEMORY_POLICY")
+                .value_parser(EvictionPolicy::from_str)
+                .default_value("noeviction")
+                .long("maxmemory-policy")
+                .help("How keys are evicted when maxmemory is reached (ie. allkeys-lru)"),
+        )
+        .arg(
+            Arg::new("MAXMEMORY_SAMPLES")
+                .value_parser(value_parser!(usize))
+                .default_value("5")
+                .long("maxmemory-samples")
+                .help("Keys sampled by the LRU/LFU eviction, more is accurate but slower"),
+        )
+        .arg(
+            Arg::new("SHARDS")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("1")
+                .long("shards")
+                .help("Number of storage processes the keys are partitioned across"),
+        )
+        .arg(
+            Arg::new("DATABASES")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("16")
+                .long("databases")
+                .help("Number of logical databases, selected with SELECT"),
+        )
+        .arg(
+            Arg::new("STORAGE_REPLICAS")
+                .value_parser(value_parser!(u16))
+                .default_value("0")
+                .long("storage-replicas")
+                .help("Number of read-only replicas of each storage process"),
+        )
+        .arg(
+            Arg::new("COMPACTION_INTERVAL")
+                .value_parser(value_parser!(u64))
+                .default_value("60")
+                .long("compaction-interval")
+                .help("Seconds between the compactions of the keyspace, 0 disables them"),
+        )
+        .arg(
+            Arg::new("TRASH_RETENTION")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("trash-retention")
+                .help("Seconds the deleted keys can be restored with UNDELETE, 0 disables it"),
+        )
+        .arg(
+            Arg::new("WATCHDOG_INTERVAL")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("watchdog-interval")
+                .help(
+                    "Seconds between the pings of the storage processes, 0 disables the watchdog",
+                ),
+        )
+        .arg(
+            Arg::new("WATCHDOG_DEADLINE")
+                .value_parser(value_parser!(u64))
+                .default_value("1000")
+                .long("watchdog-deadline")
+                .help("Milliseconds before a storage process not replying is reported as stalled"),
+        )
+        .arg(
+            Arg::new("WATCHDOG_RESTART")
+                .long("watchdog-restart")
+                .action(ArgAction::SetTrue)
+                .requires("WATCHDOG_INTERVAL")
+                .help("Restarts the storage processes stalled for several pings, needs replicas"),
+        )
+        .arg(
+            Arg::new("CHAOS_DELAY_RATE")
+                .value_parser(value_parser!(f64))
+                .default_value("0")
+                .long("chaos-delay-rate")
+                .hide(!cfg!(debug_assertions))
+                .help("Debug builds: probability of delaying a storage reply"),
+        )
+        .arg(
+            Arg::new("CHAOS_MAX_DELAY")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("chaos-max-delay")
+                .hide(!cfg!(debug_assertions))
+                .help("Debug builds: max milliseconds a storage reply is delayed"),
+        )
+        .arg(
+            Arg::new("CHAOS_DROP_RATE")
+                .value_parser(value_parser!(f64))
+                .default_value("0")
+                .long("chaos-drop-rate")
+                .hide(!cfg!(debug_assertions))
+                .help("Debug builds: probability of dropping a connection when it sends commands"),
+        )
+        .arg(
+            Arg::new("CHAOS_KILL_INTERVAL")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .long("chaos-kill-interval")
+                .hide(!cfg!(debug_assertions))
+                .help("Debug builds: seconds between kills of a storage process, needs replicas"),
+        )
         .get_matches();
-    let addr = matches.get_one::<String>("ADDR").unwrap();
-    let port = matches.get_one::<u16>("PORT").unwrap();
-    let log_level = matches.get_one::<LevelFilter>("LOG_LEVEL").unwrap();
-    (format!("{addr}:{port}"), log_level.to_owned())
+    if let Some(("bench", bench)) = matches.subcommand() {
+        return Mode::Bench(BenchConfig {
+            host: bench.get_one::<String>("HOST").unwrap().clone(),
+            port: *bench.get_one::<u16>("PORT").unwrap(),
+            clients: *bench.get_one::<usize>("CLIENTS").unwrap(),
+            requests: *bench.get_one::<usize>("REQUESTS").unwrap(),
+            pipeline: *bench.get_one::<usize>("PIPELINE").unwrap(),
+            data_size: *bench.get_one::<usize>("DATA_SIZE").unwrap(),
+            keyspace: *bench.get_one::<usize>("KEYSPACE").unwrap(),
+            tests: bench
+                .get_many::<String>("TESTS")
+                .unwrap()
+                .map(|test| test.to_lowercase())
+                .collect(),
+        });
+    }
+    if let Some(("export", export)) = matches.subcommand() {
+        return Mode::Export(ExportConfig {
+            host: export.get_one::<String>("HOST").unwrap().clone(),
+            port: *export.get_one::<u16>("PORT").unwrap(),
+            password: export.get_one::<String>("PASSWORD").cloned(),
+            db: *export.get_one::<usize>("DB").unwrap(),
+            format: *export.get_one::<ExportFormat>("FORMAT").unwrap(),
+            output: export.get_one::<String>("OUTPUT").unwrap().clone(),
+        });
+    }
+    if let Some(("import", import)) = matches.subcommand() {
+        return Mode::Import(ImportConfig {
+            host: import.get_one::<String>("HOST").unwrap().clone(),
+            port: *import.get_one::<u16>("PORT").unwrap(),
+            password: import.get_one::<String>("PASSWORD").cloned(),
+            db: *import.get_one::<usize>("DB").unwrap(),
+            input: import.get_one::<String>("INPUT").unwrap().clone(),
+        });
+    }
+    let addrs: Vec<&String> = matches.get_many::<String>("ADDR").unwrap().collect();
+    let port = *matches.get_one::<u16>("PORT").unwrap();
+    let log_config = LogConfig {
+        level: *matches.get_one::<LevelFilter>("LOG_LEVEL").unwrap(),
+        format: *matches.get_one::<LogFormat>("LOG_FORMAT").unwrap(),
+        file: matches.get_one::<String>("LOGFILE").cloned(),
+        max_size: *matches.get_one::<usize>("LOGFILE_MAX_SIZE").unwrap(),
+        keep: *matches.get_one::<usize>("LOGFILE_KEEP").unwrap(),
+    };
+    let tls = matches.get_one::<u16>("TLS_PORT").map(|_| TlsConfig {
+        cert_file: matches.get_one::<String>("TLS_CERT_FILE").unwrap().clone(),
+        key_file: matches.get_one::<String>("TLS_KEY_FILE").unwrap().clone(),
+    });
+    let config = Config {
+        bind: addrs.iter().map(|addr| addr.to_string()).collect(),
+        port,
+        tls_port: matches
+            .get_one::<u16>("TLS_PORT")
+            .copied()
+            .unwrap_or_default(),
+        requirepass: matches.get_one::<String>("REQUIREPASS").cloned(),
+        aclfile: matches.get_one::<String>("ACLFILE").cloned(),
+        tls,
+        tls_only: matches.get_flag("TLS_ONLY"),
+        protected_mode: matches.get_one::<String>("PROTECTED_MODE").unwrap() == "yes"
+            && matches.value_source("ADDR") != Some(ValueSource::CommandLine),
+        maxmemory: *matches.get_one::<usize>("MAXMEMORY").unwrap(),
+        maxmemory_policy: *matches
+            .get_one::<EvictionPolicy>("MAXMEMORY_POLICY")
+            .unwrap(),
+        maxmemory_samples: *matches.get_one::<usize>("MAXMEMORY_SAMPLES").unwrap(),
+        audit_log: matches.get_one::<String>("AUDIT_LOG").cloned(),
+        audit_writes: matches.get_flag("AUDIT_WRITES"),
+        cdc_log: matches.get_one::<String>("CDC_LOG").cloned(),
+        cdc_consumer: None,
+        otlp_endpoint: matches.get_one::<HttpUrl>("OTLP_ENDPOINT").cloned(),
+        trace_sample_ratio: *matches.get_one::<f64>("TRACE_SAMPLE_RATIO").unwrap(),
+        webhooks: matches
+            .get_many::<Webhook>("WEBHOOK")
+            .map(|webhooks| webhooks.cloned().collect())
+            .unwrap_or_default(),
+        metrics_port: matches.get_one::<u16>("METRICS_PORT").copied(),
+        memcached_port: matches.get_one::<u16>("MEMCACHED_PORT").copied(),
+        http_port: matches.get_one::<u16>("HTTP_PORT").copied(),
+        websocket_port: matches.get_one::<u16>("WEBSOCKET_PORT").copied(),
+        websocket_origins: matches
+            .get_many::<String>("WEBSOCKET_ORIGIN")
+            .map(|origins| origins.cloned().collect())
+            .unwrap_or_default(),
+        client_max_memory: *matches.get_one::<usize>("CLIENT_MAX_MEMORY").unwrap(),
+        pipeline_max_commands: *matches.get_one::<usize>("PIPELINE_MAX_COMMANDS").unwrap(),
+        pipeline_max_reply_bytes: *matches
+            .get_one::<usize>("PIPELINE_MAX_REPLY_BYTES")
+            .unwrap(),
+        read_buffer_size: *matches.get_one::<usize>("READ_BUFFER_SIZE").unwrap(),
+        read_buffer_max: *matches.get_one::<usize>("READ_BUFFER_MAX").unwrap(),
+        maxclients_per_ip: *matches.get_one::<usize>("MAXCLIENTS_PER_IP").unwrap(),
+        max_connection_rate: *matches.get_one::<usize>("MAX_CONNECTION_RATE").unwrap(),
+        acceptors: (*matches.get_one::<u16>("ACCEPTORS").unwrap()).into(),
+        timeout: *matches.get_one::<u64>("TIMEOUT").unwrap(),
+        client_write_timeout: *matches.get_one::<u64>("CLIENT_WRITE_TIMEOUT").unwrap(),
+        maxmemory_clients: *matches.get_one::<usize>("MAXMEMORY_CLIENTS").unwrap(),
+        client_output_buffer_limit: matches
+            .get_one::<OutputBufferLimits>("CLIENT_OUTPUT_BUFFER_LIMIT")
+            .copied()
+            .unwrap_or_default(),
+        compression_threshold: *matches.get_one::<usize>("COMPRESSION_THRESHOLD").unwrap(),
+        shards: (*matches.get_one::<u16>("SHARDS").unwrap()).into(),
+        databases: (*matches.get_one::<u16>("DATABASES").unwrap()).into(),
+        storage_replicas: (*matches.get_one::<u16>("STORAGE_REPLICAS").unwrap()).into(),
+        compaction_interval: *matches.get_one::<u64>("COMPACTION_INTERVAL").unwrap(),
+        trash_retention: *matches.get_one::<u64>("TRASH_RETENTION").unwrap(),
+        watchdog_interval: *matches.get_one::<u64>("WATCHDOG_INTERVAL").unwrap(),
+        watchdog_deadline_ms: *matches.get_one::<u64>("WATCHDOG_DEADLINE").unwrap(),
+        watchdog_restart: matches.get_flag("WATCHDOG_RESTART"),
+        chaos: ChaosConfig {
+            delay_rate: *matches.get_one::<f64>("CHAOS_DELAY_RATE").unwrap(),
+            max_delay_ms: *matches.get_one::<u64>("CHAOS_MAX_DELAY").unwrap(),
+            drop_rate: *matches.get_one::<f64>("CHAOS_DROP_RATE").unwrap(),
+            kill_interval_secs: *matches.get_one::<u64>("CHAOS_KILL_INTERVAL").unwrap(),
+        },
+    };
+    Mode::Server(log_config, config)
 }
@@ -0,0 +1,350 @@
+//! Listener speaking the memcached ASCII protocol, so memcached clients can use the keys
+//! of the first database. Flags aren't stored and replied as 0, and keys can't expire yet
+//! so the exptime of the commands is ignored. The commands are run by a client of the
+//! default user, like the HTTP requests, so its ACL rules and protected mode apply
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use lunatic::{
+    net::TcpStream,
+    process::{ProcessRef, StartProcess},
+    Mailbox, Process,
+};
+use lunatic_log::{debug, info};
+
+use crate::{
+    client::{ClientProcess, ClientProcessHandler, QUERY_BUFFER_FRACTION},
+    config::Config,
+    server::{self, admitted, client_config, connection_limiter, Bound},
+    types::{BulkString, RedisCmd, RedisKey, RedisValue, RespValue},
+};
+
+/// Longest key accepted by memcached
+const KEY_MAX_LENGTH: usize = 250;
+
+/// Longest command line, the data of the storage commands excluded
+const LINE_MAX_LENGTH: usize = 2048;
+
+#[derive(Debug)]
+enum Command {
+    Get(Vec<RedisKey>),
+    Set(RedisKey, RedisValue),
+    Delete(RedisKey),
+    /// The delta and if it's a decrement
+    Incr(RedisKey, u64, bool),
+    Touch(RedisKey),
+    Version,
+    Quit,
+}
+
+/// Command parsed from the start of the buffer with its length and if its reply is
+/// suppressed (noreply), None until the whole command is buffered. Commands that can't be
+/// parsed are returned as the error line to reply. The length can be longer than the
+/// buffer for a data block that is too big, the rest of it is skipped once it's read
+type Parsed = Option<(Result<Command, &'static str>, usize, bool)>;
+
+fn key(word: &[u8]) -> Result<RedisKey, &'static str> {
+    if word.len() > KEY_MAX_LENGTH {
+        return Err("CLIENT_ERROR bad command line format");
+    }
+    Ok(BulkString(word.to_vec().into()))
+}
+
+fn number(word: &[u8]) -> Result<u64, &'static str> {
+    std::str::from_utf8(word)
+        .ok()
+        .and_then(|word| word.parse().ok())
+        .ok_or("CLIENT_ERROR bad command line format")
+}
+
+/// Parse the next command, data blocks over `max_value` bytes are rejected before they
+/// are buffered
+fn parse(buffer: &[u8], max_value: usize) -> Parsed {
+    let end = match buffer.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buffer.len() > LINE_MAX_LENGTH => {
+            return Some((Err("CLIENT_ERROR line too long"), buffer.len(), false))
+        }
+        None => return None,
+    };
+    let mut words: Vec<&[u8]> = buffer[..end]
+        .split(|byte| *byte == b' ')
+        .filter(|word| !word.is_empty())
+        .collect();
+    let noreply = words.last() == Some(&&b"noreply"[..]);
+    if noreply {
+        words.pop();
+    }
+    let line = end + 2;
+    let bad_format = Err("CLIENT_ERROR bad command line format");
+    let command = match words.as_slice() {
+        [b"get" | b"gets", keys @ ..] if !keys.is_empty() => keys
+            .iter()
+            .map(|word| key(word))
+            .collect::<Result<_, _>>()
+            .map(Command::Get),
+        // set <key> <flags> <exptime> <bytes>, followed by the data block
+        [b"set", name, flags, exptime, bytes] => {
+            let parsed = (number(flags), number(exptime), number(bytes));
+            let bytes = match parsed {
+                (Ok(_), Ok(_), Ok(bytes)) => bytes,
+                _ => return Some((bad_format, line, noreply)),
+            };
+            let bytes = match usize::try_from(bytes) {
+                Ok(bytes) if bytes <= max_value => bytes,
+                // The data block is skipped instead of buffered
+                _ => {
+                    let skipped = usize::try_from(bytes).unwrap_or(usize::MAX);
+                    let len = line.saturating_add(skipped).saturating_add(2);
+                    return Some((Err("SERVER_ERROR object too large for cache"), len, noreply));
+                }
+            };
+            // Can't overflow, the line and the data are smaller than the client memory limit
+            let total = line + bytes + 2;
+            if buffer.len() < total {
+                return None;
+            }
+            if &buffer[line + bytes..total] != b"\r\n" {
+                return Some((Err("CLIENT_ERROR bad data chunk"), total, noreply));
+            }
+            let value = BulkString(buffer[line..line + bytes].to_vec().into());
+            return Some((
+                key(name).map(|name| Command::Set(name, value)),
+                total,
+                noreply,
+            ));
+        }
+        [b"delete", name] => key(name).map(Command::Delete),
+        [command @ (b"incr" | b"decr"), name, delta] => match number(delta) {
+            Ok(delta) => key(name).map(|name| Command::Incr(name, delta, *command == b"decr")),
+            Err(err) => Err(err),
+        },
+        [b"touch", name, exptime] => match number(exptime) {
+            Ok(_) => key(name).map(Command::Touch),
+            Err(err) => Err(err),
+        },
+        [b"version"] => Ok(Command::Version),
+        [b"quit"] => Ok(Command::Quit),
+        [b"get" | b"gets" | b"set" | b"delete" | b"incr" | b"decr" | b"touch", ..] => bad_format,
+        _ => Err("ERROR"),
+    };
+    Some((command, line, noreply))
+}
+
+/// Error line of a command rejected by the client, like the errors of memcached
+fn error_line(reply: RespValue) -> Vec<u8> {
+    match reply {
+        RespValue::Error(prefix, _) if prefix == "OOM" => {
+            b"SERVER_ERROR out of memory storing object\r\n".to_vec()
+        }
+        RespValue::Error(prefix, Some(description)) => {
+            format!("SERVER_ERROR {prefix} {description}\r\n").into_bytes()
+        }
+        RespValue::Error(prefix, None) => format!("SERVER_ERROR {prefix}\r\n").into_bytes(),
+        reply => format!("SERVER_ERROR unexpected reply {reply:?}\r\n").into_bytes(),
+    }
+}
+
+/// Reply of the command, executed by the client of the connection
+fn execute(client: &ProcessRef<ClientProcess>, command: Command) -> Vec<u8> {
+    let run = |cmd: RedisCmd| client.process_command(cmd).unwrap_or(RespValue::Null);
+    match command {
+        Command::Get(keys) => match run(RedisCmd::MGet(keys.clone())) {
+            RespValue::Array(values) => {
+                let mut reply = vec![];
+                // Keys holding other types than strings are missing, like with MGET
+                for (key, value) in keys.iter().zip(values) {
+                    if let RespValue::BulkString(value) = value {
+                        reply.extend_from_slice(b"VALUE ");
+                        reply.extend_from_slice(&key.0);
+                        reply.extend_from_slice(format!(" 0 {}\r\n", value.0.len()).as_bytes());
+                        reply.extend_from_slice(&value.0);
+                        reply.extend_from_slice(b"\r\n");
+                    }
+                }
+                reply.extend_from_slice(b"END\r\n");
+                reply
+            }
+            reply => error_line(reply),
+        },
+        Command::Set(key, value) => match run(RedisCmd::Set(key, value)) {
+            RespValue::SimpleString(_) => b"STORED\r\n".to_vec(),
+            reply => error_line(reply),
+        },
+        Command::Delete(key) => match run(RedisCmd::Delete(vec![key])) {
+            RespValue::Integer(0) => b"NOT_FOUND\r\n".to_vec(),
+            RespValue::Integer(_) => b"DELETED\r\n".to_vec(),
+            reply => error_line(reply),
+        },
+        Command::Incr(key, delta, decrement) => {
+            match run(RedisCmd::McIncr(key, delta, decrement)) {
+                RespValue::BulkString(value) => {
+                    let mut reply = value.0.to_vec();
+                    reply.extend_from_slice(b"\r\n");
+                    reply
+                }
+                RespValue::Null => b"NOT_FOUND\r\n".to_vec(),
+                RespValue::Error(prefix, _) if prefix == "WRONGTYPE" || prefix == "ERR" => {
+                    b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec()
+                }
+                reply => error_line(reply),
+            }
+        }
+        // Touching updates the access metadata used by eviction, there is no expire to set
+        Command::Touch(key) => match run(RedisCmd::Get(key)) {
+            RespValue::BulkString(_) => b"TOUCHED\r\n".to_vec(),
+            RespValue::Error(prefix, description) if prefix != "WRONGTYPE" => {
+                error_line(RespValue::Error(prefix, description))
+            }
+            _ => b"NOT_FOUND\r\n".to_vec(),
+        },
+        Command::Version => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+        Command::Quit => vec![],
+    }
+}
+
+/// Run the commands of the connection until it's closed or the client quits
+fn serve_commands(stream: &mut TcpStream, client: &ProcessRef<ClientProcess>, max_value: usize) {
+    let mut buffer = Vec::new();
+    let mut read = [0; 4096];
+    // Bytes of a rejected data block that weren't read yet
+    let mut skip = 0;
+    loop {
+        while let Some((command, len, noreply)) = parse(&buffer, max_value) {
+            let parsed = len.min(buffer.len());
+            buffer.drain(..parsed);
+            skip = len - parsed;
+            let reply = match command {
+                Ok(Command::Quit) => return,
+                Ok(command) => execute(client, command),
+                Err(error) => format!("{error}\r\n").into_bytes(),
+            };
+            if !noreply && stream.write_all(&reply).is_err() {
+                return;
+            }
+        }
+        match stream.read(&mut read) {
+            Ok(0) | Err(_) => return,
+            Ok(readed) => {
+                let skipped = skip.min(readed);
+                skip -= skipped;
+                buffer.extend_from_slice(&read[skipped..readed]);
+            }
+        }
+    }
+}
+
+/// Serve the commands of the connection with a client registered for its peer
+fn serve_connection(mut stream: TcpStream, peer: String, config: Config) {
+    let timeout = (config.timeout > 0).then(|| Duration::from_secs(config.timeout));
+    if let Err(err) = stream.set_read_timeout(timeout) {
+        debug!("Can't set the idle timeout: {err}");
+    }
+    let client =
+        ClientProcess::start_config((None, peer, config.clone()), None, &client_config(&config));
+    let max_value = config.client_max_memory / QUERY_BUFFER_FRACTION;
+    serve_commands(&mut stream, &client, max_value);
+    client.shutdown();
+}
+
+/// Accept memcached connections on the address, each one is served by its own process
+/// with the memory limit of the clients
+pub fn serve(addr: String, bound: Bound, config: Config) {
+    let listener = server::bind(addr, bound);
+    info!("Serving memcached on: {}", listener.local_addr().unwrap());
+    let connection_config = client_config(&config);
+    let registry = connection_limiter(&config);
+
+    while let Ok((stream, peer)) = listener.accept() {
+        if !admitted(registry.as_ref(), peer) {
+            continue;
+        }
+        debug!("Memcached connection from {peer}");
+        Process::spawn_config(
+            &connection_config,
+            (stream, peer.to_string(), config.clone()),
+            |(stream, peer, config), _: Mailbox<()>| serve_connection(stream, peer, config),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(buffer: &str) -> (Result<Command, &'static str>, usize, bool) {
+        parse(buffer.as_bytes(), 1024).expect("complete command")
+    }
+
+    #[test]
+    fn commands_are_parsed_from_their_line() {
+        let (command, len, noreply) = parsed("get a b\r\nget c\r\n");
+        assert!(matches!(command, Ok(Command::Get(keys)) if keys.len() == 2));
+        assert_eq!((len, noreply), (9, false));
+        let (command, _, noreply) = parsed("delete a noreply\r\n");
+        assert!(matches!(command, Ok(Command::Delete(key)) if key.to_string() == "a"));
+        assert!(noreply);
+        let (command, ..) = parsed("decr a 5\r\n");
+        assert!(matches!(command, Ok(Command::Incr(_, 5, true))));
+        let (command, ..) = parsed("incr  a  5\r\n");
+        assert!(matches!(command, Ok(Command::Incr(_, 5, false))));
+        assert!(matches!(parsed("touch a 10\r\n").0, Ok(Command::Touch(_))));
+        assert!(matches!(parsed("version\r\n").0, Ok(Command::Version)));
+        assert!(matches!(parsed("quit\r\n").0, Ok(Command::Quit)));
+    }
+
+    #[test]
+    fn set_waits_for_its_data_block() {
+        assert!(parse(b"set a 0 0 5\r\nhel", 1024).is_none());
+        let (command, len, _) = parsed("set a 7 0 5\r\nhello\r\nget a\r\n");
+        assert!(matches!(
+            command,
+            Ok(Command::Set(key, value)) if key.to_string() == "a" && value.to_string() == "hello"
+        ));
+        assert_eq!(len, 20);
+        let (command, len, _) = parsed("set a 0 0 1\r\nabc\r\n");
+        assert_eq!(command.unwrap_err(), "CLIENT_ERROR bad data chunk");
+        assert_eq!(len, 16);
+    }
+
+    #[test]
+    fn values_over_the_limit_are_skipped() {
+        let (command, len, noreply) = parse(b"set a 0 0 2000 noreply\r\n", 1024).unwrap();
+        assert_eq!(
+            command.unwrap_err(),
+            "SERVER_ERROR object too large for cache"
+        );
+        assert_eq!(len, 24 + 2000 + 2);
+        assert!(noreply);
+        let (_, len, _) = parsed("set a 0 0 18446744073709551615\r\n");
+        assert_eq!(len, usize::MAX);
+    }
+
+    #[test]
+    fn malformed_commands_are_errors() {
+        assert!(parse(b"get a", 1024).is_none());
+        let long = "a".repeat(LINE_MAX_LENGTH + 1);
+        let (command, len, _) = parse(long.as_bytes(), 1024).unwrap();
+        assert_eq!(command.unwrap_err(), "CLIENT_ERROR line too long");
+        assert_eq!(len, long.len());
+        let long_key = format!("get {}\r\n", "k".repeat(KEY_MAX_LENGTH + 1));
+        for line in [
+            "get\r\n",
+            "set a 0 0\r\n",
+            "set a x 0 1\r\n",
+            "incr a -1\r\n",
+            "touch a\r\n",
+            long_key.as_str(),
+        ] {
+            assert_eq!(
+                parsed(line).0.unwrap_err(),
+                "CLIENT_ERROR bad command line format",
+                "{line:?}"
+            );
+        }
+        assert_eq!(parsed("flush_all\r\n").0.unwrap_err(), "ERROR");
+    }
+}
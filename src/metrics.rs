@@ -0,0 +1,273 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+};
+
+use lunatic::{abstract_process, process::ProcessRef};
+use lunatic_log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    registry::{Registry, RegistryHandler},
+    server::{self, Bound},
+    shards::Shards,
+    types::RespValue,
+};
+
+/// Upper bounds in microseconds of the command latency histogram buckets
+const LATENCY_BUCKETS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 5000, 25000, 100000];
+
+/// Percentiles reported by INFO latencystats
+const LATENCY_PERCENTILES: [(&str, f64); 3] = [("p50", 0.5), ("p99", 0.99), ("p99.9", 0.999)];
+
+/// Calls and latency of a command
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommandStats {
+    pub calls: u64,
+    /// Total time spent running the command
+    pub usec: u64,
+    /// Slowest call, used as the upper bound of the last bucket
+    pub max_usec: u64,
+    /// Calls in each latency bucket, not cumulative, the last one counts the slowest calls
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    /// Calls not executed, because of ACL rules or missing authentication
+    pub rejected_calls: u64,
+    /// Calls executed that replied with an error
+    pub failed_calls: u64,
+}
+
+impl CommandStats {
+    pub fn record(&mut self, usec: u64, failed: bool) {
+        self.calls += 1;
+        self.usec += usec;
+        self.max_usec = self.max_usec.max(usec);
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| usec <= bound);
+        self.buckets[bucket.unwrap_or(LATENCY_BUCKETS.len())] += 1;
+        if failed {
+            self.failed_calls += 1;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        self.rejected_calls += 1;
+    }
+
+    fn merge(&mut self, other: &CommandStats) {
+        self.calls += other.calls;
+        self.usec += other.usec;
+        self.max_usec = self.max_usec.max(other.max_usec);
+        for (bucket, calls) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += calls;
+        }
+        self.rejected_calls += other.rejected_calls;
+        self.failed_calls += other.failed_calls;
+    }
+
+    /// Latency in microseconds under which the fraction of the calls ran, estimated with
+    /// the upper bound of the bucket reaching it
+    fn percentile(&self, fraction: f64) -> u64 {
+        let target = (self.calls as f64 * fraction).ceil() as u64;
+        let mut cumulative = 0;
+        for (bucket, calls) in self.buckets.iter().enumerate() {
+            cumulative += calls;
+            if cumulative >= target {
+                let bound = LATENCY_BUCKETS
+                    .get(bucket)
+                    .copied()
+                    .unwrap_or(self.max_usec);
+                return bound.min(self.max_usec);
+            }
+        }
+        self.max_usec
+    }
+
+    /// Line of INFO commandstats, without the `cmdstat_` prefix
+    pub fn info_line(&self) -> String {
+        let per_call = self.usec as f64 / self.calls.max(1) as f64;
+        format!(
+            "calls={},usec={},usec_per_call={per_call:.2},rejected_calls={},failed_calls={}",
+            self.calls, self.usec, self.rejected_calls, self.failed_calls
+        )
+    }
+
+    /// Line of INFO latencystats, without the `latency_percentiles_usec_` prefix
+    pub fn latency_line(&self) -> String {
+        let percentiles: Vec<_> = LATENCY_PERCENTILES
+            .iter()
+            .map(|(name, fraction)| format!("{name}={}", self.percentile(*fraction)))
+            .collect();
+        percentiles.join(",")
+    }
+}
+
+/// Stats collected by a client, sent to the metrics process after each batch of commands
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub commands: BTreeMap<String, CommandStats>,
+    /// Error replies by error prefix, like ERR or NOAUTH
+    pub errors: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.errors.is_empty()
+    }
+
+    /// Count the reply when it is an error
+    pub fn count_error(&mut self, reply: &RespValue) {
+        if let RespValue::Error(prefix, _) = reply {
+            *self.errors.entry(prefix.clone()).or_default() += 1;
+        }
+    }
+}
+
+/// Statistics of the commands run by all the clients, the clients aggregate the stats of
+/// each batch of commands before sending them
+#[derive(Default)]
+pub struct Metrics {
+    stats: Stats,
+}
+
+#[abstract_process(visibility = pub)]
+impl Metrics {
+    #[init]
+    fn init(_: ProcessRef<Self>, _: ()) -> Self {
+        Self::default()
+    }
+
+    #[handle_message]
+    fn record(&mut self, stats: Stats) {
+        for (name, stats) in stats.commands {
+            self.stats.commands.entry(name).or_default().merge(&stats);
+        }
+        for (prefix, count) in stats.errors {
+            *self.stats.errors.entry(prefix).or_default() += count;
+        }
+    }
+
+    #[handle_request]
+    fn commands(&mut self) -> BTreeMap<String, CommandStats> {
+        self.stats.commands.clone()
+    }
+
+    #[handle_request]
+    fn errors(&mut self) -> BTreeMap<String, u64> {
+        self.stats.errors.clone()
+    }
+
+    /// Forget all the stats (CONFIG RESETSTAT)
+    #[handle_request]
+    fn reset(&mut self) {
+        self.stats = Stats::default();
+    }
+}
+
+/// Metrics in the Prometheus text exposition format
+fn render(config: &Config) -> String {
+    let metrics = ProcessRef::<Metrics>::lookup("metrics").unwrap();
+    let registry = ProcessRef::<Registry>::lookup("registry").unwrap();
+    let mut out = String::new();
+
+    let commands = metrics.commands();
+    out.push_str("# HELP moonis_commands_total Commands processed.\n");
+    out.push_str("# TYPE moonis_commands_total counter\n");
+    for (name, stats) in &commands {
+        let _ = writeln!(
+            out,
+            "moonis_commands_total{{cmd=\"{name}\"}} {}",
+            stats.calls
+        );
+    }
+    out.push_str("# HELP moonis_command_duration_seconds Time spent running the commands.\n");
+    out.push_str("# TYPE moonis_command_duration_seconds histogram\n");
+    for (name, stats) in commands.iter().filter(|(_, stats)| stats.calls > 0) {
+        let histogram = "moonis_command_duration_seconds";
+        let mut cumulative = 0;
+        for (bound, calls) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+            cumulative += calls;
+            let le = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(
+                out,
+                "{histogram}_bucket{{cmd=\"{name}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let calls = stats.calls;
+        let seconds = stats.usec as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "{histogram}_bucket{{cmd=\"{name}\",le=\"+Inf\"}} {calls}"
+        );
+        let _ = writeln!(out, "{histogram}_sum{{cmd=\"{name}\"}} {seconds}");
+        let _ = writeln!(out, "{histogram}_count{{cmd=\"{name}\"}} {calls}");
+    }
+
+    out.push_str("# HELP moonis_errors_total Error replies by error prefix.\n");
+    out.push_str("# TYPE moonis_errors_total counter\n");
+    for (prefix, count) in metrics.errors() {
+        let _ = writeln!(out, "moonis_errors_total{{prefix=\"{prefix}\"}} {count}");
+    }
+
+    out.push_str("# HELP moonis_connected_clients Connected clients.\n");
+    out.push_str("# TYPE moonis_connected_clients gauge\n");
+    let _ = writeln!(out, "moonis_connected_clients {}", registry.list().len());
+
+    let databases: Vec<_> = (0..config.databases)
+        .map(|db| Shards::new(db, config.shards, config.storage_replicas).memory_stats())
+        .collect();
+    out.push_str("# HELP moonis_keys Keys in each database.\n");
+    out.push_str("# TYPE moonis_keys gauge\n");
+    for (db, stats) in databases.iter().enumerate() {
+        let _ = writeln!(out, "moonis_keys{{db=\"{db}\"}} {}", stats.keys);
+    }
+    out.push_str("# HELP moonis_memory_used_bytes Memory used by the keys of each database.\n");
+    out.push_str("# TYPE moonis_memory_used_bytes gauge\n");
+    for (db, stats) in databases.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "moonis_memory_used_bytes{{db=\"{db}\"}} {}",
+            stats.used
+        );
+    }
+    out.push_str("# HELP moonis_memory_max_bytes Max memory of the keys of each database.\n");
+    out.push_str("# TYPE moonis_memory_max_bytes gauge\n");
+    for (db, stats) in databases.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "moonis_memory_max_bytes{{db=\"{db}\"}} {}",
+            stats.maxmemory
+        );
+    }
+    out
+}
+
+/// Answer every HTTP request accepted on the address with the metrics, the request itself
+/// is not looked at so any path works
+pub fn serve(addr: String, bound: Bound, config: Config) {
+    let listener = server::bind(addr, bound);
+    info!("Serving metrics on: {}", listener.local_addr().unwrap());
+
+    while let Ok((mut stream, peer)) = listener.accept() {
+        // Read until the end of the request headers
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(readed) => request.extend_from_slice(&buffer[..readed]),
+            }
+        }
+        let body = render(&config);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        if let Err(err) = stream.write_all(response.as_bytes()) {
+            debug!("Can't send the metrics to {peer}: {err}");
+        }
+    }
+}
@@ -1,104 +1,397 @@
-use combine::{
-    count_min_max,
-    error::{ParseError, StreamError},
-    parser::{
-        byte::{byte, take_until_bytes},
-        choice::choice,
-        combinator::{any_send_partial_state, AnySendPartialState},
-        range::{range, recognize, take},
-    },
-    stream::{RangeStream, StreamErrorFor},
-    value, Parser,
-};
-
-use crate::types::{BulkString, RespValue};
-
-/// Line parser for resp protocol, reads until `\r\n`
-fn line<'a, Input>() -> impl Parser<Input, Output = &'a str, PartialState = AnySendPartialState> + 'a
-where
-    Input: RangeStream<Token = u8, Range = &'a [u8]> + 'a,
-    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
-{
-    any_send_partial_state(
-        recognize(take_until_bytes(&b"\r\n"[..]).with(take(2).map(|_| ()))).and_then(
-            |line: &[u8]| {
-                std::str::from_utf8(&line[..line.len() - 2]).map_err(StreamErrorFor::<Input>::other)
-            },
-        ),
-    )
+use std::{collections::VecDeque, ops::Range};
+
+use anyhow::{anyhow, bail};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{BulkString, RedisCmd, RespValue};
+
+/// Max elements of an array, same as the redis limit
+const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+
+/// Max size of a bulk string, same as the redis proto-max-bulk-len default
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// Elements allocated up front for an array, a bigger header can't make the parser
+/// preallocate before the elements arrive
+const MAX_PREALLOCATED_LEN: usize = 1024;
+
+/// Digits of the lengths decoded by the fast path, bigger lengths can't overflow on wasm32
+const MAX_FAST_DIGITS: usize = 9;
+
+/// Message sent by a client, the hot commands are decoded straight into their command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Command(RedisCmd),
+    Message(RespValue),
+}
+
+impl Request {
+    pub fn into_command(self) -> anyhow::Result<RedisCmd> {
+        match self {
+            Request::Command(cmd) => Ok(cmd),
+            Request::Message(resp) => RedisCmd::try_from(resp),
+        }
+    }
 }
 
-/// Integer parser (i64) for resp protocol
-/// ie. :42\r\n
-fn integer<'a, Input>() -> impl Parser<Input, Output = i64, PartialState = AnySendPartialState> + 'a
-where
-    Input: RangeStream<Token = u8, Range = &'a [u8]> + 'a,
-    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
-{
-    any_send_partial_state(line().and_then(|line| match line.trim().parse() {
-        Ok(value) => Ok(value),
-        Err(_) => Err(StreamErrorFor::<Input>::message_static_message(
-            "Invalid Integer",
-        )),
-    }))
+/// Progress of the message being parsed, kept between the calls to `decode` so an
+/// incomplete message continues from where it stopped instead of being parsed again
+#[derive(Debug, Default)]
+pub struct DecodeState {
+    /// Elements of the array parsed so far, and the number still missing
+    array: Option<(VecDeque<RespValue>, usize)>,
+    /// Length of the bulk string whose header was parsed, waiting for its data
+    bulk: Option<usize>,
+    /// Bytes of the incomplete line already searched for its `\r\n`
+    searched: usize,
+    /// Bytes of the array header and elements parsed so far, no longer in the buffer
+    parsed: usize,
 }
 
-/// Resp2 parser for server commands
-/// clients send only command as SimpleString (simple commands easy to send from telnet/netcat) or
-/// using Array of BulkStrings with the first element as the command
-/// That's why we only parse a subset of the resp2 protocol here, we only need to encode the rest
-/// of the spec to create anwsers to the clients
-pub fn resp_parser<'a, Input>(
-) -> impl Parser<Input, Output = RespValue, PartialState = AnySendPartialState> + 'a
-where
-    Input: RangeStream<Token = u8, Range = &'a [u8]> + 'a,
-    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
-{
-    // Simple command parser, this is just a string with args splited by whitespace
-    // ie. GET key
-    let simple_command = || {
-        line().map(|line| {
-            let values = line
-                .split_whitespace()
-                .map(|part| RespValue::BulkString(BulkString(part.into())))
-                .collect();
-            RespValue::Array(values)
-        })
+impl DecodeState {
+    /// Bytes of the incomplete message taken out of the buffer, they count with the buffer
+    /// towards the limit of the query buffer
+    pub fn pending(&self) -> usize {
+        self.parsed
+    }
+}
+
+/// Take the next line of the buffer without its `\r\n`, None when it isn't complete yet
+fn take_line(buffer: &mut BytesMut, searched: &mut usize) -> Option<BytesMut> {
+    let mut from = *searched;
+    while let Some(position) = buffer[from..].iter().position(|&byte| byte == b'\n') {
+        let end = from + position;
+        if end > 0 && buffer[end - 1] == b'\r' {
+            let mut line = buffer.split_to(end + 1);
+            line.truncate(end - 1);
+            *searched = 0;
+            return Some(line);
+        }
+        from = end + 1;
+    }
+    *searched = buffer.len();
+    None
+}
+
+/// Length of an array or bulk string, None for the negative ones (null). Rejected when over
+/// the max so a malformed header can't make the parser wait for gigabytes
+fn length(header: &[u8], max: i64, error: &'static str) -> anyhow::Result<Option<usize>> {
+    let length: i64 = std::str::from_utf8(header)
+        .ok()
+        .and_then(|header| header.trim().parse().ok())
+        .ok_or_else(|| anyhow!("Invalid Integer: `{}`", String::from_utf8_lossy(header)))?;
+    if length > max {
+        bail!(error);
+    }
+    Ok(usize::try_from(length).ok())
+}
+
+/// Simple command, a line with the arguments split by whitespace so commands are easy to
+/// send from telnet/netcat, ie. `GET key`
+fn inline_command(
+    buffer: &mut BytesMut,
+    state: &mut DecodeState,
+) -> anyhow::Result<Option<RespValue>> {
+    let line = match take_line(buffer, &mut state.searched) {
+        Some(line) => line,
+        None => return Ok(None),
     };
+    let line =
+        std::str::from_utf8(&line).map_err(|err| anyhow!("Invalid inline command: {err}"))?;
+    let values = line
+        .split_whitespace()
+        .map(|part| RespValue::BulkString(BulkString(Bytes::copy_from_slice(part.as_bytes()))))
+        .collect();
+    Ok(Some(RespValue::Array(values)))
+}
 
-    // Binary friendly string
-    let bulk = || {
-        integer().then_partial(move |&mut length| {
-            if length < 0 {
-                value(RespValue::Null).left()
-            } else {
-                take(length as usize)
-                    .map(|data: &[u8]| RespValue::BulkString(BulkString(data.into())))
-                    .skip(range(&b"\r\n"[..]))
-                    .right()
+/// Binary friendly string, the elements of the arrays. None when it isn't complete yet, its
+/// header is kept in the state once parsed
+fn bulk(buffer: &mut BytesMut, state: &mut DecodeState) -> anyhow::Result<Option<RespValue>> {
+    let length = match state.bulk {
+        Some(length) => length,
+        None => {
+            match buffer.first() {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(&byte) => bail!("Expected `$`, found `{}`", byte as char),
+            }
+            let header = match take_line(buffer, &mut state.searched) {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match length(&header[1..], MAX_BULK_LEN, "Invalid bulk length")? {
+                Some(length) => length,
+                None => return Ok(Some(RespValue::Null)),
             }
-        })
+        }
     };
+    if buffer.len() < length + 2 {
+        state.bulk = Some(length);
+        return Ok(None);
+    }
+    state.bulk = None;
+    if &buffer[length..length + 2] != b"\r\n" {
+        bail!("Expected `\\r\\n` after a bulk string of {length} bytes");
+    }
+    let data = Bytes::copy_from_slice(&buffer[..length]);
+    buffer.advance(length + 2);
+    Ok(Some(RespValue::BulkString(BulkString(data))))
+}
 
-    // Array of bulk strings
-    let array = || {
-        integer().then_partial(move |&mut length| {
-            if length < 0 {
-                value(RespValue::Null).left()
-            } else {
-                let length = length as usize;
-                count_min_max(length, length, byte(b'$').with(bulk()))
-                    .map(|mut results: Vec<_>| {
-                        // We should never hit an Err result here, because the parsing should fail
-                        // before, count_min_max should get less values if a resp value fails and
-                        // raising an all errors that happened
-                        let results = results.drain(..).map(|x| x).collect();
-                        RespValue::Array(results)
-                    })
-                    .right()
+/// Parse the next message of the buffer, None when it doesn't have a complete message yet.
+/// Clients send only commands, as arrays of bulk strings with the command first or as inline
+/// commands, so only that subset of the resp2 protocol is parsed. The parsed bytes are
+/// removed from the buffer, the progress of an incomplete message is kept in the state and
+/// parsing continues from there once more bytes are added
+pub fn decode(buffer: &mut BytesMut, state: &mut DecodeState) -> anyhow::Result<Option<RespValue>> {
+    let (mut elements, mut missing) = match state.array.take() {
+        Some(array) => array,
+        None => {
+            match buffer.first() {
+                None => return Ok(None),
+                Some(b'*') => (),
+                Some(_) => return inline_command(buffer, state),
+            }
+            let header = match take_line(buffer, &mut state.searched) {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match length(&header[1..], MAX_ARRAY_LEN, "Invalid multibulk length")? {
+                Some(length) => {
+                    let elements = VecDeque::with_capacity(length.min(MAX_PREALLOCATED_LEN));
+                    state.parsed = header.len() + 2;
+                    (elements, length)
+                }
+                None => return Ok(Some(RespValue::Null)),
+            }
+        }
+    };
+    while missing > 0 {
+        let available = buffer.len();
+        let element = bulk(buffer, state)?;
+        state.parsed += available - buffer.len();
+        match element {
+            Some(element) => {
+                elements.push_back(element);
+                missing -= 1;
             }
-        })
+            None => {
+                state.array = Some((elements, missing));
+                return Ok(None);
+            }
+        }
+    }
+    state.parsed = 0;
+    Ok(Some(RespValue::Array(elements)))
+}
+
+/// Plain length of the `*` or `$` header at `start`, with the position after the header.
+/// None for the rest, like negative lengths, they are left to `decode`
+fn fast_length(input: &[u8], start: usize, prefix: u8) -> Option<(usize, usize)> {
+    if *input.get(start)? != prefix {
+        return None;
+    }
+    let mut length = 0;
+    for (position, &byte) in input.iter().enumerate().skip(start + 1) {
+        match byte {
+            b'0'..=b'9' if position - start <= MAX_FAST_DIGITS => {
+                length = length * 10 + (byte - b'0') as usize;
+            }
+            b'\r' if position > start + 1 && input.get(position + 1) == Some(&b'\n') => {
+                return Some((length, position + 2));
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Data of the bulk string at `start`, with the position after it
+fn fast_bulk(input: &[u8], start: usize) -> Option<(Range<usize>, usize)> {
+    let (length, data) = fast_length(input, start, b'$')?;
+    let end = data + length;
+    let terminated = input.get(end..end + 2)? == b"\r\n";
+    (terminated && length as i64 <= MAX_BULK_LEN).then_some((data..end, end + 2))
+}
+
+/// GET and SET commands complete in the buffer, their arguments are slices of the input
+/// instead of copies. None for the other messages
+fn fast_command(buffer: &mut BytesMut) -> Option<RedisCmd> {
+    let (count, position) = fast_length(buffer, 0, b'*')?;
+    let (name, position) = fast_bulk(buffer, position)?;
+    let is_set = match (count, &buffer[name]) {
+        (2, name) if name.eq_ignore_ascii_case(b"get") => false,
+        (3, name) if name.eq_ignore_ascii_case(b"set") => true,
+        _ => return None,
     };
+    let (key, position) = fast_bulk(buffer, position)?;
+    let (value, position) = if is_set {
+        let (value, end) = fast_bulk(buffer, position)?;
+        (Some(value), end)
+    } else {
+        (None, position)
+    };
+    let message = buffer.split_to(position).freeze();
+    let key = BulkString(message.slice(key));
+    Some(match value {
+        Some(value) => RedisCmd::Set(key, BulkString(message.slice(value))),
+        None => RedisCmd::Get(key),
+    })
+}
+
+/// Parse the next request of a client, like `decode`. GET and SET are turned into their
+/// command without building the RespValue array and converting it
+pub fn decode_request(
+    buffer: &mut BytesMut,
+    state: &mut DecodeState,
+) -> anyhow::Result<Option<Request>> {
+    // The fast path only takes complete messages, a partial one continues in `decode`
+    if state.array.is_none() {
+        if let Some(cmd) = fast_command(buffer) {
+            state.searched = 0;
+            return Ok(Some(Request::Command(cmd)));
+        }
+    }
+    Ok(decode(buffer, state)?.map(Request::Message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arguments of a decoded command
+    fn args(value: RespValue) -> Vec<String> {
+        match value {
+            RespValue::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    RespValue::BulkString(string) => string.to_string(),
+                    value => panic!("not a bulk string: {value:?}"),
+                })
+                .collect(),
+            value => panic!("not an array: {value:?}"),
+        }
+    }
+
+    #[test]
+    fn commands_are_arrays_of_bulk_strings() {
+        let mut buffer = BytesMut::from("*2\r\n$4\r\nECHO\r\n$0\r\n\r\n*1\r\n$4\r\nPING\r\n");
+        let mut state = DecodeState::default();
+        let first = decode(&mut buffer, &mut state).unwrap().unwrap();
+        assert_eq!(args(first), ["ECHO", ""]);
+        let second = decode(&mut buffer, &mut state).unwrap().unwrap();
+        assert_eq!(args(second), ["PING"]);
+        assert!(buffer.is_empty());
+        assert!(decode(&mut buffer, &mut state).unwrap().is_none());
+        let mut null = BytesMut::from("*-1\r\n");
+        let value = decode(&mut null, &mut state).unwrap();
+        assert!(matches!(value, Some(RespValue::Null)));
+    }
+
+    #[test]
+    fn inline_commands_are_split_by_whitespace() {
+        let mut buffer = BytesMut::from("SET  key\tvalue\r\nPING\r");
+        let mut state = DecodeState::default();
+        let value = decode(&mut buffer, &mut state).unwrap().unwrap();
+        assert_eq!(args(value), ["SET", "key", "value"]);
+        assert!(decode(&mut buffer, &mut state).unwrap().is_none());
+        assert_eq!(state.searched, 5);
+        buffer.extend_from_slice(b"\n");
+        let value = decode(&mut buffer, &mut state).unwrap().unwrap();
+        assert_eq!(args(value), ["PING"]);
+    }
+
+    #[test]
+    fn incomplete_commands_continue_where_they_stopped() {
+        let message = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut state = DecodeState::default();
+        let mut buffer = BytesMut::new();
+        for (i, byte) in message.iter().enumerate() {
+            buffer.extend_from_slice(&[*byte]);
+            let decoded = decode(&mut buffer, &mut state).unwrap();
+            if i + 1 < message.len() {
+                assert!(decoded.is_none(), "decoded after {} bytes", i + 1);
+                // The parsed bytes left the buffer, they are pending in the state
+                assert_eq!(state.pending() + buffer.len(), i + 1);
+            } else {
+                assert_eq!(args(decoded.unwrap()), ["SET", "key", "value"]);
+            }
+        }
+        assert_eq!(state.pending(), 0);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn pending_counts_the_parsed_elements() {
+        let mut buffer = BytesMut::from("*2\r\n$3\r\nGET\r\n$3\r\nk");
+        let mut state = DecodeState::default();
+        assert!(decode(&mut buffer, &mut state).unwrap().is_none());
+        // The header, the first element and the header of the second one
+        assert_eq!(state.pending(), 4 + 9 + 4);
+        assert_eq!(&buffer[..], b"k");
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        let cases = [
+            ("*x\r\n", "Invalid Integer: `x`"),
+            ("*1048577\r\n", "Invalid multibulk length"),
+            ("*1\r\n$536870913\r\n", "Invalid bulk length"),
+            ("*1\r\n:1\r\n", "Expected `$`, found `:`"),
+            (
+                "*1\r\n$1\r\nab\r\n",
+                "Expected `\\r\\n` after a bulk string of 1 bytes",
+            ),
+        ];
+        for (message, error) in cases {
+            let mut state = DecodeState::default();
+            let result = decode(&mut BytesMut::from(message), &mut state);
+            assert_eq!(result.unwrap_err().to_string(), error, "{message:?}");
+        }
+    }
+
+    #[test]
+    fn get_and_set_take_the_fast_path() {
+        let mut buffer = BytesMut::from(
+            "*2\r\n$3\r\nget\r\n$3\r\nkey\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n",
+        );
+        let mut state = DecodeState::default();
+        let get = decode_request(&mut buffer, &mut state).unwrap().unwrap();
+        assert!(matches!(get, Request::Command(RedisCmd::Get(key)) if key.to_string() == "key"));
+        let set = decode_request(&mut buffer, &mut state).unwrap().unwrap();
+        assert!(matches!(
+            set,
+            Request::Command(RedisCmd::Set(key, value))
+                if key.to_string() == "a" && value.to_string() == "b"
+        ));
+        assert!(buffer.is_empty());
+    }
 
-    any_send_partial_state(choice((byte(b'*').with(array()), simple_command())))
+    #[test]
+    fn other_messages_take_the_slow_path() {
+        let messages = [
+            // Other commands, or GET and SET with other arguments
+            "*2\r\n$4\r\nECHO\r\n$1\r\na\r\n",
+            "*3\r\n$3\r\nGET\r\n$1\r\na\r\n$1\r\nb\r\n",
+            // Lengths the fast path doesn't decode
+            "*2\r\n$3\r\nGET\r\n$0000000001\r\na\r\n",
+            "GET a\r\n",
+        ];
+        for message in messages {
+            let mut buffer = BytesMut::from(message);
+            let mut state = DecodeState::default();
+            let request = decode_request(&mut buffer, &mut state).unwrap();
+            assert!(matches!(request, Some(Request::Message(_))), "{message:?}");
+        }
+        // The rest of a partial message is never taken by the fast path
+        let mut buffer = BytesMut::from("*2\r\n$3\r\nGET\r\n");
+        let mut state = DecodeState::default();
+        assert!(decode_request(&mut buffer, &mut state).unwrap().is_none());
+        buffer.extend_from_slice(b"$1\r\na\r\n");
+        let request = decode_request(&mut buffer, &mut state).unwrap().unwrap();
+        assert!(matches!(request, Request::Message(_)));
+        assert!(matches!(request.into_command(), Ok(RedisCmd::Get(_))));
+    }
 }
@@ -0,0 +1,258 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lunatic::{abstract_process, process::ProcessRef, Process};
+use lunatic_log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{audit::now_ms, config::Config};
+
+/// Clients blocked writing their replies for longer are flagged as slow in CLIENT LIST
+const SLOW_WRITE_MS: u64 = 1000;
+
+/// Connection metadata shown by CLIENT LIST
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub name: Option<String>,
+    /// Client library of the connection and its version (CLIENT SETINFO)
+    pub lib_name: Option<String>,
+    pub lib_ver: Option<String>,
+    /// Excluded from client eviction (CLIENT NO-EVICT)
+    pub no_evict: bool,
+    /// Reads don't update the LRU/LFU metadata of the keys (CLIENT NO-TOUCH)
+    pub no_touch: bool,
+    /// Bytes of the replies waiting to be written to the connection
+    pub output_buffer: usize,
+    /// When the write of the pending replies started, in milliseconds since the epoch
+    pub write_started_ms: Option<u64>,
+}
+
+impl ClientInfo {
+    /// Format the client the same way redis does in CLIENT LIST
+    pub fn to_line(&self) -> String {
+        format!(
+            "id={} addr={} name={} flags={} omem={} lib-name={} lib-ver={}",
+            self.id,
+            self.addr,
+            self.name.as_deref().unwrap_or_default(),
+            self.flags(),
+            self.output_buffer,
+            self.lib_name.as_deref().unwrap_or_default(),
+            self.lib_ver.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Whether the client isn't reading its replies, that are blocked in a write
+    fn is_slow(&self) -> bool {
+        self.write_started_ms.map_or(false, |started| {
+            now_ms().saturating_sub(started) > SLOW_WRITE_MS
+        })
+    }
+
+    /// Client flags using the same letters as redis, `N` when there are no flags
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        if self.no_evict {
+            flags.push('e');
+        }
+        if self.no_touch {
+            flags.push('T');
+        }
+        if self.is_slow() {
+            flags.push('W');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+/// Keeps track of all the connected clients
+#[derive(Default)]
+pub struct Registry {
+    next_id: u64,
+    clients: BTreeMap<u64, ClientInfo>,
+    /// Process writing the replies of each client, killed to disconnect the client
+    writers: BTreeMap<u64, Process<()>>,
+    /// Max output buffer of all the clients together, 0 means no limit
+    maxmemory_clients: usize,
+    maxclients_per_ip: usize,
+    max_connection_rate: usize,
+    /// Connections accepted from each IP address in the current second
+    connection_rate: HashMap<IpAddr, (u64, usize)>,
+    /// Open connections of each IP address, counted when they are admitted
+    connections: HashMap<IpAddr, usize>,
+    /// IP address of each client counted in the open connections
+    client_ips: BTreeMap<u64, IpAddr>,
+}
+
+impl Registry {
+    /// Disconnect the clients with the biggest output buffers until the output of all the
+    /// clients is under maxmemory-clients, clients with NO-EVICT are never disconnected
+    fn evict_clients(&mut self) {
+        if self.maxmemory_clients == 0 {
+            return;
+        }
+        while self
+            .clients
+            .values()
+            .map(|client| client.output_buffer)
+            .sum::<usize>()
+            > self.maxmemory_clients
+        {
+            let biggest = self
+                .clients
+                .values()
+                .filter(|client| !client.no_evict && client.output_buffer > 0)
+                .max_by_key(|client| client.output_buffer)
+                .map(|client| client.id);
+            let id = match biggest {
+                Some(id) => id,
+                None => break,
+            };
+            debug!("Evicting client {id}, maxmemory-clients reached");
+            if let Some(writer) = self.writers.remove(&id) {
+                writer.kill();
+            }
+            self.clients.remove(&id);
+        }
+    }
+}
+
+#[abstract_process(visibility = pub)]
+impl Registry {
+    #[init]
+    fn init(_: ProcessRef<Self>, config: Config) -> Self {
+        Self {
+            maxmemory_clients: config.maxmemory_clients,
+            maxclients_per_ip: config.maxclients_per_ip,
+            max_connection_rate: config.max_connection_rate,
+            ..Self::default()
+        }
+    }
+
+    /// Check the per-IP limits before accepting a new connection from the address. The
+    /// admitted connection is counted until its client is deregistered
+    #[handle_request]
+    fn admit(&mut self, ip: IpAddr) -> bool {
+        if self.maxclients_per_ip > 0
+            && self.connections.get(&ip).copied().unwrap_or_default() >= self.maxclients_per_ip
+        {
+            return false;
+        }
+        if self.max_connection_rate > 0 {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            self.connection_rate.retain(|_, (second, _)| *second == now);
+            let (_, count) = self.connection_rate.entry(ip).or_insert((now, 0));
+            if *count >= self.max_connection_rate {
+                return false;
+            }
+            *count += 1;
+        }
+        if self.maxclients_per_ip > 0 {
+            *self.connections.entry(ip).or_default() += 1;
+        }
+        true
+    }
+
+    /// Register a new client, returning the unique id assigned to it
+    #[handle_request]
+    fn register(&mut self, addr: String) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        // The connections of the listeners are all admitted, when the clients per IP are
+        // limited
+        if self.maxclients_per_ip > 0 {
+            if let Ok(addr) = addr.parse::<SocketAddr>() {
+                self.client_ips.insert(id, addr.ip());
+            }
+        }
+        self.clients.insert(
+            id,
+            ClientInfo {
+                id,
+                addr,
+                name: None,
+                lib_name: None,
+                lib_ver: None,
+                no_evict: false,
+                no_touch: false,
+                output_buffer: 0,
+                write_started_ms: None,
+            },
+        );
+        id
+    }
+
+    #[handle_request]
+    fn deregister(&mut self, id: u64) {
+        self.clients.remove(&id);
+        self.writers.remove(&id);
+        if let Some(ip) = self.client_ips.remove(&id) {
+            if let Some(count) = self.connections.get_mut(&ip) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.connections.remove(&ip);
+                }
+            }
+        }
+    }
+
+    #[handle_request]
+    fn set_writer(&mut self, id: u64, writer: Process<()>) {
+        self.writers.insert(id, writer);
+    }
+
+    /// Update the bytes waiting to be written to a client (maxmemory-clients), a write
+    /// starts when there are bytes and finishes when they are cleared
+    #[handle_request]
+    fn set_output_buffer(&mut self, id: u64, size: usize) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.output_buffer = size;
+            client.write_started_ms = (size > 0).then(now_ms);
+        }
+        self.evict_clients();
+    }
+
+    #[handle_request]
+    fn set_name(&mut self, id: u64, name: Option<String>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.name = name;
+        }
+    }
+
+    #[handle_request]
+    fn set_lib(&mut self, id: u64, lib_name: Option<String>, lib_ver: Option<String>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.lib_name = lib_name;
+            client.lib_ver = lib_ver;
+        }
+    }
+
+    #[handle_request]
+    fn set_flags(&mut self, id: u64, no_evict: bool, no_touch: bool) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.no_evict = no_evict;
+            client.no_touch = no_touch;
+        }
+    }
+
+    #[handle_request]
+    fn list(&mut self) -> Vec<ClientInfo> {
+        self.clients.values().cloned().collect()
+    }
+
+    #[handle_request]
+    fn info(&mut self, id: u64) -> Option<ClientInfo> {
+        self.clients.get(&id).cloned()
+    }
+}
@@ -0,0 +1,129 @@
+//! Connection to a running server, used by the command line tools like `moonis bench`
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use lunatic::net::TcpStream;
+
+use crate::types::{BulkString, RespValue};
+
+/// Command encoded as an array of bulk strings, like redis clients send them
+pub fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    request
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read a whole reply of the server
+pub fn read_reply(reader: &mut impl BufRead) -> io::Result<RespValue> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let content = line
+        .get(1..)
+        .and_then(|content| content.strip_suffix(b"\r\n"))
+        .ok_or_else(|| invalid("reply line without \\r\\n"))?;
+    let text = || String::from_utf8_lossy(content).into_owned();
+    let length = || {
+        text()
+            .parse::<i64>()
+            .map_err(|_| invalid("invalid length in reply"))
+    };
+    match line[0] {
+        b'+' => Ok(RespValue::SimpleString(text())),
+        b'-' => {
+            let text = text();
+            Ok(match text.split_once(' ') {
+                Some((prefix, description)) => {
+                    RespValue::Error(prefix.into(), Some(description.into()))
+                }
+                None => RespValue::Error(text, None),
+            })
+        }
+        b':' => Ok(RespValue::Integer(length()?)),
+        b'$' => match length()? {
+            length if length < 0 => Ok(RespValue::Null),
+            length => {
+                let mut data = vec![0; length as usize + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(length as usize);
+                Ok(RespValue::BulkString(BulkString(data.into())))
+            }
+        },
+        b'*' => match length()? {
+            length if length < 0 => Ok(RespValue::Null),
+            length => {
+                let mut values = VecDeque::with_capacity(length as usize);
+                for _ in 0..length {
+                    values.push_back(read_reply(reader)?);
+                }
+                Ok(RespValue::Array(values))
+            }
+        },
+        _ => Err(invalid("unknown reply type")),
+    }
+}
+
+/// Error of an unexpected reply
+pub fn reply_error(reply: RespValue) -> io::Error {
+    let message = match reply {
+        RespValue::Error(prefix, Some(description)) => format!("{prefix} {description}"),
+        RespValue::Error(prefix, None) => prefix,
+        reply => format!("unexpected reply: {reply:?}"),
+    };
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+fn expect_ok(reply: RespValue) -> io::Result<()> {
+    match reply {
+        RespValue::SimpleString(_) => Ok(()),
+        reply => Err(reply_error(reply)),
+    }
+}
+
+pub struct RemoteClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl RemoteClient {
+    pub fn connect(host: &str, port: u16) -> io::Result<Self> {
+        let stream = TcpStream::connect(format!("{host}:{port}"))?;
+        let reader = BufReader::new(stream.clone());
+        Ok(Self { stream, reader })
+    }
+
+    /// Write requests without waiting for the replies, to pipeline them
+    pub fn send(&mut self, requests: &[u8]) -> io::Result<()> {
+        self.stream.write_all(requests)
+    }
+
+    pub fn read_reply(&mut self) -> io::Result<RespValue> {
+        read_reply(&mut self.reader)
+    }
+
+    /// Send a command and wait for its reply
+    pub fn call(&mut self, args: &[&[u8]]) -> io::Result<RespValue> {
+        self.send(&command(args))?;
+        self.read_reply()
+    }
+
+    /// Authenticate when there is a password, and select the database
+    pub fn login(&mut self, password: Option<&str>, db: usize) -> io::Result<()> {
+        if let Some(password) = password {
+            expect_ok(self.call(&[b"AUTH", password.as_bytes()])?)?;
+        }
+        expect_ok(self.call(&[b"SELECT", db.to_string().as_bytes()])?)
+    }
+}
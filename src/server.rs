@@ -0,0 +1,530 @@
+use std::{fs, net::SocketAddr};
+
+use lunatic::{
+    net::{TcpListener, TlsListener},
+    process::{ProcessRef, StartProcess},
+    Mailbox, Process, ProcessConfig, Tag,
+};
+use lunatic_log::{debug, info};
+
+use crate::{
+    acl::{self, Acl},
+    audit::Audit,
+    cdc::Cdc,
+    chaos,
+    client::ClientProcess,
+    config::{Config, EvictionPolicy, OutputBufferLimits, TlsConfig, Webhook},
+    connection::Connection,
+    http, memcached,
+    metrics::{self, Metrics},
+    registry::{Registry, RegistryHandler},
+    shards::{replica_name, shard_name, Shards},
+    storage::StorageSupervisor,
+    telemetry::Telemetry,
+    watchdog, webhook, websocket,
+};
+
+/// Moonis server running in the processes of the application, started with
+/// `Server::builder()`. The server processes are linked to the process starting it
+pub struct Server {
+    config: Config,
+    local_addrs: Vec<SocketAddr>,
+}
+
+/// Server configuration, the defaults are the same as the command line ones
+pub struct ServerBuilder {
+    config: Config,
+    /// Invalid settings, the first one is returned by `start`
+    errors: Vec<String>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                bind: vec!["127.0.0.1".into()],
+                port: 6142,
+                protected_mode: true,
+                maxmemory_policy: EvictionPolicy::NoEviction,
+                maxmemory_samples: 5,
+                shards: 1,
+                databases: 16,
+                acceptors: 1,
+                client_max_memory: 5_000_000,
+                pipeline_max_commands: 1024,
+                pipeline_max_reply_bytes: 1024 * 1024,
+                read_buffer_size: 1024,
+                read_buffer_max: 64 * 1024,
+                compaction_interval: 60,
+                client_output_buffer_limit: OutputBufferLimits::default(),
+                ..Config::default()
+            },
+            errors: vec![],
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Replace the whole configuration
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Addresses to listen on, binding explicitly disables protected mode like `--address`
+    pub fn bind<S: Into<String>>(mut self, addrs: impl IntoIterator<Item = S>) -> Self {
+        self.config.bind = addrs.into_iter().map(Into::into).collect();
+        self.config.protected_mode = false;
+        self
+    }
+
+    /// Port to listen on, 0 to use any free port
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Listen for TLS connections on the port, with the PEM certificate chain and key files
+    pub fn tls(mut self, port: u16, cert_file: &str, key_file: &str) -> Self {
+        self.config.tls_port = port;
+        self.config.tls = Some(TlsConfig {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+        });
+        self
+    }
+
+    /// Also listen for memcached clients on the port, they use the first database
+    pub fn memcached(mut self, port: u16) -> Self {
+        self.config.memcached_port = Some(port);
+        self
+    }
+
+    /// Also serve the keys and the commands over HTTP on the port
+    pub fn http(mut self, port: u16) -> Self {
+        self.config.http_port = Some(port);
+        self
+    }
+
+    /// Also accept WebSocket connections on the port, for the browser apps
+    pub fn websocket(mut self, port: u16) -> Self {
+        self.config.websocket_port = Some(port);
+        self
+    }
+
+    /// Allow the pages of the origin to open WebSocket connections, ie.
+    /// `https://app.example.com`
+    pub fn websocket_origin(mut self, origin: &str) -> Self {
+        self.config.websocket_origins.push(origin.into());
+        self
+    }
+
+    /// Append the writes of every database to the file as JSON lines
+    pub fn cdc_log(mut self, path: &str) -> Self {
+        self.config.cdc_log = Some(path.into());
+        self
+    }
+
+    /// Send the writes of every database as `CdcEvent` messages to the process registered
+    /// by the application with the name
+    pub fn cdc_consumer(mut self, name: &str) -> Self {
+        self.config.cdc_consumer = Some(name.into());
+        self
+    }
+
+    /// POST the writes to the keys matching the pattern to the URL, the server isn't started
+    /// if it isn't a plain HTTP URL
+    pub fn webhook(mut self, pattern: &str, url: &str) -> Self {
+        match format!("{pattern} {url}").parse::<Webhook>() {
+            Ok(webhook) => self.config.webhooks.push(webhook),
+            Err(err) => self.errors.push(err),
+        }
+        self
+    }
+
+    /// Export the spans of the traced commands to the OTLP/HTTP collector URL, the server
+    /// isn't started if it isn't a plain HTTP URL. The commands not in a trace are sampled
+    /// with the ratio
+    pub fn otlp(mut self, endpoint: &str, sample_ratio: f64) -> Self {
+        match endpoint.parse() {
+            Ok(endpoint) => self.config.otlp_endpoint = Some(endpoint),
+            Err(err) => self.errors.push(err),
+        }
+        self.config.trace_sample_ratio = sample_ratio;
+        self
+    }
+
+    pub fn requirepass(mut self, password: &str) -> Self {
+        self.config.requirepass = Some(password.into());
+        self
+    }
+
+    pub fn protected_mode(mut self, enabled: bool) -> Self {
+        self.config.protected_mode = enabled;
+        self
+    }
+
+    /// Max memory used by the keys of each database, 0 means no limit
+    pub fn maxmemory(mut self, maxmemory: usize, policy: EvictionPolicy) -> Self {
+        self.config.maxmemory = maxmemory;
+        self.config.maxmemory_policy = policy;
+        self
+    }
+
+    pub fn databases(mut self, databases: usize) -> Self {
+        self.config.databases = databases.max(1);
+        self
+    }
+
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.config.shards = shards.max(1);
+        self
+    }
+
+    /// Limits of the pipelined commands and their replies in flight on each connection, 0
+    /// means no limit
+    pub fn pipeline_limits(mut self, max_commands: usize, max_reply_bytes: usize) -> Self {
+        self.config.pipeline_max_commands = max_commands;
+        self.config.pipeline_max_reply_bytes = max_reply_bytes;
+        self
+    }
+
+    /// Bytes read from each connection at a time: at first and at most once the reads grow
+    pub fn read_buffer(mut self, size: usize, max: usize) -> Self {
+        self.config.read_buffer_size = size;
+        self.config.read_buffer_max = max;
+        self
+    }
+
+    pub fn storage_replicas(mut self, replicas: usize) -> Self {
+        self.config.storage_replicas = replicas;
+        self
+    }
+
+    /// Seconds between the compactions of the keyspace, 0 disables them
+    pub fn compaction_interval(mut self, interval: u64) -> Self {
+        self.config.compaction_interval = interval;
+        self
+    }
+
+    /// Keep the deleted keys for `retention` seconds so they can be restored with UNDELETE,
+    /// 0 disables the trash
+    pub fn trash_retention(mut self, retention: u64) -> Self {
+        self.config.trash_retention = retention;
+        self
+    }
+
+    /// Ping the storage processes every `interval` seconds, reporting the ones not replying
+    /// within `deadline_ms`. With `restart` the processes stalled for several pings are
+    /// restarted, when there are storage replicas to recover the keys from
+    pub fn watchdog(mut self, interval: u64, deadline_ms: u64, restart: bool) -> Self {
+        self.config.watchdog_interval = interval;
+        self.config.watchdog_deadline_ms = deadline_ms;
+        self.config.watchdog_restart = restart;
+        self
+    }
+
+    /// Don't accept connections, the storage is only used through `Server::storage`
+    pub fn no_listeners(mut self) -> Self {
+        self.config.bind.clear();
+        self.config.tls = None;
+        self
+    }
+
+    /// Start the storage and the listeners, unless a setting is invalid or the aclfile
+    /// can't be loaded
+    pub fn start(self) -> Result<Server, String> {
+        if let Some(err) = self.errors.into_iter().next() {
+            return Err(err);
+        }
+        if let Some(path) = &self.config.aclfile {
+            acl::check_aclfile(path)?;
+        }
+        Ok(Server::start(self.config))
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    fn start(mut config: Config) -> Self {
+        // Each database runs in its own processes, so load in one can't stall the others
+        for db in 0..config.databases {
+            for shard in 0..config.shards {
+                // Replicas don't evict, the primary sends them the keys it evicts
+                let replica_config = Config {
+                    maxmemory: 0,
+                    ..config.clone()
+                };
+                let replicas: Vec<String> = (0..config.storage_replicas)
+                    .map(|replica| replica_name(db, shard, replica))
+                    .collect();
+                let primary = shard_name(db, shard);
+                for replica in &replicas {
+                    let args = (replica_config.clone(), db, Some(primary.clone()), vec![]);
+                    StorageSupervisor::start_link((args, replica.clone()), None);
+                }
+                let args = (config.clone(), db, None, replicas);
+                StorageSupervisor::start_link((args, primary), None);
+            }
+        }
+        Registry::start_link(config.clone(), Some("registry"));
+        Acl::start_link(
+            (config.requirepass.clone(), config.aclfile.clone()),
+            Some("acl"),
+        );
+
+        if let Some(path) = config.audit_log.clone() {
+            Audit::start_link(path, Some("audit"));
+        }
+        if config.cdc_enabled() {
+            let notifiers = config
+                .webhooks
+                .iter()
+                .map(|hook| {
+                    let notifier = Process::spawn_link(hook.clone(), webhook::notify);
+                    (hook.pattern.clone(), notifier)
+                })
+                .collect();
+            let args = (
+                config.cdc_log.clone(),
+                config.cdc_consumer.clone(),
+                notifiers,
+            );
+            Cdc::start_link(args, Some("cdc"));
+        }
+        Metrics::start_link((), Some("metrics"));
+        if let Some(endpoint) = config.otlp_endpoint.clone() {
+            Telemetry::start_link(endpoint, Some("telemetry"));
+        }
+
+        if config.watchdog_interval > 0 {
+            Process::spawn_link(config.clone(), |config, _: Mailbox<()>| {
+                watchdog::watch(config)
+            });
+        }
+        if config.chaos.kill_interval_secs > 0 {
+            Process::spawn_link(config.clone(), |config, _: Mailbox<()>| {
+                chaos::kill_storage(config)
+            });
+        }
+
+        // Each listener has its own accept loop, all of them spawn the clients the same way.
+        // The ports assigned by the system are known before the next listener is started,
+        // so the clients of all of them see the same ones
+        let mut local_addrs = vec![];
+        if !config.tls_only {
+            local_addrs = listen(&config.bind, config.port, |addr, bound| {
+                Process::spawn_link(
+                    (addr, bound, config.clone()),
+                    |(addr, bound, config), _: Mailbox<()>| accept_tcp(addr, bound, config),
+                );
+            });
+            config.port = local_addrs.first().map_or(config.port, SocketAddr::port);
+        }
+        if let Some(tls) = config.tls.clone() {
+            let addrs = listen(&config.bind, config.tls_port, |addr, bound| {
+                Process::spawn_link(
+                    (addr, bound, tls.clone(), config.clone()),
+                    |(addr, bound, tls, config), _: Mailbox<()>| {
+                        accept_tls(addr, bound, tls, config)
+                    },
+                );
+            });
+            config.tls_port = addrs.first().map_or(config.tls_port, SocketAddr::port);
+        }
+        if let Some(port) = config.memcached_port {
+            let addrs = listen(&config.bind, port, |addr, bound| {
+                Process::spawn_link(
+                    (addr, bound, config.clone()),
+                    |(addr, bound, config), _: Mailbox<()>| memcached::serve(addr, bound, config),
+                );
+            });
+            config.memcached_port = addrs.first().map(SocketAddr::port);
+        }
+        if let Some(port) = config.http_port {
+            let addrs = listen(&config.bind, port, |addr, bound| {
+                Process::spawn_link(
+                    (addr, bound, config.clone()),
+                    |(addr, bound, config), _: Mailbox<()>| http::serve(addr, bound, config),
+                );
+            });
+            config.http_port = addrs.first().map(SocketAddr::port);
+        }
+        if let Some(port) = config.websocket_port {
+            let addrs = listen(&config.bind, port, |addr, bound| {
+                Process::spawn_link(
+                    (addr, bound, config.clone()),
+                    |(addr, bound, config), _: Mailbox<()>| websocket::serve(addr, bound, config),
+                );
+            });
+            config.websocket_port = addrs.first().map(SocketAddr::port);
+        }
+        // Only on the first address, the metrics aren't served to the clients
+        if let Some(port) = config.metrics_port {
+            let addrs = listen(
+                config.bind.get(..1).unwrap_or_default(),
+                port,
+                |addr, bound| {
+                    Process::spawn_link(
+                        (addr, bound, config.clone()),
+                        |(addr, bound, config), _: Mailbox<()>| metrics::serve(addr, bound, config),
+                    );
+                },
+            );
+            config.metrics_port = addrs.first().map(SocketAddr::port);
+        }
+        Self {
+            config,
+            local_addrs,
+        }
+    }
+
+    /// Configuration of the server, with the ports assigned by the system for the port 0
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Addresses the RESP listeners are bound to, one for each address of `bind`, all of them
+    /// on the same port. Empty when only TLS connections are accepted
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Storage of a database, the same one used by the connected clients
+    pub fn storage(&self, db: usize) -> Option<Shards> {
+        (db < self.config.databases)
+            .then(|| Shards::new(db, self.config.shards, self.config.storage_replicas))
+    }
+}
+
+pub(crate) fn client_config(config: &Config) -> ProcessConfig {
+    let mut client_conf = ProcessConfig::new().unwrap();
+    client_conf.set_max_memory(config.client_max_memory as u64);
+    client_conf.set_can_spawn_processes(true);
+    client_conf
+}
+
+/// Processes spawning the clients of the accepted connections, the accept loop only hands
+/// the connections off so a burst of new connections isn't serialized behind one loop
+fn spawn_acceptors(config: &Config) -> Vec<Process<(Connection, String)>> {
+    (0..config.acceptors)
+        .map(|_| {
+            Process::spawn_link(
+                config.clone(),
+                |config, mailbox: Mailbox<(Connection, String)>| {
+                    let client_conf = client_config(&config);
+                    loop {
+                        let (connection, peer) = mailbox.receive();
+                        ClientProcess::start_config(
+                            (Some(connection), peer, config.clone()),
+                            None,
+                            &client_conf,
+                        );
+                    }
+                },
+            )
+        })
+        .collect()
+}
+
+/// Check the per-IP limits of a new connection, without limits there is no registry to ask
+pub(crate) fn admitted(registry: Option<&ProcessRef<Registry>>, peer: SocketAddr) -> bool {
+    let admitted = registry.map_or(true, |registry| registry.admit(peer.ip()));
+    if !admitted {
+        debug!("Rejecting connection from {peer}, per-IP limit reached");
+    }
+    admitted
+}
+
+/// Registry checking the per-IP limits, when there are limits
+pub(crate) fn connection_limiter(config: &Config) -> Option<ProcessRef<Registry>> {
+    (config.maxclients_per_ip > 0 || config.max_connection_rate > 0)
+        .then(|| ProcessRef::<Registry>::lookup("registry").unwrap())
+}
+
+/// Where the listener reports the address it's bound to, to the process starting the server
+pub(crate) type Bound = (Process<SocketAddr>, Tag);
+
+/// Start the listener on each address and wait until it's bound. The first address gets
+/// the port, with 0 the one assigned by the system is used for the other addresses too
+fn listen(bind: &[String], mut port: u16, mut spawn: impl FnMut(String, Bound)) -> Vec<SocketAddr> {
+    let mailbox: Mailbox<SocketAddr> = unsafe { Mailbox::new() };
+    bind.iter()
+        .map(|addr| {
+            let tag = Tag::new();
+            spawn(socket_addr(addr, port), (Process::this(), tag));
+            // A listener failing to bind panics, and the linked process starting the server
+            // with it
+            let local_addr = mailbox.tag_receive(&[tag]);
+            port = local_addr.port();
+            local_addr
+        })
+        .collect()
+}
+
+/// Bind the listener to the address, and report the address it's bound to
+pub(crate) fn bind(addr: String, (server, tag): Bound) -> TcpListener {
+    let listener = TcpListener::bind(addr).unwrap();
+    server.tag_send(tag, listener.local_addr().unwrap());
+    listener
+}
+
+fn accept_tcp(addr: String, bound: Bound, mut config: Config) {
+    let listener = bind(addr, bound);
+    let addr = listener.local_addr().unwrap();
+    info!("Listening to: {addr}");
+    // With port 0 the port is assigned by the system
+    config.port = addr.port();
+    let acceptors = spawn_acceptors(&config);
+    let registry = connection_limiter(&config);
+
+    for next in (0..acceptors.len()).cycle() {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(_) => break,
+        };
+        if !admitted(registry.as_ref(), peer) {
+            continue;
+        }
+        acceptors[next].send((Connection::Tcp(stream), peer.to_string()));
+    }
+}
+
+fn accept_tls(addr: String, (server, tag): Bound, tls: TlsConfig, mut config: Config) {
+    let read = |path: &str| {
+        fs::read_to_string(path).unwrap_or_else(|err| panic!("Can't read {path}: {err}"))
+    };
+    let (cert, key) = (read(&tls.cert_file), read(&tls.key_file));
+
+    let listener = TlsListener::bind(addr.as_str(), cert, key).unwrap();
+    let addr = listener.local_addr().unwrap();
+    server.tag_send(tag, addr);
+    info!("Listening to TLS connections: {addr}");
+    config.tls_port = addr.port();
+    let acceptors = spawn_acceptors(&config);
+    let registry = connection_limiter(&config);
+
+    for next in (0..acceptors.len()).cycle() {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(_) => break,
+        };
+        if !admitted(registry.as_ref(), peer) {
+            continue;
+        }
+        acceptors[next].send((Connection::Tls(stream), peer.to_string()));
+    }
+}
+
+/// Address to bind with the port, IPv6 addresses can be bracketed or not (ie. `[::1]`)
+fn socket_addr(addr: &str, port: u16) -> String {
+    let addr = addr.trim_start_matches('[').trim_end_matches(']');
+    if addr.contains(':') {
+        format!("[{addr}]:{port}")
+    } else {
+        format!("{addr}:{port}")
+    }
+}
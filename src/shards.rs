@@ -0,0 +1,393 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    iter,
+    time::Duration,
+};
+
+use lunatic::{process::ProcessRef, sleep, Mailbox, MailboxResult, Process, Tag};
+
+use crate::{
+    dict::Cursor,
+    storage::{MemoryStats, Storage, StorageHandler, Waiter},
+    timeseries::{Aggregator, Filter, Sample},
+    types::{Consistency, RedisCmd, RedisKey, RedisValue, RespValue},
+};
+
+/// Time a read waits for the replica to apply the writes of the client with read-your-writes
+/// consistency, the primary serves the read after it
+const READ_YOUR_WRITES_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Entries sized by each request of MEMORY BIGKEYS, the shards serve the other clients
+/// between the requests
+const BIGKEYS_STEP: usize = 1000;
+
+/// Keys scanned by each request of KEYS to a shard
+const KEYS_PAGE: usize = 1000;
+
+/// Lookups of a storage process being restarted by its supervisor, `LOOKUP_DELAY` apart
+const LOOKUP_ATTEMPTS: usize = 10;
+const LOOKUP_DELAY: Duration = Duration::from_millis(10);
+
+/// Name of the Storage process of a shard of a database
+pub fn shard_name(db: usize, shard: usize) -> String {
+    format!("storage-{db}-{shard}")
+}
+
+/// Name of a read-only replica of the Storage process of a shard
+pub fn replica_name(db: usize, shard: usize, replica: usize) -> String {
+    format!("storage-{db}-{shard}-replica-{replica}")
+}
+
+/// The keyspace partitioned across several Storage processes, each key lives in the shard
+/// selected by its hash and multi-key commands are sent to every shard involved
+pub struct Shards {
+    shards: Vec<String>,
+    /// Read-only replicas of each shard
+    replicas: Vec<Vec<String>>,
+    /// Round-robin position used to choose the replica serving the next read
+    next_replica: Cell<usize>,
+    /// Processes serving the reads when there are replicas
+    consistency: Consistency,
+    /// Shards the client may have written to since its last read from their replicas
+    written: Vec<Cell<bool>>,
+    /// Processes found by name since the last `forget`. A process restarted by its
+    /// supervisor is a new process, the requests to the stopped one are never answered
+    processes: RefCell<BTreeMap<String, ProcessRef<Storage>>>,
+}
+
+impl Shards {
+    pub fn new(db: usize, count: usize, replicas: usize) -> Self {
+        Self {
+            shards: (0..count).map(|shard| shard_name(db, shard)).collect(),
+            replicas: (0..count)
+                .map(|shard| {
+                    (0..replicas)
+                        .map(|replica| replica_name(db, shard, replica))
+                        .collect()
+                })
+                .collect(),
+            next_replica: Cell::new(0),
+            consistency: Consistency::default(),
+            written: (0..count).map(|_| Cell::new(false)).collect(),
+            processes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    /// Look up the processes again, the clients forget them before each of their requests
+    /// so the processes restarted meanwhile are found
+    pub fn forget(&self) {
+        self.processes.borrow_mut().clear();
+    }
+
+    /// Storage process registered with the name, the lookup is attempted again while the
+    /// process is restarted. The registry of lunatic 0.12 is local to each node, a shard
+    /// started on another node couldn't be found by its name, so all the shards run on the
+    /// node of the server
+    fn lookup(&self, name: &str, attempts: usize) -> Option<ProcessRef<Storage>> {
+        if let Some(process) = self.processes.borrow().get(name) {
+            return Some(process.clone());
+        }
+        for attempt in 1..=attempts {
+            if let Some(process) = ProcessRef::<Storage>::lookup(name) {
+                self.processes
+                    .borrow_mut()
+                    .insert(name.into(), process.clone());
+                return Some(process);
+            }
+            if attempt < attempts {
+                sleep(LOOKUP_DELAY);
+            }
+        }
+        None
+    }
+
+    /// Storage process of a shard checked with `available`
+    fn process(&self, name: &str) -> ProcessRef<Storage> {
+        self.lookup(name, LOOKUP_ATTEMPTS)
+            .unwrap_or_else(|| panic!("Storage process {name} isn't registered"))
+    }
+
+    /// Whether the primaries of the shards of the keys are running, of all the shards
+    /// without keys. The commands are rejected while a shard is restarted
+    pub fn available(&self, keys: &[RedisKey]) -> bool {
+        let shards: Vec<usize> = match keys {
+            [] => (0..self.shards.len()).collect(),
+            keys => keys.iter().map(|key| self.index(key)).collect(),
+        };
+        shards
+            .into_iter()
+            .all(|shard| self.lookup(&self.shards[shard], LOOKUP_ATTEMPTS).is_some())
+    }
+
+    /// Primary process used for the writes of the shard, and the requests that aren't
+    /// reads. The reads from its replicas wait for them with read-your-writes consistency
+    fn primary(&self, shard: usize) -> ProcessRef<Storage> {
+        self.written[shard].set(true);
+        self.process(&self.shards[shard])
+    }
+
+    fn all(&self) -> impl Iterator<Item = ProcessRef<Storage>> + '_ {
+        self.shards.iter().map(|name| self.process(name))
+    }
+
+    /// The primaries and the replicas of all the shards
+    fn all_with_replicas(&self) -> impl Iterator<Item = ProcessRef<Storage>> + '_ {
+        let replicas = self.replicas.iter().flatten();
+        let replicas = replicas.filter_map(|name| self.lookup(name, 1));
+        self.all().chain(replicas)
+    }
+
+    fn index(&self, key: &RedisKey) -> usize {
+        // DefaultHasher uses fixed keys, so every client selects the same shard for a key
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    /// Storage process owning the key
+    pub fn shard(&self, key: &RedisKey) -> ProcessRef<Storage> {
+        self.primary(self.index(key))
+    }
+
+    /// Process serving the reads of a shard, its replicas are used in turns when there are
+    /// replicas and the consistency allows it. Reads served by replicas don't update the
+    /// access metadata used by eviction
+    fn reader(&self, shard: usize) -> ProcessRef<Storage> {
+        let replicas = &self.replicas[shard];
+        let primary = &self.shards[shard];
+        if replicas.is_empty() || self.consistency == Consistency::Primary {
+            return self.process(primary);
+        }
+        let next = self.next_replica.get();
+        self.next_replica.set(next.wrapping_add(1));
+        // The primary serves the reads while the replica is restarted
+        let replica = match self.lookup(&replicas[next % replicas.len()], 1) {
+            Some(replica) => replica,
+            None => return self.process(primary),
+        };
+        if self.consistency == Consistency::ReadYourWrites && self.written[shard].get() {
+            if !self.caught_up(shard, &replica) {
+                return self.process(primary);
+            }
+            self.written[shard].set(false);
+        }
+        replica
+    }
+
+    /// Wait for the replica to apply the writes sent by the primary so far, returns false
+    /// after `READ_YOUR_WRITES_TIMEOUT`
+    fn caught_up(&self, shard: usize, replica: &ProcessRef<Storage>) -> bool {
+        let offset = self.process(&self.shards[shard]).replication_offset();
+        let tag = Tag::new();
+        if replica.wait_offset(offset, Process::this(), tag) {
+            return true;
+        }
+        let mailbox: Mailbox<()> = unsafe { Mailbox::new() };
+        match mailbox.tag_receive_timeout(&[tag], READ_YOUR_WRITES_TIMEOUT) {
+            MailboxResult::Message(()) => true,
+            // The wake up was sent meanwhile, it's received so it doesn't stay in the mailbox
+            _ if !replica.unwait_offset(tag) => {
+                mailbox.tag_receive(&[tag]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Process used to read the key, writes must use `shard`
+    pub fn read_shard(&self, key: &RedisKey) -> ProcessRef<Storage> {
+        self.reader(self.index(key))
+    }
+
+    /// Group the keys by shard, keeping the position of each key in the original list
+    fn group(&self, keys: &[RedisKey]) -> BTreeMap<usize, (Vec<usize>, Vec<RedisKey>)> {
+        let mut groups: BTreeMap<usize, (Vec<usize>, Vec<RedisKey>)> = BTreeMap::new();
+        for (position, key) in keys.iter().enumerate() {
+            let (positions, keys) = groups.entry(self.index(key)).or_default();
+            positions.push(position);
+            keys.push(key.clone());
+        }
+        groups
+    }
+
+    pub fn del(&self, keys: &[RedisKey]) -> i64 {
+        self.group(keys)
+            .into_iter()
+            .map(|(shard, (_, keys))| self.primary(shard).del(keys))
+            .sum()
+    }
+
+    /// Park the calling client until one of the keys is written or the timeout expires,
+    /// returns false on timeout. Blocking commands retry each time their client is woken,
+    /// the clients blocked on a key are woken one at a time in the order they blocked
+    pub fn block(&self, keys: &[RedisKey], client: u64, timeout: Option<Duration>) -> bool {
+        let tag = Tag::new();
+        let waiter: Waiter = (client, Process::this(), tag);
+        let mut parked = vec![];
+        let unblock = |parked: &[usize]| {
+            for shard in parked {
+                self.primary(*shard).unblock(client);
+            }
+        };
+        for (shard, (_, keys)) in self.group(keys) {
+            if !self.primary(shard).block(keys, waiter.clone()) {
+                // A key was written meanwhile, the command can be retried now
+                unblock(&parked);
+                return true;
+            }
+            parked.push(shard);
+        }
+        // Only the message with the tag is received, the other messages stay in the mailbox
+        let mailbox: Mailbox<()> = unsafe { Mailbox::new() };
+        let woken = match timeout {
+            Some(timeout) => matches!(
+                mailbox.tag_receive_timeout(&[tag], timeout),
+                MailboxResult::Message(())
+            ),
+            None => {
+                mailbox.tag_receive(&[tag]);
+                true
+            }
+        };
+        // The shard waking the client already forgot it, the others still have it
+        unblock(&parked);
+        woken
+    }
+
+    /// Values of the keys in the same order they were requested
+    pub fn mget(&self, keys: &[RedisKey], touch: bool) -> Vec<Option<RedisValue>> {
+        let mut values = vec![None; keys.len()];
+        for (shard, (positions, keys)) in self.group(keys) {
+            let shard_values = self.reader(shard).mget(keys, touch);
+            for (position, value) in positions.into_iter().zip(shard_values) {
+                values[position] = value;
+            }
+        }
+        values
+    }
+
+    /// Execute single key commands with one request per shard, the responses are returned
+    /// in the same order as the commands
+    pub fn batch(&self, cmds: Vec<RedisCmd>, touch: bool) -> Vec<RespValue> {
+        let mut groups: BTreeMap<usize, (Vec<usize>, Vec<RedisCmd>)> = BTreeMap::new();
+        let count = cmds.len();
+        for (position, cmd) in cmds.into_iter().enumerate() {
+            let (positions, cmds) = groups.entry(self.index(&cmd.keys()[0])).or_default();
+            positions.push(position);
+            cmds.push(cmd);
+        }
+        let mut responses = vec![RespValue::Null; count];
+        for (shard, (positions, cmds)) in groups {
+            let shard_responses = self.primary(shard).batch(cmds, touch);
+            for (position, response) in positions.into_iter().zip(shard_responses) {
+                responses[position] = response;
+            }
+        }
+        responses
+    }
+
+    /// Keys of all the shards matching the glob pattern, `KEYS_PAGE` keys are scanned by
+    /// each request so the keyspace isn't copied in a single message. The keys written
+    /// while scanning can be missed or listed twice, like with SCAN
+    pub fn keys<'a>(&'a self, pattern: &'a RedisKey) -> impl Iterator<Item = RedisKey> + 'a {
+        self.all().flat_map(move |shard| {
+            let mut cursor = Some(Cursor::default());
+            iter::from_fn(move || {
+                let (next, keys) = shard.keys(pattern.clone(), cursor?, KEYS_PAGE);
+                cursor = next;
+                Some(keys)
+            })
+            .flatten()
+        })
+    }
+
+    /// Range of the series matching the filters in all the shards (TS.MRANGE), sorted by key
+    pub fn ts_mrange(
+        &self,
+        from: u64,
+        to: u64,
+        aggregator: Option<Aggregator>,
+        filters: &[Filter],
+    ) -> Vec<(RedisKey, Vec<(String, String)>, Vec<Sample>)> {
+        let mut series: Vec<_> = (0..self.shards.len())
+            .flat_map(|shard| {
+                self.reader(shard)
+                    .ts_mrange(from, to, aggregator, filters.to_vec())
+            })
+            .collect();
+        series.sort_unstable_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        series
+    }
+
+    /// Keys of the database, counted by the primaries
+    pub fn dbsize(&self) -> usize {
+        self.all().map(|shard| shard.dbsize()).sum()
+    }
+
+    pub fn clear(&self) {
+        (0..self.shards.len()).for_each(|shard| self.primary(shard).clear());
+    }
+
+    pub fn purge(&self) {
+        self.all().for_each(|shard| shard.purge());
+    }
+
+    /// Keyspace hits and misses of all the shards, reads served by replicas included
+    pub fn keyspace_stats(&self) -> (u64, u64) {
+        self.all_with_replicas()
+            .map(|process| process.keyspace_stats())
+            .fold((0, 0), |(hits, misses), stats| {
+                (hits + stats.0, misses + stats.1)
+            })
+    }
+
+    pub fn reset_stats(&self) {
+        self.all_with_replicas()
+            .for_each(|process| process.reset_stats());
+    }
+
+    /// The `count` biggest keys of each type in all the shards with their sizes, biggest
+    /// first. Keys moved while scanning can be missed, like with SCAN
+    pub fn biggest_keys(&self, count: usize) -> BTreeMap<String, Vec<(RedisKey, usize)>> {
+        let mut biggest: BTreeMap<String, Vec<(RedisKey, usize)>> = BTreeMap::new();
+        for shard in self.all() {
+            let mut cursor = 0;
+            loop {
+                let (next, sizes) = shard.biggest_keys(cursor, BIGKEYS_STEP, count);
+                for (type_name, key, size) in sizes {
+                    biggest.entry(type_name).or_default().push((key, size));
+                }
+                for keys in biggest.values_mut() {
+                    keys.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+                    keys.truncate(count);
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        biggest
+    }
+
+    /// Memory stats of all the shards added together
+    pub fn memory_stats(&self) -> MemoryStats {
+        let stats = self.all().map(|shard| shard.memory_stats());
+        stats.fold(MemoryStats::default(), |total, stats| MemoryStats {
+            peak: total.peak + stats.peak,
+            used: total.used + stats.used,
+            maxmemory: total.maxmemory + stats.maxmemory,
+            keys: total.keys + stats.keys,
+            dataset: total.dataset + stats.dataset,
+            reclaimed: total.reclaimed + stats.reclaimed,
+            trash_keys: total.trash_keys + stats.trash_keys,
+            trash: total.trash + stats.trash,
+        })
+    }
+}
@@ -0,0 +1,301 @@
+//! Frequency estimation sketches stored as values: count-min sketches (CMS.*) and the
+//! HeavyKeeper top-k of RedisBloom (TOPK.*). They only depend on fixed-key hashes, even
+//! the decay of the top-k, so the replicas executing the same commands get the same sketch
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{RedisValue, RespValue};
+
+pub const TOPK_DEFAULT_WIDTH: usize = 8;
+pub const TOPK_DEFAULT_DEPTH: usize = 7;
+pub const TOPK_DEFAULT_DECAY: f64 = 0.9;
+
+/// Max increment of TOPK.INCRBY, the other item of a bucket decays once for each unit
+pub const TOPK_MAX_INCREMENT: u64 = 100_000;
+
+/// Hash of the item for a row of a sketch. DefaultHasher uses fixed keys
+fn hash(row: usize, item: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Width and depth of a count-min sketch overestimating the counts by at most `error` of
+/// the total, except with the `probability`
+pub fn dimensions(error: f64, probability: f64) -> (usize, usize) {
+    let width = (2.0 / error).ceil() as usize;
+    let depth = (probability.ln() / 0.5f64.ln()).ceil() as usize;
+    (width.max(1), depth.max(1))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    /// The counters of each row one after the other
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        Self {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    fn index(&self, row: usize, item: &[u8]) -> usize {
+        row * self.width + (hash(row, item) % self.width as u64) as usize
+    }
+
+    /// Count of the item, never under the real one
+    pub fn query(&self, item: &[u8]) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(row, item)])
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Increment the count of the item, returns its new count
+    pub fn incr_by(&mut self, item: &[u8], increment: u64) -> u64 {
+        for row in 0..self.depth {
+            let index = self.index(row, item);
+            self.counters[index] = self.counters[index].saturating_add(increment);
+        }
+        self.query(item)
+    }
+
+    /// Replace the counters by the sum of the counters of the sketches times their weights,
+    /// all of them must have the same dimensions
+    pub fn merge(&mut self, sources: &[(CountMinSketch, u64)]) -> Result<(), String> {
+        let same_dimensions =
+            |sketch: &CountMinSketch| sketch.width == self.width && sketch.depth == self.depth;
+        if !sources.iter().all(|(sketch, _)| same_dimensions(sketch)) {
+            return Err("CMS: width/depth is not equal".into());
+        }
+        let mut counters = vec![0u64; self.counters.len()];
+        for (sketch, weight) in sources {
+            for (counter, count) in counters.iter_mut().zip(&sketch.counters) {
+                *counter = counter.saturating_add(count.saturating_mul(*weight));
+            }
+        }
+        self.counters = counters;
+        Ok(())
+    }
+
+    /// Bytes used by the counters
+    pub fn size(&self) -> usize {
+        self.counters.len() * 8
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Bucket {
+    fingerprint: u64,
+    count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopK {
+    k: usize,
+    width: usize,
+    depth: usize,
+    decay: f64,
+    /// The buckets of each row one after the other
+    buckets: Vec<Bucket>,
+    /// The heavy hitters with their estimated counts, biggest first
+    top: Vec<(RedisValue, u64)>,
+}
+
+impl TopK {
+    pub fn new(k: usize, width: usize, depth: usize, decay: f64) -> Self {
+        Self {
+            k,
+            width,
+            depth,
+            decay,
+            buckets: vec![Bucket::default(); width * depth],
+            top: Vec::with_capacity(k),
+        }
+    }
+
+    /// If the item of the bucket decays, with a probability of `decay ^ count`. A hash of
+    /// the bucket is used instead of a random number, so the sketch is reproducible
+    fn decays(&self, index: usize) -> bool {
+        let bucket = &self.buckets[index];
+        let mut hasher = DefaultHasher::new();
+        (index, bucket.fingerprint, bucket.count).hash(&mut hasher);
+        let roll = hasher.finish() as f64 / u64::MAX as f64;
+        roll < self.decay.powf(bucket.count as f64)
+    }
+
+    /// Count the item `increment` times, returns the item expelled from the top-k by it
+    pub fn incr_by(&mut self, item: &RedisValue, increment: u64) -> Option<RedisValue> {
+        let fingerprint = hash(usize::MAX, &item.0);
+        let mut estimate = 0;
+        for row in 0..self.depth {
+            let index = row * self.width + (hash(row, &item.0) % self.width as u64) as usize;
+            let mut remaining = increment;
+            // Another item decays once for each unit, the bucket is taken once it's gone
+            while remaining > 0
+                && self.buckets[index].count > 0
+                && self.buckets[index].fingerprint != fingerprint
+            {
+                if self.decays(index) {
+                    self.buckets[index].count -= 1;
+                }
+                remaining -= 1;
+            }
+            let bucket = &mut self.buckets[index];
+            if bucket.count == 0 {
+                bucket.fingerprint = fingerprint;
+            }
+            if bucket.fingerprint == fingerprint {
+                bucket.count = bucket.count.saturating_add(remaining);
+                estimate = estimate.max(bucket.count);
+            }
+        }
+        if estimate == 0 {
+            return None;
+        }
+
+        let mut expelled = None;
+        match self.top.iter().position(|(top, _)| top == item) {
+            Some(position) => {
+                let count = &mut self.top[position].1;
+                *count = estimate.max(*count);
+            }
+            None if self.top.len() < self.k => self.top.push((item.clone(), estimate)),
+            None => {
+                if self.top.last().is_some_and(|(_, min)| *min < estimate) {
+                    expelled = self.top.pop().map(|(item, _)| item);
+                    self.top.push((item.clone(), estimate));
+                }
+            }
+        }
+        self.top.sort_by(|a, b| b.1.cmp(&a.1));
+        expelled
+    }
+
+    pub fn contains(&self, item: &RedisValue) -> bool {
+        self.top.iter().any(|(top, _)| top == item)
+    }
+
+    /// The heavy hitters with their estimated counts, biggest first
+    pub fn list(&self) -> &[(RedisValue, u64)] {
+        &self.top
+    }
+
+    /// Bytes used by the buckets and the items of the top-k
+    pub fn size(&self) -> usize {
+        let items: usize = self.top.iter().map(|(item, _)| item.0.len() + 8).sum();
+        self.buckets.len() * 16 + items
+    }
+}
+
+/// Reply of the counts of CMS.INCRBY and CMS.QUERY
+pub fn counts_reply(counts: Vec<u64>) -> RespValue {
+    RespValue::Array(
+        counts
+            .into_iter()
+            .map(|count| RespValue::Integer(count as i64))
+            .collect(),
+    )
+}
+
+/// Reply of TOPK.ADD and TOPK.INCRBY, the item expelled by each item or nil
+pub fn expelled_reply(expelled: Vec<Option<RedisValue>>) -> RespValue {
+    RespValue::Array(
+        expelled
+            .into_iter()
+            .map(|item| item.map_or(RespValue::Null, RespValue::BulkString))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BulkString;
+
+    fn item(item: &str) -> RedisValue {
+        BulkString(item.to_string().into())
+    }
+
+    #[test]
+    fn dimensions_bound_the_error() {
+        assert_eq!(dimensions(0.01, 0.01), (200, 7));
+        assert_eq!(dimensions(1.0, 1.0), (2, 1));
+    }
+
+    #[test]
+    fn cms_counts_are_never_under_the_real_ones() {
+        let mut sketch = CountMinSketch::new(10, 3);
+        assert_eq!(sketch.query(b"a"), 0);
+        assert_eq!(sketch.incr_by(b"a", 3), 3);
+        assert!(sketch.incr_by(b"b", 1) >= 1);
+        for i in 0..100 {
+            sketch.incr_by(format!("item:{i}").as_bytes(), 1);
+        }
+        assert!(sketch.query(b"a") >= 3);
+        assert!(sketch.query(b"b") >= 1);
+        assert_eq!(sketch.size(), 10 * 3 * 8);
+    }
+
+    #[test]
+    fn cms_merge_sums_the_weighted_counters() {
+        let mut a = CountMinSketch::new(100, 5);
+        a.incr_by(b"x", 2);
+        let mut b = CountMinSketch::new(100, 5);
+        b.incr_by(b"x", 1);
+        b.incr_by(b"y", 4);
+        let mut dest = CountMinSketch::new(100, 5);
+        dest.incr_by(b"z", 1);
+        dest.merge(&[(a, 3), (b, 1)]).unwrap();
+        assert_eq!(dest.query(b"x"), 7);
+        assert_eq!(dest.query(b"y"), 4);
+        // The counters of the destination are replaced
+        assert_eq!(dest.query(b"z"), 0);
+        let other = CountMinSketch::new(10, 5);
+        assert_eq!(
+            dest.merge(&[(other, 1)]),
+            Err("CMS: width/depth is not equal".into())
+        );
+    }
+
+    #[test]
+    fn topk_keeps_the_heavy_hitters() {
+        let mut topk = TopK::new(
+            2,
+            TOPK_DEFAULT_WIDTH,
+            TOPK_DEFAULT_DEPTH,
+            TOPK_DEFAULT_DECAY,
+        );
+        assert_eq!(topk.incr_by(&item("a"), 1), None);
+        assert_eq!(topk.incr_by(&item("b"), 1), None);
+        assert_eq!(topk.incr_by(&item("a"), 4), None);
+        assert_eq!(topk.list(), [(item("a"), 5), (item("b"), 1)]);
+        assert!(topk.contains(&item("b")) && !topk.contains(&item("c")));
+        // A bigger count expels the smallest heavy hitter
+        assert_eq!(topk.incr_by(&item("c"), 10), Some(item("b")));
+        assert_eq!(topk.list(), [(item("c"), 10), (item("a"), 5)]);
+    }
+
+    #[test]
+    fn topk_is_reproducible() {
+        let mut first = TopK::new(3, 4, 2, TOPK_DEFAULT_DECAY);
+        let mut second = first.clone();
+        for i in 0..200 {
+            let counted = item(&format!("item:{}", i % 7));
+            assert_eq!(first.incr_by(&counted, 1), second.incr_by(&counted, 1));
+        }
+        assert_eq!(first.list(), second.list());
+    }
+}
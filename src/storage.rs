@@ -1,65 +1,1795 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use lunatic::{abstract_process, process::ProcessRef};
+use lunatic::{
+    abstract_process,
+    process::ProcessRef,
+    sleep,
+    supervisor::{Supervisor, SupervisorConfig, SupervisorStrategy},
+    Mailbox, Process, Tag,
+};
+use lunatic_log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::types::{BulkString, RedisKey, RedisValue};
+use crate::{
+    audit::now_ms,
+    bloom::{self, BloomFilter, DEFAULT_CAPACITY, DEFAULT_ERROR_RATE, DEFAULT_EXPANSION},
+    cdc::{Cdc, CdcEvent, CdcHandler},
+    chaos,
+    config::{ChaosConfig, Config, EvictionPolicy},
+    dict::{Cursor, Dict},
+    glob::glob_match,
+    json::{self, Condition, Document, Path},
+    sketch::{self, CountMinSketch, TopK},
+    timeseries::{Aggregator, Filter, Sample, SeriesOptions, TimeSeries},
+    types::{
+        BloomCmd, BulkString, CmsCmd, JsonCmd, RedisCmd, RedisKey, RedisValue, RespValue, TopKCmd,
+        TsCmd,
+    },
+};
+
+/// Approximate memory used by each entry besides the key and value bytes
+const ENTRY_OVERHEAD: usize = 48;
+
+/// The keyspace is compacted once its capacity is over this many times its keys, like after
+/// deleting most of the keys or FLUSHDB
+const COMPACTION_RATIO: usize = 2;
+
+/// Integers from 0 to this are shared by all the keys instead of allocated for each value
+const SHARED_INTEGERS: usize = 10_000;
+
+/// Strings up to this length are reported as embstr by OBJECT ENCODING, like redis
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Refcount of the shared objects in redis, they are never freed
+const SHARED_REFCOUNT: u32 = i32::MAX as u32;
+
+/// The LRU clock of redis has 24 bits, DEBUG OBJECT shows the access time wrapped to it
+const LRU_CLOCK_MAX: u64 = (1 << 24) - 1;
+
+/// Initial LFU counter of new keys, so they aren't evicted before getting a chance to be used
+const LFU_INIT_VAL: u8 = 5;
+
+/// How hard is incrementing the LFU counter, with 10 it saturates around 1M hits
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// The LFU counter decrements by one every this minutes without accesses
+const LFU_DECAY_TIME: u64 = 1;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Write commands are rejected when maxmemory is reached and no key can be evicted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutOfMemory;
+
+impl From<OutOfMemory> for RespValue {
+    fn from(_: OutOfMemory) -> Self {
+        RespValue::Error(
+            "OOM".into(),
+            Some("command not allowed when used memory > 'maxmemory'.".into()),
+        )
+    }
+}
+
+/// Errors of the commands executed by Storage
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StorageError {
+    OutOfMemory,
+    /// The key holds a value of another type
+    WrongType,
+    /// Replied as ERR with the message
+    Invalid(String),
+}
+
+impl From<OutOfMemory> for StorageError {
+    fn from(_: OutOfMemory) -> Self {
+        StorageError::OutOfMemory
+    }
+}
+
+impl From<StorageError> for RespValue {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::OutOfMemory => OutOfMemory.into(),
+            StorageError::WrongType => RespValue::Error(
+                "WRONGTYPE".into(),
+                Some("Operation against a key holding the wrong kind of value".into()),
+            ),
+            StorageError::Invalid(message) => RespValue::Error("ERR".into(), Some(message)),
+        }
+    }
+}
+
+/// Value of a key, each data type is a variant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    String(RedisValue),
+    Json(Document),
+    Bloom(BloomFilter),
+    Cms(CountMinSketch),
+    TopK(TopK),
+    Ts(TimeSeries),
+}
+
+impl Value {
+    /// Name of the type, as listed by MEMORY BIGKEYS
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Json(_) => "json",
+            Value::Bloom(_) => "bloom",
+            Value::Cms(_) => "cms",
+            Value::TopK(_) => "topk",
+            Value::Ts(_) => "timeseries",
+        }
+    }
+}
+
+/// Arguments of a Storage process: the config, its database, the name of its primary when
+/// it's a replica and the names of its replicas when it's a primary
+pub type StorageArgs = (Config, usize, Option<String>, Vec<String>);
+
+/// Restarts a Storage process with the same name when it fails, instead of taking the
+/// whole server down. The restarted process recovers its keys from the primary, or from a
+/// replica for a primary, so without replicas a restart loses the keys of the shard
+pub struct StorageSupervisor;
+
+impl Supervisor for StorageSupervisor {
+    type Arg = (StorageArgs, String);
+    type Children = Storage;
+
+    fn init(config: &mut SupervisorConfig<Self>, arg: Self::Arg) {
+        let (args, name) = arg;
+        config.set_strategy(SupervisorStrategy::OneForOne);
+        config.children_args((args, Some(name)));
+    }
+}
+
+/// Change of the keys made by a write
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Change {
+    /// Key, value and the version of the key
+    Set(RedisKey, Value, u64),
+    Del(RedisKey),
+    Clear,
+}
+
+/// Write applied by the primary, sent to its replicas so they have the same keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Propagation {
+    /// Deterministic command, executed again by the replicas
+    Verbatim(RedisCmd),
+    /// Changes made by the commands that can't be executed again with the same result, and
+    /// by the server itself, like evictions
+    Effects(Vec<Change>),
+}
+
+/// Keys of a storage process sent to recover a restarted one, with their metadata so the
+/// versions, the eviction and the trash continue as before the restart
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Writes applied by the process, the replication offset
+    offset: u64,
+    /// Last version given to a key
+    version_clock: u64,
+    entries: Vec<(RedisKey, Entry)>,
+    /// Deleted keys with the time they were deleted
+    trash: Vec<(RedisKey, Entry, u64)>,
+}
+
+/// Client blocked until a key is written: its id, and the process and tag of the wake up
+pub type Waiter = (u64, Process<()>, Tag);
+
+/// Aggregated memory information, shown by MEMORY STATS
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub peak: usize,
+    pub used: usize,
+    pub maxmemory: usize,
+    pub keys: usize,
+    /// Bytes used by keys and values
+    pub dataset: usize,
+    /// Bytes of unused capacity released by the compactions since the start
+    pub reclaimed: usize,
+    /// Deleted keys kept in the trash and their bytes, counted towards maxmemory
+    pub trash_keys: usize,
+    pub trash: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Shared between entries for small integers
+    value: Value,
+    /// The string is LZ4 compressed, with its size prepended
+    compressed: bool,
+    /// Time of the last access in seconds, used by LRU eviction and OBJECT IDLETIME
+    last_access: u64,
+    /// Logarithmic access counter, used by LFU eviction and OBJECT FREQ
+    lfu_counter: u8,
+    /// Time in minutes when the LFU counter was last decremented
+    lfu_decrement_time: u64,
+    /// Given by the version clock of the process to each write of the key (GETVER)
+    version: u64,
+}
+
+impl Entry {
+    fn new(value: Value, compressed: bool, version: u64) -> Self {
+        let now = now_secs();
+        Self {
+            value,
+            compressed,
+            version,
+            last_access: now,
+            lfu_counter: LFU_INIT_VAL,
+            lfu_decrement_time: now / 60,
+        }
+    }
+
+    /// Value of a string as it was set, decompressed if needed. None for the other types
+    fn string(&self) -> Option<RedisValue> {
+        let value = match &self.value {
+            Value::String(value) => value,
+            _ => return None,
+        };
+        if !self.compressed {
+            return Some(value.clone());
+        }
+        let value = lz4_flex::decompress_size_prepended(&value.0)
+            .expect("compressed values are only created by Storage");
+        Some(BulkString(value.into()))
+    }
+
+    /// Like `string`, but an uncompressed string is moved out of the entry so it can be
+    /// modified in place. The entry is left empty until its new value is set
+    fn take_string(&mut self) -> Option<RedisValue> {
+        if self.compressed {
+            return self.string();
+        }
+        match &mut self.value {
+            Value::String(value) => Some(BulkString(mem::take(&mut value.0))),
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self.string() {
+            Some(value) => Value::String(value),
+            None => self.value.clone(),
+        }
+    }
+
+    fn idle_time(&self) -> u64 {
+        now_secs().saturating_sub(self.last_access)
+    }
+
+    /// Internal representation of the value, as named by OBJECT ENCODING
+    fn encoding(&self) -> &'static str {
+        match &self.value {
+            Value::String(_) if self.compressed => "lz4",
+            Value::String(value) if value.to_string().parse::<i64>().is_ok() => "int",
+            Value::String(value) if value.0.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            Value::String(_) => "raw",
+            Value::Json(_) => "json",
+            Value::Bloom(_) => "bloom",
+            Value::Cms(_) => "cms",
+            Value::TopK(_) => "topk",
+            Value::Ts(_) => "timeseries",
+        }
+    }
+
+    /// Bytes of the value once serialized: strings as they are stored, so compressed ones
+    /// count their compressed bytes, and the other types as JSON
+    fn serialized_length(&self) -> usize {
+        match &self.value {
+            Value::String(value) => value.0.len(),
+            value => serde_json::to_vec(value).map_or(0, |json| json.len()),
+        }
+    }
+
+    /// LFU counter after decaying it by the time elapsed since the last decrement
+    fn lfu_decayed(&self) -> u8 {
+        let periods = (now_secs() / 60).saturating_sub(self.lfu_decrement_time) / LFU_DECAY_TIME;
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    /// Update the access metadata, the LFU counter is incremented with a probability that
+    /// decreases as the counter grows, so the 8 bits can represent millions of accesses
+    fn touch(&mut self) {
+        let now = now_secs();
+        let mut counter = self.lfu_decayed();
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if rand::thread_rng().gen::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.lfu_counter = counter;
+        self.lfu_decrement_time = now / 60;
+        self.last_access = now;
+    }
+}
+
+/// Index of the value in the shared integers, only canonical integers (no sign or
+/// leading zeros) are shared so the value returned by GET is exactly the one set
+fn shared_integer(value: &RedisValue) -> Option<usize> {
+    let digits = &value.0;
+    let canonical = !digits.is_empty()
+        && digits.len() <= 4
+        && digits.iter().all(u8::is_ascii_digit)
+        && (digits[0] != b'0' || digits.len() == 1);
+    canonical.then(|| digits.iter().fold(0, |n, d| n * 10 + (d - b'0') as usize))
+}
+
+/// Use the shared value for small integers instead of keeping a new allocation
+fn intern(shared_integers: &[RedisValue], value: RedisValue) -> RedisValue {
+    match shared_integer(&value) {
+        Some(index) => shared_integers[index].clone(),
+        None => value,
+    }
+}
+
+fn entry_size(key: &RedisKey, value: &Value) -> usize {
+    let value_size = match value {
+        // Shared values are allocated once for all the keys
+        Value::String(value) if shared_integer(value).is_some() => 0,
+        Value::String(value) => value.0.len(),
+        Value::Json(document) => document.size(),
+        Value::Bloom(filter) => filter.size(),
+        Value::Cms(sketch) => sketch.size(),
+        Value::TopK(top_k) => top_k.size(),
+        Value::Ts(series) => series.size(),
+    };
+    key.0.len() + value_size + ENTRY_OVERHEAD
+}
 
 #[derive(Default)]
 pub struct Storage {
-    store: HashMap<RedisKey, RedisValue>,
+    store: Dict<RedisKey, Entry>,
+    used_memory: usize,
+    peak_memory: usize,
+    /// Max memory used by the keys before evicting, 0 means no limit
+    maxmemory: usize,
+    policy: EvictionPolicy,
+    /// Number of random keys sampled to choose the one to evict
+    maxmemory_samples: usize,
+    shared_integers: Vec<RedisValue>,
+    /// Values bigger than this are compressed, 0 disables compression
+    compression_threshold: usize,
+    /// Names of the read-only copies receiving the changes of this process, they are looked
+    /// up on each change so restarted replicas keep receiving them
+    replicas: Vec<String>,
+    /// Changes of the write being executed, they are propagated once it finishes
+    changes: Vec<Change>,
+    /// Database of the changes sent to the CDC feed, None for the replicas and when there is
+    /// no feed
+    cdc_db: Option<usize>,
+    /// Clients blocked on each key, in the order they blocked
+    blocked: HashMap<RedisKey, VecDeque<Waiter>>,
+    chaos: ChaosConfig,
+    /// Reads finding the key, shown by INFO stats
+    keyspace_hits: u64,
+    /// Reads of missing keys
+    keyspace_misses: u64,
+    /// Bytes released by the compactions, shown by MEMORY STATS
+    reclaimed_memory: usize,
+    /// Writes sent to the replicas by a primary, or applied by a replica. A restarted
+    /// process continues from the offset of the process it recovered from
+    offset: u64,
+    /// Clients waiting for a replica to reach an offset, to read their own writes
+    offset_waiters: Vec<(u64, Process<()>, Tag)>,
+    /// Seconds the keys deleted by DEL and FLUSHDB stay in the trash, 0 when disabled. The
+    /// replicas keep the deleted keys too, for the primary recovered from them
+    trash_retention: u64,
+    /// Deleted keys that can be restored by UNDELETE, with the time they were deleted
+    trash: HashMap<RedisKey, (Entry, u64)>,
+    /// Bytes of the keys in the trash, they are dropped before evicting keys
+    trash_memory: usize,
+    /// Last version given to a key. Versions are never reused, a key deleted and created
+    /// again doesn't get a version a CAS read before the delete expects. The replicas
+    /// follow the versions of their primary
+    version_clock: u64,
+}
+
+impl Storage {
+    /// Release the unused capacity of the keyspace and the blocked clients when it's over
+    /// `COMPACTION_RATIO` times the used one, or always with `force`. Returns the bytes
+    /// released by the keyspace
+    fn shrink(&mut self, force: bool) -> usize {
+        let before = self.store.allocated();
+        if force || self.store.capacity() > self.store.len() * COMPACTION_RATIO {
+            self.store.shrink_to_fit();
+        }
+        if force || self.blocked.capacity() > self.blocked.len() * COMPACTION_RATIO {
+            self.blocked.shrink_to_fit();
+        }
+        // Only used while a write is executed
+        self.changes.shrink_to_fit();
+        let reclaimed = before.saturating_sub(self.store.allocated());
+        self.reclaimed_memory += reclaimed;
+        reclaimed
+    }
+
+    fn count_lookup(&mut self, found: bool) {
+        if found {
+            self.keyspace_hits += 1;
+        } else {
+            self.keyspace_misses += 1;
+        }
+    }
+
+    fn add_used_memory(&mut self, size: usize) {
+        self.used_memory += size;
+        self.peak_memory = self.peak_memory.max(self.used_memory);
+    }
+
+    /// Keep the deleted entry to restore it with UNDELETE, replacing an older deletion of
+    /// the key
+    fn add_to_trash(&mut self, key: RedisKey, entry: Entry, deleted: u64) {
+        self.trash_memory += entry_size(&key, &entry.value);
+        if let Some((old, _)) = self.trash.insert(key.clone(), (entry, deleted)) {
+            self.trash_memory -= entry_size(&key, &old.value);
+        }
+    }
+
+    fn take_from_trash(&mut self, key: &RedisKey) -> Option<Entry> {
+        let (entry, _) = self.trash.remove(key)?;
+        self.trash_memory -= entry_size(key, &entry.value);
+        Some(entry)
+    }
+
+    /// Drop the keys deleted first until the used memory and the trash are under maxmemory
+    fn drop_trash(&mut self) {
+        let mut trashed: Vec<_> = self
+            .trash
+            .iter()
+            .map(|(key, (_, deleted))| (*deleted, key.clone()))
+            .collect();
+        trashed.sort_unstable_by_key(|(deleted, _)| *deleted);
+        for (_, key) in trashed {
+            if self.used_memory + self.trash_memory <= self.maxmemory {
+                break;
+            }
+            self.take_from_trash(&key);
+        }
+    }
+
+    /// Keep the change to propagate it, only the primaries with replicas propagate them. The
+    /// change and the old value of the key are sent to the CDC feed right away
+    fn record(&mut self, change: impl FnOnce() -> Change, old: Option<Value>) {
+        if self.replicas.is_empty() && self.cdc_db.is_none() {
+            return;
+        }
+        let change = change();
+        if let Some(db) = self.cdc_db {
+            if let Some(cdc) = ProcessRef::<Cdc>::lookup("cdc") {
+                cdc.record(CdcEvent::new(db, &change, old));
+            }
+        }
+        if !self.replicas.is_empty() {
+            self.changes.push(change);
+        }
+    }
+
+    /// Value of the key before a write, only needed by the CDC feed
+    fn old_value(&self, key: &RedisKey) -> Option<Value> {
+        self.cdc_db?;
+        self.store.get(key).map(Entry::value)
+    }
+
+    fn send(&mut self, propagation: Propagation) {
+        self.offset += 1;
+        for name in &self.replicas {
+            if let Some(replica) = ProcessRef::<Storage>::lookup(name) {
+                replica.apply(propagation.clone());
+            }
+        }
+    }
+
+    /// Wake the first client blocked on the written key, the next ones wait for the next
+    /// write so they are served in order. The client stops waiting on its other keys
+    fn wake(&mut self, key: &RedisKey) {
+        let waiter = self.blocked.get_mut(key).and_then(VecDeque::pop_front);
+        if let Some((client, process, tag)) = waiter {
+            self.remove_waiter(client);
+            process.tag_send(tag, ());
+        }
+    }
+
+    fn remove_waiter(&mut self, client: u64) {
+        self.blocked.retain(|_, waiters| {
+            waiters.retain(|(id, ..)| *id != client);
+            !waiters.is_empty()
+        });
+    }
+
+    /// Send the recorded changes to the replicas as they are
+    fn propagate_effects(&mut self) {
+        let changes = mem::take(&mut self.changes);
+        if !changes.is_empty() {
+            self.send(Propagation::Effects(changes));
+        }
+    }
+
+    /// Every write goes through here once executed, deterministic commands are sent as
+    /// they are and the others as their changes. Writes that changed nothing aren't sent
+    fn propagate(&mut self, cmd: impl FnOnce() -> RedisCmd) {
+        if self.changes.is_empty() {
+            return;
+        }
+        let cmd = cmd();
+        if cmd.is_deterministic() {
+            self.changes.clear();
+            self.send(Propagation::Verbatim(cmd));
+        } else {
+            self.propagate_effects();
+        }
+    }
+
+    /// Execute a storage command, for batches and the commands propagated by the primary
+    fn execute(&mut self, cmd: RedisCmd, touch: bool) -> RespValue {
+        match cmd {
+            RedisCmd::Get(key) => match self.get(key, touch) {
+                Ok(value) => value.map_or(RespValue::Null, RespValue::BulkString),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Set(key, value) => match self.set(key, value) {
+                Ok(_) => RespValue::SimpleString("OK".into()),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Append(key, value) => match self.append(key, value) {
+                Ok(len) => RespValue::Integer(len),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Exists(key) => RespValue::Integer(self.exists(key)),
+            RedisCmd::Delete(keys) => RespValue::Integer(self.del(keys)),
+            RedisCmd::FlushDb | RedisCmd::FlushAll => {
+                self.clear();
+                RespValue::SimpleString("OK".into())
+            }
+            RedisCmd::Json(JsonCmd::Set(key, path, document, condition)) => {
+                match self.json_set(key, path, document, condition) {
+                    Ok(true) => RespValue::SimpleString("OK".into()),
+                    Ok(false) => RespValue::Null,
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Json(JsonCmd::Del(key, path)) => match self.json_del(key, path) {
+                Ok(removed) => RespValue::Integer(removed),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Json(JsonCmd::ArrAppend(key, path, values)) => {
+                match self.json_arr_append(key, path.clone(), values) {
+                    Ok(lengths) => json::lengths_reply(&path, lengths),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Bloom(BloomCmd::Reserve(key, error_rate, capacity, expansion)) => {
+                match self.bf_reserve(key, error_rate, capacity, expansion) {
+                    Ok(()) => RespValue::SimpleString("OK".into()),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Bloom(BloomCmd::Add(key, item)) => match self.bf_add(key, vec![item]) {
+                Ok(mut added) => bloom::added_reply(added.remove(0)),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Bloom(BloomCmd::MAdd(key, items)) => match self.bf_add(key, items) {
+                Ok(added) => RespValue::Array(added.into_iter().map(bloom::added_reply).collect()),
+                Err(err) => err.into(),
+            },
+            RedisCmd::Cms(CmsCmd::InitByDim(key, width, depth)) => {
+                match self.cms_init(key, width, depth) {
+                    Ok(()) => RespValue::SimpleString("OK".into()),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Cms(CmsCmd::IncrBy(key, increments)) => {
+                match self.cms_incr_by(key, increments) {
+                    Ok(counts) => sketch::counts_reply(counts),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::TopK(TopKCmd::Reserve(key, k, width, depth, decay)) => {
+                match self.topk_reserve(key, k, width, depth, decay) {
+                    Ok(()) => RespValue::SimpleString("OK".into()),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::TopK(TopKCmd::IncrBy(key, increments)) => {
+                match self.topk_incr_by(key, increments) {
+                    Ok(expelled) => sketch::expelled_reply(expelled),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Ts(TsCmd::Create(key, options)) => match self.ts_create(key, options) {
+                Ok(()) => RespValue::SimpleString("OK".into()),
+                Err(err) => err.into(),
+            },
+            // The samples of the compaction rules are added by the primary's clients
+            RedisCmd::Ts(TsCmd::Add(key, timestamp, value, options)) => {
+                match self.ts_add(key, timestamp, value, options) {
+                    Ok((timestamp, _)) => RespValue::Integer(timestamp as i64),
+                    Err(err) => err.into(),
+                }
+            }
+            RedisCmd::Ts(TsCmd::CreateRule(key, dest, aggregator)) => {
+                match self.ts_create_rule(key, dest, aggregator) {
+                    Ok(()) => RespValue::SimpleString("OK".into()),
+                    Err(err) => err.into(),
+                }
+            }
+            cmd => RespValue::Error(
+                "ERR".into(),
+                Some(format!("'{}' command can't be batched", cmd.name())),
+            ),
+        }
+    }
+
+    /// Value as stored in the entry and if it's compressed, compression is only used for
+    /// strings when it makes them smaller
+    fn encode(&self, value: Value) -> (Value, bool) {
+        let value = match value {
+            Value::String(value) => value,
+            value => return (value, false),
+        };
+        if self.compression_threshold > 0 && value.0.len() > self.compression_threshold {
+            let compressed = lz4_flex::compress_prepend_size(&value.0);
+            if compressed.len() < value.0.len() {
+                return (Value::String(BulkString(compressed.into())), true);
+            }
+        }
+        (Value::String(intern(&self.shared_integers, value)), false)
+    }
+
+    fn insert(&mut self, key: RedisKey, value: Value) -> bool {
+        self.version_clock += 1;
+        self.insert_version(key, value, self.version_clock)
+    }
+
+    /// Insert the key with the version given by the primary or the recovered snapshot
+    fn insert_version(&mut self, key: RedisKey, value: Value, version: u64) -> bool {
+        let old = self.old_value(&key);
+        self.record(|| Change::Set(key.clone(), value.clone(), version), old);
+        self.wake(&key);
+        let (value, compressed) = self.encode(value);
+        self.add_used_memory(entry_size(&key, &value));
+        match self
+            .store
+            .insert(key.clone(), Entry::new(value, compressed, version))
+        {
+            Some(old) => {
+                self.used_memory -= entry_size(&key, &old.value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: &RedisKey) -> bool {
+        self.take(key).is_some()
+    }
+
+    /// Remove the key, returning its entry
+    fn take(&mut self, key: &RedisKey) -> Option<Entry> {
+        let entry = self.store.swap_remove(key)?;
+        let old = self.cdc_db.map(|_| entry.value());
+        self.record(|| Change::Del(key.clone()), old);
+        self.used_memory -= entry_size(key, &entry.value);
+        Some(entry)
+    }
+
+    /// Random keys to choose the eviction candidate from, like redis the LRU/LFU is
+    /// approximated to avoid keeping all the keys sorted
+    fn sample(&self, count: usize) -> impl Iterator<Item = (&RedisKey, &Entry)> {
+        let mut rng = rand::thread_rng();
+        let len = self.store.len();
+        (0..count.min(len)).filter_map(move |_| self.store.get_index(rng.gen_range(0..len)))
+    }
+
+    /// Key to free according to the eviction policy
+    fn eviction_candidate(&self) -> Option<RedisKey> {
+        use EvictionPolicy::*;
+        let candidate = match self.policy {
+            AllKeysRandom => self.sample(1).next(),
+            AllKeysLru => self
+                .sample(self.maxmemory_samples)
+                .max_by_key(|(_, entry)| entry.idle_time()),
+            AllKeysLfu => self
+                .sample(self.maxmemory_samples)
+                .min_by_key(|(_, entry)| (entry.lfu_decayed(), u64::MAX - entry.idle_time())),
+            // Keys can't have an expire yet, so there is nothing to evict for volatile policies
+            _ => None,
+        };
+        candidate.map(|(key, _)| key.clone())
+    }
+
+    /// Evict keys until the used memory is under maxmemory, the evictions are propagated
+    /// before the write needing the room. The trash is dropped before evicting any key
+    fn make_room(&mut self) -> Result<(), OutOfMemory> {
+        if self.maxmemory > 0 && self.used_memory + self.trash_memory > self.maxmemory {
+            self.drop_trash();
+        }
+        let mut result = Ok(());
+        while self.maxmemory > 0 && self.used_memory > self.maxmemory {
+            let key = match self.eviction_candidate() {
+                Some(key) => key,
+                None => {
+                    result = Err(OutOfMemory);
+                    break;
+                }
+            };
+            self.remove(&key);
+        }
+        self.propagate_effects();
+        result
+    }
+
+    /// Modify the value of the key in place, `update` returns its result and if it changed
+    /// the value. Returns None when the key doesn't exist
+    fn update<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut Value) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        let recorded = !self.replicas.is_empty() || self.cdc_db.is_some();
+        let old = self.old_value(key);
+        let entry = match self.store.get_mut(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let old_size = entry_size(key, &entry.value);
+        let (result, changed) = update(&mut entry.value)?;
+        if !changed {
+            return Ok(Some(result));
+        }
+        let new_size = entry_size(key, &entry.value);
+        let value = recorded.then(|| entry.value.clone());
+        entry.touch();
+        self.version_clock += 1;
+        entry.version = self.version_clock;
+        let version = entry.version;
+        if let Some(value) = value {
+            self.record(|| Change::Set(key.clone(), value, version), old);
+        }
+        self.wake(key);
+        self.used_memory -= old_size;
+        self.add_used_memory(new_size);
+        Ok(Some(result))
+    }
+
+    /// Read the value of the key, without touch the access metadata is not updated. Returns
+    /// None when the key doesn't exist
+    fn read<T>(
+        &mut self,
+        key: &RedisKey,
+        touch: bool,
+        read: impl FnOnce(&Value) -> Result<T, StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        let result = self.store.get_mut(key).map(|entry| {
+            if touch {
+                entry.touch();
+            }
+            read(&entry.value)
+        });
+        self.count_lookup(result.is_some());
+        result.transpose()
+    }
+
+    fn update_json<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut Document) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        self.update(key, |value| match value {
+            Value::Json(document) => update(document),
+            _ => Err(StorageError::WrongType),
+        })
+    }
+
+    fn update_bloom<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut BloomFilter) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        self.update(key, |value| match value {
+            Value::Bloom(filter) => update(filter),
+            _ => Err(StorageError::WrongType),
+        })
+    }
+
+    fn update_cms<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut CountMinSketch) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        self.update(key, |value| match value {
+            Value::Cms(sketch) => update(sketch),
+            _ => Err(StorageError::WrongType),
+        })
+    }
+
+    fn update_topk<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut TopK) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        self.update(key, |value| match value {
+            Value::TopK(top_k) => update(top_k),
+            _ => Err(StorageError::WrongType),
+        })
+    }
+
+    fn update_ts<T>(
+        &mut self,
+        key: &RedisKey,
+        update: impl FnOnce(&mut TimeSeries) -> Result<(T, bool), StorageError>,
+    ) -> Result<Option<T>, StorageError> {
+        self.update(key, |value| match value {
+            Value::Ts(series) => update(series),
+            _ => Err(StorageError::WrongType),
+        })
+    }
 }
 
 #[abstract_process(visibility = pub)]
 impl Storage {
+    /// Each shard gets an equal part of maxmemory. When the process is restarted the keys
+    /// are copied from its primary, or from the first replica for a primary
     #[init]
-    fn init(_: ProcessRef<Self>, _: ()) -> Self {
-        Self::default()
+    fn init(this: ProcessRef<Self>, args: StorageArgs) -> Self {
+        let (config, db, primary, replicas) = args;
+        if config.compaction_interval > 0 {
+            let interval = Duration::from_secs(config.compaction_interval);
+            Process::spawn_link(
+                (this.clone(), interval),
+                |(storage, interval), _: Mailbox<()>| loop {
+                    sleep(interval);
+                    storage.compact();
+                },
+            );
+        }
+        // The replicas keep a trash too, a primary recovered from its replica restores it
+        if config.trash_retention > 0 {
+            Process::spawn_link(this, |storage, _: Mailbox<()>| loop {
+                sleep(Duration::from_secs(1));
+                storage.empty_trash();
+            });
+        }
+        let mut storage = Self {
+            maxmemory: config.maxmemory / config.shards,
+            policy: config.maxmemory_policy,
+            maxmemory_samples: config.maxmemory_samples,
+            compression_threshold: config.compression_threshold,
+            trash_retention: config.trash_retention,
+            chaos: config.chaos,
+            shared_integers: (0..SHARED_INTEGERS)
+                .map(|n| BulkString(n.to_string().into()))
+                .collect(),
+            ..Self::default()
+        };
+        // On the first start the replicas are empty and the primary doesn't exist yet
+        let source = primary.iter().chain(replicas.first());
+        if let Some(source) = source.find_map(|name| ProcessRef::<Storage>::lookup(name)) {
+            let snapshot = source.snapshot();
+            for (key, entry) in snapshot.entries {
+                storage.add_used_memory(entry_size(&key, &entry.value));
+                storage.store.insert(key, entry);
+            }
+            for (key, entry, deleted) in snapshot.trash {
+                storage.add_to_trash(key, entry, deleted);
+            }
+            storage.offset = snapshot.offset;
+            storage.version_clock = snapshot.version_clock;
+        }
+        // The replicas already have the recovered keys, and the CDC feed had their writes
+        storage.cdc_db = (config.cdc_enabled() && primary.is_none()).then_some(db);
+        storage.replicas = replicas;
+        storage
+    }
+
+    /// All the keys and the trash with their metadata, used to recover a restarted process
+    #[handle_request]
+    fn snapshot(&mut self) -> Snapshot {
+        let entries = (0..self.store.len())
+            .filter_map(|index| self.store.get_index(index))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        let trash = self
+            .trash
+            .iter()
+            .map(|(key, (entry, deleted))| (key.clone(), entry.clone(), *deleted))
+            .collect();
+        Snapshot {
+            offset: self.offset,
+            version_clock: self.version_clock,
+            entries,
+            trash,
+        }
+    }
+
+    /// Writes sent to the replicas so far, the offset a replica must reach to have them
+    #[handle_request]
+    fn replication_offset(&mut self) -> u64 {
+        self.offset
     }
 
+    /// Whether a replica already applied the writes up to the offset, otherwise the
+    /// process is woken with the tag once it does
     #[handle_request]
-    fn get(&mut self, key: RedisKey) -> Option<RedisValue> {
-        self.store.get(&key).cloned()
+    fn wait_offset(&mut self, offset: u64, process: Process<()>, tag: Tag) -> bool {
+        if self.offset >= offset {
+            return true;
+        }
+        self.offset_waiters.push((offset, process, tag));
+        false
     }
 
+    /// Forget a client waiting for an offset, once its timeout expired. Returns false when
+    /// it was already woken
     #[handle_request]
-    fn set(&mut self, key: RedisKey, value: RedisValue) -> bool {
-        self.store.insert(key, value).is_some()
+    fn unwait_offset(&mut self, tag: Tag) -> bool {
+        let waiting = self.offset_waiters.len();
+        self.offset_waiters.retain(|(_, _, waiter)| *waiter != tag);
+        self.offset_waiters.len() < waiting
+    }
+
+    /// Get the string of a key, without touch the access metadata is not updated
+    #[handle_request]
+    fn get(&mut self, key: RedisKey, touch: bool) -> Result<Option<RedisValue>, StorageError> {
+        let value = self.store.get_mut(&key).map(|entry| {
+            if touch {
+                entry.touch();
+            }
+            entry.string().ok_or(StorageError::WrongType)
+        });
+        self.count_lookup(value.is_some());
+        value.transpose()
+    }
+
+    /// The keys holding other types than strings are returned as missing, like redis
+    #[handle_request]
+    fn mget(&mut self, keys: Vec<RedisKey>, touch: bool) -> Vec<Option<RedisValue>> {
+        chaos::delay(&self.chaos);
+        keys.into_iter()
+            .map(|key| self.get(key, touch).ok().flatten())
+            .collect()
+    }
+
+    #[handle_request]
+    fn set(&mut self, key: RedisKey, value: RedisValue) -> Result<bool, OutOfMemory> {
+        self.make_room()?;
+        let replaced = self.insert(key.clone(), Value::String(value.clone()));
+        self.propagate(|| RedisCmd::Set(key, value));
+        Ok(replaced)
+    }
+
+    /// String of a key with its version, without touch the access metadata is not updated
+    #[handle_request]
+    fn get_version(
+        &mut self,
+        key: RedisKey,
+        touch: bool,
+    ) -> Result<Option<(RedisValue, u64)>, StorageError> {
+        let value = self.store.get_mut(&key).map(|entry| {
+            if touch {
+                entry.touch();
+            }
+            let value = entry.string().ok_or(StorageError::WrongType)?;
+            Ok((value, entry.version))
+        });
+        self.count_lookup(value.is_some());
+        value.transpose()
+    }
+
+    /// Set the string when the key is still at the expected version, 0 for a missing key.
+    /// Returns the new version, None when the key was written meanwhile
+    #[handle_request]
+    fn cas(
+        &mut self,
+        key: RedisKey,
+        expected: u64,
+        value: RedisValue,
+    ) -> Result<Option<u64>, OutOfMemory> {
+        // Making room first, an evicted key is missing for the check
+        self.make_room()?;
+        let version = self.store.get(&key).map_or(0, |entry| entry.version);
+        if version != expected {
+            return Ok(None);
+        }
+        self.insert(key.clone(), Value::String(value.clone()));
+        self.propagate(|| RedisCmd::Cas(key, expected, value));
+        Ok(Some(self.version_clock))
     }
 
     #[handle_request]
     fn del(&mut self, keys: Vec<RedisKey>) -> i64 {
+        chaos::delay(&self.chaos);
         let mut removed = 0;
-        for key in keys {
-            if self.store.remove(&key).is_some() {
+        let now = now_secs();
+        for key in &keys {
+            if let Some(entry) = self.take(key) {
+                if self.trash_retention > 0 {
+                    self.add_to_trash(key.clone(), entry, now);
+                }
                 removed += 1;
             }
         }
+        self.propagate(|| RedisCmd::Delete(keys));
         removed
     }
 
     #[handle_request]
-    fn append(&mut self, key: RedisKey, mut value: BulkString) -> i64 {
-        let current_value = self
-            .store
-            .entry(key.clone())
-            .or_insert_with(|| BulkString("".into()));
-        current_value.append(&mut value);
-        current_value.0.len() as i64
+    fn append(&mut self, key: RedisKey, value: BulkString) -> Result<i64, StorageError> {
+        self.make_room()?;
+        let old = self.old_value(&key);
+        let (mut new_value, old_size) = match self.store.get_mut(&key) {
+            Some(entry) => {
+                let old_size = entry_size(&key, &entry.value);
+                match entry.take_string() {
+                    Some(old_value) => (old_value, old_size),
+                    None => return Err(StorageError::WrongType),
+                }
+            }
+            None => {
+                let len = value.0.len() as i64;
+                self.insert(key.clone(), Value::String(value.clone()));
+                self.propagate(|| RedisCmd::Append(key, value));
+                return Ok(len);
+            }
+        };
+        // Appended in place unless the bytes are still shared, ie. by an interned integer
+        new_value.append(&value);
+        let len = new_value.0.len() as i64;
+        self.version_clock += 1;
+        let version = self.version_clock;
+        self.record(
+            || Change::Set(key.clone(), Value::String(new_value.clone()), version),
+            old,
+        );
+        self.wake(&key);
+        let (new_value, compressed) = self.encode(Value::String(new_value));
+        let new_size = entry_size(&key, &new_value);
+        if let Some(entry) = self.store.get_mut(&key) {
+            entry.value = new_value;
+            entry.compressed = compressed;
+            entry.touch();
+            entry.version = version;
+        }
+        self.used_memory -= old_size;
+        self.add_used_memory(new_size);
+        self.propagate(|| RedisCmd::Append(key, value));
+        Ok(len)
+    }
+
+    /// Add the delta to the unsigned integer of the key (memcached incr and decr), wrapping
+    /// around on overflow and stopping at 0 when decrementing. Returns None for a missing
+    /// key, the new value is replicated as a SET
+    #[handle_request]
+    fn incr_unsigned(
+        &mut self,
+        key: RedisKey,
+        delta: u64,
+        decrement: bool,
+    ) -> Result<Option<u64>, StorageError> {
+        self.make_room()?;
+        let value = match self.store.get(&key) {
+            Some(entry) => entry.string().ok_or(StorageError::WrongType)?,
+            None => return Ok(None),
+        };
+        let number: u64 = match value.to_string().parse() {
+            Ok(number) => number,
+            Err(_) => return Err(StorageError::Invalid("non-numeric value".into())),
+        };
+        let number = if decrement {
+            number.saturating_sub(delta)
+        } else {
+            number.wrapping_add(delta)
+        };
+        let value = BulkString(number.to_string().into_bytes().into());
+        self.insert(key.clone(), Value::String(value.clone()));
+        self.propagate(|| RedisCmd::Set(key, value));
+        Ok(Some(number))
+    }
+
+    /// Execute several single key commands in one request, so pipelines don't need a
+    /// round trip for each command
+    #[handle_request]
+    fn batch(&mut self, cmds: Vec<RedisCmd>, touch: bool) -> Vec<RespValue> {
+        chaos::delay(&self.chaos);
+        cmds.into_iter()
+            .map(|cmd| self.execute(cmd, touch))
+            .collect()
+    }
+
+    /// Park a client until one of the keys is written, for blocking commands. Returns false
+    /// without parking it when a key already exists, so a write can't be missed
+    #[handle_request]
+    fn block(&mut self, keys: Vec<RedisKey>, waiter: Waiter) -> bool {
+        if keys.iter().any(|key| self.store.contains_key(key)) {
+            return false;
+        }
+        for key in keys {
+            self.blocked
+                .entry(key)
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        true
+    }
+
+    /// Forget a blocked client, once its timeout expired
+    #[handle_request]
+    fn unblock(&mut self, client: u64) {
+        self.remove_waiter(client);
     }
 
+    /// Keys matching the glob pattern among the next `count` keys of the cursor, and the
+    /// cursor of the next ones, None once all of them were scanned. The keys written between
+    /// the requests are missed or returned twice like with SCAN
     #[handle_request]
-    fn keys(&mut self, _key: RedisKey) -> Vec<RedisKey> {
-        // TODO: handle patterns
-        self.store.keys().cloned().collect()
+    fn keys(
+        &mut self,
+        pattern: RedisKey,
+        cursor: Cursor,
+        count: usize,
+    ) -> (Option<Cursor>, Vec<RedisKey>) {
+        let (keys, next) = self.store.scan(cursor, count);
+        let keys = keys
+            .into_iter()
+            .filter(|key| glob_match(&pattern.0, &key.0))
+            .cloned()
+            .collect();
+        (next, keys)
     }
 
     #[handle_request]
     fn exists(&mut self, key: RedisKey) -> i64 {
-        self.store.contains_key(&key).into()
+        let found = self.store.contains_key(&key);
+        self.count_lookup(found);
+        found.into()
+    }
+
+    /// Seconds since the key was last accessed (OBJECT IDLETIME)
+    #[handle_request]
+    fn idle_time(&mut self, key: RedisKey) -> Option<u64> {
+        self.store.get(&key).map(Entry::idle_time)
+    }
+
+    /// Internal representation of the value (OBJECT ENCODING)
+    #[handle_request]
+    fn encoding(&mut self, key: RedisKey) -> Option<String> {
+        self.store
+            .get(&key)
+            .map(|entry| entry.encoding().to_string())
+    }
+
+    /// Low level details of the key, in the format of redis (DEBUG OBJECT). The shared
+    /// integers have the refcount of the redis shared objects
+    #[handle_request]
+    fn debug_object(&mut self, key: RedisKey) -> Option<String> {
+        self.store.get(&key).map(|entry| {
+            let shared =
+                matches!(&entry.value, Value::String(value) if shared_integer(value).is_some());
+            format!(
+                "refcount:{} encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}",
+                if shared { SHARED_REFCOUNT } else { 1 },
+                entry.encoding(),
+                entry.serialized_length(),
+                entry.last_access & LRU_CLOCK_MAX,
+                entry.idle_time()
+            )
+        })
+    }
+
+    /// Logarithmic access frequency of the key (OBJECT FREQ)
+    #[handle_request]
+    fn frequency(&mut self, key: RedisKey) -> Option<u8> {
+        self.store.get(&key).map(Entry::lfu_decayed)
+    }
+
+    /// Bytes used by the key and its value (MEMORY USAGE)
+    #[handle_request]
+    fn memory_usage(&mut self, key: RedisKey) -> Option<usize> {
+        self.store
+            .get(&key)
+            .map(|entry| entry_size(&key, &entry.value))
+    }
+
+    /// Biggest keys of each type of the `step` entries from the cursor with their sizes,
+    /// and the cursor of the next entries, 0 once all of them were scanned (MEMORY BIGKEYS)
+    #[handle_request]
+    fn biggest_keys(
+        &mut self,
+        cursor: usize,
+        step: usize,
+        count: usize,
+    ) -> (usize, Vec<(String, RedisKey, usize)>) {
+        let end = cursor.saturating_add(step).min(self.store.len());
+        let mut sizes: Vec<_> = (cursor..end)
+            .filter_map(|index| self.store.get_index(index))
+            .map(|(key, entry)| (entry.value.type_name(), key, entry_size(key, &entry.value)))
+            .collect();
+        sizes.sort_unstable_by(|a, b| b.2.cmp(&a.2));
+        let mut listed: HashMap<&str, usize> = HashMap::new();
+        let sizes = sizes
+            .into_iter()
+            .filter(|(type_name, ..)| {
+                let listed = listed.entry(*type_name).or_default();
+                *listed += 1;
+                *listed <= count
+            })
+            .map(|(type_name, key, size)| (type_name.to_string(), key.clone(), size))
+            .collect();
+        let next = if end < self.store.len() { end } else { 0 };
+        (next, sizes)
+    }
+
+    #[handle_request]
+    fn memory_stats(&mut self) -> MemoryStats {
+        MemoryStats {
+            peak: self.peak_memory,
+            used: self.used_memory,
+            maxmemory: self.maxmemory,
+            keys: self.store.len(),
+            dataset: self.used_memory - self.store.len() * ENTRY_OVERHEAD,
+            reclaimed: self.reclaimed_memory,
+            trash_keys: self.trash.len(),
+            trash: self.trash_memory,
+        }
+    }
+
+    /// Periodic maintenance, the long-lived processes give back the capacity left by the
+    /// deleted keys
+    #[handle_message]
+    fn compact(&mut self) {
+        let reclaimed = self.shrink(false);
+        if reclaimed > 0 {
+            debug!("Compaction released {reclaimed} bytes");
+        }
+    }
+
+    /// Restore a deleted key from the trash, unless it was set again meanwhile. Returns
+    /// false when there is nothing to restore
+    #[handle_request]
+    fn undelete(&mut self, key: RedisKey) -> Result<bool, OutOfMemory> {
+        if self.store.contains_key(&key) || !self.trash.contains_key(&key) {
+            return Ok(false);
+        }
+        self.make_room()?;
+        match self.take_from_trash(&key) {
+            Some(entry) => {
+                self.insert(key.clone(), entry.value());
+                self.propagate(|| RedisCmd::Undelete(key));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Drop the keys deleted more than `trash_retention` seconds ago
+    #[handle_message]
+    fn empty_trash(&mut self) {
+        let oldest = now_secs().saturating_sub(self.trash_retention);
+        let trash_memory = &mut self.trash_memory;
+        self.trash.retain(|key, (entry, deleted)| {
+            let kept = *deleted > oldest;
+            if !kept {
+                *trash_memory -= entry_size(key, &entry.value);
+            }
+            kept
+        });
+    }
+
+    /// Keys of the shard (DBSIZE)
+    #[handle_request]
+    fn dbsize(&mut self) -> usize {
+        self.store.len()
+    }
+
+    /// Reply at once, the watchdog checks the process isn't stalled
+    #[handle_request]
+    fn ping(&mut self) {}
+
+    /// Keyspace hits and misses of the reads served by this process
+    #[handle_request]
+    fn keyspace_stats(&mut self) -> (u64, u64) {
+        (self.keyspace_hits, self.keyspace_misses)
+    }
+
+    /// Forget the keyspace hits and misses (CONFIG RESETSTAT)
+    #[handle_request]
+    fn reset_stats(&mut self) {
+        self.keyspace_hits = 0;
+        self.keyspace_misses = 0;
+    }
+
+    /// Release the unused capacity of the keyspace (MEMORY PURGE)
+    #[handle_request]
+    fn purge(&mut self) {
+        self.shrink(true);
     }
 
     #[handle_request]
     fn clear(&mut self) {
-        self.store.clear()
+        self.record(|| Change::Clear, None);
+        if self.trash_retention > 0 {
+            let now = now_secs();
+            let deleted: Vec<_> = self.store.drain().collect();
+            for (key, entry) in deleted {
+                self.add_to_trash(key, entry, now);
+            }
+        } else {
+            self.store.clear();
+        }
+        self.used_memory = 0;
+        self.propagate(|| RedisCmd::FlushDb);
+    }
+
+    /// Set the value at the path of a JSON document, new documents are created by setting
+    /// their root. Returns false when the condition isn't met or the path has no parent
+    #[handle_request]
+    fn json_set(
+        &mut self,
+        key: RedisKey,
+        path: Path,
+        document: Document,
+        condition: Option<Condition>,
+    ) -> Result<bool, StorageError> {
+        self.make_room()?;
+        let updated = self.update_json(&key, |current| {
+            let exists = current.exists(&path);
+            match condition {
+                Some(Condition::Nx) if exists => Ok((false, false)),
+                Some(Condition::Xx) if !exists => Ok((false, false)),
+                _ => {
+                    let set = current.set(&path, &document.0);
+                    Ok((set, set))
+                }
+            }
+        })?;
+        let set = match updated {
+            Some(set) => set,
+            None if !path.is_root() => {
+                return Err(StorageError::Invalid(
+                    "new objects must be created at the root".into(),
+                ))
+            }
+            None if condition == Some(Condition::Xx) => false,
+            None => {
+                self.insert(key.clone(), Value::Json(document.clone()));
+                true
+            }
+        };
+        self.propagate(|| RedisCmd::Json(JsonCmd::Set(key, path, document, condition)));
+        Ok(set)
+    }
+
+    /// Text of the values at the paths of a JSON document, without paths its root
+    #[handle_request]
+    fn json_get(
+        &mut self,
+        key: RedisKey,
+        paths: Vec<Path>,
+        touch: bool,
+    ) -> Result<Option<String>, StorageError> {
+        self.read(&key, touch, |value| match value {
+            Value::Json(document) => document.format(&paths).map_err(StorageError::Invalid),
+            _ => Err(StorageError::WrongType),
+        })
+    }
+
+    /// Remove the values at the path of a JSON document, removing the root deletes the key
+    #[handle_request]
+    fn json_del(&mut self, key: RedisKey, path: Path) -> Result<i64, StorageError> {
+        let is_json = self
+            .store
+            .get(&key)
+            .map(|entry| matches!(entry.value, Value::Json(_)));
+        let removed = match is_json {
+            None => 0,
+            Some(false) => return Err(StorageError::WrongType),
+            Some(true) if path.is_root() => {
+                self.remove(&key);
+                1
+            }
+            Some(true) => self
+                .update_json(&key, |document| {
+                    let removed = document.delete(&path);
+                    Ok((removed, removed > 0))
+                })?
+                .unwrap_or_default(),
+        };
+        self.propagate(|| RedisCmd::Json(JsonCmd::Del(key, path)));
+        Ok(removed as i64)
+    }
+
+    /// Append the values to the arrays at the path of a JSON document, returns the new
+    /// length of each array matched
+    #[handle_request]
+    fn json_arr_append(
+        &mut self,
+        key: RedisKey,
+        path: Path,
+        values: Vec<Document>,
+    ) -> Result<Vec<Option<usize>>, StorageError> {
+        self.make_room()?;
+        let lengths = self
+            .update_json(&key, |document| {
+                let lengths = document
+                    .arr_append(&path, &values)
+                    .map_err(StorageError::Invalid)?;
+                let changed = lengths.iter().any(Option::is_some);
+                Ok((lengths, changed))
+            })?
+            .ok_or_else(|| {
+                StorageError::Invalid(
+                    "could not perform this operation on a key that doesn't exist".into(),
+                )
+            })?;
+        self.propagate(|| RedisCmd::Json(JsonCmd::ArrAppend(key, path, values)));
+        Ok(lengths)
+    }
+
+    /// Create an empty bloom filter (BF.RESERVE)
+    #[handle_request]
+    fn bf_reserve(
+        &mut self,
+        key: RedisKey,
+        error_rate: f64,
+        capacity: usize,
+        expansion: usize,
+    ) -> Result<(), StorageError> {
+        self.make_room()?;
+        if self.store.contains_key(&key) {
+            return Err(StorageError::Invalid("item exists".into()));
+        }
+        let filter = BloomFilter::new(error_rate, capacity, expansion);
+        self.insert(key.clone(), Value::Bloom(filter));
+        self.propagate(|| RedisCmd::Bloom(BloomCmd::Reserve(key, error_rate, capacity, expansion)));
+        Ok(())
+    }
+
+    /// Add the items to the bloom filter, created with the default options when missing.
+    /// Returns if each item was added, false when it may already be in the filter
+    #[handle_request]
+    fn bf_add(
+        &mut self,
+        key: RedisKey,
+        items: Vec<BulkString>,
+    ) -> Result<Vec<Result<bool, String>>, StorageError> {
+        self.make_room()?;
+        if !self.store.contains_key(&key) {
+            let filter = BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY, DEFAULT_EXPANSION);
+            self.insert(key.clone(), Value::Bloom(filter));
+        }
+        let added = self
+            .update_bloom(&key, |filter| {
+                let added: Vec<_> = items.iter().map(|item| filter.add(&item.0)).collect();
+                let changed = added.iter().any(|added| matches!(added, Ok(true)));
+                Ok((added, changed))
+            })?
+            .unwrap_or_default();
+        // BF.ADD is replicated as the BF.MADD of its item
+        self.propagate(|| RedisCmd::Bloom(BloomCmd::MAdd(key, items)));
+        Ok(added)
+    }
+
+    /// If each item may be in the bloom filter, items are never in missing filters
+    #[handle_request]
+    fn bf_exists(
+        &mut self,
+        key: RedisKey,
+        items: Vec<BulkString>,
+        touch: bool,
+    ) -> Result<Vec<bool>, StorageError> {
+        let exists = self.read(&key, touch, |value| match value {
+            Value::Bloom(filter) => Ok(items.iter().map(|item| filter.contains(&item.0)).collect()),
+            _ => Err(StorageError::WrongType),
+        })?;
+        Ok(exists.unwrap_or_else(|| vec![false; items.len()]))
+    }
+
+    /// Create an empty count-min sketch (CMS.INITBYDIM, CMS.INITBYPROB)
+    #[handle_request]
+    fn cms_init(&mut self, key: RedisKey, width: usize, depth: usize) -> Result<(), StorageError> {
+        self.make_room()?;
+        if self.store.contains_key(&key) {
+            return Err(StorageError::Invalid("CMS: key already exists".into()));
+        }
+        let sketch = CountMinSketch::new(width, depth);
+        self.insert(key.clone(), Value::Cms(sketch));
+        self.propagate(|| RedisCmd::Cms(CmsCmd::InitByDim(key, width, depth)));
+        Ok(())
+    }
+
+    /// Increment the counts of the items, returns their new counts
+    #[handle_request]
+    fn cms_incr_by(
+        &mut self,
+        key: RedisKey,
+        increments: Vec<(RedisValue, u64)>,
+    ) -> Result<Vec<u64>, StorageError> {
+        self.make_room()?;
+        let counts = self
+            .update_cms(&key, |sketch| {
+                let counts = increments
+                    .iter()
+                    .map(|(item, increment)| sketch.incr_by(&item.0, *increment))
+                    .collect();
+                Ok((counts, true))
+            })?
+            .ok_or_else(|| StorageError::Invalid("CMS: key does not exist".into()))?;
+        self.propagate(|| RedisCmd::Cms(CmsCmd::IncrBy(key, increments)));
+        Ok(counts)
+    }
+
+    /// Estimated counts of the items
+    #[handle_request]
+    fn cms_query(
+        &mut self,
+        key: RedisKey,
+        items: Vec<RedisValue>,
+        touch: bool,
+    ) -> Result<Vec<u64>, StorageError> {
+        self.read(&key, touch, |value| match value {
+            Value::Cms(sketch) => Ok(items.iter().map(|item| sketch.query(&item.0)).collect()),
+            _ => Err(StorageError::WrongType),
+        })?
+        .ok_or_else(|| StorageError::Invalid("CMS: key does not exist".into()))
+    }
+
+    /// The count-min sketch of the key, the sources of CMS.MERGE can be in other shards
+    #[handle_request]
+    fn cms_sketch(&mut self, key: RedisKey) -> Result<CountMinSketch, StorageError> {
+        self.read(&key, false, |value| match value {
+            Value::Cms(sketch) => Ok(sketch.clone()),
+            _ => Err(StorageError::WrongType),
+        })?
+        .ok_or_else(|| StorageError::Invalid("CMS: key does not exist".into()))
+    }
+
+    /// Replace the sketch by the sum of the sketches times their weights (CMS.MERGE). The
+    /// replicas can't read the sources, so the merge is propagated by its effects
+    #[handle_request]
+    fn cms_merge(
+        &mut self,
+        key: RedisKey,
+        sources: Vec<(CountMinSketch, u64)>,
+    ) -> Result<(), StorageError> {
+        self.make_room()?;
+        self.update_cms(&key, |sketch| {
+            sketch.merge(&sources).map_err(StorageError::Invalid)?;
+            Ok(((), true))
+        })?
+        .ok_or_else(|| StorageError::Invalid("CMS: key does not exist".into()))?;
+        self.propagate_effects();
+        Ok(())
+    }
+
+    /// Create an empty top-k (TOPK.RESERVE)
+    #[handle_request]
+    fn topk_reserve(
+        &mut self,
+        key: RedisKey,
+        k: usize,
+        width: usize,
+        depth: usize,
+        decay: f64,
+    ) -> Result<(), StorageError> {
+        self.make_room()?;
+        if self.store.contains_key(&key) {
+            return Err(StorageError::Invalid("TopK: key already exists".into()));
+        }
+        let top_k = TopK::new(k, width, depth, decay);
+        self.insert(key.clone(), Value::TopK(top_k));
+        self.propagate(|| RedisCmd::TopK(TopKCmd::Reserve(key, k, width, depth, decay)));
+        Ok(())
+    }
+
+    /// Count the items, returns the item expelled from the top-k by each one
+    #[handle_request]
+    fn topk_incr_by(
+        &mut self,
+        key: RedisKey,
+        increments: Vec<(RedisValue, u64)>,
+    ) -> Result<Vec<Option<RedisValue>>, StorageError> {
+        self.make_room()?;
+        let expelled = self
+            .update_topk(&key, |top_k| {
+                let expelled = increments
+                    .iter()
+                    .map(|(item, increment)| top_k.incr_by(item, *increment))
+                    .collect();
+                Ok((expelled, true))
+            })?
+            .ok_or_else(|| StorageError::Invalid("TopK: key does not exist".into()))?;
+        // TOPK.ADD is replicated as the TOPK.INCRBY of its items
+        self.propagate(|| RedisCmd::TopK(TopKCmd::IncrBy(key, increments)));
+        Ok(expelled)
+    }
+
+    /// If each item is in the top-k
+    #[handle_request]
+    fn topk_query(
+        &mut self,
+        key: RedisKey,
+        items: Vec<RedisValue>,
+        touch: bool,
+    ) -> Result<Vec<bool>, StorageError> {
+        self.read(&key, touch, |value| match value {
+            Value::TopK(top_k) => Ok(items.iter().map(|item| top_k.contains(item)).collect()),
+            _ => Err(StorageError::WrongType),
+        })?
+        .ok_or_else(|| StorageError::Invalid("TopK: key does not exist".into()))
+    }
+
+    /// The items of the top-k with their estimated counts, biggest first
+    #[handle_request]
+    fn topk_list(
+        &mut self,
+        key: RedisKey,
+        touch: bool,
+    ) -> Result<Vec<(RedisValue, u64)>, StorageError> {
+        self.read(&key, touch, |value| match value {
+            Value::TopK(top_k) => Ok(top_k.list().to_vec()),
+            _ => Err(StorageError::WrongType),
+        })?
+        .ok_or_else(|| StorageError::Invalid("TopK: key does not exist".into()))
+    }
+
+    /// Create an empty time series (TS.CREATE)
+    #[handle_request]
+    fn ts_create(&mut self, key: RedisKey, options: SeriesOptions) -> Result<(), StorageError> {
+        self.make_room()?;
+        if self.store.contains_key(&key) {
+            return Err(StorageError::Invalid("TSDB: key already exists".into()));
+        }
+        self.insert(key.clone(), Value::Ts(TimeSeries::new(options.clone())));
+        self.propagate(|| RedisCmd::Ts(TsCmd::Create(key, options)));
+        Ok(())
+    }
+
+    /// Add a sample at the timestamp, the current time when None. The series is created
+    /// with the options when missing. Returns the timestamp and the samples closed for the
+    /// compaction rules, to be added to their destination series
+    #[handle_request]
+    fn ts_add(
+        &mut self,
+        key: RedisKey,
+        timestamp: Option<u64>,
+        value: f64,
+        options: Option<SeriesOptions>,
+    ) -> Result<(u64, Vec<(RedisKey, Sample)>), StorageError> {
+        self.make_room()?;
+        let timestamp = timestamp.unwrap_or_else(now_ms);
+        let add = |series: &mut TimeSeries| {
+            let closed = series
+                .add((timestamp, value))
+                .map_err(StorageError::Invalid)?;
+            Ok((closed, true))
+        };
+        let closed = match self.update_ts(&key, add)? {
+            Some(closed) => closed,
+            None => {
+                let mut series = match &options {
+                    Some(options) => TimeSeries::new(options.clone()),
+                    None => {
+                        return Err(StorageError::Invalid("TSDB: the key does not exist".into()))
+                    }
+                };
+                let closed = series
+                    .add((timestamp, value))
+                    .map_err(StorageError::Invalid)?;
+                self.insert(key.clone(), Value::Ts(series));
+                closed
+            }
+        };
+        // The current time is resolved here, so the replicas add the same sample
+        self.propagate(|| RedisCmd::Ts(TsCmd::Add(key, Some(timestamp), value, options)));
+        Ok((timestamp, closed))
+    }
+
+    /// Downsample the series into the destination (TS.CREATERULE), the destination is
+    /// checked by the client since it can be in another shard
+    #[handle_request]
+    fn ts_create_rule(
+        &mut self,
+        key: RedisKey,
+        dest: RedisKey,
+        aggregator: Aggregator,
+    ) -> Result<(), StorageError> {
+        self.update_ts(&key, |series| {
+            if !series.add_rule(dest.clone(), aggregator) {
+                return Err(StorageError::Invalid(
+                    "TSDB: the destination key already has a rule".into(),
+                ));
+            }
+            Ok(((), true))
+        })?
+        .ok_or_else(|| StorageError::Invalid("TSDB: the key does not exist".into()))?;
+        self.propagate(|| RedisCmd::Ts(TsCmd::CreateRule(key, dest, aggregator)));
+        Ok(())
+    }
+
+    /// Samples of the series from a timestamp to another, both included
+    #[handle_request]
+    fn ts_range(
+        &mut self,
+        key: RedisKey,
+        from: u64,
+        to: u64,
+        aggregator: Option<Aggregator>,
+        touch: bool,
+    ) -> Result<Vec<Sample>, StorageError> {
+        self.read(&key, touch, |value| match value {
+            Value::Ts(series) => Ok(series.range(from, to, aggregator)),
+            _ => Err(StorageError::WrongType),
+        })?
+        .ok_or_else(|| StorageError::Invalid("TSDB: the key does not exist".into()))
+    }
+
+    /// Range of all the series matching the filters (TS.MRANGE), with their labels
+    #[handle_request]
+    fn ts_mrange(
+        &mut self,
+        from: u64,
+        to: u64,
+        aggregator: Option<Aggregator>,
+        filters: Vec<Filter>,
+    ) -> Vec<(RedisKey, Vec<(String, String)>, Vec<Sample>)> {
+        (0..self.store.len())
+            .filter_map(|index| self.store.get_index(index))
+            .filter_map(|(key, entry)| match &entry.value {
+                Value::Ts(series) if series.matches(&filters) => Some((
+                    key.clone(),
+                    series.labels().to_vec(),
+                    series.range(from, to, aggregator),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Apply a write of the primary, replicas only receive writes this way
+    #[handle_message]
+    fn apply(&mut self, propagation: Propagation) {
+        match propagation {
+            Propagation::Verbatim(cmd) => {
+                self.execute(cmd, false);
+            }
+            Propagation::Effects(changes) => {
+                for change in changes {
+                    match change {
+                        Change::Set(key, value, version) => {
+                            self.version_clock = self.version_clock.max(version);
+                            self.insert_version(key, value, version);
+                        }
+                        Change::Del(key) => {
+                            self.remove(&key);
+                        }
+                        Change::Clear => self.clear(),
+                    }
+                }
+            }
+        }
+        // Woken once the write is applied, so their next read finds it
+        self.offset += 1;
+        let offset = self.offset;
+        self.offset_waiters.retain(|(target, process, tag)| {
+            if *target <= offset {
+                process.tag_send(*tag, ());
+            }
+            *target > offset
+        });
     }
 }
@@ -0,0 +1,174 @@
+//! OpenTelemetry tracing of the commands. The clients send the span of each traced command
+//! to the telemetry process, which exports them in batches to the OTLP/HTTP endpoint of a
+//! collector as JSON. A client joins the trace of its caller with
+//! `CLIENT SETINFO traceparent <traceparent>`, the other commands are sampled
+//! ```text
+//! moonis --otlp-endpoint http://localhost:4318/v1/traces --trace-sample-ratio 0.01
+//! ```
+
+use std::{
+    mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lunatic::{abstract_process, process::ProcessRef, sleep, Mailbox, Process};
+use lunatic_log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{config::HttpUrl, http::post_json};
+
+/// Spans exported in each request to the collector
+const BATCH_SIZE: usize = 512;
+
+/// Spans kept while the collector is unreachable, the older ones are dropped
+const MAX_PENDING: usize = 8192;
+
+/// The pending spans are exported at least this often
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Nanoseconds since the UNIX epoch, the timestamps of the spans
+pub fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Trace context of the W3C `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceParent {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    /// Parse `00-<trace id>-<span id>-<flags>`, the ids can't be all zeros
+    pub fn parse(text: &str) -> Option<Self> {
+        let parts: Vec<&str> = text.split('-').collect();
+        match parts.as_slice() {
+            ["00", trace_id, span_id, flags]
+                if trace_id.len() == 32 && span_id.len() == 16 && flags.len() == 2 =>
+            {
+                let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+                let span_id = u64::from_str_radix(span_id, 16).ok()?;
+                let flags = u8::from_str_radix(flags, 16).ok()?;
+                (trace_id != 0 && span_id != 0).then_some(Self {
+                    trace_id,
+                    span_id,
+                    sampled: flags & 1 == 1,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Execution of a command by a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Span {
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// Span of the caller, None for the root spans of the sampled commands
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    pub start_ns: u64,
+    pub end_ns: u64,
+    pub attributes: Vec<(String, String)>,
+    /// Error replied by the command
+    pub error: Option<String>,
+}
+
+impl Span {
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+        // Status codes are 1 for ok and 2 for error, the kind 2 is a server span
+        let status = match &self.error {
+            Some(error) => json!({ "code": 2, "message": error }),
+            None => json!({ "code": 1 }),
+        };
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            "kind": 2,
+            "startTimeUnixNano": self.start_ns.to_string(),
+            "endTimeUnixNano": self.end_ns.to_string(),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{parent:016x}"));
+        }
+        span
+    }
+}
+
+/// Request body of the OTLP/HTTP JSON protocol with the spans
+fn export_request(spans: &[Span]) -> String {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "moonis" } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "moonis", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(Span::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+    .to_string()
+}
+
+/// Collects the spans of all the clients and exports them to the collector, a failed
+/// export is retried with the next one
+pub struct Telemetry {
+    endpoint: HttpUrl,
+    pending: Vec<Span>,
+}
+
+#[abstract_process(visibility = pub)]
+impl Telemetry {
+    #[init]
+    fn init(this: ProcessRef<Self>, endpoint: HttpUrl) -> Self {
+        Process::spawn_link(this, |telemetry, _: Mailbox<()>| loop {
+            sleep(EXPORT_INTERVAL);
+            telemetry.export();
+        });
+        Self {
+            endpoint,
+            pending: vec![],
+        }
+    }
+
+    #[handle_message]
+    fn record(&mut self, span: Span) {
+        self.pending.push(span);
+        if self.pending.len() >= BATCH_SIZE {
+            self.export();
+        }
+    }
+
+    #[handle_message]
+    fn export(&mut self) {
+        while !self.pending.is_empty() {
+            let batch: Vec<Span> = self
+                .pending
+                .drain(..BATCH_SIZE.min(self.pending.len()))
+                .collect();
+            if let Err(err) = post_json(&self.endpoint, &export_request(&batch)) {
+                debug!("Can't export the spans to {}: {err}", self.endpoint.addr);
+                let newer = mem::replace(&mut self.pending, batch);
+                self.pending.extend(newer);
+                let dropped = self.pending.len().saturating_sub(MAX_PENDING);
+                self.pending.drain(..dropped);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Time series stored as values (TS.ADD, TS.RANGE...), samples are appended in timestamp
+//! order and dropped once older than the retention. Compaction rules downsample a series
+//! into another one, a bucket is added to the destination once a later sample closes it
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{RedisKey, RespValue};
+
+/// Timestamp in milliseconds and value
+pub type Sample = (u64, f64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Sum,
+}
+
+impl Aggregation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_ref() {
+            "avg" => Some(Aggregation::Avg),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "sum" => Some(Aggregation::Sum),
+            _ => None,
+        }
+    }
+}
+
+/// Aggregation of the samples in buckets of `bucket` milliseconds, aligned to the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Aggregator {
+    pub aggregation: Aggregation,
+    pub bucket: u64,
+}
+
+impl Aggregator {
+    fn bucket_start(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.bucket
+    }
+}
+
+/// Aggregate of the samples of a bucket being filled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Bucket {
+    start: u64,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Bucket {
+    fn new(start: u64, value: f64) -> Self {
+        Self {
+            start,
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn value(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Avg => self.sum / self.count as f64,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Sum => self.sum,
+        }
+    }
+}
+
+/// Compaction rule of TS.CREATERULE
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rule {
+    dest: RedisKey,
+    aggregator: Aggregator,
+    /// The last bucket, added to the destination once a sample of a later bucket comes
+    open: Option<Bucket>,
+}
+
+/// Options of TS.CREATE, also used by TS.ADD when the key doesn't exist
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeriesOptions {
+    /// Milliseconds the samples are kept compared to the last one, 0 keeps them forever
+    pub retention: u64,
+    pub labels: Vec<(String, String)>,
+}
+
+/// Label matcher of TS.MRANGE, `label=value` or `label!=value`. A missing label matches an
+/// empty value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    label: String,
+    value: String,
+    equal: bool,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Option<Self> {
+        let (label, value, equal) = match text.split_once("!=") {
+            Some((label, value)) => (label, value, false),
+            None => {
+                let (label, value) = text.split_once('=')?;
+                (label, value, true)
+            }
+        };
+        if label.is_empty() {
+            return None;
+        }
+        Some(Self {
+            label: label.into(),
+            value: value.into(),
+            equal,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeSeries {
+    samples: VecDeque<Sample>,
+    options: SeriesOptions,
+    rules: Vec<Rule>,
+}
+
+impl TimeSeries {
+    pub fn new(options: SeriesOptions) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            options,
+            rules: vec![],
+        }
+    }
+
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.options.labels
+    }
+
+    /// Add a sample, returns the buckets closed by it for each compaction rule. Samples
+    /// older than the last bucket of a rule aren't compacted
+    pub fn add(&mut self, (timestamp, value): Sample) -> Result<Vec<(RedisKey, Sample)>, String> {
+        let last = self.samples.back().map(|(last, _)| *last);
+        let retention = self.options.retention;
+        if let Some(last) = last {
+            if retention > 0 && timestamp.saturating_add(retention) < last {
+                return Err("TSDB: Timestamp is older than retention".into());
+            }
+        }
+        // Appending is the common case, older samples are inserted at their position
+        match last {
+            Some(last) if timestamp <= last => {
+                match self
+                    .samples
+                    .binary_search_by_key(&timestamp, |(time, _)| *time)
+                {
+                    Ok(_) => {
+                        return Err("TSDB: Error at upsert, update is not supported when \
+                                    DUPLICATE_POLICY is set to BLOCK mode"
+                            .into())
+                    }
+                    Err(position) => self.samples.insert(position, (timestamp, value)),
+                }
+            }
+            _ => self.samples.push_back((timestamp, value)),
+        }
+        if let Some(last) = self.samples.back().map(|(last, _)| *last) {
+            while retention > 0
+                && self
+                    .samples
+                    .front()
+                    .is_some_and(|(time, _)| time.saturating_add(retention) < last)
+            {
+                self.samples.pop_front();
+            }
+        }
+
+        let mut closed = vec![];
+        for rule in &mut self.rules {
+            let start = rule.aggregator.bucket_start(timestamp);
+            match &mut rule.open {
+                Some(open) if open.start == start => open.add(value),
+                Some(open) if open.start > start => (),
+                open => {
+                    if let Some(bucket) = open.replace(Bucket::new(start, value)) {
+                        let sample = (bucket.start, bucket.value(rule.aggregator.aggregation));
+                        closed.push((rule.dest.clone(), sample));
+                    }
+                }
+            }
+        }
+        Ok(closed)
+    }
+
+    /// Add a compaction rule, returns false when there is already one for the destination
+    pub fn add_rule(&mut self, dest: RedisKey, aggregator: Aggregator) -> bool {
+        if self.rules.iter().any(|rule| rule.dest == dest) {
+            return false;
+        }
+        self.rules.push(Rule {
+            dest,
+            aggregator,
+            open: None,
+        });
+        true
+    }
+
+    /// Samples from `from` to `to` included, aggregated when there is an aggregator
+    pub fn range(&self, from: u64, to: u64, aggregator: Option<Aggregator>) -> Vec<Sample> {
+        let samples = self
+            .samples
+            .iter()
+            .filter(|(time, _)| (from..=to).contains(time));
+        let aggregator = match aggregator {
+            Some(aggregator) => aggregator,
+            None => return samples.copied().collect(),
+        };
+        let mut buckets: Vec<Bucket> = vec![];
+        for (time, value) in samples {
+            let start = aggregator.bucket_start(*time);
+            match buckets.last_mut() {
+                Some(bucket) if bucket.start == start => bucket.add(*value),
+                _ => buckets.push(Bucket::new(start, *value)),
+            }
+        }
+        buckets
+            .iter()
+            .map(|bucket| (bucket.start, bucket.value(aggregator.aggregation)))
+            .collect()
+    }
+
+    pub fn matches(&self, filters: &[Filter]) -> bool {
+        filters.iter().all(|filter| {
+            let value = self
+                .options
+                .labels
+                .iter()
+                .find(|(label, _)| *label == filter.label)
+                .map_or("", |(_, value)| value.as_str());
+            (value == filter.value) == filter.equal
+        })
+    }
+
+    /// Bytes used by the samples, the labels and the rules
+    pub fn size(&self) -> usize {
+        let labels: usize = self
+            .options
+            .labels
+            .iter()
+            .map(|(label, value)| label.len() + value.len())
+            .sum();
+        let rules: usize = self.rules.iter().map(|rule| rule.dest.0.len() + 48).sum();
+        self.samples.len() * 16 + labels + rules
+    }
+}
+
+/// Reply of the samples of TS.RANGE, each one a timestamp and its value
+pub fn samples_reply(samples: Vec<Sample>) -> RespValue {
+    RespValue::Array(
+        samples
+            .into_iter()
+            .map(|(timestamp, value)| {
+                RespValue::Array(
+                    [
+                        RespValue::Integer(timestamp as i64),
+                        RespValue::SimpleString(value.to_string()),
+                    ]
+                    .into(),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Reply of the labels of a series, each one a pair of its name and value
+pub fn labels_reply(labels: Vec<(String, String)>) -> RespValue {
+    RespValue::Array(
+        labels
+            .into_iter()
+            .map(|(label, value)| {
+                RespValue::Array(
+                    [
+                        RespValue::SimpleString(label),
+                        RespValue::SimpleString(value),
+                    ]
+                    .into(),
+                )
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BulkString;
+
+    fn series(retention: u64) -> TimeSeries {
+        TimeSeries::new(SeriesOptions {
+            retention,
+            labels: vec![("sensor".into(), "a".into()), ("room".into(), "1".into())],
+        })
+    }
+
+    fn aggregator(aggregation: Aggregation, bucket: u64) -> Aggregator {
+        Aggregator {
+            aggregation,
+            bucket,
+        }
+    }
+
+    #[test]
+    fn aggregations_are_parsed_ignoring_case() {
+        assert_eq!(Aggregation::parse("AVG"), Some(Aggregation::Avg));
+        assert_eq!(Aggregation::parse("max"), Some(Aggregation::Max));
+        assert_eq!(Aggregation::parse("count"), None);
+    }
+
+    #[test]
+    fn samples_are_kept_in_timestamp_order() {
+        let mut series = series(0);
+        for timestamp in [3000, 1000, 2000] {
+            assert_eq!(series.add((timestamp, 1.0)), Ok(vec![]));
+        }
+        assert_eq!(
+            series.range(0, u64::MAX, None),
+            [(1000, 1.0), (2000, 1.0), (3000, 1.0)]
+        );
+        assert_eq!(series.range(1500, 2000, None), [(2000, 1.0)]);
+        assert!(series
+            .add((2000, 2.0))
+            .unwrap_err()
+            .starts_with("TSDB: Error at upsert"));
+    }
+
+    #[test]
+    fn samples_older_than_the_retention_are_dropped() {
+        let mut series = series(1000);
+        series.add((1000, 1.0)).unwrap();
+        series.add((1500, 2.0)).unwrap();
+        series.add((2500, 3.0)).unwrap();
+        assert_eq!(series.range(0, u64::MAX, None), [(1500, 2.0), (2500, 3.0)]);
+        assert_eq!(
+            series.add((1000, 4.0)),
+            Err("TSDB: Timestamp is older than retention".into())
+        );
+    }
+
+    #[test]
+    fn range_aggregates_the_buckets() {
+        let mut series = series(0);
+        for (timestamp, value) in [(1000, 1.0), (1500, 3.0), (2100, 5.0), (4000, 7.0)] {
+            series.add((timestamp, value)).unwrap();
+        }
+        let range = |aggregation| series.range(0, u64::MAX, Some(aggregator(aggregation, 1000)));
+        assert_eq!(
+            range(Aggregation::Avg),
+            [(1000, 2.0), (2000, 5.0), (4000, 7.0)]
+        );
+        assert_eq!(
+            range(Aggregation::Min),
+            [(1000, 1.0), (2000, 5.0), (4000, 7.0)]
+        );
+        assert_eq!(
+            range(Aggregation::Max),
+            [(1000, 3.0), (2000, 5.0), (4000, 7.0)]
+        );
+        assert_eq!(
+            range(Aggregation::Sum),
+            [(1000, 4.0), (2000, 5.0), (4000, 7.0)]
+        );
+    }
+
+    #[test]
+    fn rules_add_the_closed_buckets() {
+        let mut series = series(0);
+        let dest = BulkString("dest".to_string().into());
+        assert!(series.add_rule(dest.clone(), aggregator(Aggregation::Sum, 1000)));
+        assert!(!series.add_rule(dest.clone(), aggregator(Aggregation::Max, 1000)));
+        assert_eq!(series.add((1000, 1.0)), Ok(vec![]));
+        assert_eq!(series.add((1999, 2.0)), Ok(vec![]));
+        assert_eq!(series.add((3000, 4.0)), Ok(vec![(dest, (1000, 3.0))]));
+        // Samples of the closed buckets aren't compacted
+        assert_eq!(series.add((1500, 8.0)), Ok(vec![]));
+    }
+
+    #[test]
+    fn filters_match_the_labels() {
+        let series = series(0);
+        let matches = |filters: &[&str]| {
+            let filters: Vec<_> = filters.iter().map(|f| Filter::parse(f).unwrap()).collect();
+            series.matches(&filters)
+        };
+        assert!(matches(&["sensor=a"]));
+        assert!(matches(&["sensor=a", "room!=2"]));
+        assert!(!matches(&["sensor=a", "room=2"]));
+        assert!(!matches(&["sensor!=a"]));
+        // A missing label matches an empty value
+        assert!(matches(&["floor="]));
+        assert!(!matches(&["floor!="]));
+        assert!(Filter::parse("=a").is_none());
+        assert!(Filter::parse("sensor").is_none());
+    }
+}
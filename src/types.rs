@@ -1,15 +1,34 @@
 use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fmt;
+use std::mem;
 
+use crate::{
+    commands::{self, Flag},
+    json::{Condition, Document, Path},
+    timeseries::{Aggregator, Filter, SeriesOptions},
+};
+
+/// Binary safe string, the bytes are reference counted so clones don't copy the data.
+/// Messages to other processes carry it as a single length-prefixed byte frame, copied
+/// once into the receiving process since processes don't share memory
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct BulkString(pub Vec<u8>);
+pub struct BulkString(pub Bytes);
 
 impl BulkString {
-    pub fn append(&mut self, other: &mut BulkString) {
-        self.0.append(&mut other.0);
+    /// Append in place when no other clone shares the bytes, the buffer grows to at least
+    /// double its capacity so repeated APPENDs copy the value only O(log n) times
+    pub fn append(&mut self, other: &BulkString) {
+        let mut buffer = match mem::take(&mut self.0).try_into_mut() {
+            Ok(buffer) => buffer,
+            Err(shared) => BytesMut::from(&shared[..]),
+        };
+        buffer.reserve(other.0.len());
+        buffer.extend_from_slice(&other.0);
+        self.0 = buffer.freeze();
     }
 }
 
@@ -17,7 +36,7 @@ impl fmt::Display for BulkString {
     /// Try to display a friendly string, not a vec of u8
     /// most of the time BulkStrings are a string, but can be used to store binary data
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match String::from_utf8(self.0.to_vec()) {
+        match std::str::from_utf8(&self.0) {
             Ok(value) => write!(f, "{}", value),
             Err(_) => write!(f, "{:?}", self.0),
         }
@@ -63,21 +82,344 @@ impl RespValue {
 pub type RedisKey = BulkString;
 pub type RedisValue = BulkString;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RedisCmd {
     Ping(Option<RedisValue>),
+    Quit,
     Get(RedisKey),
+    MGet(Vec<RedisKey>),
     Delete(Vec<RedisKey>),
     Set(RedisKey, RedisValue),
     Append(RedisKey, RedisValue),
+    /// Key, expected version and value
+    Cas(RedisKey, u64, RedisValue),
+    GetVer(RedisKey),
+    Undelete(RedisKey),
+    /// Unsigned increment of memcached, wrapping around on overflow, or decrement stopping
+    /// at 0 when the flag is set
+    McIncr(RedisKey, u64, bool),
     Keys(RedisValue),
+    DbSize,
     Exists(RedisKey),
     FlushAll,
+    FlushDb,
+    Select(RedisValue),
     Command,
+    Client(ClientCmd),
+    Hello(
+        Option<i64>,
+        Option<(RedisValue, RedisValue)>,
+        Option<RedisValue>,
+    ),
+    Time,
+    Lolwut,
+    Auth(Option<RedisValue>, RedisValue),
+    Acl(AclCmd),
+    Object(ObjectCmd),
+    Memory(MemoryCmd),
+    Config(ConfigCmd),
+    /// Lowercase names of the sections, empty for the default ones
+    Info(Vec<String>),
+    Debug(DebugCmd),
+    Json(JsonCmd),
+    Bloom(BloomCmd),
+    Cms(CmsCmd),
+    TopK(TopKCmd),
+    Ts(TsCmd),
+}
+
+impl RedisCmd {
+    /// Lowercase name of the command, as used by ACL rules
+    pub fn name(&self) -> &'static str {
+        use RedisCmd::*;
+        match self {
+            Ping(_) => "ping",
+            Quit => "quit",
+            Get(_) => "get",
+            MGet(_) => "mget",
+            Delete(_) => "del",
+            Set(..) => "set",
+            Append(..) => "append",
+            Cas(..) => "cas",
+            GetVer(_) => "getver",
+            Undelete(_) => "undelete",
+            McIncr(_, _, false) => "mc.incr",
+            McIncr(_, _, true) => "mc.decr",
+            Keys(_) => "keys",
+            DbSize => "dbsize",
+            Exists(_) => "exists",
+            FlushAll => "flushall",
+            FlushDb => "flushdb",
+            Select(_) => "select",
+            Command => "command",
+            Client(_) => "client",
+            Hello(..) => "hello",
+            Time => "time",
+            Lolwut => "lolwut",
+            Auth(..) => "auth",
+            Acl(_) => "acl",
+            Object(_) => "object",
+            Memory(_) => "memory",
+            Config(_) => "config",
+            Info(_) => "info",
+            Debug(_) => "debug",
+            Json(cmd) => match cmd {
+                JsonCmd::Set(..) => "json.set",
+                JsonCmd::Get(..) => "json.get",
+                JsonCmd::Del(..) => "json.del",
+                JsonCmd::ArrAppend(..) => "json.arrappend",
+            },
+            Bloom(cmd) => match cmd {
+                BloomCmd::Reserve(..) => "bf.reserve",
+                BloomCmd::Add(..) => "bf.add",
+                BloomCmd::MAdd(..) => "bf.madd",
+                BloomCmd::Exists(..) => "bf.exists",
+            },
+            Cms(cmd) => match cmd {
+                CmsCmd::InitByDim(..) => "cms.initbydim",
+                CmsCmd::InitByProb(..) => "cms.initbyprob",
+                CmsCmd::IncrBy(..) => "cms.incrby",
+                CmsCmd::Query(..) => "cms.query",
+                CmsCmd::Merge(..) => "cms.merge",
+            },
+            TopK(cmd) => match cmd {
+                TopKCmd::Reserve(..) => "topk.reserve",
+                TopKCmd::Add(..) => "topk.add",
+                TopKCmd::IncrBy(..) => "topk.incrby",
+                TopKCmd::Query(..) => "topk.query",
+                TopKCmd::List(..) => "topk.list",
+            },
+            Ts(cmd) => match cmd {
+                TsCmd::Create(..) => "ts.create",
+                TsCmd::Add(..) => "ts.add",
+                TsCmd::CreateRule(..) => "ts.createrule",
+                TsCmd::Range(..) => "ts.range",
+                TsCmd::MRange(..) => "ts.mrange",
+            },
+        }
+    }
+
+    /// Uppercase name of the subcommand, for the commands with subcommands
+    pub fn subcommand(&self) -> Option<&'static str> {
+        use RedisCmd::*;
+        match self {
+            Client(cmd) => Some(match cmd {
+                ClientCmd::Id => "ID",
+                ClientCmd::GetName => "GETNAME",
+                ClientCmd::SetName(_) => "SETNAME",
+                ClientCmd::List => "LIST",
+                ClientCmd::Info => "INFO",
+                ClientCmd::Reply(_) => "REPLY",
+                ClientCmd::NoEvict(_) => "NO-EVICT",
+                ClientCmd::NoTouch(_) => "NO-TOUCH",
+                ClientCmd::SetInfo(..) => "SETINFO",
+                ClientCmd::Consistency(_) => "CONSISTENCY",
+            }),
+            Acl(cmd) => Some(match cmd {
+                AclCmd::SetUser(..) => "SETUSER",
+                AclCmd::GetUser(_) => "GETUSER",
+                AclCmd::DelUser(_) => "DELUSER",
+                AclCmd::List => "LIST",
+                AclCmd::Users => "USERS",
+                AclCmd::Load => "LOAD",
+                AclCmd::Save => "SAVE",
+                AclCmd::Log(_) | AclCmd::LogReset => "LOG",
+                AclCmd::WhoAmI => "WHOAMI",
+                AclCmd::Cat(_) => "CAT",
+            }),
+            Object(cmd) => Some(match cmd {
+                ObjectCmd::IdleTime(_) => "IDLETIME",
+                ObjectCmd::Freq(_) => "FREQ",
+                ObjectCmd::Encoding(_) => "ENCODING",
+            }),
+            Memory(cmd) => Some(match cmd {
+                MemoryCmd::Usage(_) => "USAGE",
+                MemoryCmd::BigKeys(_) => "BIGKEYS",
+                MemoryCmd::Stats => "STATS",
+                MemoryCmd::Doctor => "DOCTOR",
+                MemoryCmd::Purge => "PURGE",
+            }),
+            Config(cmd) => Some(match cmd {
+                ConfigCmd::Get(_) => "GET",
+                ConfigCmd::ResetStat => "RESETSTAT",
+            }),
+            Debug(cmd) => Some(match cmd {
+                DebugCmd::LoadProto(_) => "LOADPROTO",
+                DebugCmd::CommandId => "COMMANDID",
+                DebugCmd::Object(_) => "OBJECT",
+            }),
+            _ => None,
+        }
+    }
+
+    /// Writes replicated by executing them again, the others are replicated by their
+    /// effects (ie. SPOP as SREM, INCRBYFLOAT as SET)
+    pub fn is_deterministic(&self) -> bool {
+        commands::flags(self).contains(&Flag::Deterministic)
+    }
+
+    /// Single key commands executed by Storage, they can be sent together in a batch
+    pub fn is_batchable(&self) -> bool {
+        commands::flags(self).contains(&Flag::Batchable)
+    }
+
+    /// Keys accessed by the command
+    pub fn keys(&self) -> Vec<RedisKey> {
+        commands::keys(self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientCmd {
+    Id,
+    GetName,
+    SetName(RedisValue),
+    List,
+    /// CLIENT LIST line of the connection
+    Info,
+    Reply(ReplyMode),
+    NoEvict(bool),
+    NoTouch(bool),
+    /// Attribute of the connection and its value
+    SetInfo(String, RedisValue),
+    Consistency(Consistency),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AclCmd {
+    SetUser(String, Vec<String>),
+    GetUser(String),
+    DelUser(Vec<String>),
+    List,
+    Users,
+    Load,
+    Save,
+    Log(usize),
+    LogReset,
+    WhoAmI,
+    Cat(Option<String>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ObjectCmd {
+    IdleTime(RedisKey),
+    Freq(RedisKey),
+    Encoding(RedisKey),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MemoryCmd {
+    Usage(RedisKey),
+    /// Number of keys listed for each type
+    BigKeys(usize),
+    Stats,
+    Doctor,
+    Purge,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConfigCmd {
+    /// Parameters matching any of the glob patterns
+    Get(Vec<String>),
+    ResetStat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DebugCmd {
+    /// Commands encoded as RESP, like the input of `redis-cli --pipe`
+    LoadProto(RedisValue),
+    /// Id of the command in its connection, the previous commands have lower ids
+    CommandId,
+    /// Refcount, encoding, serialized length and LRU clock of the key
+    Object(RedisKey),
+}
+
+/// Commands of the JSON documents, each one is a command of its own (JSON.SET...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JsonCmd {
+    Set(RedisKey, Path, Document, Option<Condition>),
+    /// Without paths the whole document is returned
+    Get(RedisKey, Vec<Path>),
+    Del(RedisKey, Path),
+    ArrAppend(RedisKey, Path, Vec<Document>),
+}
+
+/// Commands of the bloom filters (BF.RESERVE...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BloomCmd {
+    /// Error rate, capacity and expansion, 0 for the filters that don't scale
+    Reserve(RedisKey, f64, usize, usize),
+    Add(RedisKey, RedisValue),
+    MAdd(RedisKey, Vec<RedisValue>),
+    Exists(RedisKey, RedisValue),
+}
+
+/// Commands of the count-min sketches (CMS.INITBYDIM...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CmsCmd {
+    /// Width and depth
+    InitByDim(RedisKey, usize, usize),
+    /// Error and probability of the error
+    InitByProb(RedisKey, f64, f64),
+    IncrBy(RedisKey, Vec<(RedisValue, u64)>),
+    Query(RedisKey, Vec<RedisValue>),
+    /// Destination, sources and the weight of each source
+    Merge(RedisKey, Vec<RedisKey>, Vec<u64>),
+}
+
+/// Commands of the top-k heavy hitters (TOPK.RESERVE...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TopKCmd {
+    /// K, width, depth and decay
+    Reserve(RedisKey, usize, usize, usize, f64),
+    Add(RedisKey, Vec<RedisValue>),
+    IncrBy(RedisKey, Vec<(RedisValue, u64)>),
+    Query(RedisKey, Vec<RedisValue>),
+    /// If the counts are listed with the items (WITHCOUNT)
+    List(RedisKey, bool),
+}
+
+/// Commands of the time series (TS.CREATE...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TsCmd {
+    Create(RedisKey, SeriesOptions),
+    /// Timestamp, None for the current time, and value. The options create the missing
+    /// series, the samples of compaction rules have none and need an existing series
+    Add(RedisKey, Option<u64>, f64, Option<SeriesOptions>),
+    /// Source, destination and the aggregation of the destination's samples
+    CreateRule(RedisKey, RedisKey, Aggregator),
+    /// Samples from a timestamp to another, both included
+    Range(RedisKey, u64, u64, Option<Aggregator>),
+    /// Range of all the series matching the filters, with their labels when set
+    MRange(u64, u64, Option<Aggregator>, Vec<Filter>, bool),
+}
+
+/// Controls if the server replies to the client commands (CLIENT REPLY)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplyMode {
+    On,
+    Off,
+    /// Skip the reply of the next command
+    Skip,
+}
+
+/// Processes serving the reads of a client when there are storage replicas
+/// (CLIENT CONSISTENCY). The replicas apply the writes after the primary, so reading from
+/// them spreads the load but can miss the latest writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Consistency {
+    /// Only the primary, never stale but it serves all the reads
+    Primary,
+    /// Any replica in turns, the fastest and the default
+    #[default]
+    Replica,
+    /// A replica once it applied the writes of the client, waiting for it up to 100ms
+    /// before reading from the primary. Each read after a write asks the primary its offset
+    ReadYourWrites,
 }
 
 /// Get the next argument from a RespValue::Array
-fn get_next_value(resp: &mut VecDeque<RespValue>) -> Result<BulkString> {
+pub(crate) fn get_next_value(resp: &mut VecDeque<RespValue>) -> Result<BulkString> {
     let value = resp
         .pop_front()
         .ok_or_else(|| anyhow::anyhow!("Not enough arguments"))?;
@@ -87,6 +429,215 @@ fn get_next_value(resp: &mut VecDeque<RespValue>) -> Result<BulkString> {
     }
 }
 
+/// Get all the remaining arguments as strings
+pub(crate) fn get_remaining_strings(resp: &mut VecDeque<RespValue>) -> Result<Vec<String>> {
+    resp.drain(..)
+        .map(|value| match value {
+            RespValue::BulkString(value) => Ok(value.to_string()),
+            _ => Err(anyhow!("Invalid argument, must be BulkString")),
+        })
+        .collect()
+}
+
+/// Get the next argument as an ON/OFF switch
+fn get_next_switch(resp: &mut VecDeque<RespValue>) -> Result<bool> {
+    match get_next_value(resp)?.to_string().to_uppercase().as_ref() {
+        "ON" => Ok(true),
+        "OFF" => Ok(false),
+        _ => Err(anyhow!("Invalid argument, must be ON or OFF")),
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for ClientCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the CLIENT command into a ClientCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No CLIENT subcommand specified")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            "ID" => Ok(ClientCmd::Id),
+            "GETNAME" => Ok(ClientCmd::GetName),
+            "SETNAME" => Ok(ClientCmd::SetName(
+                get_next_value(&mut resp).context("Name must be set for CLIENT SETNAME")?,
+            )),
+            "LIST" => Ok(ClientCmd::List),
+            "INFO" => Ok(ClientCmd::Info),
+            "REPLY" => {
+                let mode =
+                    get_next_value(&mut resp).context("Mode must be set for CLIENT REPLY")?;
+                match mode.to_string().to_uppercase().as_ref() {
+                    "ON" => Ok(ClientCmd::Reply(ReplyMode::On)),
+                    "OFF" => Ok(ClientCmd::Reply(ReplyMode::Off)),
+                    "SKIP" => Ok(ClientCmd::Reply(ReplyMode::Skip)),
+                    _ => Err(anyhow!("Invalid CLIENT REPLY mode")),
+                }
+            }
+            "NO-EVICT" => Ok(ClientCmd::NoEvict(
+                get_next_switch(&mut resp).context("Can't get the mode of CLIENT NO-EVICT")?,
+            )),
+            "NO-TOUCH" => Ok(ClientCmd::NoTouch(
+                get_next_switch(&mut resp).context("Can't get the mode of CLIENT NO-TOUCH")?,
+            )),
+            "SETINFO" => Ok(ClientCmd::SetInfo(
+                get_next_value(&mut resp)
+                    .context("Attribute must be set for CLIENT SETINFO")?
+                    .to_string(),
+                get_next_value(&mut resp).context("Value must be set for CLIENT SETINFO")?,
+            )),
+            "CONSISTENCY" => {
+                let level = get_next_value(&mut resp)
+                    .context("Level must be set for CLIENT CONSISTENCY")?;
+                match level.to_string().to_uppercase().as_ref() {
+                    "PRIMARY" => Ok(ClientCmd::Consistency(Consistency::Primary)),
+                    "REPLICA" => Ok(ClientCmd::Consistency(Consistency::Replica)),
+                    "READ-YOUR-WRITES" => Ok(ClientCmd::Consistency(Consistency::ReadYourWrites)),
+                    _ => Err(anyhow!("Invalid CLIENT CONSISTENCY level")),
+                }
+            }
+            _ => Err(anyhow!("Invalid CLIENT subcommand")),
+        }
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for AclCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the ACL command into an AclCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No ACL subcommand specified")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            "SETUSER" => {
+                let mut args = get_remaining_strings(&mut resp)?;
+                if args.is_empty() {
+                    bail!("Username must be set for ACL SETUSER");
+                }
+                let username = args.remove(0);
+                Ok(AclCmd::SetUser(username, args))
+            }
+            "GETUSER" => Ok(AclCmd::GetUser(
+                get_next_value(&mut resp)
+                    .context("Username must be set for ACL GETUSER")?
+                    .to_string(),
+            )),
+            "DELUSER" => {
+                let usernames = get_remaining_strings(&mut resp)?;
+                if usernames.is_empty() {
+                    bail!("Username must be set for ACL DELUSER");
+                }
+                Ok(AclCmd::DelUser(usernames))
+            }
+            "LIST" => Ok(AclCmd::List),
+            "USERS" => Ok(AclCmd::Users),
+            "LOAD" => Ok(AclCmd::Load),
+            "SAVE" => Ok(AclCmd::Save),
+            "LOG" => match get_next_value(&mut resp).ok() {
+                Some(arg) if arg.to_string().to_uppercase() == "RESET" => Ok(AclCmd::LogReset),
+                Some(count) => Ok(AclCmd::Log(
+                    count
+                        .to_string()
+                        .parse()
+                        .context("ACL LOG count must be an integer")?,
+                )),
+                None => Ok(AclCmd::Log(10)),
+            },
+            "WHOAMI" => Ok(AclCmd::WhoAmI),
+            "CAT" => Ok(AclCmd::Cat(
+                get_next_value(&mut resp)
+                    .ok()
+                    .map(|category| category.to_string()),
+            )),
+            _ => Err(anyhow!("Invalid ACL subcommand")),
+        }
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for ObjectCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the OBJECT command into an ObjectCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No OBJECT subcommand specified")?;
+        let key = get_next_value(&mut resp).context("Key must be set for OBJECT")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            "IDLETIME" => Ok(ObjectCmd::IdleTime(key)),
+            "FREQ" => Ok(ObjectCmd::Freq(key)),
+            "ENCODING" => Ok(ObjectCmd::Encoding(key)),
+            _ => Err(anyhow!("Invalid OBJECT subcommand")),
+        }
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for MemoryCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the MEMORY command into a MemoryCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No MEMORY subcommand specified")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            // SAMPLES is accepted but ignored, values are strings so the size is always exact
+            "USAGE" => Ok(MemoryCmd::Usage(
+                get_next_value(&mut resp).context("Key must be set for MEMORY USAGE")?,
+            )),
+            "BIGKEYS" => match get_next_value(&mut resp).ok() {
+                None => Ok(MemoryCmd::BigKeys(10)),
+                Some(option) if option.to_string().eq_ignore_ascii_case("COUNT") => {
+                    Ok(MemoryCmd::BigKeys(
+                        get_next_value(&mut resp)
+                            .context("Count must be set for MEMORY BIGKEYS")?
+                            .to_string()
+                            .parse()
+                            .context("Count is not an integer")?,
+                    ))
+                }
+                Some(_) => Err(anyhow!("Invalid MEMORY BIGKEYS option")),
+            },
+            "STATS" => Ok(MemoryCmd::Stats),
+            "DOCTOR" => Ok(MemoryCmd::Doctor),
+            "PURGE" => Ok(MemoryCmd::Purge),
+            _ => Err(anyhow!("Invalid MEMORY subcommand")),
+        }
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for ConfigCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the CONFIG command into a ConfigCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No CONFIG subcommand specified")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            "GET" => {
+                let patterns = get_remaining_strings(&mut resp)?;
+                if patterns.is_empty() {
+                    bail!("Parameter must be set for CONFIG GET");
+                }
+                Ok(ConfigCmd::Get(patterns))
+            }
+            "RESETSTAT" => Ok(ConfigCmd::ResetStat),
+            _ => Err(anyhow!("Invalid CONFIG subcommand")),
+        }
+    }
+}
+
+impl TryFrom<VecDeque<RespValue>> for DebugCmd {
+    type Error = anyhow::Error;
+
+    /// Convert the arguments of the DEBUG command into a DebugCmd
+    fn try_from(mut resp: VecDeque<RespValue>) -> Result<Self, Self::Error> {
+        let subcommand = get_next_value(&mut resp).context("No DEBUG subcommand specified")?;
+        match subcommand.to_string().to_uppercase().as_ref() {
+            "LOADPROTO" => Ok(DebugCmd::LoadProto(
+                get_next_value(&mut resp).context("Payload must be set for DEBUG LOADPROTO")?,
+            )),
+            "COMMANDID" => Ok(DebugCmd::CommandId),
+            "OBJECT" => Ok(DebugCmd::Object(
+                get_next_value(&mut resp).context("Key must be set for DEBUG OBJECT")?,
+            )),
+            _ => Err(anyhow!("Invalid DEBUG subcommand")),
+        }
+    }
+}
+
 impl TryFrom<RespValue> for RedisCmd {
     type Error = anyhow::Error;
 
@@ -102,39 +653,13 @@ impl TryFrom<RespValue> for RedisCmd {
             .pop_front()
             .ok_or_else(|| anyhow::anyhow!("No command specified"))?;
 
-        match cmd.to_string().unwrap_or_default().to_uppercase().as_ref() {
-            "GET" => Ok(RedisCmd::Get(get_next_value(&mut resp)?)),
-            "SET" => Ok(RedisCmd::Set(
-                get_next_value(&mut resp).context("Can't get the key of set CMD")?,
-                get_next_value(&mut resp).context("Value must be set for set CMD")?,
-            )),
-            "DEL" => {
-                // let mut keys = Vec::with_capacity(resp.len());
-                // keys = resp.into();
-                Ok(RedisCmd::Delete(
-                    resp.drain(..)
-                        .map(|key| match key {
-                            RespValue::BulkString(key) => key,
-                            other => unreachable!("Invalid Type for key: {other:?}"),
-                        })
-                        .collect(),
-                ))
-            }
-            "APPEND" => Ok(RedisCmd::Append(
-                get_next_value(&mut resp).context("Can't get the key of append CMD")?,
-                get_next_value(&mut resp).context("Value must be set for append CMD")?,
-            )),
-            // "CONFIG" => Ok(RedisCmd::Append(
-            //     get_next_value(&mut resp).context("Can't get the key of append CMD")?,
-            //     get_next_value(&mut resp).context("Value must be set for append CMD")?,
-            // )),
-            "PING" => Ok(RedisCmd::Ping(get_next_value(&mut resp).ok())),
-            "KEYS" => Ok(RedisCmd::Keys(get_next_value(&mut resp)?)),
-            "EXISTS" => Ok(RedisCmd::Exists(get_next_value(&mut resp)?)),
-            "FLUSHALL" => Ok(RedisCmd::FlushAll),
-            "COMMAND" => Ok(RedisCmd::Command),
-            "" => Err(anyhow!("No command specified")),
-            _ => Err(anyhow!("Invalid Command")),
+        let name = cmd.to_string().unwrap_or_default();
+        if name.is_empty() {
+            bail!("No command specified");
+        }
+        match commands::lookup(&name) {
+            Some(handler) => handler.parse(resp),
+            None => Err(anyhow!("Invalid Command")),
         }
     }
 }
@@ -0,0 +1,144 @@
+//! Watchdog of the storage processes. Each primary and replica is pinged with a deadline,
+//! so a stalled shard is reported instead of the server silently hanging, and optionally
+//! killed to be restarted by its supervisor
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lunatic::{process::ProcessRef, sleep, Mailbox, MailboxResult, Process, Tag};
+use lunatic_log::{debug, error, info, warn};
+
+use crate::{
+    config::Config,
+    shards::{replica_name, shard_name},
+    storage::{Storage, StorageHandler},
+};
+
+/// Consecutive missed deadlines before a stalled process is killed, when restarts are
+/// enabled. A single slow command, like KEYS on a big database, isn't enough
+const MISSES_BEFORE_RESTART: u32 = 3;
+
+/// Storage process watched, with its database to find the other processes it can be
+/// waiting on
+struct Watched {
+    name: String,
+    db: usize,
+    /// Consecutive pings that missed the deadline
+    misses: u32,
+    last_reply: Instant,
+}
+
+/// Ping every storage process each `watchdog_interval` seconds, runs forever
+pub fn watch(config: Config) {
+    if config.watchdog_interval == 0 {
+        return;
+    }
+    // A killed primary recovers its keys from its replicas, without them they would be lost
+    let restart = config.watchdog_restart && config.storage_replicas > 0;
+    if config.watchdog_restart && !restart {
+        warn!("Watchdog: restarts need storage replicas, the stalled processes are only reported");
+    }
+    let interval = Duration::from_secs(config.watchdog_interval);
+    let deadline = Duration::from_millis(config.watchdog_deadline_ms);
+    let mut watched = vec![];
+    for db in 0..config.databases {
+        for shard in 0..config.shards {
+            let replicas =
+                (0..config.storage_replicas).map(|replica| replica_name(db, shard, replica));
+            for name in [shard_name(db, shard)].into_iter().chain(replicas) {
+                watched.push(Watched {
+                    name,
+                    db,
+                    misses: 0,
+                    last_reply: Instant::now(),
+                });
+            }
+        }
+    }
+    loop {
+        sleep(interval);
+        for process in &mut watched {
+            let storage = match ProcessRef::<Storage>::lookup(&process.name) {
+                Some(storage) => storage,
+                // Being restarted by its supervisor
+                None => {
+                    debug!("Watchdog: {} isn't registered", process.name);
+                    continue;
+                }
+            };
+            if ping(storage, deadline) {
+                if process.misses > 0 {
+                    info!(
+                        "Watchdog: {} is responding again after {} missed pings",
+                        process.name, process.misses
+                    );
+                }
+                process.misses = 0;
+                process.last_reply = Instant::now();
+            } else {
+                process.misses += 1;
+            }
+        }
+        report(&mut watched, deadline, restart);
+    }
+}
+
+/// Log the stalled processes with the other stalled processes of their database, the
+/// replicas recover their keys from the primary so a stalled primary can block them
+fn report(watched: &mut [Watched], deadline: Duration, restart: bool) {
+    let mut stalled: HashMap<usize, Vec<String>> = HashMap::new();
+    for process in watched.iter().filter(|process| process.misses > 0) {
+        stalled
+            .entry(process.db)
+            .or_default()
+            .push(process.name.clone());
+    }
+    for process in watched.iter_mut().filter(|process| process.misses > 0) {
+        let others: Vec<&str> = stalled[&process.db]
+            .iter()
+            .filter(|name| **name != process.name)
+            .map(String::as_str)
+            .collect();
+        warn!(
+            "Watchdog: {} missed the {}ms deadline {} times in a row, last reply {}s ago, \
+             also stalled in db {}: [{}]",
+            process.name,
+            deadline.as_millis(),
+            process.misses,
+            process.last_reply.elapsed().as_secs(),
+            process.db,
+            others.join(", ")
+        );
+        if restart && process.misses >= MISSES_BEFORE_RESTART {
+            if let Some(storage) = ProcessRef::<Storage>::lookup(&process.name) {
+                error!("Watchdog: killing {} to restart it", process.name);
+                storage.kill();
+                process.misses = 0;
+            }
+        }
+    }
+}
+
+/// Whether the storage process replies before the deadline. The request is made by a probe
+/// process, killed when the deadline expires so it doesn't stay blocked
+fn ping(storage: ProcessRef<Storage>, deadline: Duration) -> bool {
+    let tag = Tag::new();
+    let probe = Process::spawn(
+        (storage, Process::<()>::this(), tag),
+        |(storage, watchdog, tag), _: Mailbox<()>| {
+            storage.ping();
+            watchdog.tag_send(tag, ());
+        },
+    );
+    // Only the message with the tag is received, like the wake ups of the blocked clients
+    let mailbox: Mailbox<()> = unsafe { Mailbox::new() };
+    match mailbox.tag_receive_timeout(&[tag], deadline) {
+        MailboxResult::Message(()) => true,
+        _ => {
+            probe.kill();
+            false
+        }
+    }
+}
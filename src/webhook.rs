@@ -0,0 +1,67 @@
+//! Webhooks of the CDC feed, each endpoint is called by a process of its own so a slow
+//! endpoint only delays its events
+
+use std::{collections::VecDeque, time::Duration};
+
+use lunatic::{sleep, Mailbox, MailboxResult};
+use lunatic_log::{debug, error};
+
+use crate::{cdc::CdcEvent, config::Webhook, http::post_json};
+
+/// Attempts to deliver an event before dropping it
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled after each failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Events kept while the endpoint is slow or failing, the older ones are dropped
+const MAX_PENDING: usize = 8192;
+
+/// Move the events waiting in the mailbox to the pending ones, without waiting
+fn queue(webhook: &Webhook, mailbox: &Mailbox<CdcEvent>, pending: &mut VecDeque<CdcEvent>) {
+    while let MailboxResult::Message(event) = mailbox.receive_timeout(Duration::ZERO) {
+        pending.push_back(event);
+    }
+    let dropped = pending.len().saturating_sub(MAX_PENDING);
+    if dropped > 0 {
+        error!(
+            "Dropping {dropped} events for the webhook {}, it can't keep up",
+            webhook.url.addr
+        );
+        pending.drain(..dropped);
+    }
+}
+
+/// Deliver the events received from the CDC process to the endpoint, in order. Events are
+/// retried with an exponential backoff, and dropped after `MAX_ATTEMPTS`. The events
+/// received meanwhile are queued, up to `MAX_PENDING`
+pub fn notify(webhook: Webhook, mailbox: Mailbox<CdcEvent>) {
+    let mut pending = VecDeque::new();
+    loop {
+        queue(&webhook, &mailbox, &mut pending);
+        let event = pending.pop_front().unwrap_or_else(|| mailbox.receive());
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Can't serialize the CDC event {event:?}: {err}");
+                continue;
+            }
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match post_json(&webhook.url, &body) {
+                Ok(()) => break,
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    debug!("Webhook {} failed, retrying: {err}", webhook.url.addr);
+                    sleep(backoff);
+                    backoff *= 2;
+                    queue(&webhook, &mailbox, &mut pending);
+                }
+                Err(err) => error!(
+                    "Dropping the event for the webhook {}: {err}",
+                    webhook.url.addr
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! WebSocket bridge for the browser apps: binary messages carry RESP, like a TCP connection
+//! split in frames, and the replies are sent back as binary messages. Text messages carry
+//! a command as a JSON array, replied as JSON like the HTTP gateway. Each connection is a
+//! client of its own, authenticated with the AUTH command
+
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, BytesMut};
+use lunatic::{net::TcpStream, process::ProcessRef, Mailbox, Process};
+use lunatic_log::{debug, info};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    client::{ClientProcess, ClientProcessHandler, QUERY_BUFFER_FRACTION},
+    config::Config,
+    encoder::encode,
+    http::{command_args, to_json},
+    parser::{self, DecodeState},
+    server::{self, admitted, client_config, connection_limiter, Bound},
+    types::RespValue,
+};
+
+/// Longest request line and headers of the handshake
+const HEADERS_MAX_LENGTH: usize = 8192;
+
+/// Appended to the key of the handshake before hashing it, from RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC11B85";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Status codes of the close frames
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Whether the browser page opening the connection may use it. Clients without an Origin
+/// header aren't browsers, the pages must be served by the host of the connection or by
+/// one of the allowed origins
+fn origin_allowed(origin: Option<&str>, host: Option<&str>, allowed: &[String]) -> bool {
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let same_host = origin
+        .split_once("://")
+        .zip(host)
+        .map_or(false, |((_, origin_host), host)| {
+            origin_host.eq_ignore_ascii_case(host)
+        });
+    same_host
+        || allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+}
+
+/// Read the handshake request, returns the accept key and the bytes read after it. Errors
+/// are the status of the HTTP response to send
+fn read_handshake(
+    stream: &mut TcpStream,
+    origins: &[String],
+) -> Result<(String, Vec<u8>), &'static str> {
+    const BAD_REQUEST: &str = "400 Bad Request";
+    let mut buffer = vec![];
+    let mut read = [0; 4096];
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > HEADERS_MAX_LENGTH {
+            return Err(BAD_REQUEST);
+        }
+        match stream.read(&mut read) {
+            Ok(0) | Err(_) => return Err(BAD_REQUEST),
+            Ok(readed) => buffer.extend_from_slice(&read[..readed]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+    let mut lines = head.split("\r\n");
+    if !lines.next().unwrap_or_default().starts_with("GET ") {
+        return Err(BAD_REQUEST);
+    }
+    let mut upgrade = false;
+    let mut key = None;
+    let mut origin = None;
+    let mut host = None;
+    for line in lines {
+        match line.split_once(':') {
+            Some((name, value)) => match name.trim().to_lowercase().as_ref() {
+                "upgrade" => upgrade = value.trim().eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.trim()),
+                "origin" => origin = Some(value.trim()),
+                "host" => host = Some(value.trim()),
+                _ => (),
+            },
+            None => return Err(BAD_REQUEST),
+        }
+    }
+    if !origin_allowed(origin, host, origins) {
+        debug!(
+            "WebSocket upgrade from a denied origin: {}",
+            origin.unwrap_or_default()
+        );
+        return Err("403 Forbidden");
+    }
+    match (upgrade, key) {
+        (true, Some(key)) => {
+            let digest = Sha1::digest(format!("{key}{HANDSHAKE_GUID}"));
+            Ok((STANDARD.encode(digest), buffer.split_off(end + 4)))
+        }
+        _ => Err(BAD_REQUEST),
+    }
+}
+
+/// Read a frame sent by the browser, returns if it's the last one of the message, its
+/// opcode and unmasked payload. Errors are the status of the close frame to send
+fn read_frame(stream: &mut impl Read, max_payload: usize) -> Result<(bool, u8, Vec<u8>), u16> {
+    let going_away = |_| CLOSE_GOING_AWAY;
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).map_err(going_away)?;
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0F);
+    // The frames of the browsers are always masked
+    if head[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).map_err(going_away)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            stream.read_exact(&mut length).map_err(going_away)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > max_payload as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask).map_err(going_away)?;
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload).map_err(going_away)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// Send an unmasked frame with the whole message
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = BytesMut::with_capacity(payload.len() + 10);
+    frame.put_u8(0x80 | opcode);
+    match payload.len() {
+        length if length < 126 => frame.put_u8(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.put_u8(126);
+            frame.put_u16(length as u16);
+        }
+        length => {
+            frame.put_u8(127);
+            frame.put_u64(length as u64);
+        }
+    }
+    frame.put(payload);
+    stream.write_all(&frame)
+}
+
+/// RESP of a binary message, commands can be split between messages
+struct RespMessages {
+    buffer: BytesMut,
+    state: DecodeState,
+    max_query_buffer: usize,
+}
+
+impl RespMessages {
+    /// Reply of the complete commands, and whether the client sent QUIT. Invalid RESP closes
+    /// the connection like on TCP
+    fn process(
+        &mut self,
+        client: &ProcessRef<ClientProcess>,
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, bool), u16> {
+        self.buffer.put(payload);
+        let mut commands = vec![];
+        loop {
+            match parser::decode_request(&mut self.buffer, &mut self.state) {
+                Ok(Some(command)) => commands.push(command),
+                Ok(None) if self.buffer.len() + self.state.pending() > self.max_query_buffer => {
+                    return Err(CLOSE_TOO_BIG)
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("Invalid input: {err}");
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+            }
+        }
+        let mut reply = BytesMut::new();
+        let (responses, quit) = client.process_batch(commands);
+        for response in responses {
+            encode(response, &mut reply);
+        }
+        Ok((reply.to_vec(), quit))
+    }
+}
+
+/// Reply of the command of a text message as JSON
+fn json_reply(client: &ProcessRef<ClientProcess>, payload: &[u8]) -> Option<String> {
+    let reply = match command_args(payload) {
+        Some(args) => match client.process(RespValue::Array(args.into()))? {
+            RespValue::Error(prefix, Some(description)) => {
+                json!({ "error": format!("{prefix} {description}") })
+            }
+            RespValue::Error(prefix, None) => json!({ "error": prefix }),
+            reply => json!({ "result": to_json(reply) }),
+        },
+        None => json!({ "error": "the message must be a JSON array of strings" }),
+    };
+    Some(reply.to_string())
+}
+
+/// Serve the messages of the connection until it's closed, with a client registered for
+/// its peer. The client is started first so the connection counted by the per-IP limits is
+/// always released
+fn serve_connection(mut stream: TcpStream, peer: String, config: Config) {
+    let client =
+        ClientProcess::start_config((None, peer, config.clone()), None, &client_config(&config));
+    let (accept, rest) = match read_handshake(&mut stream, &config.websocket_origins) {
+        Ok(handshake) => handshake,
+        Err(status) => {
+            let response =
+                format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            let _ = stream.write_all(response.as_bytes());
+            client.shutdown();
+            return;
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    if stream.write_all(response.as_bytes()).is_err() {
+        client.shutdown();
+        return;
+    }
+
+    let mut reader_stream = stream.clone();
+    let timeout = (config.timeout > 0).then(|| Duration::from_secs(config.timeout));
+    if let Err(err) = reader_stream.set_read_timeout(timeout) {
+        debug!("Can't set the idle timeout: {err}");
+    }
+    // Frames sent right after the handshake may have been read with it
+    let mut reader = io::Cursor::new(rest).chain(reader_stream);
+    let max_payload = config.client_max_memory / QUERY_BUFFER_FRACTION;
+    let mut resp = RespMessages {
+        buffer: BytesMut::new(),
+        state: DecodeState::default(),
+        max_query_buffer: max_payload,
+    };
+    // Opcode and payload of the fragmented message being received
+    let mut fragmented: Option<(u8, Vec<u8>)> = None;
+    let status = loop {
+        let (fin, opcode, payload) = match read_frame(&mut reader, max_payload) {
+            Ok(frame) => frame,
+            Err(status) => break status,
+        };
+        let message = match (opcode, fragmented.as_mut()) {
+            (OPCODE_CLOSE, _) => break CLOSE_NORMAL,
+            (OPCODE_PING, _) => {
+                if write_frame(&mut stream, OPCODE_PONG, &payload).is_err() {
+                    break CLOSE_GOING_AWAY;
+                }
+                continue;
+            }
+            (OPCODE_PONG, _) => continue,
+            (OPCODE_TEXT | OPCODE_BINARY, None) if fin => (opcode, payload),
+            (OPCODE_TEXT | OPCODE_BINARY, None) => {
+                fragmented = Some((opcode, payload));
+                continue;
+            }
+            (OPCODE_CONTINUATION, Some((_, message))) => {
+                if message.len() + payload.len() > max_payload {
+                    break CLOSE_TOO_BIG;
+                }
+                message.extend_from_slice(&payload);
+                if !fin {
+                    continue;
+                }
+                fragmented.take().unwrap()
+            }
+            _ => break CLOSE_PROTOCOL_ERROR,
+        };
+        let written = match message {
+            (OPCODE_TEXT, payload) => match json_reply(&client, &payload) {
+                Some(reply) => write_frame(&mut stream, OPCODE_TEXT, reply.as_bytes()),
+                None => Ok(()),
+            },
+            (_, payload) => match resp.process(&client, &payload) {
+                Ok((reply, quit)) => {
+                    let written = if reply.is_empty() {
+                        Ok(())
+                    } else {
+                        write_frame(&mut stream, OPCODE_BINARY, &reply)
+                    };
+                    if quit && written.is_ok() {
+                        break CLOSE_NORMAL;
+                    }
+                    written
+                }
+                Err(status) => break status,
+            },
+        };
+        if written.is_err() {
+            break CLOSE_GOING_AWAY;
+        }
+    };
+    debug!("Closing WebSocket with status {status}");
+    let _ = write_frame(&mut stream, OPCODE_CLOSE, &status.to_be_bytes());
+    client.shutdown();
+}
+
+/// Accept WebSocket connections on the address, each one is served by its own process
+pub fn serve(addr: String, bound: Bound, config: Config) {
+    let listener = server::bind(addr, bound);
+    info!("Serving WebSocket on: {}", listener.local_addr().unwrap());
+
+    let registry = connection_limiter(&config);
+
+    while let Ok((stream, peer)) = listener.accept() {
+        if !admitted(registry.as_ref(), peer) {
+            continue;
+        }
+        Process::spawn(
+            (stream, peer.to_string(), config.clone()),
+            |(stream, peer, config), _: Mailbox<()>| serve_connection(stream, peer, config),
+        );
+    }
+}
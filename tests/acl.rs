@@ -0,0 +1,103 @@
+//! Byte-exact replies of the commands of the ACL users, as redis clients see them on the wire
+
+mod common;
+
+use common::{assert_replies, assert_reply, command, connect_resp, server};
+
+#[lunatic::test]
+fn acl() {
+    let server = server().databases(2).start().unwrap();
+    let mut stream = connect_resp(&server);
+
+    // A user bound to a database can't use or flush the other ones
+    let rules = ["on", ">secret", "db=1", "allkeys", "+@all"];
+    assert_reply(
+        &mut stream,
+        &command(&[&["ACL", "SETUSER", "tenant"][..], &rules].concat()),
+        "+OK\r\n",
+    );
+    assert_replies(
+        &mut connect_resp(&server),
+        &[
+            (&["AUTH", "tenant", "secret"], "+OK\r\n"),
+            (&["SET", "owned", "1"], "+OK\r\n"),
+            (
+                &["SELECT", "0"],
+                "-NOPERM No permissions to access the database 0\r\n",
+            ),
+            (
+                &["FLUSHALL"],
+                "-NOPERM User tenant has no permissions to run the 'flushall' command\r\n",
+            ),
+        ],
+    );
+    assert_reply(&mut stream, &command(&["EXISTS", "owned"]), ":0\r\n");
+
+    // Selectors allow more commands on other keys, the keys of a command must all be allowed
+    // by the same permissions
+    let rules = ["on", ">secret", "~public:*", "+@read", "(~private:* +get)"];
+    assert_reply(
+        &mut stream,
+        &command(&[&["ACL", "SETUSER", "reader"][..], &rules].concat()),
+        "+OK\r\n",
+    );
+    for key in ["public:ts", "private:ts"] {
+        let args = ["TS.ADD", key, "1000", "1", "LABELS", "sensor", "b"];
+        assert_reply(&mut stream, &command(&args), ":1000\r\n");
+    }
+    assert_replies(
+        &mut connect_resp(&server),
+        &[
+            (&["AUTH", "reader", "secret"], "+OK\r\n"),
+            (
+                &["TS.MRANGE", "-", "+", "FILTER", "sensor=b"],
+                "*1\r\n*3\r\n$9\r\npublic:ts\r\n*0\r\n*1\r\n*2\r\n:1000\r\n+1\r\n",
+            ),
+            (&["EXISTS", "public:a"], ":0\r\n"),
+            (&["GET", "private:a"], "$-1\r\n"),
+            (
+                &["EXISTS", "private:a"],
+                "-NOPERM No permissions to access a key\r\n",
+            ),
+            (
+                &["MGET", "public:a", "private:a"],
+                "-NOPERM No permissions to access a key\r\n",
+            ),
+            (
+                &["SET", "private:a", "1"],
+                "-NOPERM User reader has no permissions to run the 'set' command\r\n",
+            ),
+        ],
+    );
+    // The pipelines are checked against the permissions of the client, updated when the user
+    // is changed by another connection
+    let mut reader = connect_resp(&server);
+    assert_reply(
+        &mut reader,
+        &command(&["AUTH", "reader", "secret"]),
+        "+OK\r\n",
+    );
+    let rules = ["ACL", "SETUSER", "reader", "-get", "+set"];
+    assert_reply(&mut stream, &command(&rules), "+OK\r\n");
+    let pipeline = [
+        command(&["SET", "public:a", "1"]),
+        command(&["GET", "public:a"]),
+    ]
+    .concat();
+    assert_reply(
+        &mut reader,
+        &pipeline,
+        "+OK\r\n-NOPERM User reader has no permissions to run the 'get' command\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &command(&["ACL", "DELUSER", "reader"]),
+        ":1\r\n",
+    );
+    assert_reply(
+        &mut reader,
+        &pipeline,
+        "-NOPERM User reader is disabled or was removed\r\n\
+         -NOPERM User reader is disabled or was removed\r\n",
+    );
+}
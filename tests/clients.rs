@@ -0,0 +1,175 @@
+//! Byte-exact replies of the connections, as redis clients see them on the wire
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use common::{assert_replies, assert_reply, command, connect_resp, server};
+use lunatic::sleep;
+
+#[lunatic::test]
+fn clients() {
+    let server = server().pipeline_limits(2, 16).start().unwrap();
+    let mut stream = connect_resp(&server);
+    assert_replies(
+        &mut stream,
+        &[
+            (&["CLIENT", "GETNAME"], "$-1\r\n"),
+            (&["CLIENT", "SETNAME", "conformance"], "+OK\r\n"),
+            (&["CLIENT", "GETNAME"], "$11\r\nconformance\r\n"),
+            (
+                &[
+                    "CLIENT",
+                    "SETINFO",
+                    "traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ],
+                "+OK\r\n",
+            ),
+            (
+                &["CLIENT", "SETINFO", "traceparent", "bad"],
+                "-ERR Invalid traceparent\r\n",
+            ),
+            (
+                &["CLIENT", "SETINFO", "other", "x"],
+                "-ERR Unrecognized option 'other'\r\n",
+            ),
+            (&["CLIENT", "SETINFO", "traceparent", ""], "+OK\r\n"),
+            (&["CLIENT", "CONSISTENCY", "READ-YOUR-WRITES"], "+OK\r\n"),
+            (
+                &["CLIENT", "CONSISTENCY", "eventual"],
+                "-INVALID_COMMAND\r\n",
+            ),
+            (&["CLIENT", "CONSISTENCY", "replica"], "+OK\r\n"),
+        ],
+    );
+
+    // Inline commands, as sent by telnet
+    assert_reply(&mut stream, b"PING\r\n", "+PONG\r\n");
+
+    // Pipelined commands are replied in order
+    let mut pipeline = command(&["SET", "a", "1"]);
+    pipeline.extend(command(&["GET", "a"]));
+    pipeline.extend(command(&["DEL", "a"]));
+    assert_reply(&mut stream, &pipeline, "+OK\r\n$1\r\n1\r\n:1\r\n");
+
+    // Pipelines over the limits are executed and replied a part at a time, in order
+    let value = "0123456789".repeat(4);
+    let mut pipeline = command(&["SET", "pipelined", &value]);
+    pipeline.extend(command(&["GET", "pipelined"]));
+    pipeline.extend(command(&["PING"]));
+    pipeline.extend(command(&["PING"]));
+    pipeline.extend(command(&["GET", "pipelined"]));
+    let expected = format!("+OK\r\n$40\r\n{value}\r\n+PONG\r\n+PONG\r\n$40\r\n{value}\r\n");
+    assert_reply(&mut stream, &pipeline, &expected);
+
+    // A command split across several writes is parsed once complete
+    let request = command(&["SET", "split", "value"]);
+    let (first, second) = request.split_at(7);
+    stream.write_all(first).unwrap();
+    sleep(Duration::from_millis(20));
+    assert_reply(&mut stream, second, "+OK\r\n");
+
+    // The complete commands are replied without waiting for the rest of the split one, also
+    // when they fill a batch
+    for complete in [1, 2] {
+        let mut pipeline = command(&["PING"]).repeat(complete);
+        pipeline.extend_from_slice(first);
+        assert_reply(&mut stream, &pipeline, &"+PONG\r\n".repeat(complete));
+        assert_reply(&mut stream, second, "+OK\r\n");
+    }
+
+    // Replies suppressed with CLIENT REPLY, until it's turned ON again
+    let mut pipeline = command(&["CLIENT", "REPLY", "OFF"]);
+    pipeline.extend(command(&["PING"]));
+    pipeline.extend(command(&["CLIENT", "REPLY", "ON"]));
+    assert_reply(&mut stream, &pipeline, "+OK\r\n");
+
+    // Mass insertion of the commands of the payload, as sent by `moonis import`
+    let mut payload = command(&["SET", "loaded", "1"]);
+    payload.extend(command(&["APPEND", "loaded", "2"]));
+    let payload = String::from_utf8(payload).unwrap();
+    assert_reply(
+        &mut stream,
+        &command(&["DEBUG", "LOADPROTO", &payload]),
+        ":2\r\n",
+    );
+    assert_reply(&mut stream, &command(&["GET", "loaded"]), "$2\r\n12\r\n");
+    let payload = String::from_utf8(command(&["GET", "loaded"])).unwrap();
+    assert_reply(
+        &mut stream,
+        &command(&["DEBUG", "LOADPROTO", &payload]),
+        "-ERR 'get' can't be loaded\r\n",
+    );
+
+    // Each command of a connection gets the next id
+    let mut numbered = connect_resp(&server);
+    let mut pipeline = command(&["PING"]);
+    pipeline.extend(command(&["DEBUG", "COMMANDID"]));
+    assert_reply(&mut numbered, &pipeline, "+PONG\r\n:2\r\n");
+
+    // The LRU clock of DEBUG OBJECT changes with time, only the fields before it are checked
+    let mut inspected = connect_resp(&server);
+    assert_reply(
+        &mut inspected,
+        &command(&["SET", "inspected", "value"]),
+        "+OK\r\n",
+    );
+    assert_reply(
+        &mut inspected,
+        &command(&["DEBUG", "OBJECT", "inspected"]),
+        "+refcount:1 encoding:embstr serializedlength:5 lru:",
+    );
+
+    // The library set by the client is shown by CLIENT INFO after its id and address
+    let mut library = connect_resp(&server);
+    assert_replies(
+        &mut library,
+        &[
+            (&["CLIENT", "SETINFO", "LIB-NAME", "redis-py"], "+OK\r\n"),
+            (&["CLIENT", "SETINFO", "lib-ver", "5.0"], "+OK\r\n"),
+            (
+                &["CLIENT", "SETINFO", "lib-name", "redis py"],
+                "-ERR lib-name cannot contain spaces, newlines or special characters.\r\n",
+            ),
+        ],
+    );
+    library.write_all(&command(&["CLIENT", "INFO"])).unwrap();
+    let mut info = vec![];
+    while !info.ends_with(b"\n\r\n") {
+        let mut read = [0; 256];
+        let readed = library.read(&mut read).unwrap();
+        info.extend_from_slice(&read[..readed]);
+    }
+    let info = String::from_utf8_lossy(&info);
+    assert!(
+        info.ends_with(" lib-name=redis-py lib-ver=5.0\n\r\n"),
+        "{info}"
+    );
+    // The reply of HELLO has the id of the connection shown by CLIENT INFO
+    let id = info
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("id="))
+        .unwrap();
+    let hello = format!(
+        "*14\r\n$6\r\nserver\r\n$6\r\nmoonis\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+         $5\r\nproto\r\n:2\r\n$2\r\nid\r\n:{id}\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+         $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+        env!("CARGO_PKG_VERSION").len(),
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_reply(&mut library, &command(&["HELLO"]), &hello);
+
+    // QUIT replies after the previous commands, the ones after it are ignored and the
+    // connection is closed
+    let mut quitting = connect_resp(&server);
+    let mut pipeline = command(&["PING"]);
+    pipeline.extend(command(&["QUIT"]));
+    pipeline.extend(command(&["SET", "after-quit", "1"]));
+    assert_reply(&mut quitting, &pipeline, "+PONG\r\n+OK\r\n");
+    assert_eq!(quitting.read(&mut [0; 1]).unwrap(), 0);
+    assert_reply(&mut stream, &command(&["EXISTS", "after-quit"]), ":0\r\n");
+}
@@ -0,0 +1,101 @@
+//! Helpers of the conformance tests, each test file starts its own server on the ports
+//! assigned by the system
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+
+use lunatic::net::{TcpListener, TcpStream};
+use moonis::server::{Server, ServerBuilder};
+
+/// Server of a test, listening on any free port
+pub fn server() -> ServerBuilder {
+    Server::builder().port(0)
+}
+
+/// Request in the RESP format sent by redis clients, an array of bulk strings
+pub fn command(args: &[&str]) -> Vec<u8> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n{arg}\r\n", arg.len()).as_bytes());
+    }
+    request
+}
+
+/// The listeners are bound when the server is started, on the ports assigned by the system
+pub fn connect(port: Option<u16>) -> TcpStream {
+    TcpStream::connect(format!("127.0.0.1:{}", port.unwrap())).unwrap()
+}
+
+/// Connection of a redis client to the server
+pub fn connect_resp(server: &Server) -> TcpStream {
+    connect(server.local_addrs().first().map(|addr| addr.port()))
+}
+
+/// Send the request and check the reply is exactly the expected bytes
+pub fn assert_reply(stream: &mut TcpStream, request: &[u8], expected: &str) {
+    stream.write_all(request).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        expected,
+        "Reply of {:?}",
+        String::from_utf8_lossy(request)
+    );
+}
+
+/// Send each command and check its reply
+pub fn assert_replies(stream: &mut TcpStream, cases: &[(&[&str], &str)]) {
+    for (args, expected) in cases {
+        assert_reply(stream, &command(args), expected);
+    }
+}
+
+/// Response of the HTTP gateway, which closes the connection after each one
+pub fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// WebSocket frame with the whole message, masked like the frames sent by the browsers
+pub fn websocket_frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![
+        0x80 | opcode,
+        payload.len() as u8 | if masked { 0x80 } else { 0 },
+    ];
+    if masked {
+        frame.extend_from_slice(&mask);
+    }
+    let payload = payload.iter().enumerate();
+    frame.extend(payload.map(|(i, byte)| if masked { byte ^ mask[i % 4] } else { *byte }));
+    frame
+}
+
+/// Body of the next request received by the webhook endpoint, replied with 204
+pub fn webhook_request(listener: &TcpListener) -> String {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut request = vec![];
+    let mut read = [0; 1024];
+    loop {
+        let readed = stream.read(&mut read).unwrap();
+        request.extend_from_slice(&read[..readed]);
+        let request = String::from_utf8_lossy(&request).to_string();
+        let length = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .and_then(|length| length.parse::<usize>().ok());
+        if let (Some((head, body)), Some(length)) = (request.split_once("\r\n\r\n"), length) {
+            if body.len() >= length {
+                assert!(head.starts_with("POST /events HTTP/1.1\r\n"), "{head}");
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .unwrap();
+                return body.to_string();
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Byte-exact responses of the HTTP gateway
+
+mod common;
+
+use common::{assert_reply, connect, http_response, server};
+
+#[lunatic::test]
+fn http() {
+    let server = server().http(0).start().unwrap();
+
+    // HTTP requests are served by a client of the default user, one per connection
+    let json = "application/json";
+    let cases: &[(&str, &str, &str, String)] = &[
+        (
+            "PUT",
+            "/keys/http%20key",
+            "hello",
+            http_response("204 No Content", "text/plain", ""),
+        ),
+        (
+            "GET",
+            "/keys/http%20key",
+            "",
+            http_response("200 OK", "application/octet-stream", "hello"),
+        ),
+        (
+            "POST",
+            "/command",
+            r#"["APPEND", "http key", "!"]"#,
+            http_response("200 OK", json, r#"{"result":6}"#),
+        ),
+        (
+            "POST",
+            "/command",
+            r#"["MGET", "http key", "missing"]"#,
+            http_response("200 OK", json, r#"{"result":["hello!",null]}"#),
+        ),
+        (
+            "POST",
+            "/command",
+            r#"["JSON.SET", "http doc", "$", 1]"#,
+            http_response("200 OK", json, r#"{"result":"OK"}"#),
+        ),
+        (
+            "GET",
+            "/keys/http%20doc",
+            "",
+            http_response(
+                "400 Bad Request",
+                json,
+                r#"{"error":"WRONGTYPE Operation against a key holding the wrong kind of value"}"#,
+            ),
+        ),
+        (
+            "POST",
+            "/command",
+            r#"{"command": "PING"}"#,
+            http_response(
+                "400 Bad Request",
+                json,
+                r#"{"error":"the body must be a JSON array of strings"}"#,
+            ),
+        ),
+        (
+            "DELETE",
+            "/keys/http%20key",
+            "",
+            http_response("204 No Content", "text/plain", ""),
+        ),
+        (
+            "DELETE",
+            "/keys/http%20key",
+            "",
+            http_response("404 Not Found", json, r#"{"error":"key not found"}"#),
+        ),
+        (
+            "GET",
+            "/nowhere",
+            "",
+            http_response("404 Not Found", json, r#"{"error":"unknown path"}"#),
+        ),
+    ];
+    for (method, path, body, expected) in cases {
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let port = server.config().http_port;
+        assert_reply(&mut connect(port), request.as_bytes(), expected);
+    }
+}
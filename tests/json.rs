@@ -0,0 +1,29 @@
+//! Byte-exact replies of the JSON commands, as redis clients see them on the wire
+
+mod common;
+
+use common::{assert_replies, connect_resp, server};
+
+#[lunatic::test]
+fn json() {
+    let server = server().start().unwrap();
+    assert_replies(
+        &mut connect_resp(&server),
+        &[
+            (&["JSON.SET", "doc", "$", r#"{"a":[1],"b":"x"}"#], "+OK\r\n"),
+            (&["JSON.GET", "doc"], "$17\r\n{\"a\":[1],\"b\":\"x\"}\r\n"),
+            (&["JSON.ARRAPPEND", "doc", "$.a", "2", "3"], "*1\r\n:3\r\n"),
+            (&["JSON.GET", "doc", "$.a"], "$9\r\n[[1,2,3]]\r\n"),
+            (&["JSON.GET", "doc", ".b"], "$3\r\n\"x\"\r\n"),
+            (&["JSON.SET", "doc", "$.c", "true", "NX"], "+OK\r\n"),
+            (&["JSON.SET", "doc", "$.c", "false", "NX"], "$-1\r\n"),
+            (&["JSON.DEL", "doc", "$.a"], ":1\r\n"),
+            (&["JSON.GET", "doc"], "$18\r\n{\"b\":\"x\",\"c\":true}\r\n"),
+            (&["OBJECT", "ENCODING", "doc"], "$4\r\njson\r\n"),
+            (
+                &["GET", "doc"],
+                "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            ),
+        ],
+    );
+}
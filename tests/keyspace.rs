@@ -0,0 +1,87 @@
+//! Byte-exact replies of the string and keyspace commands, as redis clients see them on the wire
+
+mod common;
+
+use common::{assert_replies, assert_reply, command, connect_resp, server};
+
+#[lunatic::test]
+fn keyspace() {
+    let server = server().databases(2).trash_retention(60).start().unwrap();
+    let mut stream = connect_resp(&server);
+    assert_replies(
+        &mut stream,
+        &[
+            (&["PING"], "+PONG\r\n"),
+            (&["PING", "hello"], "$5\r\nhello\r\n"),
+            (&["SET", "key", "value"], "+OK\r\n"),
+            (&["GET", "key"], "$5\r\nvalue\r\n"),
+            (&["GET", "missing"], "$-1\r\n"),
+            (&["APPEND", "key", "s"], ":6\r\n"),
+            (&["EXISTS", "key"], ":1\r\n"),
+            (&["EXISTS", "missing"], ":0\r\n"),
+            (&["MGET", "key", "missing"], "*2\r\n$6\r\nvalues\r\n$-1\r\n"),
+            (&["SET", "number", "42"], "+OK\r\n"),
+            (&["OBJECT", "ENCODING", "number"], "$3\r\nint\r\n"),
+            (&["OBJECT", "ENCODING", "key"], "$6\r\nembstr\r\n"),
+            (&["DEBUG", "OBJECT", "missing"], "-ERR no such key\r\n"),
+            (&["DEL", "number", "missing"], ":1\r\n"),
+            (&["SELECT", "1"], "+OK\r\n"),
+            (&["GET", "key"], "$-1\r\n"),
+            (&["DBSIZE"], ":0\r\n"),
+            (&["SET", "other", "1"], "+OK\r\n"),
+            (&["DBSIZE"], ":1\r\n"),
+            (&["SELECT", "2"], "-ERR DB index is out of range\r\n"),
+            (&["SELECT", "0"], "+OK\r\n"),
+            (&["DBSIZE"], ":1\r\n"),
+            (
+                &["INFO", "keyspace"],
+                "$78\r\n# Keyspace\r\ndb0:keys=1,expires=0,avg_ttl=0\r\n\
+                 db1:keys=1,expires=0,avg_ttl=0\r\n\r\n\r\n",
+            ),
+            (&["KEYS", "*"], "*1\r\n$3\r\nkey\r\n"),
+            (&["KEYS", "k[a-f]?"], "*1\r\n$3\r\nkey\r\n"),
+            (&["KEYS", "other*"], "*0\r\n"),
+            (
+                &["MEMORY", "BIGKEYS", "COUNT", "1"],
+                "*2\r\n$6\r\nstring\r\n*2\r\n$3\r\nkey\r\n:57\r\n",
+            ),
+            (&["FLUSHDB"], "+OK\r\n"),
+            (&["KEYS", "*"], "*0\r\n"),
+            // The versions are given by each storage process, the database 1 only has `other`
+            (&["SELECT", "1"], "+OK\r\n"),
+            (&["GETVER", "versioned"], "$-1\r\n"),
+            (&["CAS", "versioned", "0", "a"], ":2\r\n"),
+            (&["CAS", "versioned", "0", "b"], "$-1\r\n"),
+            (&["CAS", "versioned", "2", "b"], ":3\r\n"),
+            (&["APPEND", "versioned", "c"], ":2\r\n"),
+            (&["GETVER", "versioned"], "*2\r\n$2\r\nbc\r\n:4\r\n"),
+            (&["CAS", "versioned", "3", "d"], "$-1\r\n"),
+            // A key deleted and created again doesn't reuse its versions
+            (&["DEL", "versioned"], ":1\r\n"),
+            (&["SET", "versioned", "e"], "+OK\r\n"),
+            (&["CAS", "versioned", "1", "f"], "$-1\r\n"),
+            (&["GETVER", "versioned"], "*2\r\n$1\r\ne\r\n:5\r\n"),
+            (&["SELECT", "0"], "+OK\r\n"),
+            (&["SET", "trashed", "1"], "+OK\r\n"),
+            (&["DEL", "trashed"], ":1\r\n"),
+            (&["GET", "trashed"], "$-1\r\n"),
+            (&["UNDELETE", "trashed"], ":1\r\n"),
+            (&["GET", "trashed"], "$1\r\n1\r\n"),
+            (&["UNDELETE", "trashed"], ":0\r\n"),
+            (&["SET", "hits", "18446744073709551615"], "+OK\r\n"),
+            (&["MC.INCR", "hits", "2"], "$1\r\n1\r\n"),
+            (&["MC.DECR", "hits", "5"], "$1\r\n0\r\n"),
+            (&["AUTH", "password"], "-ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?\r\n"),
+            (&["NOSUCHCOMMAND"], "-INVALID_COMMAND\r\n"),
+        ],
+    );
+
+    // The port assigned by the system, the clients see it like the configured ones
+    let bound = server.config().port.to_string();
+    let port_reply = format!("*2\r\n$4\r\nport\r\n${}\r\n{bound}\r\n", bound.len());
+    assert_reply(
+        &mut stream,
+        &command(&["CONFIG", "GET", "port"]),
+        &port_reply,
+    );
+}
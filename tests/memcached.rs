@@ -0,0 +1,49 @@
+//! Byte-exact replies of the memcached text protocol, as memcached clients see them on the wire
+
+mod common;
+
+use common::{assert_reply, command, connect, connect_resp, server};
+
+#[lunatic::test]
+fn memcached() {
+    let server = server().memcached(0).start().unwrap();
+    let port = server.config().memcached_port;
+
+    // Memcached clients use the keys of the first database
+    let mut memcached = connect(port);
+    let cases: &[(&str, &str)] = &[
+        ("set mc 5 0 2\r\n10\r\n", "STORED\r\n"),
+        ("get mc missing\r\n", "VALUE mc 0 2\r\n10\r\nEND\r\n"),
+        ("incr mc 5\r\n", "15\r\n"),
+        ("decr mc 20\r\n", "0\r\n"),
+        ("incr missing 1\r\n", "NOT_FOUND\r\n"),
+        ("touch mc 10\r\n", "TOUCHED\r\n"),
+        (
+            "set quiet 0 0 1 noreply\r\na\r\nget quiet\r\n",
+            "VALUE quiet 0 1\r\na\r\nEND\r\n",
+        ),
+        ("delete mc\r\n", "DELETED\r\n"),
+        ("delete mc\r\n", "NOT_FOUND\r\n"),
+        ("nosuchcommand\r\n", "ERROR\r\n"),
+        // Last, the end of the bad data block is left in the buffer
+        (
+            "set bad 0 0 1\r\nabc\r\n",
+            "CLIENT_ERROR bad data chunk\r\n",
+        ),
+    ];
+    for (request, expected) in cases {
+        assert_reply(&mut memcached, request.as_bytes(), expected);
+    }
+    assert_reply(
+        &mut connect_resp(&server),
+        &command(&["GET", "quiet"]),
+        "$1\r\na\r\n",
+    );
+
+    // The data of a value over the client memory limit isn't buffered
+    assert_reply(
+        &mut connect(port),
+        b"set huge 0 0 4000000000\r\n",
+        "SERVER_ERROR object too large for cache\r\n",
+    );
+}
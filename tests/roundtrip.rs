@@ -0,0 +1,162 @@
+//! Encoded commands parsed back by `parser::decode`, fed in random-sized chunks through the
+//! partial parsing used by the client reader
+
+use bytes::BytesMut;
+use moonis::{
+    encoder::encode,
+    parser::{self, DecodeState},
+    types::{BulkString, RedisCmd, RespValue},
+};
+use proptest::prelude::*;
+
+/// Commands as sent by redis clients, arrays of binary bulk strings that can be null
+fn command() -> impl Strategy<Value = RespValue> {
+    let arg = prop::option::of(prop::collection::vec(any::<u8>(), 0..64));
+    prop::collection::vec(arg, 0..8).prop_map(|args| {
+        RespValue::Array(
+            args.into_iter()
+                .map(|arg| match arg {
+                    Some(arg) => RespValue::BulkString(BulkString(arg.into())),
+                    None => RespValue::Null,
+                })
+                .collect(),
+        )
+    })
+}
+
+/// Inline commands, words separated by spaces. They can't start with `*`, that's an array
+fn inline_command() -> impl Strategy<Value = Vec<String>> {
+    prop::collection::vec("[a-zA-Z0-9_:.-][!-~]{0,15}", 1..8)
+}
+
+/// GET and SET with binary keys and values, the commands of the fast path
+fn hot_command() -> impl Strategy<Value = RespValue> {
+    let arg = || prop::collection::vec(any::<u8>(), 0..64);
+    let get = (prop::sample::select(vec!["GET", "get", "gEt"]), arg())
+        .prop_map(|(name, key)| vec![name.as_bytes().to_vec(), key]);
+    let set = (
+        prop::sample::select(vec!["SET", "set", "sEt"]),
+        arg(),
+        arg(),
+    )
+        .prop_map(|(name, key, value)| vec![name.as_bytes().to_vec(), key, value]);
+    prop_oneof![get, set].prop_map(|args| {
+        RespValue::Array(
+            args.into_iter()
+                .map(|arg| RespValue::BulkString(BulkString(arg.into())))
+                .collect(),
+        )
+    })
+}
+
+fn encoded(values: Vec<RespValue>) -> Vec<u8> {
+    let mut buffer = BytesMut::new();
+    for value in values {
+        encode(value, &mut buffer);
+    }
+    buffer.to_vec()
+}
+
+/// Parse the input received in chunks of the given sizes, the same way `RespReader` parses
+/// what it reads from the connection
+fn parse_chunks(input: &[u8], chunk_sizes: &[usize]) -> Result<Vec<RespValue>, String> {
+    let mut buffer = BytesMut::new();
+    let mut state = DecodeState::default();
+    let mut messages = vec![];
+    let mut sizes = chunk_sizes.iter().cycle();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let size = sizes.next().copied().unwrap_or(1).clamp(1, rest.len());
+        let (chunk, remaining) = rest.split_at(size);
+        rest = remaining;
+        buffer.extend_from_slice(chunk);
+        while !buffer.is_empty() {
+            let resp = parser::decode(&mut buffer, &mut state).map_err(|err| err.to_string())?;
+            match resp {
+                Some(resp) => messages.push(resp),
+                // Incomplete, wait for the next chunk
+                None => break,
+            }
+        }
+    }
+    if !buffer.is_empty() {
+        return Err(format!("{} bytes left unparsed", buffer.len()));
+    }
+    Ok(messages)
+}
+
+/// Commands of the input received in chunks, decoded as requests like `RespReader` does
+fn commands_of_chunks(input: &[u8], chunk_sizes: &[usize]) -> Result<Vec<String>, String> {
+    let mut buffer = BytesMut::new();
+    let mut state = DecodeState::default();
+    let mut commands = vec![];
+    let mut sizes = chunk_sizes.iter().cycle();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let size = sizes.next().copied().unwrap_or(1).clamp(1, rest.len());
+        let (chunk, remaining) = rest.split_at(size);
+        rest = remaining;
+        buffer.extend_from_slice(chunk);
+        while let Some(request) =
+            parser::decode_request(&mut buffer, &mut state).map_err(|err| err.to_string())?
+        {
+            let cmd = request.into_command().map_err(|err| err.to_string())?;
+            commands.push(format!("{cmd:?}"));
+        }
+    }
+    Ok(commands)
+}
+
+proptest! {
+    // Failures can't be persisted, the tests run in the wasm sandbox without a filesystem
+    #![proptest_config(ProptestConfig {
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn pipelined_commands_round_trip(
+        commands in prop::collection::vec(command(), 1..8),
+        chunk_sizes in prop::collection::vec(1..32usize, 1..16),
+    ) {
+        let input = encoded(commands);
+        let parsed = parse_chunks(&input, &chunk_sizes);
+        prop_assert!(parsed.is_ok(), "{:?}", parsed);
+        prop_assert_eq!(encoded(parsed.unwrap()), input);
+    }
+
+    #[test]
+    fn inline_commands_are_split_by_whitespace(
+        words in inline_command(),
+        chunk_sizes in prop::collection::vec(1..8usize, 1..16),
+    ) {
+        let input = format!("{}\r\n", words.join(" "));
+        let parsed = parse_chunks(input.as_bytes(), &chunk_sizes);
+        prop_assert!(parsed.is_ok(), "{:?}", parsed);
+        let expected = RespValue::Array(
+            words
+                .into_iter()
+                .map(|word| RespValue::BulkString(BulkString(word.into())))
+                .collect(),
+        );
+        prop_assert_eq!(encoded(parsed.unwrap()), encoded(vec![expected]));
+    }
+
+    #[test]
+    fn decoded_requests_match_the_parsed_commands(
+        commands in prop::collection::vec(prop_oneof![hot_command(), command()], 1..8),
+        chunk_sizes in prop::collection::vec(1..32usize, 1..16),
+    ) {
+        let expected: Vec<String> = commands
+            .iter()
+            .filter_map(|resp| RedisCmd::try_from(resp.clone()).ok())
+            .map(|cmd| format!("{cmd:?}"))
+            .collect();
+        let valid: Vec<RespValue> = commands
+            .into_iter()
+            .filter(|resp| RedisCmd::try_from(resp.clone()).is_ok())
+            .collect();
+        let decoded = commands_of_chunks(&encoded(valid), &chunk_sizes);
+        prop_assert_eq!(decoded, Ok(expected));
+    }
+}
@@ -0,0 +1,43 @@
+//! Byte-exact replies of the bloom filter, count-min sketch and top-k commands, as redis
+//! clients see them on the wire
+
+mod common;
+
+use common::{assert_replies, connect_resp, server};
+
+#[lunatic::test]
+fn sketches() {
+    let server = server().start().unwrap();
+    assert_replies(
+        &mut connect_resp(&server),
+        &[
+            (&["BF.RESERVE", "bf", "0.01", "100"], "+OK\r\n"),
+            (&["BF.ADD", "bf", "a"], ":1\r\n"),
+            (&["BF.ADD", "bf", "a"], ":0\r\n"),
+            (&["BF.MADD", "bf", "b", "a"], "*2\r\n:1\r\n:0\r\n"),
+            (&["BF.EXISTS", "bf", "b"], ":1\r\n"),
+            (&["BF.EXISTS", "bf", "c"], ":0\r\n"),
+            (&["BF.RESERVE", "bf", "0.01", "100"], "-ERR item exists\r\n"),
+            (&["CMS.INITBYDIM", "cms", "100", "5"], "+OK\r\n"),
+            (
+                &["CMS.INCRBY", "cms", "a", "3", "b", "1"],
+                "*2\r\n:3\r\n:1\r\n",
+            ),
+            (&["CMS.QUERY", "cms", "a", "c"], "*2\r\n:3\r\n:0\r\n"),
+            (
+                &["CMS.QUERY", "nocms", "a"],
+                "-ERR CMS: key does not exist\r\n",
+            ),
+            (
+                &["CMS.INITBYDIM", "cms", "100", "5"],
+                "-ERR CMS: key already exists\r\n",
+            ),
+            (&["OBJECT", "ENCODING", "cms"], "$3\r\ncms\r\n"),
+            (&["TOPK.RESERVE", "tk", "2"], "+OK\r\n"),
+            (&["TOPK.ADD", "tk", "a", "b"], "*2\r\n$-1\r\n$-1\r\n"),
+            (&["TOPK.INCRBY", "tk", "a", "4"], "*1\r\n$-1\r\n"),
+            (&["TOPK.QUERY", "tk", "a", "c"], "*2\r\n:1\r\n:0\r\n"),
+            (&["TOPK.LIST", "tk"], "*2\r\n$1\r\na\r\n$1\r\nb\r\n"),
+        ],
+    );
+}
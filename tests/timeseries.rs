@@ -0,0 +1,49 @@
+//! Byte-exact replies of the time series commands, as redis clients see them on the wire
+
+mod common;
+
+use common::{assert_replies, connect_resp, server};
+
+#[lunatic::test]
+fn timeseries() {
+    let server = server().start().unwrap();
+    assert_replies(
+        &mut connect_resp(&server),
+        &[
+            (&["TS.CREATE", "ts", "LABELS", "sensor", "a"], "+OK\r\n"),
+            (&["TS.ADD", "ts", "1000", "1"], ":1000\r\n"),
+            (&["TS.ADD", "ts", "1500", "3"], ":1500\r\n"),
+            (
+                &["TS.ADD", "ts", "1500", "4"],
+                "-ERR TSDB: Error at upsert, update is not supported when DUPLICATE_POLICY is set \
+                 to BLOCK mode\r\n",
+            ),
+            (
+                &["TS.RANGE", "ts", "-", "+", "AGGREGATION", "avg", "1000"],
+                "*1\r\n*2\r\n:1000\r\n+2\r\n",
+            ),
+            (&["TS.CREATE", "tsmax"], "+OK\r\n"),
+            (
+                &["TS.CREATERULE", "ts", "tsmax", "AGGREGATION", "max", "1000"],
+                "+OK\r\n",
+            ),
+            (&["TS.ADD", "ts", "2000", "5"], ":2000\r\n"),
+            (&["TS.ADD", "ts", "3000", "7"], ":3000\r\n"),
+            (
+                &["TS.RANGE", "tsmax", "-", "+"],
+                "*1\r\n*2\r\n:2000\r\n+5\r\n",
+            ),
+            (
+                &[
+                    "TS.MRANGE",
+                    "1000",
+                    "1000",
+                    "WITHLABELS",
+                    "FILTER",
+                    "sensor=a",
+                ],
+                "*1\r\n*3\r\n$2\r\nts\r\n*1\r\n*2\r\n+sensor\r\n+a\r\n*1\r\n*2\r\n:1000\r\n+1\r\n",
+            ),
+        ],
+    );
+}
@@ -0,0 +1,54 @@
+//! Events posted to the webhooks
+
+mod common;
+
+use common::{assert_reply, command, connect_resp, server, webhook_request};
+use lunatic::net::TcpListener;
+
+#[lunatic::test]
+fn webhook() {
+    // Invalid URLs are reported by start, the server isn't started
+    let invalid = server()
+        .webhook("hook:*", "https://localhost/events")
+        .start();
+    assert_eq!(
+        invalid.err().as_deref(),
+        Some("Invalid URL, it must start with http://: https://localhost/events")
+    );
+    let invalid = server().otlp("localhost:4318", 1.0).start();
+    assert_eq!(
+        invalid.err().as_deref(),
+        Some("Invalid URL, it must start with http://: localhost:4318")
+    );
+
+    // Bound first, so the webhook events of the whole test are queued on it
+    let webhook = TcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook_addr = webhook.local_addr().unwrap();
+    let server = server()
+        .webhook("hook:*", &format!("http://{webhook_addr}/events"))
+        .start()
+        .unwrap();
+
+    // The webhook receives the writes to the keys matching its pattern, not the other ones
+    let mut stream = connect_resp(&server);
+    assert_reply(&mut stream, &command(&["SET", "hook:1", "a"]), "+OK\r\n");
+    assert_reply(&mut stream, &command(&["SET", "other", "a"]), "+OK\r\n");
+    assert_reply(&mut stream, &command(&["SET", "hook:1", "b"]), "+OK\r\n");
+    let events: Vec<String> = std::iter::repeat_with(|| webhook_request(&webhook))
+        .filter(|event| event.contains(r#""operation":"set""#))
+        .take(2)
+        .collect();
+    let expected = [
+        concat!(
+            r#""db":0,"operation":"set","key":"hook:1","old":null,"#,
+            r#""new":{"type":"string","value":"a"}}"#
+        ),
+        concat!(
+            r#""db":0,"operation":"set","key":"hook:1","old":{"type":"string","value":"a"},"#,
+            r#""new":{"type":"string","value":"b"}}"#
+        ),
+    ];
+    for (event, expected) in events.iter().zip(expected) {
+        assert!(event.ends_with(expected), "{event}");
+    }
+}
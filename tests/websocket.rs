@@ -0,0 +1,70 @@
+//! Byte-exact frames of the WebSocket gateway
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::{assert_reply, connect, server, websocket_frame};
+
+#[lunatic::test]
+fn websocket() {
+    let server = server()
+        .websocket(0)
+        .websocket_origin("http://app.example")
+        .start()
+        .unwrap();
+    let port = server.config().websocket_port;
+
+    // Browser pages of other origins can't open WebSocket connections unless allowed
+    let upgrades = [
+        ("http://evil.example", "HTTP/1.1 403 Forbidden\r\n"),
+        ("http://localhost", "HTTP/1.1 101 Switching Protocols\r\n"),
+        ("http://app.example", "HTTP/1.1 101 Switching Protocols\r\n"),
+    ];
+    for (origin, expected) in upgrades {
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nOrigin: {origin}\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        );
+        assert_reply(&mut connect(port), request.as_bytes(), expected);
+    }
+
+    // WebSocket binary messages carry RESP and text messages JSON commands
+    let mut websocket = connect(port);
+    assert_reply(
+        &mut websocket,
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+    );
+    let cases: &[(&[(u8, &[u8])], (u8, &[u8]))] = &[
+        (&[(0x2, b"*1\r\n$4\r\nPING\r\n")], (0x2, b"+PONG\r\n")),
+        (
+            &[(0x1, br#"["SET", "ws", 1]"#)],
+            (0x1, br#"{"result":"OK"}"#),
+        ),
+        (
+            &[(0x1, br#"["NOPE"]"#)],
+            (0x1, br#"{"error":"INVALID_COMMAND"}"#),
+        ),
+        // A command can be split between messages
+        (
+            &[(0x2, b"*2\r\n$3\r\nGET\r\n"), (0x2, b"$2\r\nws\r\n")],
+            (0x2, b"$1\r\n1\r\n"),
+        ),
+        (&[(0x9, b"hi")], (0xA, b"hi")),
+        (&[(0x8, b"")], (0x8, &[0x03, 0xE8])),
+    ];
+    for (frames, (opcode, payload)) in cases {
+        for (opcode, payload) in frames.iter() {
+            websocket
+                .write_all(&websocket_frame(*opcode, payload, true))
+                .unwrap();
+        }
+        let expected = websocket_frame(*opcode, payload, false);
+        let mut reply = vec![0; expected.len()];
+        websocket.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected, "Reply of {frames:?}");
+    }
+}